@@ -1,80 +1,625 @@
 use async_recursion::async_recursion;
-use clap::Args;
-use log::warn;
+use clap::{Args, ValueEnum};
 use prost::Message;
 use sha2::Digest;
 use sha2::Sha256;
-use std::time::SystemTime;
-use std::{collections::HashMap, path::Path, pin::pin};
-use tokio::sync::Semaphore;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, SystemTime};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
 
-use futures::future::{try_join, try_join_all, BoxFuture};
+use futures::{
+    future::{try_join, try_join_all, BoxFuture},
+    stream::{self, StreamExt, TryStreamExt},
+};
 use tokio::{
-    fs::{self, read_dir, File},
-    io::{self, AsyncReadExt, AsyncSeekExt},
+    fs,
+    io::{self, AsyncRead, AsyncReadExt},
 };
 
-use crate::constants::CHUNK_SIZE;
 use crate::{
-    data::backup::{sub_dir_entry::Content, DirEntry, FileEntry, Snapshot, SubDirEntry},
-    storage::Collection,
-    util::{fs::sanitize_os_string, hash::read_hash, time::as_unix_timestamp},
+    data::{
+        backup::{sub_dir_entry::Content, BackupStats, DirEntry, FileEntry, Snapshot, SubDirEntry},
+        config::DatabaseConfig,
+    },
+    storage::{file::is_repository, Collection},
+    util::{
+        database::{dump_database, DATABASE_DUMP_DIR},
+        fs::sanitize_os_string,
+        fs_snapshot::FsSnapshot,
+        glob::PathFilter,
+        hash::{
+            read_chunk_hashes, read_hash, run_blocking, sha256_hex, split_blob_key, HashingReader,
+            SHA256_KEY_PREFIX,
+        },
+        hooks::{fire_hook, HookEvent},
+        lock::with_lock,
+        sampled_log::SampledLog,
+        size::parse_size,
+        time::{as_unix_timestamp_nanos, modified_matches},
+    },
 };
-use log::{debug, info};
+use tracing::{debug, error, field, info, instrument, warn, Span};
 
 use super::common::*;
 
-#[derive(Debug, Args)]
-pub struct BackupArgs {}
+pub mod source;
+
+use source::{BackupSource, EntryType, LocalBackupSource, SourceReader};
+
+#[derive(Debug, Default, Clone, Args)]
+pub struct BackupArgs {
+    /// Back up block devices found in the backup target.
+    #[arg(long)]
+    pub block_devices: bool,
+    /// Use fixed-block mode for regular files too, for disk images.
+    #[arg(long)]
+    pub fixed_block: bool,
+    /// Block size in bytes for fixed-block mode. Defaults to 4 MiB, or
+    /// tuning.block_size in the config.
+    #[arg(long)]
+    pub block_size: Option<usize>,
+    /// Store all-zero blocks in fixed-block mode too. They are left out by
+    /// default, and restored as holes in regular files.
+    #[arg(long)]
+    pub store_zero_blocks: bool,
+    /// Mark the snapshot as expiring after this long, e.g. "90d". Expired
+    /// snapshots are forgotten by forget --expired.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub expire_after: Option<Duration>,
+    /// Tag the snapshot with a retention class, e.g. "monthly". Forget applies
+    /// its rules to each class separately, and --keep-class keeps a number of
+    /// snapshots of a class on top of them.
+    #[arg(long)]
+    pub retention_class: Option<String>,
+    /// Only back up paths matching this glob, relative to the backup target.
+    /// Everything below a matching directory is included. Can be repeated.
+    #[arg(long)]
+    pub include: Vec<String>,
+    /// Approximate memory limit for file buffers, e.g. "512M". Fewer chunks
+    /// are read ahead when they don't fit, and chunks larger than half of
+    /// the limit are streamed instead of read whole. Blocks of --fixed-block
+    /// are always read whole.
+    #[arg(long, value_parser = parse_size)]
+    pub max_memory: Option<u64>,
+    /// Create the snapshot even if the backup target is empty or missing,
+    /// which usually means a misconfigured path or an unmounted drive.
+    #[arg(long)]
+    pub allow_empty_source: bool,
+    /// Back up other freebck repositories found in the backup target. The
+    /// repository being backed up to is always left out.
+    #[arg(long)]
+    pub include_repos: bool,
+    /// Leave out directories that have no files below them, such as empty
+    /// cache directories.
+    #[arg(long)]
+    pub prune_empty_dirs: bool,
+    /// Leave out entries of this type without warning about them. Such
+    /// entries can't be backed up yet, so they are skipped either way.
+    /// Can be repeated.
+    #[arg(long, value_enum)]
+    pub exclude_type: Vec<SpecialType>,
+    /// Also back up the further archives listed in the config.
+    #[arg(long)]
+    pub all: bool,
+    /// How many archives to back up at the same time with --all. Overrides
+    /// parallel_archives in the config, defaults to one.
+    #[arg(long, requires = "all")]
+    pub parallel: Option<usize>,
+    /// Attach metadata to the snapshot, e.g. "commit=1a2b3c". Can be
+    /// repeated.
+    #[arg(long, value_parser = parse_meta)]
+    pub meta: Vec<(String, String)>,
+    /// Read the backup target and report how much would be uploaded, leaving
+    /// out data the repository already has, without creating a snapshot.
+    #[arg(long)]
+    pub estimate: bool,
+}
+
+/// How much of a backup the repository lacks, as found by --estimate.
+#[derive(Debug, Default, PartialEq)]
+pub struct BackupEstimate {
+    /// Blobs read from the backup target, and their total size. Files
+    /// unchanged since the last snapshot aren't read.
+    pub blobs: u64,
+    pub bytes: u64,
+    /// Blobs missing from the repository, and their total size.
+    pub new_blobs: u64,
+    pub new_bytes: u64,
+}
+
+/// Parse a "key=value" metadata pair.
+pub fn parse_meta(text: &str) -> Result<(String, String), String> {
+    match text.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Invalid metadata {:?}, expected key=value", text)),
+    }
+}
 
-trait IgnoreAlreadyExists {
-    fn ignore_already_exists(self) -> io::Result<()>;
+/// Kinds of directory entries other than regular files and directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SpecialType {
+    Socket,
+    Fifo,
+    /// Block and character devices. Block devices are still backed up with
+    /// --block-devices unless this is excluded.
+    Device,
+    Symlink,
 }
 
-impl<T> IgnoreAlreadyExists for io::Result<T> {
-    fn ignore_already_exists(self) -> io::Result<()> {
+impl SpecialType {
+    fn of(entry_type: EntryType) -> Option<Self> {
+        match entry_type {
+            EntryType::Socket => Some(Self::Socket),
+            EntryType::Fifo => Some(Self::Fifo),
+            EntryType::BlockDevice | EntryType::CharDevice => Some(Self::Device),
+            EntryType::Symlink => Some(Self::Symlink),
+            EntryType::File | EntryType::Dir => None,
+        }
+    }
+
+    fn plural(self) -> &'static str {
         match self {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::AlreadyExists {
-                    Ok(())
-                } else {
-                    Err(e)
-                }
-            }
+            Self::Socket => "sockets",
+            Self::Fifo => "FIFOs",
+            Self::Device => "devices",
+            Self::Symlink => "symlinks",
         }
     }
 }
 
-pub async fn backup(context: &ProgramContext, args: &BackupArgs) -> CommandResult {
-    info!("Backup starting");
-    let started = as_unix_timestamp(SystemTime::now());
+/// What the walk of the backup target does with a directory entry.
+pub(super) enum Walk {
+    /// Back up its contents, as a file or a block device.
+    File,
+    /// Walk it. `included` tells whether it matched --include itself.
+    Dir {
+        included: bool,
+    },
+    Skip,
+}
 
-    let previous_snapshot_number = get_highest_snapshot_number(context).await?;
-    let mut previous_snapshot_root: Option<DirEntry> = None;
-    if previous_snapshot_number > 0 {
-        let previous_snapshot_name =
-            format!("{}/{}", context.archive_name, previous_snapshot_number);
-        let mut previous_snapshot_buffer: Vec<u8> = Vec::new();
-        context
-            .storage
-            .read(
-                Collection::Snapshot,
-                &previous_snapshot_name,
-                &mut previous_snapshot_buffer,
+pub(super) struct BackupState<'a> {
+    pub(super) source: &'a dyn BackupSource,
+    /// Paths are kept under the local path of the source, so that they can
+    /// be shown as they are. Without one they are relative.
+    pub(super) root: PathBuf,
+    pub(super) filter: PathFilter,
+    chunk_size: usize,
+    /// Largest object the storage accepts, if it has a limit.
+    max_object_size: Option<u64>,
+    /// Memory budget for file buffers in KiB, if limited.
+    memory: Option<Arc<Semaphore>>,
+    memory_limit_kib: u32,
+    /// Device and inode of directories that are never backed up.
+    own_dirs: Vec<(u64, u64)>,
+    /// Number of entries skipped by type, other than excluded ones.
+    skipped: Mutex<BTreeMap<SpecialType, u64>>,
+    /// Bytes uploaded so far, leaving out data the repository already had.
+    new_bytes: AtomicU64,
+    /// Prepended to the hex hash for blob keys.
+    key_prefix: &'static str,
+    /// Blobs the repository is known to have, listed at the start and added
+    /// to as blobs are uploaded. Writing these would only fail with
+    /// AlreadyExists after a round trip.
+    known_blobs: Mutex<HashSet<String>>,
+    /// With --estimate, sizes of the blobs by key instead of uploading them.
+    estimate: Option<Mutex<HashMap<String, u64>>>,
+    /// Settings that change what is backed up, hashed along with the
+    /// metadata so that directories aren't reused across different settings.
+    metadata_salt: String,
+    /// Debug lines about the entries gone through.
+    pub(super) path_log: SampledLog,
+    /// Limits the files open at the same time.
+    file_opens: Semaphore,
+}
+
+impl<'a> BackupState<'a> {
+    pub(super) fn new(
+        context: &ProgramContext,
+        args: &BackupArgs,
+        source: &'a dyn BackupSource,
+    ) -> CommandResult<Self> {
+        let filter = PathFilter::new(&args.include);
+        let root = source
+            .local_path()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        // Files are split into smaller chunks for storage that can't take
+        // full-size ones.
+        let max_object_size = context.storage.max_object_size();
+        let tuning = &context.tuning;
+        let max_chunk_size = max_object_size.map_or(tuning.chunk_size, |max| {
+            tuning.chunk_size.min(max.try_into().unwrap_or(usize::MAX))
+        });
+        let metadata_salt = format!(
+            "{:?}",
+            (
+                args.fixed_block,
+                args.block_devices,
+                args.block_size,
+                args.store_zero_blocks,
+                &args.include,
+                args.include_repos,
+                args.prune_empty_dirs,
+                max_object_size,
+                tuning.chunk_size,
+                tuning.block_size
             )
+        );
+        // Half of the limit goes to file buffers, the rest is left for the
+        // directory tree, copies made while storing and the runtime. The
+        // chunk size stays the same, so that chunks deduplicate whatever the
+        // limit is.
+        let memory_limit = args.max_memory.map(|max_memory| max_memory / 2);
+        if memory_limit.is_some_and(|limit| limit < tuning.read_buffer_size as u64) {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                format!(
+                    "Memory limit must be at least {} bytes",
+                    2 * tuning.read_buffer_size
+                ),
+            ));
+        }
+        let memory_limit_kib =
+            memory_limit.map_or(0, |limit| (limit / 1024).clamp(1, u32::MAX as u64) as u32);
+        if let Some(max_memory) = args.max_memory {
+            debug!("Memory limit {} bytes", max_memory);
+        }
+
+        Ok(Self {
+            source,
+            root,
+            filter,
+            chunk_size: max_chunk_size,
+            max_object_size,
+            memory: memory_limit.map(|_| Arc::new(Semaphore::new(memory_limit_kib as usize))),
+            memory_limit_kib,
+            own_dirs: Vec::new(),
+            skipped: Default::default(),
+            new_bytes: AtomicU64::new(0),
+            key_prefix: "",
+            known_blobs: Default::default(),
+            estimate: None,
+            metadata_salt,
+            path_log: SampledLog::new("entries"),
+            file_opens: Semaphore::new(tuning.open_files),
+        })
+    }
+
+    /// Path relative to the source, as given to it.
+    pub(super) fn source_relative<'p>(&self, path: &'p Path) -> &'p Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    fn blob_key(&self, hash: String) -> String {
+        match self.key_prefix {
+            "" => hash,
+            prefix => format!("{}{}", prefix, hash),
+        }
+    }
+
+    /// Leave out the repository and the state directory when they are inside
+    /// the backup target, as with the default path of "..".
+    pub(super) async fn skip_own_dirs(&mut self, context: &ProgramContext) {
+        let source = self.source;
+        let Some(root) = source.local_path() else {
+            return;
+        };
+        // When backing up from a file system snapshot, the directories are
+        // looked up at the same place within the snapshot.
+        let target = fs::canonicalize(&context.backup_target).await.ok();
+        let own_dirs = [
+            context.storage.local_path(),
+            Some(context.state_dir.as_path()),
+        ];
+        for dir in own_dirs.into_iter().flatten() {
+            let mut dir = fs::canonicalize(dir).await.unwrap_or(dir.to_path_buf());
+            if let Some(relative) = target
+                .as_ref()
+                .and_then(|target| dir.strip_prefix(target).ok())
+            {
+                dir = root.join(relative);
+            }
+            if let Ok(metadata) = fs::metadata(&dir).await {
+                self.own_dirs.push((metadata.dev(), metadata.ino()));
+            }
+        }
+    }
+
+    /// Decide what the walk does with the entry at `path`, logging and
+    /// counting the entries that are left out. `included` tells whether a
+    /// parent matched --include.
+    pub(super) async fn walk_entry(
+        &self,
+        context: &ProgramContext,
+        args: &BackupArgs,
+        path: &Path,
+        entry_type: EntryType,
+        included: bool,
+    ) -> CommandResult<Walk> {
+        // Without an include match on a parent, each entry has to match on its
+        // own, or be a directory that may contain matches.
+        let mut entry_included = included;
+        if !included {
+            let relative_path = filter_path(self.source_relative(path))?;
+            entry_included = self.filter.includes(&relative_path);
+            let descend =
+                entry_type == EntryType::Dir && self.filter.should_descend(&relative_path);
+            if !entry_included && !descend {
+                return Ok(Walk::Skip);
+            }
+        }
+
+        let special_type = SpecialType::of(entry_type);
+        if special_type.is_some_and(|t| args.exclude_type.contains(&t)) {
+            self.path_log
+                .log(format_args!("Excluding {}", path.display()));
+            return Ok(Walk::Skip);
+        }
+
+        if entry_type == EntryType::File
+            || (args.block_devices && entry_type == EntryType::BlockDevice)
+        {
+            let block_size = args.block_size.unwrap_or(context.tuning.block_size) as u64;
+            let fixed_block = args.fixed_block || entry_type == EntryType::BlockDevice;
+            let max_object_size = self.max_object_size.filter(|max| block_size > *max);
+            if let Some(max) = max_object_size.filter(|_| fixed_block) {
+                warn!(
+                    "Skipping {}, its blocks of {} bytes are larger than the storage accepts ({} bytes), pass a smaller --block-size",
+                    path.display(),
+                    block_size,
+                    max
+                );
+                return Ok(Walk::Skip);
+            }
+            return Ok(Walk::File);
+        }
+        if entry_type == EntryType::Dir {
+            if let Some(reason) = skipped_dir_reason(args, self, path).await? {
+                info!("Skipping {}, {}", path.display(), reason);
+                return Ok(Walk::Skip);
+            }
+            return Ok(Walk::Dir {
+                included: entry_included,
+            });
+        }
+        if let Some(special_type) = special_type {
+            self.path_log.log(format_args!(
+                "Skipping {}, it can't be backed up yet",
+                path.display()
+            ));
+            *self
+                .skipped
+                .lock()
+                .unwrap()
+                .entry(special_type)
+                .or_default() += 1;
+        }
+        Ok(Walk::Skip)
+    }
+
+    /// Wait until `bytes` of the memory budget are free and reserve them.
+    async fn reserve_memory(&self, bytes: u64) -> CommandResult<Option<OwnedSemaphorePermit>> {
+        let Some(ref memory) = self.memory else {
+            return Ok(None);
+        };
+
+        let kib = bytes.div_ceil(1024).min(self.memory_limit_kib as u64) as u32;
+        memory
+            .clone()
+            .acquire_many_owned(kib)
             .await
-            .into_command_result(CommandErrorKind::System, "Failed to get previous snapshot")?;
+            .map(Some)
+            .into_command_result(CommandErrorKind::System, "Failed to reserve memory")
+    }
+
+    /// Whether chunks of `size` bytes are streamed instead of read whole,
+    /// because they are large or don't fit the memory budget.
+    fn streams_chunk(&self, context: &ProgramContext, size: usize) -> bool {
+        size > context.tuning.stream_chunk_size
+            || (self.memory.is_some() && size.div_ceil(1024) > self.memory_limit_kib as usize)
+    }
 
-        let previous_snapshot = Snapshot::decode(previous_snapshot_buffer.as_slice())
-            .into_command_result(CommandErrorKind::System, "Failed to decode snapshot")?;
+    /// Upload a blob, counting its size if the repository didn't have it.
+    /// Blobs that aren't `compressible` are stored uncompressed.
+    async fn upload_blob(
+        &self,
+        context: &ProgramContext,
+        hash: &str,
+        data: &[u8],
+        compressible: bool,
+    ) -> io::Result<()> {
+        if let Some(max) = self.max_object_size.filter(|max| data.len() as u64 > *max) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Object of {} bytes is larger than the storage accepts ({} bytes)",
+                    data.len(),
+                    max
+                ),
+            ));
+        }
+        if let Some(ref estimate) = self.estimate {
+            estimate
+                .lock()
+                .unwrap()
+                .insert(hash.to_string(), data.len() as u64);
+            return Ok(());
+        }
+        if !self.known_blobs.lock().unwrap().insert(hash.to_string()) {
+            return Ok(());
+        }
+        if compressible {
+            context.storage.write(Collection::Blob, hash, data).await?;
+        } else {
+            context
+                .storage
+                .write_uncompressed(Collection::Blob, hash, data)
+                .await?;
+        }
+        self.new_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
 
+    /// Upload a blob of `size` bytes streamed from `reader`, counting its size
+    /// if the repository didn't have it. The stream has to hash to `key`, so
+    /// that a file changing while it is read can't leave a blob under the
+    /// wrong key. The reader isn't read at all if the blob isn't uploaded.
+    async fn upload_blob_stream(
+        &self,
+        context: &ProgramContext,
+        key: &str,
+        size: u64,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> io::Result<()> {
+        if let Some(ref estimate) = self.estimate {
+            estimate.lock().unwrap().insert(key.to_string(), size);
+            return Ok(());
+        }
+        if !self.known_blobs.lock().unwrap().insert(key.to_string()) {
+            return Ok(());
+        }
+        let mut hashing = HashingReader::new(reader);
+        context
+            .storage
+            .write_stream(Collection::Blob, key, &mut hashing)
+            .await?;
+        let bytes = hashing.bytes();
+        if bytes != size || hashing.finish() != split_blob_key(key).1 {
+            if let Err(e) = context.storage.delete(Collection::Blob, key).await {
+                warn!("Failed to remove blob {}: {}", key, e);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "File changed while it was backed up",
+            ));
+        }
+        self.new_bytes.fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub(super) fn warn_skipped(&self) {
+        for (special_type, count) in self.skipped.lock().unwrap().iter() {
+            warn!(
+                "Skipped {} {}, which can't be backed up yet, pass --exclude-type {} to leave them out without a warning",
+                count,
+                special_type.plural(),
+                special_type.to_possible_value().unwrap().get_name()
+            );
+        }
+    }
+}
+
+/// Back up each context's archive, up to `parallel` at a time. The contexts
+/// should share their storage so that they share its connections and
+/// limits. A failed archive doesn't stop the others.
+pub async fn backup_all(
+    contexts: &[ProgramContext],
+    args: &BackupArgs,
+    parallel: usize,
+) -> CommandResult {
+    let mut names = HashSet::new();
+    for context in contexts {
+        if !names.insert(context.archive_name.as_str()) {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                format!("Archive {} is configured twice", context.archive_name),
+            ));
+        }
+    }
+
+    let results: Vec<_> = stream::iter(contexts)
+        .map(|context| async move {
+            info!("Backing up archive {}", context.archive_name);
+            (context, backup(context, args).await)
+        })
+        .buffer_unordered(parallel.max(1))
+        .collect()
+        .await;
+
+    let mut failures = MultiError::default();
+    for (context, result) in results {
+        if let Err(e) = result {
+            error!("Backup of archive {} failed: {}", context.archive_name, e);
+            failures.push(context.backup_target.clone(), e);
+        }
+    }
+    failures.into_result("Backup of all archives")
+}
+
+/// Read how much of a backup of the target the repository is missing.
+pub async fn estimate_backup(
+    context: &ProgramContext,
+    args: &BackupArgs,
+) -> CommandResult<BackupEstimate> {
+    let source = LocalBackupSource::new(context.backup_target.clone());
+    estimate_backup_from(context, args, &source).await
+}
+
+async fn estimate_backup_from(
+    context: &ProgramContext,
+    args: &BackupArgs,
+    source: &dyn BackupSource,
+) -> CommandResult<BackupEstimate> {
+    let mut state = BackupState::new(context, args, source)?;
+    state.estimate = Some(Default::default());
+    let previous_snapshot = get_latest_snapshot(context, None)
+        .await?
+        .map(|(_, snapshot)| snapshot);
+    backup_root(context, args, &mut state, previous_snapshot).await?;
+
+    let blobs: Vec<(String, u64)> = state
+        .estimate
+        .unwrap()
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .collect();
+    let keys: Vec<String> = blobs.iter().map(|(key, _)| key.clone()).collect();
+    let exists = context
+        .storage
+        .has_many(Collection::Blob, &keys)
+        .await
+        .into_io_command_result("Failed to look up blobs")?;
+
+    let mut estimate = BackupEstimate::default();
+    for ((_, size), exists) in blobs.iter().zip(exists) {
+        estimate.blobs += 1;
+        estimate.bytes += size;
+        if !exists {
+            estimate.new_blobs += 1;
+            estimate.new_bytes += size;
+        }
+    }
+    Ok(estimate)
+}
+
+/// Back up the target's contents, returning the key and size of the root
+/// directory entry. Files unchanged since `previous_snapshot` are reused.
+async fn backup_root(
+    context: &ProgramContext,
+    args: &BackupArgs,
+    state: &mut BackupState<'_>,
+    previous_snapshot: Option<Snapshot>,
+) -> CommandResult<(String, u64)> {
+    let mut previous_snapshot_root: Option<DirEntry> = None;
+    if let Some(ref snapshot) = previous_snapshot {
         let mut previous_root_buffer: Vec<u8> = Vec::new();
         context
             .storage
             .read(
                 Collection::Blob,
-                &previous_snapshot.root_hash,
+                &snapshot.root_hash,
                 &mut previous_root_buffer,
             )
             .await
@@ -90,31 +635,212 @@ pub async fn backup(context: &ProgramContext, args: &BackupArgs) -> CommandResul
     }
 
     // Create a backup entry and write it to the storage.
-    let backup_root_entry = backup_dir(
-        context,
-        &args,
-        &context.backup_target,
-        previous_snapshot_root.as_ref(),
-    )
-    .await?
-    .encode_to_vec();
-    let root_hash = format!("{:x}", Sha256::digest(&backup_root_entry));
+    if use_prefixed_keys(context, previous_snapshot.as_ref()).await? {
+        state.key_prefix = SHA256_KEY_PREFIX;
+    }
+    state.skip_own_dirs(context).await;
+    let source_exists = match state.source.metadata(Path::new("")).await {
+        Ok(_) => true,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => {
+            return Err(
+                e.into_command_error(CommandErrorKind::System, "Failed to access backup target")
+            )
+        }
+    };
+    let backup_root = if source_exists {
+        let backup_root = backup_dir(
+            context,
+            args,
+            state,
+            &state.root,
+            state.filter.is_empty(),
+            previous_snapshot_root.as_ref(),
+        )
+        .await?;
+        if previous_snapshot_root.is_some_and(|previous_root| {
+            !previous_root.metadata_hash.is_empty()
+                && previous_root.metadata_hash == backup_root.metadata_hash
+        }) {
+            info!("Nothing changed since the previous snapshot");
+        }
+        backup_root
+    } else if args.allow_empty_source {
+        DirEntry::default()
+    } else {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            format!(
+                "Backup target {} does not exist, pass --allow-empty-source to back it up anyway",
+                context.backup_target.display()
+            ),
+        ));
+    };
+    state.path_log.finish();
+    state.warn_skipped();
+    if backup_root.file.is_empty() && backup_root.sub_dir.is_empty() && !args.allow_empty_source {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            format!(
+                "Nothing to back up in {}, pass --allow-empty-source to create an empty snapshot",
+                context.backup_target.display()
+            ),
+        ));
+    }
+    let size = backup_root.size;
+    let root_hash = store_dir_entry(context, state, backup_root).await?;
+    Ok((root_hash, size))
+}
+
+/// Store `dir_entry` as a blob of its own and return its key.
+async fn store_dir_entry(
+    context: &ProgramContext,
+    state: &BackupState<'_>,
+    dir_entry: DirEntry,
+) -> CommandResult<String> {
+    let (encoded, hash) = run_blocking(move || {
+        let encoded = dir_entry.encode_to_vec();
+        let hash = format!("{:x}", Sha256::digest(&encoded));
+        (encoded, hash)
+    })
+    .await
+    .into_command_result(CommandErrorKind::System, "Failed to encode directory entry")?;
+    let hash = state.blob_key(hash);
+
+    state
+        .upload_blob(context, &hash, encoded.as_slice(), true)
+        .await
+        .ignore_already_exists()
+        .into_command_result(CommandErrorKind::System, "Failed to upload directory entry")?;
+    Ok(hash)
+}
+
+pub async fn backup(context: &ProgramContext, args: &BackupArgs) -> CommandResult {
+    if let Some(ref config) = context.database {
+        return backup_database(context, args, config).await;
+    }
+    let Some(ref config) = context.fs_snapshot else {
+        let source = LocalBackupSource::new(context.backup_target.clone());
+        return backup_from(context, args, &source).await;
+    };
+    let snapshot = FsSnapshot::create(config).await?;
+    let source = LocalBackupSource::new(snapshot.path().to_path_buf());
+    let result = backup_from(context, args, &source).await;
+    let removed = snapshot.remove().await;
+    result?;
+    removed
+}
+
+/// Dump the database into the state directory and back up the dump.
+async fn backup_database(
+    context: &ProgramContext,
+    args: &BackupArgs,
+    config: &DatabaseConfig,
+) -> CommandResult {
+    if context.fs_snapshot.is_some() {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "An archive can't have both fs_snapshot and database set".to_string(),
+        ));
+    }
+    let dir = context.state_dir.join(DATABASE_DUMP_DIR);
+    // Left over from an interrupted backup.
+    match fs::remove_dir_all(&dir).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            return Err(e.into_io_command_error("Failed to remove old database dump"))
+        }
+        _ => {}
+    }
+    fs::create_dir_all(&dir)
+        .await
+        .into_io_command_result("Failed to create database dump directory")?;
 
+    let result = async {
+        let mut meta = dump_database(config, &dir).await?;
+        meta.extend(args.meta.iter().cloned());
+        let args = BackupArgs {
+            meta,
+            ..args.clone()
+        };
+        backup_from(context, &args, &LocalBackupSource::new(dir.clone())).await
+    }
+    .await;
+    if let Err(e) = fs::remove_dir_all(&dir).await {
+        warn!("Failed to remove database dump {}: {}", dir.display(), e);
+    }
+    result
+}
+
+/// Back up `source` instead of the backup target directory.
+pub async fn backup_from(
+    context: &ProgramContext,
+    args: &BackupArgs,
+    source: &dyn BackupSource,
+) -> CommandResult {
+    if args.estimate {
+        let estimate = estimate_backup_from(context, args, source).await?;
+        println!(
+            "{} of {} bytes in {} of {} blobs would be uploaded",
+            estimate.new_bytes, estimate.bytes, estimate.new_blobs, estimate.blobs
+        );
+        return Ok(());
+    }
+
+    info!("Backup starting");
+    // Prune takes an exclusive lock, so it can't delete the blobs that are
+    // known to exist while the backup runs.
+    with_lock(context, false, run_backup(context, args, source)).await
+}
+
+async fn run_backup(
+    context: &ProgramContext,
+    args: &BackupArgs,
+    source: &dyn BackupSource,
+) -> CommandResult {
+    let (started, started_nanos) = as_unix_timestamp_nanos(SystemTime::now());
+    let mut state = BackupState::new(context, args, source)?;
+    state.known_blobs = Mutex::new(
+        context
+            .storage
+            .get_collection_items(Collection::Blob)
+            .await
+            .into_io_command_result("Failed to list blobs")?
+            .into_iter()
+            .collect(),
+    );
+    // The latest snapshot is read once, both to reuse its files and to
+    // order the new one after it.
+    let previous_snapshot = get_latest_snapshot(context, None)
+        .await?
+        .map(|(_, snapshot)| snapshot);
+    let sequence = previous_snapshot
+        .as_ref()
+        .map_or(0, |previous| previous.sequence)
+        + 1;
+    let (root_hash, size) = backup_root(context, args, &mut state, previous_snapshot).await?;
     context
         .storage
-        .write(Collection::Blob, &root_hash, backup_root_entry.as_slice())
+        .flush()
         .await
-        .ignore_already_exists()
-        .into_command_result(
-            CommandErrorKind::System,
-            "Failed to upload backup root entry",
-        )?;
+        .into_io_command_result("Failed to write blobs")?;
 
-    let finished = as_unix_timestamp(SystemTime::now());
+    let (finished, finished_nanos) = as_unix_timestamp_nanos(SystemTime::now());
+    let expires = match args.expire_after {
+        Some(expire_after) => started + expire_after.as_secs() as i64,
+        None => 0,
+    };
     let snapshot = Snapshot {
         root_hash,
         started,
         finished,
+        client_id: context.client_id.clone(),
+        expires,
+        retention_class: args.retention_class.clone().unwrap_or_default(),
+        started_nanos,
+        finished_nanos,
+        meta: args.meta.iter().cloned().collect(),
+        sequence,
+        ..Default::default()
     };
 
     const MAX_LOOP_ITERATIONS: u32 = 100;
@@ -135,6 +861,27 @@ pub async fn backup(context: &ProgramContext, args: &BackupArgs) -> CommandResul
             Ok(_) => {
                 // Backup complete.
                 info!("Backup complete. Wrote snapshot: {}", snapshot_name);
+                let duration_nanos = (finished - started) as i128 * 1_000_000_000
+                    + finished_nanos as i128
+                    - started_nanos as i128;
+                let stats = BackupStats {
+                    started,
+                    duration_millis: (duration_nanos / 1_000_000).max(0) as u64,
+                    size,
+                    new_bytes: state.new_bytes.load(Ordering::Relaxed),
+                    stored_bytes: 0,
+                };
+                record_stats(context, &snapshot_name, stats).await;
+                fire_hook(
+                    context,
+                    HookEvent::SnapshotCreated {
+                        snapshot: &snapshot_name,
+                        root_hash: &snapshot.root_hash,
+                        started,
+                        finished,
+                    },
+                )
+                .await;
                 return Ok(());
             }
             Err(e) => {
@@ -157,39 +904,19 @@ pub async fn backup(context: &ProgramContext, args: &BackupArgs) -> CommandResul
     ))
 }
 
-async fn get_highest_snapshot_number(context: &ProgramContext) -> CommandResult<u32> {
-    // Find the highest snapshot number.
-    let snapshots = context
-        .storage
-        .get_collection_items(Collection::Snapshot)
-        .await
-        .into_command_result(CommandErrorKind::System, "Failed to get snapshots")?;
-    let mut highest_snapshot: u32 = 0;
-    for snapshot_name in snapshots {
-        let parts: Vec<_> = snapshot_name.split('/').collect();
-        if parts.len() != 2 {
-            warn!("Invalid snapshot name: {}", snapshot_name);
-            continue;
-        }
-
-        if parts[0] != context.archive_name {
-            continue;
-        }
-        if let Ok(snapshot_number) = parts[1].parse::<u32>() {
-            highest_snapshot = highest_snapshot.max(snapshot_number);
-        }
-    }
-    Ok(highest_snapshot)
-}
-
 #[async_recursion]
+#[instrument(level = "debug", skip_all, fields(path = %path.display()))]
 async fn backup_dir(
     context: &ProgramContext,
     args: &BackupArgs,
+    state: &BackupState<'_>,
     path: &Path,
+    included: bool,
     previous_snapshot: Option<&'async_recursion DirEntry>,
 ) -> CommandResult<DirEntry> {
-    debug!("Backing up directory: {:}", path.display());
+    state
+        .path_log
+        .log(format_args!("Backing up directory: {:}", path.display()));
 
     let mut previous_sub_dirs: HashMap<&String, &SubDirEntry> = HashMap::new();
     let mut previous_files: HashMap<&String, &FileEntry> = HashMap::new();
@@ -204,75 +931,148 @@ async fn backup_dir(
     }
 
     struct SubDirTaskResult {
-        sub_dir: SubDirEntry,
+        name: String,
+        metadata_hash: String,
+        /// None if the directory isn't kept.
+        sub_dir: Option<SubDirEntry>,
         size: u64,
     }
 
+    // Block devices are always read, so a directory that has them can't be
+    // reused from its metadata alone.
+    let mut reusable = true;
+
     let mut file_futures: Vec<BoxFuture<CommandResult<FileEntry>>> = Vec::new();
     let mut sub_dir_futures: Vec<BoxFuture<CommandResult<SubDirTaskResult>>> = Vec::new();
 
-    let mut dir_entries = read_dir(path).await.into_command_result(
-        CommandErrorKind::System,
-        format!("Failed to list directory entries in: {}", path.display()).as_str(),
-    )?;
-    while let Some(dir_entry) = dir_entries.next_entry().await.into_command_result(
-        CommandErrorKind::System,
-        format!("Failed to iterate directory entries in: {}", path.display()).as_str(),
-    )? {
-        let name = sanitize_os_string(dir_entry.file_name())?;
-        let path = dir_entry.path();
-        let file_type = dir_entry.file_type().await.into_command_result(
+    let dir_entries = state
+        .source
+        .read_dir(state.source_relative(path))
+        .await
+        .into_command_result(
             CommandErrorKind::System,
-            format!("Failed to get file type: {}", path.display()).as_str(),
+            format!("Failed to list directory entries in: {}", path.display()).as_str(),
         )?;
+    for dir_entry in dir_entries {
+        let path = path.join(&dir_entry.name);
+        let name = sanitize_os_string(dir_entry.name)?;
+        let entry_type = dir_entry.entry_type;
 
-        if file_type.is_file() {
-            let file_entry = previous_files.get(&name).copied();
-            file_futures.push(Box::pin(async move {
-                backup_file(context, name, &args, &path, file_entry).await
-            }));
-        } else if file_type.is_dir() {
-            let previous_sub_dirs = &previous_sub_dirs;
-            sub_dir_futures.push(Box::pin(async move {
-                let fetched_sub_dir: DirEntry;
-
-                let sub_dir_entry: Option<&DirEntry> = match previous_sub_dirs.get(&name) {
-                    Some(ref previous_sub_dir) => match previous_sub_dir.content {
-                        Some(Content::Inline(ref dir_entry)) => Some(dir_entry),
-                        Some(Content::Hash(ref hash)) => {
-                            fetched_sub_dir = get_dir_entry(context, &hash).await?;
-                            Some(&fetched_sub_dir)
-                        }
+        match state
+            .walk_entry(context, args, &path, entry_type, included)
+            .await?
+        {
+            Walk::Skip => {}
+            Walk::File => {
+                reusable &= entry_type == EntryType::File;
+                let file_entry = previous_files.get(&name).copied();
+                file_futures.push(Box::pin(async move {
+                    backup_file(context, name, &args, state, &path, file_entry).await
+                }));
+            }
+            Walk::Dir {
+                included: entry_included,
+            } => {
+                let previous_sub_dirs = &previous_sub_dirs;
+                sub_dir_futures.push(Box::pin(async move {
+                    let fetched_sub_dir: DirEntry;
+
+                    let sub_dir_entry: Option<&DirEntry> = match previous_sub_dirs.get(&name) {
+                        Some(ref previous_sub_dir) => match previous_sub_dir.content {
+                            Some(Content::Inline(ref dir_entry)) => Some(dir_entry),
+                            Some(Content::Hash(ref hash)) => {
+                                fetched_sub_dir = get_dir_entry(context, &hash).await?;
+                                Some(&fetched_sub_dir)
+                            }
+                            None => None,
+                        },
                         None => None,
-                    },
-                    None => None,
-                };
+                    };
 
-                backup_dir(context, &args, &path, sub_dir_entry)
-                    .await
-                    .map(|dir_entry| SubDirTaskResult {
-                        size: dir_entry.size,
-                        sub_dir: SubDirEntry {
+                    let dir_entry =
+                        backup_dir(context, &args, state, &path, entry_included, sub_dir_entry)
+                            .await?;
+                    let metadata_hash = dir_entry.metadata_hash.clone();
+
+                    // Parents of included paths are implied, but not kept if
+                    // nothing below them was included.
+                    let keep = (entry_included && !args.prune_empty_dirs)
+                        || !dir_entry.sub_dir.is_empty()
+                        || !dir_entry.file.is_empty();
+                    let reused = sub_dir_entry.is_some_and(|previous| {
+                        !previous.metadata_hash.is_empty()
+                            && previous.metadata_hash == metadata_hash
+                    });
+                    let size = dir_entry.size;
+                    let sub_dir = match keep {
+                        // Keeps the previous entry as it was stored, whether
+                        // inline or by hash.
+                        true if reused => Some((*previous_sub_dirs[&name]).clone()),
+                        true => Some(SubDirEntry {
+                            name: name.clone(),
+                            content: Some(Content::Inline(dir_entry)),
+                        }),
+                        false => None,
+                    };
+                    // Large directories are stored on their own, so that
+                    // only their hash stays in memory.
+                    let sub_dir = match sub_dir {
+                        Some(SubDirEntry {
                             name,
                             content: Some(Content::Inline(dir_entry)),
-                        },
+                        }) if dir_entry.encoded_len() > context.tuning.inline_dir_size => {
+                            let hash = store_dir_entry(context, state, dir_entry).await?;
+                            Some(SubDirEntry {
+                                name,
+                                content: Some(Content::Hash(hash)),
+                            })
+                        }
+                        sub_dir => sub_dir,
+                    };
+                    Ok(SubDirTaskResult {
+                        name,
+                        metadata_hash,
+                        sub_dir,
+                        size,
                     })
-            }));
-        } else {
-            return Err(CommandError::new(
-                CommandErrorKind::Program,
-                "Unsupported file type".to_owned(),
-            ));
+                }));
+            }
         }
     }
 
     let (sub_dir_tasks, mut file) =
         try_join(try_join_all(sub_dir_futures), try_join_all(file_futures)).await?;
+    // The previous entry is kept as it was stored, so that an unchanged
+    // directory isn't encoded and stored again.
+    let metadata_hash = reusable
+        .then(|| {
+            let sub_dirs = sub_dir_tasks
+                .iter()
+                .map(|i| (i.name.as_str(), i.metadata_hash.as_str()));
+            metadata_hash(state, &file, sub_dirs)
+        })
+        .flatten()
+        .unwrap_or_default();
+    if let Some(previous) = previous_snapshot
+        .filter(|previous| !previous.metadata_hash.is_empty())
+        .filter(|previous| previous.metadata_hash == metadata_hash)
+    {
+        state.path_log.log(format_args!(
+            "Reusing unchanged directory {}",
+            path.display()
+        ));
+        return Ok(previous.clone());
+    }
+
+    let sub_dir_tasks: Vec<_> = sub_dir_tasks
+        .into_iter()
+        .filter(|i| i.sub_dir.is_some())
+        .collect();
     let size = sub_dir_tasks.iter().map(|i| i.size).sum::<u64>()
         + file.iter().map(|i| i.size).sum::<u64>();
     let mut sub_dir = sub_dir_tasks
         .into_iter()
-        .map(|i| i.sub_dir)
+        .filter_map(|i| i.sub_dir)
         .collect::<Vec<_>>();
 
     sub_dir.sort_by(|a, b| a.name.cmp(&b.name));
@@ -282,48 +1082,162 @@ async fn backup_dir(
         sub_dir,
         file,
         size,
+        metadata_hash,
     })
 }
 
-static BACKUP_FILE_OPENS: Semaphore = Semaphore::const_new(16);
+/// Hash the names, sizes and modified times of the files in a directory and
+/// the metadata hashes of the directories below it, so that the next backup
+/// can tell whether anything changed. None if a sub-directory has no hash.
+///
+/// The hash is only known once the directory has been walked, so it saves
+/// storing the entry again, not listing the directory or reading metadata.
+/// Modified times of directories can't be used to skip the walk, as they
+/// don't change when a file below them is changed in place.
+fn metadata_hash<'a>(
+    state: &BackupState<'_>,
+    files: &[FileEntry],
+    sub_dirs: impl Iterator<Item = (&'a str, &'a str)>,
+) -> Option<String> {
+    // One line per entry, with the length of the name so that names can't
+    // run into the rest of the line.
+    let mut lines = Vec::with_capacity(files.len());
+    for file in files {
+        lines.push(format!(
+            "f {} {} {} {}.{:09}",
+            file.name.len(),
+            file.name,
+            file.size,
+            file.modified,
+            file.modified_nanos
+        ));
+    }
+    for (name, hash) in sub_dirs {
+        if hash.is_empty() {
+            return None;
+        }
+        lines.push(format!("d {} {} {}", name.len(), name, hash));
+    }
+
+    lines.sort();
+    let mut hasher = Sha256::new();
+    hasher.update(state.metadata_salt.as_bytes());
+    for line in lines {
+        hasher.update(b"\n");
+        hasher.update(line.as_bytes());
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+/// Why the directory at `path` is left out of the backup, if it is.
+async fn skipped_dir_reason(
+    args: &BackupArgs,
+    state: &BackupState<'_>,
+    path: &Path,
+) -> CommandResult<Option<&'static str>> {
+    if state.source.local_path().is_none() {
+        return Ok(None);
+    }
+    let metadata = fs::metadata(path).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to get directory metadata: {}", path.display()).as_str(),
+    )?;
+    if state.own_dirs.contains(&(metadata.dev(), metadata.ino())) {
+        return Ok(Some("it is used by freebck"));
+    }
+    if !args.include_repos && is_repository(path).await {
+        return Ok(Some(
+            "it is a freebck repository, pass --include-repos to back it up",
+        ));
+    }
+    Ok(None)
+}
 
+#[instrument(level = "debug", skip_all, fields(path = %path.display(), size = field::Empty))]
 async fn backup_file(
     context: &ProgramContext,
     name: String,
-    _args: &BackupArgs,
+    args: &BackupArgs,
+    state: &BackupState<'_>,
     path: &Path,
     previous_snapshot: Option<&FileEntry>,
 ) -> CommandResult<FileEntry> {
-    let metadata = fs::metadata(path).await.into_command_result(
-        CommandErrorKind::System,
-        format!("Failed to get file metadata: {}", path.display()).as_str(),
-    )?;
-    let modified = as_unix_timestamp(
-        metadata
-            .modified()
-            .into_command_result(CommandErrorKind::System, "Failed to get file modified time")?,
-    );
-    let size = metadata.len();
+    let source_path = state.source_relative(path);
+    let metadata = state
+        .source
+        .metadata(source_path)
+        .await
+        .into_command_result(
+            CommandErrorKind::System,
+            format!("Failed to get file metadata: {}", path.display()).as_str(),
+        )?;
+    let modified_time = metadata.modified;
+    let (modified, modified_nanos) = as_unix_timestamp_nanos(modified_time);
+    let size = metadata.size;
+    Span::current().record("size", size);
+    let is_block_device = metadata.entry_type == EntryType::BlockDevice;
 
+    // Block devices report no size and writes to them don't update the
+    // modified time, so they always have to be read.
     if let Some(previous_snapshot) = previous_snapshot {
-        if previous_snapshot.modified == modified && previous_snapshot.size == size {
+        if !is_block_device
+            && modified_matches(
+                previous_snapshot.modified,
+                previous_snapshot.modified_nanos,
+                modified_time,
+            )
+            && previous_snapshot.size == size
+        {
             return Ok(previous_snapshot.clone());
         }
     }
 
-    let _permit = BACKUP_FILE_OPENS.acquire().await.into_command_result(
+    let tuning = &context.tuning;
+    let fixed_block = is_block_device || args.fixed_block;
+    let _permit = state.file_opens.acquire().await.into_command_result(
         CommandErrorKind::System,
         "Failed to acquire file open permit",
     )?;
-    debug!("Backing up file: {:}", path.display());
+    state
+        .path_log
+        .log(format_args!("Backing up file: {:}", path.display()));
 
-    let mut file = pin!(File::open(path).await.into_command_result(
-        CommandErrorKind::System,
-        format!("Failed to open file: {}", path.display()).as_str()
-    )?);
-    let content_hash = read_hash(file.as_mut())
-        .await
-        .into_command_result(CommandErrorKind::System, "Failed to calculate file hash")?;
+    let open = || async {
+        state.source.open(source_path).await.into_command_result(
+            CommandErrorKind::System,
+            format!("Failed to open file: {}", path.display()).as_str(),
+        )
+    };
+    let compressible = context.compression.may_compress(path);
+    let mut file = open().await?;
+    if fixed_block {
+        return backup_fixed_block_file(
+            context,
+            name,
+            args,
+            state,
+            file,
+            modified_time,
+            compressible,
+        )
+        .await;
+    }
+
+    // Streamed chunks are hashed along with the file, as they are uploaded
+    // under their hash without ever being in memory whole.
+    let buffer_size = state.chunk_size.min(size as usize);
+    let streamed = state.streams_chunk(context, buffer_size);
+    let memory = state.reserve_memory(tuning.read_buffer_size as u64).await?;
+    let reader: Pin<&mut (dyn AsyncRead + Send + Unpin)> = Pin::new(&mut *file);
+    let (content_hash, streamed_chunks) = if streamed {
+        read_chunk_hashes(reader, tuning.read_buffer_size, state.chunk_size as u64).await
+    } else {
+        read_hash(reader, tuning.read_buffer_size)
+            .await
+            .map(|hash| (hash, Vec::new()))
+    }
+    .into_command_result(CommandErrorKind::System, "Failed to calculate file hash")?;
+    drop(memory);
 
     if let Some(previous_snapshot) = previous_snapshot {
         if previous_snapshot.content_hash == content_hash {
@@ -333,42 +1247,254 @@ async fn backup_file(
                 chunk_hash: previous_snapshot.chunk_hash.clone(),
                 size,
                 modified,
+                block_size: previous_snapshot.block_size,
+                chunk_size: previous_snapshot.chunk_size.clone(),
+                modified_nanos,
             });
         }
     }
 
-    file.seek(io::SeekFrom::Start(0))
-        .await
-        .into_command_result(CommandErrorKind::System, "Failed to seek file")?;
-    let mut buffer: Vec<u8> = vec![0; CHUNK_SIZE];
-    let mut chunk_hashes = Vec::new();
+    // Sources needn't be seekable, so the chunks are read from a new handle.
+    let mut file = open().await?;
 
-    loop {
-        let mut chunk = file.as_mut().take(CHUNK_SIZE as u64);
+    if streamed {
+        let _memory = state.reserve_memory(tuning.read_buffer_size as u64).await?;
+        let mut chunk_hashes = Vec::new();
+        let mut chunk_sizes = Vec::new();
+        for (hash, chunk_size) in streamed_chunks {
+            let hash = state.blob_key(hash);
+            let mut chunk = (&mut file).take(chunk_size);
+            state
+                .upload_blob_stream(context, &hash, chunk_size, &mut chunk)
+                .await
+                .ignore_already_exists()
+                .into_command_result(CommandErrorKind::System, "Failed to upload file chunk")?;
+            // Chunks that weren't uploaded are read past.
+            io::copy(&mut chunk, &mut io::sink())
+                .await
+                .into_command_result(CommandErrorKind::System, "Failed to read file chunk")?;
+            chunk_hashes.push(hash);
+            chunk_sizes.push(chunk_size);
+        }
 
-        buffer.clear();
-        io::copy(&mut chunk, &mut buffer)
-            .await
-            .into_command_result(CommandErrorKind::System, "Failed to read file chunk")?;
-        if buffer.len() == 0 {
-            break;
+        return Ok(FileEntry {
+            name,
+            content_hash,
+            chunk_hash: chunk_hashes,
+            size,
+            modified,
+            block_size: 0,
+            chunk_size: chunk_sizes,
+            modified_nanos,
+        });
+    }
+
+    let (sender, receiver) = mpsc::channel(context.tuning.chunk_queue_depth);
+    let read_chunks = async move {
+        let mut chunk_hashes = Vec::new();
+        let mut chunk_sizes = Vec::new();
+
+        loop {
+            let mut chunk = (&mut file).take(state.chunk_size as u64);
+
+            // The reservation is released once the chunk is uploaded.
+            let memory = state.reserve_memory(buffer_size as u64).await?;
+            let mut buffer: Vec<u8> = Vec::with_capacity(buffer_size);
+            io::copy(&mut chunk, &mut buffer)
+                .await
+                .into_command_result(CommandErrorKind::System, "Failed to read file chunk")?;
+            if buffer.is_empty() {
+                break;
+            }
+
+            let hash;
+            (buffer, hash) = sha256_hex(buffer)
+                .await
+                .into_command_result(CommandErrorKind::System, "Failed to hash file chunk")?;
+            let hash = state.blob_key(hash);
+            chunk_hashes.push(hash.clone());
+            chunk_sizes.push(buffer.len() as u64);
+            let chunk = PendingChunk {
+                hash,
+                data: buffer,
+                _memory: memory,
+            };
+            if sender.send(chunk).await.is_err() {
+                // The upload failed, its error is returned instead.
+                break;
+            }
         }
 
-        let hash = format!("{:x}", Sha256::digest(&buffer));
-        context
-            .storage
-            .write(Collection::Blob, &hash, &buffer)
+        Ok((chunk_hashes, chunk_sizes))
+    };
+    let ((chunk_hashes, chunk_sizes), ()) = try_join(
+        read_chunks,
+        upload_chunks(context, state, receiver, compressible),
+    )
+    .await?;
+
+    Ok(FileEntry {
+        name,
+        content_hash,
+        chunk_hash: chunk_hashes,
+        size,
+        modified,
+        block_size: 0,
+        chunk_size: chunk_sizes,
+        modified_nanos,
+    })
+}
+
+/// Store the stats of a new snapshot. The backup is complete by then, so
+/// failures are only logged.
+async fn record_stats(context: &ProgramContext, snapshot_name: &str, mut stats: BackupStats) {
+    let history = match get_stats_history(context, None).await {
+        Ok(history) => history,
+        Err(e) => {
+            warn!("Failed to record backup stats: {}", e);
+            return;
+        }
+    };
+    let previous_stored_bytes = history
+        .last()
+        .map_or(0, |(_, previous)| previous.stored_bytes);
+    stats.stored_bytes = previous_stored_bytes + stats.new_bytes;
+    if let Err(e) = context
+        .storage
+        .write(Collection::Stats, snapshot_name, &stats.encode_to_vec())
+        .await
+    {
+        warn!("Failed to record backup stats: {}", e);
+    }
+}
+
+/// Chunk of a file on its way to the storage, with the memory reserved for
+/// it.
+struct PendingChunk {
+    hash: String,
+    data: Vec<u8>,
+    _memory: Option<OwnedSemaphorePermit>,
+}
+
+/// Upload the chunks sent by a file reader, so that reading the next chunks
+/// overlaps with uploading the previous ones.
+async fn upload_chunks(
+    context: &ProgramContext,
+    state: &BackupState<'_>,
+    receiver: mpsc::Receiver<PendingChunk>,
+    compressible: bool,
+) -> CommandResult {
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    })
+    .map(|chunk| async move {
+        state
+            .upload_blob(context, &chunk.hash, &chunk.data, compressible)
             .await
             .ignore_already_exists()
-            .into_command_result(CommandErrorKind::System, "Failed to upload file chunk")?;
-        chunk_hashes.push(hash);
+            .into_command_result(CommandErrorKind::System, "Failed to upload file chunk")
+    })
+    .buffer_unordered(context.tuning.chunk_uploads)
+    .try_collect()
+    .await
+}
+
+/// Back up a file or block device in fixed-size blocks, so that blocks stay
+/// aligned between runs and all-zero blocks can be skipped entirely.
+async fn backup_fixed_block_file(
+    context: &ProgramContext,
+    name: String,
+    args: &BackupArgs,
+    state: &BackupState<'_>,
+    mut file: SourceReader,
+    modified_time: SystemTime,
+    compressible: bool,
+) -> CommandResult<FileEntry> {
+    let (modified, modified_nanos) = as_unix_timestamp_nanos(modified_time);
+    let block_size = args.block_size.unwrap_or(context.tuning.block_size);
+    if block_size == 0 {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Block size must be positive".to_string(),
+        ));
     }
 
+    let elide_zeros = !args.store_zero_blocks;
+    let (sender, receiver) = mpsc::channel(context.tuning.chunk_queue_depth);
+    let read_blocks = async move {
+        let mut hasher = Sha256::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut memory = None;
+        let mut chunk_hashes = Vec::new();
+        let mut chunk_sizes = Vec::new();
+        let mut size: u64 = 0;
+
+        loop {
+            let mut block = (&mut file).take(block_size as u64);
+
+            // The reservation is released once the block is uploaded.
+            if memory.is_none() {
+                memory = Some(state.reserve_memory(block_size as u64).await?);
+            }
+            buffer.clear();
+            buffer.reserve(block_size);
+            io::copy(&mut block, &mut buffer)
+                .await
+                .into_command_result(CommandErrorKind::System, "Failed to read file block")?;
+            if buffer.is_empty() {
+                break;
+            }
+            size += buffer.len() as u64;
+            chunk_sizes.push(buffer.len() as u64);
+
+            let block_hash;
+            (hasher, buffer, block_hash) = run_blocking(move || {
+                hasher.update(&buffer);
+                let block_hash = if elide_zeros && buffer.iter().all(|b| *b == 0) {
+                    None
+                } else {
+                    Some(format!("{:x}", Sha256::digest(&buffer)))
+                };
+                (hasher, buffer, block_hash)
+            })
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to hash file block")?;
+            let Some(hash) = block_hash else {
+                // Zero blocks are not stored, so their buffer and its
+                // reservation can be reused.
+                chunk_hashes.push(String::new());
+                continue;
+            };
+            let hash = state.blob_key(hash);
+
+            chunk_hashes.push(hash.clone());
+            let chunk = PendingChunk {
+                hash,
+                data: std::mem::take(&mut buffer),
+                _memory: memory.take().unwrap(),
+            };
+            if sender.send(chunk).await.is_err() {
+                // The upload failed, its error is returned instead.
+                break;
+            }
+        }
+
+        Ok((hasher, chunk_hashes, chunk_sizes, size))
+    };
+    let ((hasher, chunk_hashes, chunk_sizes, size), ()) = try_join(
+        read_blocks,
+        upload_chunks(context, state, receiver, compressible),
+    )
+    .await?;
+
     Ok(FileEntry {
         name,
-        content_hash,
+        content_hash: format!("{:x}", hasher.finalize()),
         chunk_hash: chunk_hashes,
         size,
         modified,
+        block_size: block_size as u64,
+        chunk_size: chunk_sizes,
+        modified_nanos,
     })
 }
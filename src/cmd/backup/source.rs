@@ -0,0 +1,139 @@
+use std::{
+    ffi::OsString,
+    fs::FileType,
+    io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    fs::{self, File},
+    io::AsyncRead,
+};
+
+pub type SourceReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Type of an entry in a backup source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    File,
+    Dir,
+    BlockDevice,
+    CharDevice,
+    Symlink,
+    Socket,
+    Fifo,
+}
+
+impl EntryType {
+    pub fn of(file_type: FileType) -> Option<Self> {
+        if file_type.is_file() {
+            Some(Self::File)
+        } else if file_type.is_dir() {
+            Some(Self::Dir)
+        } else if file_type.is_block_device() {
+            Some(Self::BlockDevice)
+        } else if file_type.is_char_device() {
+            Some(Self::CharDevice)
+        } else if file_type.is_symlink() {
+            Some(Self::Symlink)
+        } else if file_type.is_socket() {
+            Some(Self::Socket)
+        } else if file_type.is_fifo() {
+            Some(Self::Fifo)
+        } else {
+            None
+        }
+    }
+}
+
+pub struct SourceEntry {
+    pub name: OsString,
+    pub entry_type: EntryType,
+}
+
+pub struct SourceMetadata {
+    pub entry_type: EntryType,
+    /// Size in bytes, unknown for block devices.
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// What a backup reads the tree to back up from. Paths are relative to the
+/// root of the source, which is the empty path.
+#[async_trait]
+pub trait BackupSource: Send + Sync {
+    // Entries of the directory at `path`, in any order.
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>>;
+
+    // Metadata of the entry at `path`. A missing root fails with NotFound.
+    async fn metadata(&self, path: &Path) -> io::Result<SourceMetadata>;
+
+    // Open the file at `path` for reading from the start. A file may be
+    // opened more than once, e.g. to hash it before reading its chunks.
+    async fn open(&self, path: &Path) -> io::Result<SourceReader>;
+
+    // Directory on the local file system that the source reads from. Only
+    // then are the repository, the state directory and other repositories
+    // inside the source left out.
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Back up a directory on the local file system, the default.
+pub struct LocalBackupSource {
+    root: PathBuf,
+}
+
+impl LocalBackupSource {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+fn unsupported_type(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("Unsupported file type: {}", path.display()),
+    )
+}
+
+#[async_trait]
+impl BackupSource for LocalBackupSource {
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        let path = self.root.join(path);
+        let mut dir_entries = fs::read_dir(&path).await?;
+        let mut entries = Vec::new();
+        while let Some(dir_entry) = dir_entries.next_entry().await? {
+            let entry_type = EntryType::of(dir_entry.file_type().await?)
+                .ok_or_else(|| unsupported_type(&dir_entry.path()))?;
+            entries.push(SourceEntry {
+                name: dir_entry.file_name(),
+                entry_type,
+            });
+        }
+        Ok(entries)
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        let path = self.root.join(path);
+        let metadata = fs::metadata(&path).await?;
+        Ok(SourceMetadata {
+            entry_type: EntryType::of(metadata.file_type())
+                .ok_or_else(|| unsupported_type(&path))?,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    async fn open(&self, path: &Path) -> io::Result<SourceReader> {
+        Ok(Box::new(File::open(self.root.join(path)).await?))
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
@@ -0,0 +1,39 @@
+use clap::Args;
+use tokio::io::{self, AsyncWriteExt};
+
+use crate::storage::Collection;
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct CatArgs {
+    /// Hash of the blob to write to stdout.
+    #[arg(long)]
+    pub blob: String,
+}
+
+pub async fn cat(context: &ProgramContext, args: &CatArgs) -> CommandResult {
+    let mut reader = match context
+        .storage
+        .read_stream(Collection::Blob, &args.blob)
+        .await
+    {
+        Ok(reader) => reader,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(CommandError::new(
+                CommandErrorKind::NotFound,
+                format!("Blob not found {}", args.blob),
+            ));
+        }
+        Err(e) => return Err(e.into_io_command_error("Failed to download blob")),
+    };
+
+    let mut stdout = io::stdout();
+    io::copy(&mut reader, &mut stdout)
+        .await
+        .into_io_command_result("Failed to copy blob to stdout")?;
+    stdout
+        .flush()
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to write to stdout")
+}
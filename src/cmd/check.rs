@@ -0,0 +1,376 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    io,
+    path::Path,
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::Args;
+use tokio::fs;
+use tracing::{debug, info, warn};
+
+use crate::{
+    data::backup::sub_dir_entry::Content,
+    storage::Collection,
+    util::{
+        hash::blob_key_matches,
+        hooks::{fire_hook, HookEvent},
+        size::parse_size,
+        time::as_unix_timestamp,
+    },
+};
+
+use super::common::*;
+
+// File in the state directory recording when each blob was last verified.
+const VERIFIED_BLOBS_FILE: &str = "verified_blobs";
+// File in the state directory listing the blobs found damaged, one per line,
+// until they are repaired or verified again.
+const DAMAGED_BLOBS_FILE: &str = "damaged_blobs";
+
+#[derive(Debug, Default, Args)]
+pub struct CheckArgs {
+    /// Verify blob contents, oldest verified first, until a budget runs out.
+    /// Snapshots aren't checked in this mode.
+    #[arg(long)]
+    pub auto: bool,
+    /// Stop after reading this many bytes, e.g. "10G".
+    #[arg(long, value_parser = parse_size)]
+    pub max_bytes: Option<u64>,
+    /// Stop after this much time has passed, e.g. "30m".
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub max_time: Option<Duration>,
+    /// Also read every blob the snapshots use and check that it matches its
+    /// hash.
+    #[arg(long, conflicts_with = "auto")]
+    pub read_data: bool,
+    /// Read only this share of the blobs the snapshots use, e.g. "5%", the
+    /// least recently verified ones first.
+    #[arg(long, value_parser = parse_percentage, conflicts_with_all = ["auto", "read_data"])]
+    pub read_data_subset: Option<f64>,
+}
+
+/// Parse a percentage such as "5%" into a fraction.
+fn parse_percentage(value: &str) -> Result<f64, String> {
+    let number = value.trim().trim_end_matches('%');
+    match number.parse::<f64>() {
+        Ok(percentage) if (0.0..=100.0).contains(&percentage) => Ok(percentage / 100.0),
+        _ => Err(format!("Invalid percentage: {}", value)),
+    }
+}
+
+pub async fn check(context: &ProgramContext, args: &CheckArgs) -> CommandResult {
+    if args.auto {
+        return check_auto(context, args).await;
+    }
+    check_snapshots(context, args).await
+}
+
+/// Read the last verification time of each blob. Blobs never verified are
+/// missing from the result.
+async fn read_verified_blobs(path: &Path) -> CommandResult<HashMap<String, i64>> {
+    let content = match fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => {
+            return Err(e.into_command_error(
+                CommandErrorKind::System,
+                format!("Failed to read {}", path.display()).as_str(),
+            ))
+        }
+    };
+
+    let mut verified = HashMap::new();
+    for line in content.lines() {
+        match line.split_once(' ') {
+            Some((time, hash)) => match time.parse() {
+                Ok(time) => {
+                    verified.insert(hash.to_string(), time);
+                }
+                Err(_) => warn!("Invalid line in {}: {}", path.display(), line),
+            },
+            None => warn!("Invalid line in {}: {}", path.display(), line),
+        }
+    }
+    Ok(verified)
+}
+
+async fn write_verified_blobs(path: &Path, verified: &HashMap<String, i64>) -> CommandResult {
+    let mut entries: Vec<_> = verified.iter().collect();
+    entries.sort();
+    let content: String = entries
+        .into_iter()
+        .map(|(hash, time)| format!("{} {}\n", time, hash))
+        .collect();
+
+    let tmp_path = path.with_extension("tmp");
+    fs::create_dir_all(path.parent().unwrap())
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to create state directory")?;
+    fs::write(&tmp_path, content).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to write {}", tmp_path.display()).as_str(),
+    )?;
+    fs::rename(&tmp_path, path).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to write {}", path.display()).as_str(),
+    )
+}
+
+/// Hashes of the blobs that the last checks found damaged.
+pub async fn read_damaged_blobs(context: &ProgramContext) -> CommandResult<BTreeSet<String>> {
+    let path = context.state_dir.join(DAMAGED_BLOBS_FILE);
+    match fs::read_to_string(&path).await {
+        Ok(content) => Ok(content.lines().map(|line| line.to_string()).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into_command_error(
+            CommandErrorKind::System,
+            format!("Failed to read {}", path.display()).as_str(),
+        )),
+    }
+}
+
+pub async fn write_damaged_blobs(
+    context: &ProgramContext,
+    damaged: &BTreeSet<String>,
+) -> CommandResult {
+    let path = context.state_dir.join(DAMAGED_BLOBS_FILE);
+    if damaged.is_empty() {
+        return match fs::remove_file(&path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into_command_error(
+                CommandErrorKind::System,
+                format!("Failed to remove {}", path.display()).as_str(),
+            )),
+            _ => Ok(()),
+        };
+    }
+
+    let content: String = damaged.iter().map(|hash| format!("{}\n", hash)).collect();
+    fs::create_dir_all(&context.state_dir)
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to create state directory")?;
+    fs::write(&path, content).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to write {}", path.display()).as_str(),
+    )
+}
+
+async fn check_auto(context: &ProgramContext, args: &CheckArgs) -> CommandResult {
+    let started = Instant::now();
+    let now = as_unix_timestamp(SystemTime::now());
+    let verified_path = context.state_dir.join(VERIFIED_BLOBS_FILE);
+
+    let blobs = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?;
+    let mut verified = read_verified_blobs(&verified_path).await?;
+    let mut damaged = read_damaged_blobs(context).await?;
+    // Forget blobs that no longer exist.
+    let existing: HashSet<&String> = blobs.iter().collect();
+    verified.retain(|hash, _| existing.contains(hash));
+
+    let mut queue: Vec<(i64, &String)> = blobs
+        .iter()
+        .map(|hash| (verified.get(hash).copied().unwrap_or(0), hash))
+        .collect();
+    queue.sort();
+
+    let mut buffer = Vec::new();
+    let mut checked = 0;
+    let mut bytes: u64 = 0;
+    let mut corrupt = 0;
+    for (_, hash) in queue {
+        if args.max_bytes.is_some_and(|max| bytes >= max)
+            || args.max_time.is_some_and(|max| started.elapsed() >= max)
+        {
+            break;
+        }
+
+        if !verify_blob(context, hash, &mut buffer, now, &mut verified, &mut damaged).await {
+            corrupt += 1;
+        }
+        checked += 1;
+        bytes += buffer.len() as u64;
+    }
+
+    write_verified_blobs(&verified_path, &verified).await?;
+    write_damaged_blobs(context, &damaged).await?;
+    info!(
+        "Checked {} of {} blobs, {} bytes",
+        checked,
+        blobs.len(),
+        bytes
+    );
+
+    if corrupt > 0 {
+        fire_hook(
+            context,
+            HookEvent::CheckFailed {
+                damaged_blobs: corrupt,
+            },
+        )
+        .await;
+        return Err(CommandError::new(
+            CommandErrorKind::Corrupt,
+            format!("{} damaged blobs found", corrupt),
+        ));
+    }
+    Ok(())
+}
+
+/// Read a blob and check that it matches its hash, recording the outcome in
+/// `verified` and `damaged`. Returns whether the blob is good.
+async fn verify_blob(
+    context: &ProgramContext,
+    hash: &String,
+    buffer: &mut Vec<u8>,
+    now: i64,
+    verified: &mut HashMap<String, i64>,
+    damaged: &mut BTreeSet<String>,
+) -> bool {
+    debug!("Checking blob {}", hash);
+    buffer.clear();
+    match context.storage.read(Collection::Blob, hash, buffer).await {
+        Ok(()) if blob_key_matches(hash, buffer) => {
+            verified.insert(hash.clone(), now);
+            damaged.remove(hash);
+            return true;
+        }
+        Ok(()) => warn!("Blob {} does not match its hash", hash),
+        Err(e) => warn!("Failed to read blob {}: {}", hash, e),
+    }
+    verified.remove(hash);
+    damaged.insert(hash.clone());
+    false
+}
+
+/// Check that every snapshot can be read and that every blob it uses
+/// exists, reading the blobs too with --read-data or --read-data-subset.
+async fn check_snapshots(context: &ProgramContext, args: &CheckArgs) -> CommandResult {
+    let now = as_unix_timestamp(SystemTime::now());
+    let verified_path = context.state_dir.join(VERIFIED_BLOBS_FILE);
+    let snapshots = context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let existing: HashSet<String> = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?
+        .into_iter()
+        .collect();
+    let mut verified = read_verified_blobs(&verified_path).await?;
+    let mut damaged = read_damaged_blobs(context).await?;
+    let mut problems = 0;
+
+    let mut pending = Vec::new();
+    for snapshot_name in &snapshots {
+        info!("Checking snapshot {}", snapshot_name);
+        match get_snapshot(context, snapshot_name).await {
+            Ok(snapshot) => pending.push(snapshot.root_hash),
+            Err(e) => {
+                warn!("Snapshot {} can't be read: {}", snapshot_name, e);
+                problems += 1;
+            }
+        }
+    }
+
+    // Directory entries that are missing are counted with the other missing
+    // blobs below.
+    let mut reachable = HashSet::new();
+    while let Some(hash) = pending.pop() {
+        if !reachable.insert(hash.clone()) || !existing.contains(&hash) {
+            continue;
+        }
+        let dir_entry = match get_dir_entry(context, &hash).await {
+            Ok(dir_entry) => dir_entry,
+            Err(e) => {
+                warn!("Directory entry {} can't be read: {}", hash, e);
+                verified.remove(&hash);
+                damaged.insert(hash);
+                continue;
+            }
+        };
+        let mut dir_entries = vec![dir_entry];
+        while let Some(dir_entry) = dir_entries.pop() {
+            for file in dir_entry.file {
+                reachable.extend(file.chunk_hash.into_iter().filter(|hash| !hash.is_empty()));
+            }
+            for sub_dir in dir_entry.sub_dir {
+                match sub_dir.content {
+                    Some(Content::Hash(hash)) => pending.push(hash),
+                    Some(Content::Inline(dir_entry)) => dir_entries.push(dir_entry),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    let mut missing: Vec<&String> = reachable
+        .iter()
+        .filter(|hash| !existing.contains(*hash))
+        .collect();
+    missing.sort();
+    for hash in &missing {
+        warn!("Blob {} is missing", hash);
+    }
+    problems += missing.len();
+
+    // Blobs found damaged before are read again first, in case they were
+    // repaired, then the ones verified longest ago.
+    let mut to_read: Vec<(bool, i64, &String)> = reachable
+        .iter()
+        .filter(|hash| existing.contains(*hash))
+        .map(|hash| {
+            let time = verified.get(hash).copied().unwrap_or(0);
+            (!damaged.contains(hash), time, hash)
+        })
+        .collect();
+    to_read.sort();
+    let count = match args.read_data_subset {
+        Some(fraction) => (to_read.len() as f64 * fraction).ceil() as usize,
+        None if args.read_data => to_read.len(),
+        None => 0,
+    };
+    let mut buffer = Vec::new();
+    let mut bytes: u64 = 0;
+    for (_, _, hash) in &to_read[..count] {
+        verify_blob(context, hash, &mut buffer, now, &mut verified, &mut damaged).await;
+        bytes += buffer.len() as u64;
+    }
+    if count > 0 {
+        write_verified_blobs(&verified_path, &verified).await?;
+    }
+    write_damaged_blobs(context, &damaged).await?;
+    let damaged_used = reachable.iter().filter(|hash| damaged.contains(*hash));
+    for hash in damaged_used {
+        warn!("Blob {} is damaged", hash);
+        problems += 1;
+    }
+    info!(
+        "Checked {} snapshots using {} blobs, read {} blobs, {} bytes",
+        snapshots.len(),
+        reachable.len(),
+        count,
+        bytes
+    );
+
+    if problems > 0 {
+        fire_hook(
+            context,
+            HookEvent::CheckFailed {
+                damaged_blobs: problems,
+            },
+        )
+        .await;
+        return Err(CommandError::new(
+            CommandErrorKind::Corrupt,
+            format!("{} problems found", problems),
+        ));
+    }
+    Ok(())
+}
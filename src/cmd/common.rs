@@ -1,50 +1,170 @@
 use std::{
+    collections::HashSet,
     error::Error,
     fmt::{self, Display, Formatter},
-    io::Cursor,
-    path::PathBuf,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use log::{error, warn};
 use prost::Message;
+use tracing::{error, warn};
 
 use crate::{
-    data::backup::DirEntry,
+    data::{
+        backup::{sub_dir_entry::Content, BackupStats, DirEntry, FileEntry, Snapshot},
+        config::{CompressionConfig, DatabaseConfig, FsSnapshotConfig, HooksConfig, TuningConfig},
+    },
     storage::{Collection, Storage},
+    util::{fs::sanitize_os_string, hash::SHA256_KEY_PREFIX, time::parse_time},
 };
 
+/// Everything commands need to know about the repository and the archive.
+/// Cloning is cheap and shares the storage, so one opened repository can
+/// serve several commands at the same time, such as concurrent restores.
+#[derive(Clone)]
 pub struct ProgramContext {
     pub archive_name: String,
-    pub storage: Box<dyn Storage>,
+    pub client_id: String,
+    pub storage: Arc<dyn Storage>,
     pub backup_target: PathBuf,
+    /// Directory for local state such as the client id and caches.
+    pub state_dir: PathBuf,
+    pub hooks: HooksConfig,
+    /// Back up from a snapshot of the file system made for the backup.
+    pub fs_snapshot: Option<FsSnapshotConfig>,
+    /// Back up a dump of this database instead of the backup target.
+    pub database: Option<DatabaseConfig>,
+    /// Which files backup compresses. Blobs are compressed by the storage.
+    pub compression: CompressionConfig,
+    pub tuning: RuntimeTuning,
 }
 
-#[derive(Debug, Clone)]
+/// Buffer sizes and pipeline depths used by the commands. The defaults suit
+/// most machines, `tuning` in the config changes them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeTuning {
+    /// Largest chunk that files are split into outside of fixed-block mode.
+    pub chunk_size: usize,
+    /// Block size of fixed-block mode when --block-size isn't given.
+    pub block_size: usize,
+    /// Buffer for reading files while hashing them.
+    pub read_buffer_size: usize,
+    /// Chunks read ahead of the uploads per file.
+    pub chunk_queue_depth: usize,
+    /// Chunks uploaded concurrently per file while the next ones are read.
+    pub chunk_uploads: usize,
+    /// Files a backup keeps open at the same time.
+    pub open_files: usize,
+    /// Largest encoded directory that is stored inside its parent. Larger
+    /// ones are stored as blobs of their own, so that a backup doesn't keep
+    /// the whole tree in memory.
+    pub inline_dir_size: usize,
+    /// Chunks larger than this are streamed between the file and the
+    /// storage instead of being held in memory whole.
+    pub stream_chunk_size: usize,
+}
+
+impl Default for RuntimeTuning {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024 * 1024 * 1024,
+            block_size: 4 * 1024 * 1024,
+            read_buffer_size: 1024 * 1024,
+            chunk_queue_depth: 1,
+            chunk_uploads: 2,
+            open_files: 16,
+            inline_dir_size: 256 * 1024,
+            stream_chunk_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl RuntimeTuning {
+    /// The defaults with the values set in `config`.
+    pub fn from_config(config: &TuningConfig) -> CommandResult<Self> {
+        let default = Self::default();
+        let positive = |name: &str, value: Option<u64>, default: usize| match value {
+            None => Ok(default),
+            Some(0) => Err(CommandError::new(
+                CommandErrorKind::User,
+                format!("tuning.{} must be positive", name),
+            )),
+            Some(value) => Ok(value.try_into().unwrap_or(usize::MAX)),
+        };
+        Ok(Self {
+            chunk_size: positive("chunk_size", config.chunk_size, default.chunk_size)?,
+            block_size: positive("block_size", config.block_size, default.block_size)?,
+            read_buffer_size: positive(
+                "read_buffer_size",
+                config.read_buffer_size,
+                default.read_buffer_size,
+            )?,
+            chunk_queue_depth: positive(
+                "chunk_queue_depth",
+                config.chunk_queue_depth.map(|n| n as u64),
+                default.chunk_queue_depth,
+            )?,
+            chunk_uploads: positive(
+                "chunk_uploads",
+                config.chunk_uploads.map(|n| n as u64),
+                default.chunk_uploads,
+            )?,
+            open_files: positive(
+                "open_files",
+                config.open_files.map(|n| n as u64),
+                default.open_files,
+            )?,
+            inline_dir_size: positive(
+                "inline_dir_size",
+                config.inline_dir_size,
+                default.inline_dir_size,
+            )?,
+            stream_chunk_size: positive(
+                "stream_chunk_size",
+                config.stream_chunk_size,
+                default.stream_chunk_size,
+            )?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum CommandErrorKind {
     /// An error that is caused by the user.
     User,
+    /// A snapshot, object or session that was asked for does not exist.
+    NotFound,
     /// Operation was aborted due to conflicting files or directories.
     FileSystemConflict,
     /// An error that is caused by invalid backup data.
     Corrupt,
+    /// The backup does not match the data it was compared against.
+    Mismatch,
     /// An error that is caused by the program.
     Program,
     /// An error that is caused by the system.
     System,
+    /// Some paths failed in a keep-going run. The error's source is a
+    /// `MultiError` listing them.
+    Partial,
+    /// Another operation holds a conflicting lock of the repository.
+    Locked,
 }
 
 #[derive(Debug)]
 pub struct CommandError {
     error_type: CommandErrorKind,
     message: String,
-    source: Option<Box<dyn Error + Send>>,
+    source: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl CommandError {
     pub fn with_source(
         error_type: CommandErrorKind,
         message: String,
-        source: Box<dyn Error + Send>,
+        source: Box<dyn Error + Send + Sync>,
     ) -> Self {
         CommandError {
             error_type,
@@ -62,7 +182,50 @@ impl CommandError {
     }
 
     pub fn with_message(self: Self, message: String) -> Self {
-        CommandError::with_source(self.error_type.clone(), message, Box::new(self))
+        CommandError::with_source(self.error_type, message, Box::new(self))
+    }
+
+    pub fn kind(&self) -> CommandErrorKind {
+        self.error_type
+    }
+
+    pub fn is_user(&self) -> bool {
+        self.error_type == CommandErrorKind::User
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.error_type == CommandErrorKind::NotFound
+    }
+
+    pub fn is_corrupt(&self) -> bool {
+        self.error_type == CommandErrorKind::Corrupt
+    }
+
+    pub fn is_mismatch(&self) -> bool {
+        self.error_type == CommandErrorKind::Mismatch
+    }
+
+    /// The per-path failures of a keep-going run that ended with errors.
+    pub fn failures(&self) -> Option<&MultiError> {
+        self.source.as_ref()?.downcast_ref::<MultiError>()
+    }
+}
+
+impl From<io::ErrorKind> for CommandErrorKind {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => CommandErrorKind::NotFound,
+            io::ErrorKind::AlreadyExists => CommandErrorKind::FileSystemConflict,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => CommandErrorKind::Corrupt,
+            io::ErrorKind::InvalidInput => CommandErrorKind::User,
+            _ => CommandErrorKind::System,
+        }
+    }
+}
+
+impl From<io::Error> for CommandError {
+    fn from(e: io::Error) -> Self {
+        CommandError::with_source(e.kind().into(), e.to_string(), Box::new(e))
     }
 }
 
@@ -87,7 +250,7 @@ pub trait IntoCommandError {
 
 impl<T> IntoCommandError for T
 where
-    T: std::error::Error + Send + 'static,
+    T: std::error::Error + Send + Sync + 'static,
 {
     fn into_command_error(self, kind: CommandErrorKind, message: &str) -> CommandError {
         CommandError::with_source(kind, message.to_string(), Box::new(self))
@@ -102,7 +265,7 @@ pub trait IntoCommandResult<T> {
 
 impl<T, E> IntoCommandResult<T> for Result<T, E>
 where
-    E: std::error::Error + Send + 'static,
+    E: std::error::Error + Send + Sync + 'static,
 {
     fn into_command_result(self, kind: CommandErrorKind, message: &str) -> CommandResult<T> {
         match self {
@@ -116,8 +279,107 @@ where
     }
 }
 
+pub trait IntoIoCommandError {
+    /// Like `into_command_error`, with the error kind derived from the I/O
+    /// error kind.
+    fn into_io_command_error(self, message: &str) -> CommandError;
+}
+
+impl IntoIoCommandError for io::Error {
+    fn into_io_command_error(self, message: &str) -> CommandError {
+        CommandError::with_source(self.kind().into(), message.to_string(), Box::new(self))
+    }
+}
+
+pub trait IntoIoCommandResult<T> {
+    /// Like `into_command_result`, with the error kind derived from the I/O
+    /// error kind.
+    fn into_io_command_result(self, message: &str) -> CommandResult<T>;
+}
+
+impl<T> IntoIoCommandResult<T> for io::Result<T> {
+    fn into_io_command_result(self, message: &str) -> CommandResult<T> {
+        self.map_err(|e| e.into_io_command_error(message))
+    }
+}
+
+/// Failure of a single path that a keep-going run skipped over.
+#[derive(Debug)]
+pub struct PathError {
+    pub path: PathBuf,
+    pub error: CommandError,
+}
+
+/// All failures of a keep-going run.
+#[derive(Debug, Default)]
+pub struct MultiError {
+    errors: Vec<PathError>,
+}
+
+impl MultiError {
+    pub fn push(&mut self, path: PathBuf, error: CommandError) {
+        self.errors.push(PathError { path, error });
+    }
+
+    pub fn errors(&self) -> &[PathError] {
+        &self.errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Failures grouped by kind, in the order each kind first failed.
+    pub fn grouped(&self) -> Vec<(CommandErrorKind, Vec<&PathError>)> {
+        let mut groups: Vec<(CommandErrorKind, Vec<&PathError>)> = Vec::new();
+        for error in &self.errors {
+            match groups
+                .iter_mut()
+                .find(|(kind, _)| *kind == error.error.kind())
+            {
+                Some((_, errors)) => errors.push(error),
+                None => groups.push((error.error.kind(), vec![error])),
+            }
+        }
+        groups
+    }
+
+    /// Ok if nothing failed, otherwise a `Partial` error with the failures as
+    /// its source.
+    pub fn into_result(self, operation: &str) -> CommandResult {
+        if self.is_empty() {
+            return Ok(());
+        }
+        Err(CommandError::with_source(
+            CommandErrorKind::Partial,
+            format!(
+                "{} finished with {} failed paths",
+                operation,
+                self.errors.len()
+            ),
+            Box::new(self),
+        ))
+    }
+}
+
+impl Display for MultiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} paths failed", self.errors.len())
+    }
+}
+
+impl Error for MultiError {}
+
 pub trait KeepGoingOrErr<E> {
-    fn keep_going_or_err<F>(self, keep_going: bool, f: F) -> CommandResult
+    /// With `keep_going`, log the error and record it for `path` in
+    /// `failures`. Otherwise log it and abort.
+    fn keep_going_or_err<F>(
+        self,
+        keep_going: bool,
+        failures: &Mutex<MultiError>,
+        path: &Path,
+        f: F,
+    ) -> CommandResult
     where
         F: FnOnce(E) -> CommandError;
 }
@@ -126,7 +388,13 @@ impl<T, E> KeepGoingOrErr<E> for Result<T, E>
 where
     E: std::error::Error,
 {
-    fn keep_going_or_err<F>(self, keep_going: bool, f: F) -> CommandResult
+    fn keep_going_or_err<F>(
+        self,
+        keep_going: bool,
+        failures: &Mutex<MultiError>,
+        path: &Path,
+        f: F,
+    ) -> CommandResult
     where
         F: FnOnce(E) -> CommandError,
     {
@@ -134,7 +402,9 @@ where
             Ok(_) => Ok(()),
             Err(e) => {
                 if keep_going {
-                    warn!("{}", f(e));
+                    let error = f(e);
+                    warn!("{}", error);
+                    failures.lock().unwrap().push(path.to_owned(), error);
                     Ok(())
                 } else {
                     error!("{}", f(e));
@@ -148,13 +418,32 @@ where
     }
 }
 
+pub trait IgnoreAlreadyExists {
+    fn ignore_already_exists(self) -> io::Result<()>;
+}
+
+impl<T> IgnoreAlreadyExists for io::Result<T> {
+    fn ignore_already_exists(self) -> io::Result<()> {
+        match self {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if e.kind() == io::ErrorKind::AlreadyExists {
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+}
+
 pub async fn get_dir_entry(context: &ProgramContext, hash: &str) -> CommandResult<DirEntry> {
     let mut dir_entry_buf = Vec::new(); // TODO: Setup a pool of buffers.
     context
         .storage
         .read(Collection::Blob, hash, &mut dir_entry_buf)
         .await
-        .into_command_result(CommandErrorKind::System, "Failed to download dir entry")?;
+        .into_io_command_result("Failed to download dir entry")?;
     let dir_entry = DirEntry::decode(Cursor::new(dir_entry_buf)).map_err(|e| {
         CommandError::with_source(
             CommandErrorKind::Corrupt,
@@ -165,3 +454,305 @@ pub async fn get_dir_entry(context: &ProgramContext, hash: &str) -> CommandResul
 
     return Ok(dir_entry);
 }
+
+/// Add the hashes of all blobs reachable from the directory entry stored as
+/// `root_hash`, including the root itself, to `blobs`.
+pub async fn collect_reachable_blobs(
+    context: &ProgramContext,
+    root_hash: &str,
+    blobs: &mut HashSet<String>,
+) -> CommandResult {
+    let mut pending = vec![root_hash.to_string()];
+    while let Some(hash) = pending.pop() {
+        if !blobs.insert(hash.clone()) {
+            continue;
+        }
+
+        let mut dir_entries = vec![get_dir_entry(context, &hash).await?];
+        while let Some(dir_entry) = dir_entries.pop() {
+            for file in dir_entry.file {
+                blobs.extend(file.chunk_hash.into_iter().filter(|hash| !hash.is_empty()));
+            }
+            for sub_dir in dir_entry.sub_dir {
+                match sub_dir.content {
+                    Some(Content::Hash(hash)) => pending.push(hash),
+                    Some(Content::Inline(dir_entry)) => dir_entries.push(dir_entry),
+                    None => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Chunk size of files backed up before chunk lengths were recorded.
+const LEGACY_CHUNK_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Length of the chunk at `index` of a file, which starts at `offset`. Files
+/// from before chunk lengths were recorded have full-size chunks except for
+/// the last one. Fails if the chunks go past the size of the file, which
+/// only a corrupt entry does.
+pub fn chunk_length(file_entry: &FileEntry, index: usize, offset: u64) -> CommandResult<u64> {
+    if let Some(length) = file_entry.chunk_size.get(index) {
+        return Ok(*length);
+    }
+
+    let chunk_size = if file_entry.block_size != 0 {
+        file_entry.block_size
+    } else {
+        LEGACY_CHUNK_SIZE
+    };
+    let remaining = file_entry.size.checked_sub(offset).ok_or_else(|| {
+        CommandError::new(
+            CommandErrorKind::Corrupt,
+            format!(
+                "Chunks of {} go past its size of {} bytes",
+                file_entry.name, file_entry.size
+            ),
+        )
+    })?;
+    Ok(chunk_size.min(remaining))
+}
+
+/// Resolve a snapshot given on the command line to its storage key. The
+/// snapshot is either a number within `archive` (the configured archive by
+/// default) or a fully-qualified "archive/number".
+pub fn resolve_snapshot_name(
+    context: &ProgramContext,
+    archive: Option<&str>,
+    snapshot: &str,
+) -> String {
+    if snapshot.contains('/') {
+        return snapshot.to_string();
+    }
+
+    let archive = archive.unwrap_or(&context.archive_name);
+    format!("{}/{}", archive, snapshot)
+}
+
+/// Highest number in the storage keys of the configured archive's snapshots.
+/// This only picks the key of a new snapshot, the newest one is found by
+/// get_latest_snapshot.
+pub async fn get_highest_snapshot_number(context: &ProgramContext) -> CommandResult<u32> {
+    // Find the highest snapshot number.
+    let snapshots = context
+        .storage
+        .get_collection_items_with_prefix(
+            Collection::Snapshot,
+            &format!("{}/", context.archive_name),
+        )
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to get snapshots")?;
+    let mut highest_snapshot: u32 = 0;
+    for snapshot_name in snapshots {
+        let parts: Vec<_> = snapshot_name.split('/').collect();
+        if parts.len() != 2 {
+            warn!("Invalid snapshot name: {}", snapshot_name);
+            continue;
+        }
+
+        if parts[0] != context.archive_name {
+            continue;
+        }
+        if let Ok(snapshot_number) = parts[1].parse::<u32>() {
+            highest_snapshot = highest_snapshot.max(snapshot_number);
+        }
+    }
+    Ok(highest_snapshot)
+}
+
+/// Key ordering the snapshots of an archive oldest first by what they
+/// record: their sequence, then their start time for snapshots from before
+/// sequences were recorded. The storage key only breaks ties.
+pub fn snapshot_order<'a>(name: &'a str, snapshot: &Snapshot) -> (u64, i64, u32, &'a str) {
+    (
+        snapshot.sequence,
+        snapshot.started,
+        snapshot.started_nanos,
+        name,
+    )
+}
+
+/// Archive part of a snapshot name.
+pub fn snapshot_archive(snapshot_name: &str) -> &str {
+    snapshot_name
+        .rsplit_once('/')
+        .map_or(snapshot_name, |(archive, _)| archive)
+}
+
+/// The newest snapshot of `archive` (the configured archive by default) in
+/// `snapshot_order`, or None if it has none. Snapshots that can't be read,
+/// e.g. because they were forgotten meanwhile, are skipped.
+pub async fn get_latest_snapshot(
+    context: &ProgramContext,
+    archive: Option<&str>,
+) -> CommandResult<Option<(String, Snapshot)>> {
+    let archive = archive.unwrap_or(&context.archive_name);
+    let snapshot_names = context
+        .storage
+        .get_collection_items_with_prefix(Collection::Snapshot, &format!("{}/", archive))
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let mut latest: Option<(String, Snapshot)> = None;
+    for snapshot_name in snapshot_names {
+        let snapshot = match get_snapshot(context, &snapshot_name).await {
+            Ok(snapshot) => snapshot,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    CommandErrorKind::NotFound | CommandErrorKind::Corrupt
+                ) =>
+            {
+                warn!("Skipping snapshot {}: {}", snapshot_name, e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if latest.as_ref().is_none_or(|(latest_name, latest)| {
+            snapshot_order(&snapshot_name, &snapshot) > snapshot_order(latest_name, latest)
+        }) {
+            latest = Some((snapshot_name, snapshot));
+        }
+    }
+    Ok(latest)
+}
+
+pub async fn get_snapshot(
+    context: &ProgramContext,
+    snapshot_name: &str,
+) -> CommandResult<Snapshot> {
+    let mut snapshot_buf = Vec::new();
+    if let Err(e) = context
+        .storage
+        .read(Collection::Snapshot, snapshot_name, &mut snapshot_buf)
+        .await
+    {
+        if e.kind() == io::ErrorKind::NotFound {
+            return Err(CommandError::new(
+                CommandErrorKind::NotFound,
+                format!("Snapshot not found {}", snapshot_name),
+            ));
+        } else {
+            return Err(e.into_io_command_error("Failed to download snapshot"));
+        }
+    }
+
+    Snapshot::decode(Cursor::new(snapshot_buf)).map_err(|e| {
+        CommandError::with_source(
+            CommandErrorKind::Corrupt,
+            format!("Error decoding snapshot {}", snapshot_name),
+            Box::new(e),
+        )
+    })
+}
+
+/// Stats records of the backups of `archive` (the configured archive by
+/// default) by snapshot name, oldest first.
+pub async fn get_stats_history(
+    context: &ProgramContext,
+    archive: Option<&str>,
+) -> CommandResult<Vec<(String, BackupStats)>> {
+    let archive = archive.unwrap_or(&context.archive_name);
+    let items = context
+        .storage
+        .get_collection_items_with_prefix(Collection::Stats, &format!("{}/", archive))
+        .await
+        .into_io_command_result("Failed to list backup stats")?;
+    let mut numbered: Vec<(u32, String)> = items
+        .into_iter()
+        .filter_map(|name| {
+            let number = name
+                .strip_prefix(archive)?
+                .strip_prefix('/')?
+                .parse::<u32>()
+                .ok()?;
+            Some((number, name))
+        })
+        .collect();
+    numbered.sort();
+
+    let mut history = Vec::with_capacity(numbered.len());
+    let mut buffer = Vec::new();
+    for (_, name) in numbered {
+        context
+            .storage
+            .read(Collection::Stats, &name, &mut buffer)
+            .await
+            .into_io_command_result("Failed to download backup stats")?;
+        let stats = BackupStats::decode(buffer.as_slice())
+            .into_command_result(CommandErrorKind::Corrupt, "Failed to decode backup stats")?;
+        history.push((name, stats));
+    }
+    Ok(history)
+}
+
+/// Whether new blob keys should name their hash algorithm. New repositories
+/// use prefixed keys. Older ones keep the form of their snapshots, given by
+/// `snapshot` or else by any snapshot, so that new data still deduplicates
+/// against the old.
+pub async fn use_prefixed_keys(
+    context: &ProgramContext,
+    snapshot: Option<&Snapshot>,
+) -> CommandResult<bool> {
+    let root_hash = match snapshot {
+        Some(snapshot) => snapshot.root_hash.clone(),
+        None => {
+            let snapshots = context
+                .storage
+                .get_collection_items(Collection::Snapshot)
+                .await
+                .into_io_command_result("Failed to list snapshots")?;
+            match snapshots.first() {
+                Some(snapshot_name) => get_snapshot(context, snapshot_name).await?.root_hash,
+                None => return Ok(true),
+            }
+        }
+    };
+    Ok(root_hash.starts_with(SHA256_KEY_PREFIX))
+}
+
+/// Name of the newest snapshot in the archive that was started before
+/// `time`, given as e.g. "2024-05-01 12:00".
+pub async fn resolve_snapshot_before(
+    context: &ProgramContext,
+    archive: Option<&str>,
+    time: &str,
+) -> CommandResult<String> {
+    let before =
+        parse_time(time).map_err(|message| CommandError::new(CommandErrorKind::User, message))?;
+    let archive = archive.unwrap_or(&context.archive_name);
+    let snapshots = context
+        .storage
+        .get_collection_items_with_prefix(Collection::Snapshot, &format!("{}/", archive))
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+
+    let mut newest: Option<(String, Snapshot)> = None;
+    for snapshot_name in snapshots {
+        let snapshot = get_snapshot(context, &snapshot_name).await?;
+        if snapshot.started >= before {
+            continue;
+        }
+        if newest.as_ref().is_none_or(|(newest_name, newest)| {
+            snapshot_order(&snapshot_name, &snapshot) > snapshot_order(newest_name, newest)
+        }) {
+            newest = Some((snapshot_name, snapshot));
+        }
+    }
+
+    newest.map(|(name, _)| name).ok_or_else(|| {
+        CommandError::new(
+            CommandErrorKind::NotFound,
+            format!("No snapshot in archive {} before {}", archive, time),
+        )
+    })
+}
+
+/// Path relative to the backup target as filters match it.
+pub fn filter_path(relative_path: &Path) -> CommandResult<String> {
+    let components = relative_path
+        .iter()
+        .map(|component| sanitize_os_string(component.to_owned()))
+        .collect::<CommandResult<Vec<_>>>()?;
+    Ok(components.join("/"))
+}
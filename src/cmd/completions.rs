@@ -0,0 +1,128 @@
+use std::{io, path::PathBuf};
+
+use clap::{Args, Command};
+use clap_complete::Shell;
+use clap_mangen::Man;
+use tokio::fs;
+use tracing::info;
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct CompletionsArgs {
+    /// Shell to write the completion script for.
+    pub shell: Shell,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ManpagesArgs {
+    /// Directory to write the man pages to.
+    pub dir: PathBuf,
+}
+
+/// Completion script for `shell`, generated by clap_complete.
+pub fn completion_script(command: &mut Command, shell: Shell) -> Vec<u8> {
+    let name = command.get_name().to_string();
+    let mut script = Vec::new();
+    clap_complete::generate(shell, command, name, &mut script);
+    script
+}
+
+pub fn completions(command: &mut Command, args: &CompletionsArgs) -> CommandResult {
+    let script = completion_script(command, args.shell);
+    io::Write::write_all(&mut io::stdout(), &script)
+        .into_command_result(CommandErrorKind::System, "Failed to write the script")
+}
+
+fn collect_manpages(command: &Command, output: &mut Vec<(String, Vec<u8>)>) -> io::Result<()> {
+    // Subcommands are named after their parents once the command is built,
+    // e.g. "freebck-repo-export".
+    let name = command
+        .get_display_name()
+        .unwrap_or(command.get_name())
+        .to_string();
+    let mut page = Vec::new();
+    Man::new(command.clone()).render(&mut page)?;
+    output.push((format!("{}.1", name), page));
+    for subcommand in command
+        .get_subcommands()
+        .filter(|c| !c.is_hide_set() && c.get_name() != "help")
+    {
+        collect_manpages(subcommand, output)?;
+    }
+    Ok(())
+}
+
+/// Man pages for the command and each of its subcommands, as pairs of file
+/// name and roff source, generated by clap_mangen.
+pub fn manpage_files(command: &mut Command) -> io::Result<Vec<(String, Vec<u8>)>> {
+    command.build();
+    let mut output = Vec::new();
+    collect_manpages(command, &mut output)?;
+    Ok(output)
+}
+
+pub async fn manpages(command: &mut Command, args: &ManpagesArgs) -> CommandResult {
+    fs::create_dir_all(&args.dir)
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to create directory")?;
+    let pages = manpage_files(command)
+        .into_command_result(CommandErrorKind::Program, "Failed to render man pages")?;
+    for (name, page) in &pages {
+        let path = args.dir.join(name);
+        fs::write(&path, page).await.into_command_result(
+            CommandErrorKind::System,
+            format!("Failed to write {}", path.display()).as_str(),
+        )?;
+    }
+    info!("Wrote {} man pages to {}", pages.len(), args.dir.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_command() -> Command {
+        Command::new("tool")
+            .version("1.0")
+            .about("A tool")
+            .arg(clap::Arg::new("verbose").long("verbose").short('v'))
+            .subcommand(
+                Command::new("repo")
+                    .about("Repository commands")
+                    .subcommand(
+                        Command::new("export")
+                            .about("Export it")
+                            .arg(clap::Arg::new("file").required(true))
+                            .arg(clap::Arg::new("snapshot").long("snapshot").help("Don't")),
+                    ),
+            )
+    }
+
+    #[test]
+    fn test_completion_scripts() {
+        let script = completion_script(&mut test_command(), Shell::Bash);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("complete -F _tool"), "{}", script);
+        assert!(script.contains("--snapshot"));
+
+        let script = completion_script(&mut test_command(), Shell::Fish);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("-l snapshot"), "{}", script);
+        assert!(script.contains("Repository commands"));
+    }
+
+    #[test]
+    fn test_manpages() {
+        let pages = manpage_files(&mut test_command()).unwrap();
+        let names: Vec<&str> = pages.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["tool.1", "tool-repo.1", "tool-repo-export.1"]);
+
+        let export = String::from_utf8(pages[2].1.clone()).unwrap();
+        assert!(export.starts_with(".ie \\n(.g .ds Aq"), "{}", export);
+        assert!(export.contains(".TH tool-repo-export 1"), "{}", export);
+        assert!(export.contains("tool\\-repo\\-export \\- Export it"));
+        assert!(export.contains("\\-\\-snapshot"));
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use async_recursion::async_recursion;
+use clap::Args;
+use tokio::{fs::File, io::BufReader};
+use tracing::info;
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry},
+    util::{
+        tar::{TarEntryKind, TarReader},
+        time::format_time,
+    },
+};
+
+use super::{
+    common::*,
+    verify::{compare_with_dir, Difference, DifferenceKind},
+};
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// Snapshot to compare.
+    #[arg(required_unless_present = "before")]
+    pub snapshot: Option<String>,
+    /// Compare the newest snapshot started before this time, e.g.
+    /// "2024-05-01 12:00".
+    #[arg(long, conflicts_with = "snapshot")]
+    pub before: Option<String>,
+    /// Directory to compare the snapshot against.
+    #[arg(
+        long,
+        required_unless_present = "against_tar",
+        conflicts_with = "against_tar"
+    )]
+    pub against_dir: Option<PathBuf>,
+    /// Tar archive to compare the snapshot against.
+    #[arg(long)]
+    pub against_tar: Option<PathBuf>,
+    /// Compare file contents byte by byte instead of by hash. Only applies
+    /// to directories.
+    #[arg(long)]
+    pub bitwise: bool,
+}
+
+pub async fn diff(context: &ProgramContext, args: &DiffArgs) -> CommandResult {
+    let snapshot_name = match (&args.snapshot, &args.before) {
+        (Some(snapshot), _) => resolve_snapshot_name(context, None, snapshot),
+        (None, Some(before)) => resolve_snapshot_before(context, None, before).await?,
+        (None, None) => {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                "No snapshot to compare".to_string(),
+            ))
+        }
+    };
+    let snapshot = get_snapshot(context, &snapshot_name).await?;
+    let root_dir_entry = get_dir_entry(context, &snapshot.root_hash).await?;
+
+    let differences = match (&args.against_dir, &args.against_tar) {
+        (Some(against_dir), _) => {
+            compare_with_dir(context, root_dir_entry, against_dir, args.bitwise).await?
+        }
+        (None, Some(against_tar)) => compare_with_tar(context, root_dir_entry, against_tar).await?,
+        (None, None) => {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                "Nothing to compare against".to_string(),
+            ))
+        }
+    };
+
+    for difference in differences.iter() {
+        println!("{}", difference);
+    }
+    info!(
+        "{} differences between snapshot {} ({}) and the compared data",
+        differences.len(),
+        snapshot_name,
+        format_time(snapshot.started)
+    );
+    Ok(())
+}
+
+#[derive(Debug)]
+enum FlatEntry {
+    Directory,
+    File {
+        size: u64,
+        modified: i64,
+        content_hash: String,
+    },
+    Other,
+}
+
+#[async_recursion]
+async fn flatten_snapshot(
+    context: &ProgramContext,
+    dir_entry: DirEntry,
+    relative_path: &Path,
+    entries: &mut BTreeMap<PathBuf, FlatEntry>,
+) -> CommandResult {
+    for file in dir_entry.file.into_iter() {
+        entries.insert(
+            relative_path.join(&file.name),
+            FlatEntry::File {
+                size: file.size,
+                modified: file.modified,
+                content_hash: file.content_hash,
+            },
+        );
+    }
+
+    for sub_dir in dir_entry.sub_dir.into_iter() {
+        let sub_dir_path = relative_path.join(&sub_dir.name);
+        let sub_dir_entry = match sub_dir.content {
+            Some(Content::Inline(dir_entry)) => dir_entry,
+            Some(Content::Hash(hash)) => get_dir_entry(context, &hash).await?,
+            None => {
+                return Err(CommandError::new(
+                    CommandErrorKind::Corrupt,
+                    format!("Sub dir entry without content {}", sub_dir_path.display()),
+                ))
+            }
+        };
+        flatten_snapshot(context, sub_dir_entry, &sub_dir_path, entries).await?;
+        entries.insert(sub_dir_path, FlatEntry::Directory);
+    }
+
+    Ok(())
+}
+
+async fn read_tar(path: &Path) -> CommandResult<BTreeMap<PathBuf, FlatEntry>> {
+    let file = File::open(path).await.into_command_result(
+        CommandErrorKind::User,
+        format!("Failed to open tar archive: {}", path.display()).as_str(),
+    )?;
+    let mut reader = TarReader::new(BufReader::new(file));
+
+    let mut entries = BTreeMap::new();
+    while let Some(tar_entry) = reader
+        .next_entry()
+        .await
+        .into_command_result(CommandErrorKind::User, "Failed to read tar archive")?
+    {
+        let entry_path: PathBuf = tar_entry
+            .path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect();
+        if entry_path.as_os_str().is_empty() {
+            continue;
+        }
+
+        // Archives don't need to contain their parent directories.
+        for parent in entry_path.ancestors().skip(1) {
+            if parent.as_os_str().is_empty() {
+                break;
+            }
+            entries
+                .entry(parent.to_owned())
+                .or_insert(FlatEntry::Directory);
+        }
+
+        let flat_entry = match tar_entry.kind {
+            TarEntryKind::File => FlatEntry::File {
+                size: tar_entry.size,
+                modified: tar_entry.modified,
+                content_hash: tar_entry.content_hash,
+            },
+            TarEntryKind::Directory => FlatEntry::Directory,
+            TarEntryKind::Other => FlatEntry::Other,
+        };
+        entries.insert(entry_path, flat_entry);
+    }
+
+    Ok(entries)
+}
+
+async fn compare_with_tar(
+    context: &ProgramContext,
+    dir_entry: DirEntry,
+    path: &Path,
+) -> CommandResult<Vec<Difference>> {
+    let mut snapshot_entries = BTreeMap::new();
+    flatten_snapshot(context, dir_entry, Path::new(""), &mut snapshot_entries).await?;
+    let mut tar_entries = read_tar(path).await?;
+
+    let mut differences = Vec::new();
+    for (path, snapshot_entry) in snapshot_entries.into_iter() {
+        let tar_entry = tar_entries.remove(&path);
+        let kinds = match (snapshot_entry, tar_entry) {
+            (_, None) => vec![DifferenceKind::OnlyInSnapshot],
+            (FlatEntry::Directory, Some(FlatEntry::Directory)) => vec![],
+            (
+                FlatEntry::File {
+                    size,
+                    modified,
+                    content_hash,
+                },
+                Some(FlatEntry::File {
+                    size: tar_size,
+                    modified: tar_modified,
+                    content_hash: tar_content_hash,
+                }),
+            ) => {
+                let mut kinds = Vec::new();
+                if modified != tar_modified {
+                    kinds.push(DifferenceKind::Modified);
+                }
+                if size != tar_size {
+                    kinds.push(DifferenceKind::Size);
+                } else if content_hash != tar_content_hash {
+                    kinds.push(DifferenceKind::Content);
+                }
+                kinds
+            }
+            _ => vec![DifferenceKind::TypeMismatch],
+        };
+
+        for kind in kinds {
+            differences.push(Difference {
+                path: path.clone(),
+                kind,
+            });
+        }
+    }
+
+    for path in tar_entries.into_keys() {
+        differences.push(Difference {
+            path,
+            kind: DifferenceKind::NotInSnapshot,
+        });
+    }
+
+    Ok(differences)
+}
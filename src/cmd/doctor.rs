@@ -0,0 +1,240 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::Args;
+use tokio::fs;
+
+use crate::{storage::Collection, util::time::as_unix_timestamp};
+
+use super::{common::*, restore::RESTORE_SESSIONS_DIR};
+
+// Storage listings slower than this are reported.
+const SLOW_STORAGE: Duration = Duration::from_secs(5);
+// Snapshots finished further than this in the future point to a wrong clock.
+const CLOCK_TOLERANCE: i64 = 5 * 60;
+
+#[derive(Debug, Default, Args)]
+pub struct DoctorArgs {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Problem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, message: String) -> Self {
+        Self { severity, message }
+    }
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Problem => "PROBLEM",
+        };
+        write!(f, "[{}] {}", label, self.message)
+    }
+}
+
+pub async fn doctor(context: &ProgramContext, _args: &DoctorArgs) -> CommandResult {
+    let findings = diagnose(context).await;
+    for finding in &findings {
+        println!("{}", finding);
+    }
+
+    let problems = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Problem)
+        .count();
+    if problems > 0 {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            format!("{} problems found", problems),
+        ));
+    }
+    Ok(())
+}
+
+/// Run all environment checks. The config has already been loaded
+/// successfully by the time a context exists.
+pub async fn diagnose(context: &ProgramContext) -> Vec<Finding> {
+    let mut findings = vec![Finding::new(
+        Severity::Ok,
+        format!("Config is valid, archive {}", context.archive_name),
+    )];
+
+    check_storage(context, &mut findings).await;
+    check_backup_target(context, &mut findings).await;
+    check_state_dir(context, &mut findings).await;
+    findings
+}
+
+async fn check_storage(context: &ProgramContext, findings: &mut Vec<Finding>) {
+    let started = Instant::now();
+    let snapshots = match context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+    {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Problem,
+                format!(
+                    "Storage is not reachable: {}. Check the storage settings and credentials",
+                    e
+                ),
+            ));
+            return;
+        }
+    };
+    let latency = started.elapsed();
+    findings.push(Finding::new(
+        if latency > SLOW_STORAGE {
+            Severity::Warning
+        } else {
+            Severity::Ok
+        },
+        format!(
+            "Storage is reachable, listed {} snapshots in {}",
+            snapshots.len(),
+            humantime::format_duration(Duration::from_millis(latency.as_millis() as u64))
+        ),
+    ));
+
+    // The newest snapshot is found by what the snapshots record, so all of
+    // them have to be readable.
+    let (latest, snapshot) = match get_latest_snapshot(context, None).await {
+        Ok(Some(latest)) => latest,
+        Ok(None) => return,
+        Err(e) => {
+            let cause = e.source().map(|e| format!(": {}", e)).unwrap_or_default();
+            findings.push(Finding::new(
+                Severity::Problem,
+                format!(
+                    "Failed to read the snapshots of archive {}. {}{}. It may have been written by an incompatible version",
+                    context.archive_name, e, cause
+                ),
+            ));
+            return;
+        }
+    };
+    findings.push(Finding::new(
+        Severity::Ok,
+        format!("The latest snapshot {} is readable", latest),
+    ));
+
+    let now = as_unix_timestamp(SystemTime::now());
+    if snapshot.finished > now + CLOCK_TOLERANCE {
+        findings.push(Finding::new(
+            Severity::Warning,
+            format!(
+                "The latest snapshot {} finished {} seconds in the future. Check the system clock",
+                latest,
+                snapshot.finished - now
+            ),
+        ));
+    } else {
+        findings.push(Finding::new(
+            Severity::Ok,
+            "System clock is consistent with the latest snapshot".to_string(),
+        ));
+    }
+}
+
+async fn check_backup_target(context: &ProgramContext, findings: &mut Vec<Finding>) {
+    let target = context.backup_target.display();
+    match fs::read_dir(&context.backup_target).await {
+        Ok(_) => findings.push(Finding::new(
+            Severity::Ok,
+            format!("Backup target {} is readable", target),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => findings.push(Finding::new(
+            Severity::Problem,
+            format!(
+                "Backup target {} does not exist. Check \"path\" in the config",
+                target
+            ),
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => findings.push(Finding::new(
+            Severity::Problem,
+            format!(
+                "Backup target {} is not readable. Run as a user with access to it",
+                target
+            ),
+        )),
+        Err(e) => findings.push(Finding::new(
+            Severity::Problem,
+            format!("Failed to read backup target {}: {}", target, e),
+        )),
+    }
+}
+
+async fn check_state_dir(context: &ProgramContext, findings: &mut Vec<Finding>) {
+    let mut entries = match fs::read_dir(&context.state_dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            findings.push(Finding::new(
+                Severity::Warning,
+                format!(
+                    "Failed to read state directory {}: {}",
+                    context.state_dir.display(),
+                    e
+                ),
+            ));
+            return;
+        }
+    };
+
+    let mut stale = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            stale.push(path.display().to_string());
+        }
+    }
+    if stale.is_empty() {
+        findings.push(Finding::new(
+            Severity::Ok,
+            "No leftover temporary files".to_string(),
+        ));
+    } else {
+        findings.push(Finding::new(
+            Severity::Warning,
+            format!(
+                "Leftover temporary files from an interrupted run, safe to delete: {}",
+                stale.join(", ")
+            ),
+        ));
+    }
+
+    let mut sessions = Vec::new();
+    if let Ok(mut entries) = fs::read_dir(context.state_dir.join(RESTORE_SESSIONS_DIR)).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            sessions.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    if !sessions.is_empty() {
+        sessions.sort();
+        findings.push(Finding::new(
+            Severity::Warning,
+            format!(
+                "Unfinished restore sessions, continue them with restore --resume: {}",
+                sessions.join(", ")
+            ),
+        ));
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use async_recursion::async_recursion;
+use clap::Args;
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry, Snapshot},
+    storage::Collection,
+    util::time::format_short_time,
+};
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct FindArgs {
+    /// SHA-256 of the file contents to look for, in hex.
+    #[arg(long)]
+    pub hash: String,
+    /// Only search this archive. All archives are searched by default.
+    #[arg(long)]
+    pub archive: Option<String>,
+}
+
+/// Snapshot containing files with the hash searched for.
+#[derive(Debug)]
+pub struct FindMatch {
+    pub snapshot_name: String,
+    pub snapshot: Snapshot,
+    /// Paths of the files, relative to the backup target.
+    pub paths: Vec<String>,
+}
+
+pub async fn find(context: &ProgramContext, args: &FindArgs) -> CommandResult {
+    let matches = find_hash(context, &args.hash, args.archive.as_deref()).await?;
+    let (Some(first), Some(last)) = (matches.first(), matches.last()) else {
+        return Err(CommandError::new(
+            CommandErrorKind::NotFound,
+            format!("No snapshot has a file with hash {}", args.hash),
+        ));
+    };
+
+    for found in &matches {
+        for path in &found.paths {
+            println!(
+                "{:<20} {:<16} {}",
+                found.snapshot_name,
+                format_short_time(found.snapshot.started),
+                path
+            );
+        }
+    }
+    println!(
+        "Found in {} snapshots, first in {} at {}, last in {} at {}",
+        matches.len(),
+        first.snapshot_name,
+        format_short_time(first.snapshot.started),
+        last.snapshot_name,
+        format_short_time(last.snapshot.started)
+    );
+    Ok(())
+}
+
+/// Every snapshot with a file whose contents hash to `hash`, oldest first.
+pub async fn find_hash(
+    context: &ProgramContext,
+    hash: &str,
+    archive: Option<&str>,
+) -> CommandResult<Vec<FindMatch>> {
+    let hash = hash.to_ascii_lowercase();
+    if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            format!("Not a SHA-256 hash: {}", hash),
+        ));
+    }
+
+    let snapshot_names = context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let mut matches = Vec::new();
+    // Snapshots share most of their directories, so each is searched once.
+    let mut dir_paths = HashMap::new();
+    for snapshot_name in snapshot_names {
+        if archive.is_some_and(|archive| snapshot_name.split('/').next() != Some(archive)) {
+            continue;
+        }
+        let snapshot = get_snapshot(context, &snapshot_name).await?;
+        let paths = find_in_dir(context, &snapshot.root_hash, &hash, &mut dir_paths).await?;
+        if !paths.is_empty() {
+            matches.push(FindMatch {
+                snapshot_name,
+                snapshot,
+                paths,
+            });
+        }
+    }
+    matches.sort_by(|a, b| {
+        (a.snapshot.started, &a.snapshot_name).cmp(&(b.snapshot.started, &b.snapshot_name))
+    });
+    Ok(matches)
+}
+
+/// Paths of the files with `content_hash` below the directory entry `hash`,
+/// relative to it. Results are kept in `dir_paths` by directory hash.
+async fn find_in_dir(
+    context: &ProgramContext,
+    hash: &str,
+    content_hash: &str,
+    dir_paths: &mut HashMap<String, Vec<String>>,
+) -> CommandResult<Vec<String>> {
+    if let Some(paths) = dir_paths.get(hash) {
+        return Ok(paths.clone());
+    }
+    let dir_entry = get_dir_entry(context, hash).await?;
+    let paths = find_in_entry(context, &dir_entry, content_hash, dir_paths).await?;
+    dir_paths.insert(hash.to_string(), paths.clone());
+    Ok(paths)
+}
+
+#[async_recursion]
+async fn find_in_entry(
+    context: &ProgramContext,
+    dir_entry: &DirEntry,
+    content_hash: &str,
+    dir_paths: &mut HashMap<String, Vec<String>>,
+) -> CommandResult<Vec<String>> {
+    let mut paths: Vec<String> = dir_entry
+        .file
+        .iter()
+        .filter(|file| file.content_hash == content_hash)
+        .map(|file| file.name.clone())
+        .collect();
+    for sub_dir in &dir_entry.sub_dir {
+        let sub_paths = match sub_dir.content {
+            Some(Content::Hash(ref hash)) => {
+                find_in_dir(context, hash, content_hash, dir_paths).await?
+            }
+            Some(Content::Inline(ref dir_entry)) => {
+                find_in_entry(context, dir_entry, content_hash, dir_paths).await?
+            }
+            None => continue,
+        };
+        paths.extend(
+            sub_paths
+                .into_iter()
+                .map(|path| format!("{}/{}", sub_dir.name, path)),
+        );
+    }
+    paths.sort();
+    Ok(paths)
+}
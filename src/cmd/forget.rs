@@ -0,0 +1,397 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+use clap::Args;
+use tracing::info;
+
+use crate::{
+    data::{backup::Snapshot, config::RetentionConfig},
+    storage::Collection,
+    util::{
+        hooks::{fire_hook, HookEvent},
+        time::{as_unix_timestamp, calendar_periods, display_utc, format_short_time},
+    },
+};
+
+use super::{
+    common::*,
+    snapshots::{list_snapshots, SnapshotsArgs},
+};
+
+#[derive(Debug, Default, Args)]
+pub struct ForgetArgs {
+    /// Keep this many of the most recent snapshots.
+    #[arg(long)]
+    pub keep_last: Option<usize>,
+    /// Keep the newest snapshot of each of this many most recent days that
+    /// have one.
+    #[arg(long)]
+    pub keep_daily: Option<usize>,
+    /// Keep the newest snapshot of each of this many most recent weeks that
+    /// have one.
+    #[arg(long)]
+    pub keep_weekly: Option<usize>,
+    /// Keep the newest snapshot of each of this many most recent months that
+    /// have one.
+    #[arg(long)]
+    pub keep_monthly: Option<usize>,
+    /// Keep this many of the most recent snapshots of a retention class, as
+    /// "class=count". Can be repeated.
+    #[arg(long, value_parser = parse_keep_class)]
+    pub keep_class: Vec<(String, usize)>,
+    /// Forget snapshots past the expiry time given with backup
+    /// --expire-after. The --keep flags only apply to the rest.
+    #[arg(long)]
+    pub expired: bool,
+    /// Archive to forget snapshots of, the configured one by default.
+    #[arg(long)]
+    pub archive: Option<String>,
+    /// Only show which snapshots would be forgotten.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl ForgetArgs {
+    /// Policy given by the --keep flags, or `config` if there are none.
+    pub fn policy(&self, config: Option<&RetentionConfig>) -> RetentionConfig {
+        let policy = RetentionConfig {
+            keep_last: self.keep_last,
+            keep_daily: self.keep_daily,
+            keep_weekly: self.keep_weekly,
+            keep_monthly: self.keep_monthly,
+            keep_class: self.keep_class.iter().cloned().collect(),
+        };
+        match config {
+            Some(config) if policy == RetentionConfig::default() => config.clone(),
+            _ => policy,
+        }
+    }
+}
+
+/// Parse a "class=count" pair of --keep-class.
+fn parse_keep_class(text: &str) -> Result<(String, usize), String> {
+    let invalid = || format!("Invalid class count {:?}, expected class=count", text);
+    match text.split_once('=') {
+        Some((class, count)) if !class.is_empty() => {
+            Ok((class.to_string(), count.parse().map_err(|_| invalid())?))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Whether `snapshot` was given an expiry time that is before `now`.
+pub fn is_expired(snapshot: &Snapshot, now: i64) -> bool {
+    snapshot.expires != 0 && snapshot.expires < now
+}
+
+/// Why the policy keeps each of `snapshots`, which are ordered oldest first.
+/// Snapshots with no reasons are forgotten.
+pub fn apply_policy(
+    snapshots: &[(String, Snapshot)],
+    policy: &RetentionConfig,
+    utc: bool,
+) -> Vec<Vec<&'static str>> {
+    struct Rule {
+        reason: &'static str,
+        remaining: usize,
+        /// Period of a snapshot given its position in its class and calendar
+        /// periods. Every snapshot is a period of its own for keep_last.
+        period: fn(usize, (i64, i64, i64)) -> i64,
+        last: Option<i64>,
+    }
+
+    let mut classes: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (index, (_, snapshot)) in snapshots.iter().enumerate() {
+        classes
+            .entry(&snapshot.retention_class)
+            .or_default()
+            .push(index);
+    }
+
+    let mut reasons = vec![Vec::new(); snapshots.len()];
+    for (class, indices) in classes {
+        let keep_class = match class {
+            "" => None,
+            class => policy.keep_class.get(class).copied(),
+        };
+        let mut rules: Vec<Rule> = [
+            (
+                "last",
+                policy.keep_last,
+                (|position, _| position as i64) as fn(_, _) -> _,
+            ),
+            ("daily", policy.keep_daily, |_, (day, _, _)| day),
+            ("weekly", policy.keep_weekly, |_, (_, week, _)| week),
+            ("monthly", policy.keep_monthly, |_, (_, _, month)| month),
+            ("class", keep_class, |position, _| position as i64),
+        ]
+        .into_iter()
+        .filter_map(|(reason, count, period)| {
+            Some(Rule {
+                reason,
+                remaining: count?,
+                period,
+                last: None,
+            })
+        })
+        .collect();
+
+        for (position, &index) in indices.iter().enumerate().rev() {
+            let periods = calendar_periods(snapshots[index].1.started, utc);
+            for rule in rules.iter_mut() {
+                let period = (rule.period)(position, periods);
+                if rule.remaining > 0 && rule.last != Some(period) {
+                    rule.remaining -= 1;
+                    rule.last = Some(period);
+                    reasons[index].push(rule.reason);
+                }
+            }
+        }
+    }
+    reasons
+}
+
+pub async fn forget(
+    context: &ProgramContext,
+    args: &ForgetArgs,
+    config: Option<&RetentionConfig>,
+) -> CommandResult {
+    let policy = args.policy(config);
+    let no_policy = policy == RetentionConfig::default();
+    if no_policy && !args.expired {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "No retention policy, pass --keep-* flags or --expired, or set retention in the config"
+                .to_string(),
+        ));
+    }
+
+    let archive = args.archive.as_ref().unwrap_or(&context.archive_name);
+    let snapshots = list_snapshots(
+        context,
+        &SnapshotsArgs {
+            archive: Some(archive.clone()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    // Expired snapshots are forgotten whatever the policy says, and don't
+    // take the place of others in it.
+    let now = as_unix_timestamp(SystemTime::now());
+    let expired: Vec<bool> = snapshots
+        .iter()
+        .map(|(_, snapshot)| args.expired && is_expired(snapshot, now))
+        .collect();
+    let unexpired: Vec<(String, Snapshot)> = snapshots
+        .iter()
+        .zip(&expired)
+        .filter(|(_, expired)| !**expired)
+        .map(|(snapshot, _)| snapshot.clone())
+        .collect();
+    let mut unexpired_reasons = if no_policy {
+        vec![vec!["unexpired"]; unexpired.len()]
+    } else {
+        apply_policy(&unexpired, &policy, display_utc())
+    }
+    .into_iter();
+    let reasons: Vec<Vec<&str>> = expired
+        .iter()
+        .map(|expired| match expired {
+            true => Vec::new(),
+            false => unexpired_reasons.next().unwrap(),
+        })
+        .collect();
+
+    println!(
+        "{:<20} {:<16} {:<7} Reasons",
+        "Snapshot", "Started", "Action"
+    );
+    for (((name, snapshot), reasons), expired) in snapshots.iter().zip(&reasons).zip(&expired) {
+        println!(
+            "{:<20} {:<16} {:<7} {}",
+            name,
+            format_short_time(snapshot.started),
+            if reasons.is_empty() { "forget" } else { "keep" },
+            if *expired {
+                "expired".to_string()
+            } else {
+                reasons.join(", ")
+            }
+        );
+    }
+
+    let forgotten: Vec<&(String, Snapshot)> = snapshots
+        .iter()
+        .zip(&reasons)
+        .filter(|(_, reasons)| reasons.is_empty())
+        .map(|(snapshot, _)| snapshot)
+        .collect();
+    if args.dry_run {
+        info!("Would forget {} snapshots", forgotten.len());
+        return Ok(());
+    }
+    for (name, _) in &forgotten {
+        context
+            .storage
+            .delete(Collection::Snapshot, name)
+            .await
+            .into_io_command_result(format!("Failed to forget snapshot {}", name).as_str())?;
+        context
+            .storage
+            .delete(Collection::Stats, name)
+            .await
+            .into_io_command_result(format!("Failed to delete stats of {}", name).as_str())?;
+    }
+    context
+        .storage
+        .flush()
+        .await
+        .into_io_command_result("Failed to flush storage")?;
+    for (name, snapshot) in &forgotten {
+        fire_hook(
+            context,
+            HookEvent::SnapshotForgotten {
+                snapshot: name,
+                started: snapshot.started,
+            },
+        )
+        .await;
+    }
+    info!(
+        "Forgot {} snapshots, the data only they used stays until it is pruned",
+        forgotten.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_policy() {
+        let day = 86_400;
+        // 2024-01-01 was a Monday. Two snapshots a day for 40 days.
+        let start = 1_704_067_200;
+        let snapshots: Vec<(String, Snapshot)> = (0..80)
+            .map(|i| {
+                let snapshot = Snapshot {
+                    started: start + i * day / 2,
+                    ..Default::default()
+                };
+                (format!("home/{}", i + 1), snapshot)
+            })
+            .collect();
+        let kept = |policy: RetentionConfig| -> Vec<String> {
+            apply_policy(&snapshots, &policy, true)
+                .iter()
+                .zip(&snapshots)
+                .filter(|(reasons, _)| !reasons.is_empty())
+                .map(|(_, (name, _))| name.clone())
+                .collect()
+        };
+
+        assert_eq!(
+            kept(RetentionConfig {
+                keep_last: Some(2),
+                ..Default::default()
+            }),
+            ["home/79", "home/80"]
+        );
+        // The newest snapshot of each day is the second one.
+        assert_eq!(
+            kept(RetentionConfig {
+                keep_daily: Some(3),
+                ..Default::default()
+            }),
+            ["home/76", "home/78", "home/80"]
+        );
+        // Weeks start on Mondays, the last one is February 5 to 9.
+        assert_eq!(
+            kept(RetentionConfig {
+                keep_weekly: Some(2),
+                ..Default::default()
+            }),
+            ["home/70", "home/80"]
+        );
+        assert_eq!(
+            kept(RetentionConfig {
+                keep_monthly: Some(5),
+                ..Default::default()
+            }),
+            ["home/62", "home/80"]
+        );
+
+        let reasons = apply_policy(
+            &snapshots,
+            &RetentionConfig {
+                keep_last: Some(1),
+                keep_daily: Some(1),
+                ..Default::default()
+            },
+            true,
+        );
+        assert_eq!(reasons[79], ["last", "daily"]);
+        assert!(reasons[78].is_empty());
+    }
+
+    #[test]
+    fn test_apply_policy_by_class() {
+        // Every third snapshot is a monthly one.
+        let snapshots: Vec<(String, Snapshot)> = (0..10)
+            .map(|i| {
+                let snapshot = Snapshot {
+                    started: 1_704_067_200 + i * 3600,
+                    retention_class: if i % 3 == 0 { "monthly" } else { "" }.to_string(),
+                    ..Default::default()
+                };
+                (format!("home/{}", i + 1), snapshot)
+            })
+            .collect();
+
+        let reasons = apply_policy(
+            &snapshots,
+            &RetentionConfig {
+                keep_last: Some(2),
+                keep_class: [("monthly".to_string(), 3)].into(),
+                ..Default::default()
+            },
+            true,
+        );
+        let kept: Vec<usize> = (0..10).filter(|i| !reasons[*i].is_empty()).collect();
+        assert_eq!(kept, [3, 6, 7, 8, 9]);
+        assert_eq!(reasons[9], ["last", "class"]);
+        assert_eq!(reasons[3], ["class"]);
+        assert_eq!(reasons[8], ["last"]);
+    }
+
+    #[test]
+    fn test_parse_keep_class() {
+        assert_eq!(
+            parse_keep_class("monthly=12"),
+            Ok(("monthly".to_string(), 12))
+        );
+        assert!(parse_keep_class("monthly").is_err());
+        assert!(parse_keep_class("=12").is_err());
+        assert!(parse_keep_class("monthly=x").is_err());
+    }
+
+    #[test]
+    fn test_policy_from_config() {
+        let config = RetentionConfig {
+            keep_daily: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(ForgetArgs::default().policy(Some(&config)), config);
+        let args = ForgetArgs {
+            keep_last: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            args.policy(Some(&config)),
+            RetentionConfig {
+                keep_last: Some(3),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::{Duration, SystemTime};
+
+use clap::Args;
+use tracing::info;
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct GcArgs {
+    /// Remove temporary objects and abort uploads left behind by interrupted
+    /// writes, and empty the trash of file storage with trash set.
+    #[arg(long)]
+    pub maintenance: bool,
+    /// Only remove temporary data older than this, e.g. "12h". Writes still
+    /// in progress must not be affected.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1d")]
+    pub older_than: Duration,
+}
+
+pub async fn gc(context: &ProgramContext, args: &GcArgs) -> CommandResult {
+    if !args.maintenance {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Nothing to collect, pass --maintenance".to_string(),
+        ));
+    }
+
+    let removed = context
+        .storage
+        .clean_temporary(SystemTime::now() - args.older_than)
+        .await
+        .into_io_command_result("Failed to clean up temporary objects")?;
+    info!("Removed {} temporary objects", removed);
+    Ok(())
+}
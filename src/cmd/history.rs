@@ -0,0 +1,185 @@
+use std::{
+    io,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::debug;
+
+use crate::util::{
+    size::format_size,
+    time::{as_unix_timestamp, format_short_time},
+};
+
+use super::common::*;
+
+// File in the state directory with one JSON object per line for each run.
+pub const HISTORY_FILE: &str = "history";
+// Runs kept in the history, older ones are dropped as new ones are recorded.
+const MAX_RUNS: usize = 1000;
+
+#[derive(Debug, Args)]
+pub struct HistoryArgs {
+    /// Number of most recent runs to show.
+    #[arg(long, default_value_t = 20)]
+    pub limit: usize,
+    /// Only show runs that failed.
+    #[arg(long)]
+    pub failed: bool,
+}
+
+/// Outcome of one run of a command, as kept in the history.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub command: String,
+    pub archive: String,
+    pub started: i64,
+    pub finished: i64,
+    /// What the command failed with, if it did.
+    pub error: Option<String>,
+    /// Snapshots created by the run.
+    pub snapshots: Vec<String>,
+    /// Bytes uploaded by the run, leaving out data the repository already had.
+    pub new_bytes: u64,
+}
+
+impl RunRecord {
+    pub fn start(command: &str, archive: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            archive: archive.to_string(),
+            started: as_unix_timestamp(SystemTime::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Add the snapshots that the run created in the archive of `context`, as
+    /// found by the stats their backups recorded.
+    pub async fn add_snapshots(&mut self, context: &ProgramContext) {
+        match get_stats_history(context, None).await {
+            Ok(history) => {
+                for (name, stats) in history {
+                    if stats.started >= self.started {
+                        self.snapshots.push(name);
+                        self.new_bytes += stats.new_bytes;
+                    }
+                }
+            }
+            Err(e) => debug!("Failed to read backup stats for the history: {}", e),
+        }
+    }
+
+    pub fn finish(&mut self, result: &CommandResult) {
+        self.finished = as_unix_timestamp(SystemTime::now());
+        self.error = result.as_ref().err().map(|e| e.to_string());
+    }
+}
+
+/// Runs recorded in the history, oldest first. Lines that can't be parsed
+/// are left out.
+pub async fn read_history(state_dir: &Path) -> CommandResult<Vec<RunRecord>> {
+    let path = state_dir.join(HISTORY_FILE);
+    match fs::read_to_string(&path).await {
+        Ok(content) => Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into_command_error(
+            CommandErrorKind::System,
+            format!("Failed to read {}", path.display()).as_str(),
+        )),
+    }
+}
+
+/// Add `record` to the history, dropping the oldest runs beyond the limit.
+pub async fn record_run(state_dir: &Path, record: &RunRecord) -> CommandResult {
+    let mut runs = read_history(state_dir).await?;
+    runs.push(record.clone());
+    let skip = runs.len().saturating_sub(MAX_RUNS);
+    let mut content = String::new();
+    for run in &runs[skip..] {
+        let line = serde_json::to_string(run)
+            .into_command_result(CommandErrorKind::System, "Failed to encode the history")?;
+        content.push_str(&line);
+        content.push('\n');
+    }
+
+    let path = state_dir.join(HISTORY_FILE);
+    fs::create_dir_all(state_dir)
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to create state directory")?;
+    fs::write(&path, content).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to write {}", path.display()).as_str(),
+    )
+}
+
+pub async fn history(state_dir: &Path, args: &HistoryArgs) -> CommandResult {
+    let runs: Vec<RunRecord> = read_history(state_dir)
+        .await?
+        .into_iter()
+        .filter(|run| !args.failed || run.error.is_some())
+        .collect();
+    let skip = runs.len().saturating_sub(args.limit);
+
+    println!(
+        "{:<16} {:>9} {:<10} {:<16} {:<6} {:>11} Snapshots",
+        "Started", "Duration", "Command", "Archive", "Result", "New data"
+    );
+    for run in &runs[skip..] {
+        let duration = Duration::from_secs(run.finished.saturating_sub(run.started).max(0) as u64);
+        println!(
+            "{:<16} {:>9} {:<10} {:<16} {:<6} {:>11} {}",
+            format_short_time(run.started),
+            humantime::format_duration(duration).to_string(),
+            run.command,
+            run.archive,
+            if run.error.is_some() { "failed" } else { "ok" },
+            format_size(run.new_bytes),
+            run.snapshots.join(" ")
+        );
+        if let Some(ref error) = run.error {
+            println!("  {}", error);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_run() -> CommandResult {
+        let state_dir = tempfile::tempdir().unwrap();
+        let succeeded = RunRecord {
+            command: "backup".to_string(),
+            archive: "home".to_string(),
+            started: 1_700_000_000,
+            finished: 1_700_000_060,
+            error: None,
+            snapshots: vec!["home/3".to_string()],
+            new_bytes: 1234,
+        };
+        let failed = RunRecord {
+            error: Some("Failed to upload \"a\"\nbroken pipe".to_string()),
+            snapshots: Vec::new(),
+            ..succeeded.clone()
+        };
+        record_run(state_dir.path(), &succeeded).await?;
+        record_run(state_dir.path(), &failed).await?;
+
+        assert_eq!(
+            read_history(state_dir.path()).await?,
+            [succeeded.clone(), failed.clone()]
+        );
+        assert!(read_history(&state_dir.path().join("missing"))
+            .await?
+            .is_empty());
+        Ok(())
+    }
+}
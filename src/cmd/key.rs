@@ -0,0 +1,354 @@
+use std::{env, future::Future, path::PathBuf};
+
+use clap::{Args, Subcommand};
+use tokio::fs;
+use tracing::info;
+
+use crate::{
+    data::backup::Key,
+    storage::{
+        encrypted::{add_key, read_keys, unwrap_master_key, wrap_master_key, KdfParams, MasterKey},
+        Collection,
+    },
+    util::{size::format_size, time::format_time},
+};
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct KeyArgs {
+    #[command(subcommand)]
+    pub command: KeyCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCommand {
+    /// List the keys that open the repository.
+    List,
+    /// Add a key that opens the repository with another passphrase.
+    Add(NewPassphraseArgs),
+    /// Remove a key, which can't be the last one or the one in use.
+    Remove(RemoveKeyArgs),
+    /// Change the passphrase of the key in use. The data doesn't have to be
+    /// encrypted again.
+    Passwd(NewPassphraseArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct NewPassphraseArgs {
+    /// File with the new passphrase, which can be a key file. Taken from
+    /// FREEBCK_NEW_PASSPHRASE if not given.
+    #[arg(long)]
+    pub new_passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct RemoveKeyArgs {
+    /// Name of the key as shown by key list.
+    pub name: String,
+}
+
+/// Manage the keys of an encrypted repository. `passphrase` gives the
+/// passphrase in use, and is only awaited by the commands that need it.
+/// New keys are derived with `kdf`.
+pub async fn key(
+    context: &ProgramContext,
+    args: &KeyArgs,
+    passphrase: impl Future<Output = CommandResult<String>>,
+    kdf: KdfParams,
+) -> CommandResult {
+    let keys = read_keys(&*context.storage)
+        .await
+        .into_io_command_result("Failed to read the repository keys")?;
+    if keys.is_empty() {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Repository isn't encrypted".to_string(),
+        ));
+    }
+
+    match args.command {
+        KeyCommand::List => {
+            list(&keys);
+            Ok(())
+        }
+        KeyCommand::Add(ref add_args) => {
+            let (_, master_key) = current_key(&keys, &passphrase.await?).await?;
+            let new_passphrase = new_passphrase(add_args).await?;
+            let name = store_key(context, &master_key, &new_passphrase, kdf).await?;
+            info!("Added key {}", name);
+            Ok(())
+        }
+        KeyCommand::Remove(ref remove_args) => {
+            if !keys.iter().any(|(name, _)| *name == remove_args.name) {
+                return Err(CommandError::new(
+                    CommandErrorKind::NotFound,
+                    format!("Repository has no key {}", remove_args.name),
+                ));
+            }
+            if keys.len() == 1 {
+                return Err(CommandError::new(
+                    CommandErrorKind::User,
+                    "Can't remove the last key of the repository".to_string(),
+                ));
+            }
+            let (current, _) = current_key(&keys, &passphrase.await?).await?;
+            if current == remove_args.name {
+                return Err(CommandError::new(
+                    CommandErrorKind::User,
+                    format!(
+                        "Key {} is in use, remove it with another passphrase",
+                        current
+                    ),
+                ));
+            }
+            remove_key(context, &remove_args.name).await
+        }
+        KeyCommand::Passwd(ref passwd_args) => {
+            let (current, master_key) = current_key(&keys, &passphrase.await?).await?;
+            let new_passphrase = new_passphrase(passwd_args).await?;
+            // The new key is stored first, so that the repository can still
+            // be opened if removing the old one fails.
+            let name = store_key(context, &master_key, &new_passphrase, kdf).await?;
+            remove_key(context, &current).await?;
+            info!("Replaced key {} with {}", current, name);
+            Ok(())
+        }
+    }
+}
+
+fn list(keys: &[(String, Key)]) {
+    let mut keys: Vec<_> = keys.iter().collect();
+    keys.sort_by_key(|(_, key)| key.created);
+    println!(
+        "{:<16} {:<16} {:>10} {:>10} Created",
+        "Name", "Client", "Memory", "Iterations"
+    );
+    for (name, key) in keys {
+        println!(
+            "{:<16} {:<16} {:>10} {:>10} {}",
+            name,
+            if key.client_id.is_empty() {
+                "-"
+            } else {
+                &key.client_id
+            },
+            format_size(u64::from(key.memory_kib) * 1024),
+            key.iterations,
+            format_time(key.created)
+        );
+    }
+}
+
+/// Name and master key of the key that opens with `passphrase`.
+async fn current_key(
+    keys: &[(String, Key)],
+    passphrase: &str,
+) -> CommandResult<(String, MasterKey)> {
+    for (name, key) in keys {
+        if let Some(master_key) = unwrap_master_key(key, passphrase)
+            .await
+            .into_io_command_result("Failed to open the repository key")?
+        {
+            return Ok((name.clone(), master_key));
+        }
+    }
+    Err(CommandError::new(
+        CommandErrorKind::User,
+        "No key of the repository opens with the passphrase".to_string(),
+    ))
+}
+
+/// Passphrase of a new key, from the file in `args` or FREEBCK_NEW_PASSPHRASE.
+async fn new_passphrase(args: &NewPassphraseArgs) -> CommandResult<String> {
+    let passphrase = match args.new_passphrase_file {
+        Some(ref path) => fs::read_to_string(path).await.into_command_result(
+            CommandErrorKind::User,
+            format!("Failed to read passphrase file {}", path.display()).as_str(),
+        )?,
+        None => env::var("FREEBCK_NEW_PASSPHRASE").map_err(|_| {
+            CommandError::new(
+                CommandErrorKind::User,
+                "Give the new passphrase with --new-passphrase-file or FREEBCK_NEW_PASSPHRASE"
+                    .to_string(),
+            )
+        })?,
+    };
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "New passphrase is empty".to_string(),
+        ));
+    }
+    Ok(passphrase.to_string())
+}
+
+async fn store_key(
+    context: &ProgramContext,
+    master_key: &MasterKey,
+    passphrase: &str,
+    kdf: KdfParams,
+) -> CommandResult<String> {
+    let key = wrap_master_key(master_key, passphrase, kdf, &context.client_id)
+        .await
+        .into_io_command_result("Failed to create key")?;
+    add_key(&*context.storage, &key)
+        .await
+        .into_io_command_result("Failed to write key")
+}
+
+async fn remove_key(context: &ProgramContext, name: &str) -> CommandResult {
+    context
+        .storage
+        .delete(Collection::Key, name)
+        .await
+        .into_io_command_result(format!("Failed to remove key {}", name).as_str())?;
+    info!("Removed key {}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::{encrypted::EncryptedStorage, memory::MemoryStorage, Storage};
+
+    /// Cheap enough for debug builds.
+    const TEST_KDF: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+    };
+
+    async fn test_context(inner: Arc<MemoryStorage>) -> ProgramContext {
+        let storage =
+            EncryptedStorage::open(Box::new(inner), &[], "first", TEST_KDF, "test_client")
+                .await
+                .unwrap();
+        storage.init("test").await.unwrap();
+        ProgramContext {
+            archive_name: "test".to_owned(),
+            client_id: "test_client".to_owned(),
+            storage: Arc::new(storage),
+            backup_target: Default::default(),
+            state_dir: Default::default(),
+            hooks: Default::default(),
+            fs_snapshot: None,
+            database: None,
+            compression: Default::default(),
+            tuning: Default::default(),
+        }
+    }
+
+    async fn run_key(
+        context: &ProgramContext,
+        command: KeyCommand,
+        passphrase: &str,
+    ) -> CommandResult {
+        let args = KeyArgs { command };
+        key(
+            context,
+            &args,
+            async { Ok(passphrase.to_owned()) },
+            TEST_KDF,
+        )
+        .await
+    }
+
+    async fn new_passphrase_args(dir: &tempfile::TempDir, passphrase: &str) -> NewPassphraseArgs {
+        let path = dir.path().join(format!("{}.txt", passphrase));
+        fs::write(&path, format!("{}\n", passphrase)).await.unwrap();
+        NewPassphraseArgs {
+            new_passphrase_file: Some(path),
+        }
+    }
+
+    async fn open(inner: &Arc<MemoryStorage>, passphrase: &str) -> std::io::Result<()> {
+        let keys = read_keys(&**inner).await?;
+        let storage = EncryptedStorage::open(
+            Box::new(inner.clone()),
+            &keys,
+            passphrase,
+            TEST_KDF,
+            "test_client",
+        )
+        .await?;
+        let mut buffer = Vec::new();
+        storage
+            .read(Collection::Snapshot, "test/1", &mut buffer)
+            .await?;
+        assert_eq!(buffer, b"snapshot");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keys_are_added_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = Arc::new(MemoryStorage::new());
+        let context = test_context(inner.clone()).await;
+        context
+            .storage
+            .write(Collection::Snapshot, "test/1", b"snapshot")
+            .await
+            .unwrap();
+        let first = read_keys(&*inner).await.unwrap()[0].0.clone();
+
+        // The only key can't be removed.
+        let error = run_key(
+            &context,
+            KeyCommand::Remove(RemoveKeyArgs {
+                name: first.clone(),
+            }),
+            "second",
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), CommandErrorKind::User);
+
+        let args = new_passphrase_args(&dir, "second").await;
+        let error = run_key(&context, KeyCommand::Add(args), "wrong")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), CommandErrorKind::User);
+        let args = new_passphrase_args(&dir, "second").await;
+        run_key(&context, KeyCommand::Add(args), "first")
+            .await
+            .unwrap();
+        open(&inner, "first").await.unwrap();
+        open(&inner, "second").await.unwrap();
+
+        // The key in use can't be removed.
+        let remove = || {
+            KeyCommand::Remove(RemoveKeyArgs {
+                name: first.clone(),
+            })
+        };
+        let error = run_key(&context, remove(), "first").await.unwrap_err();
+        assert_eq!(error.kind(), CommandErrorKind::User);
+        run_key(&context, remove(), "second").await.unwrap();
+        let error = open(&inner, "first").await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+        open(&inner, "second").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn passwd_replaces_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let inner = Arc::new(MemoryStorage::new());
+        let context = test_context(inner.clone()).await;
+        context
+            .storage
+            .write(Collection::Snapshot, "test/1", b"snapshot")
+            .await
+            .unwrap();
+
+        let args = new_passphrase_args(&dir, "changed").await;
+        run_key(&context, KeyCommand::Passwd(args), "first")
+            .await
+            .unwrap();
+        assert_eq!(read_keys(&*inner).await.unwrap().len(), 1);
+        open(&inner, "changed").await.unwrap();
+        assert!(open(&inner, "first").await.is_err());
+    }
+}
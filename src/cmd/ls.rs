@@ -0,0 +1,148 @@
+use std::path::{Component, Path, PathBuf};
+
+use clap::Args;
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry, FileEntry},
+    util::{size::format_size, time::format_short_time},
+};
+
+use super::common::*;
+
+#[derive(Debug, Default, Args)]
+pub struct LsArgs {
+    /// Snapshot number, or "archive/number" to list another archive.
+    #[arg(conflicts_with_all = ["tree", "before"])]
+    pub snapshot: Option<String>,
+    /// Directory within the snapshot to list, its root by default.
+    #[arg(requires = "snapshot")]
+    pub path: Option<PathBuf>,
+    /// Archive of the snapshot instead of the configured one.
+    #[arg(long)]
+    pub archive: Option<String>,
+    /// Hash of the directory entry to list.
+    #[arg(long, conflicts_with = "before")]
+    pub tree: Option<String>,
+    /// List the root of the newest snapshot started before this time, e.g.
+    /// "2024-05-01 12:00".
+    #[arg(long)]
+    pub before: Option<String>,
+}
+
+/// What a path within a snapshot refers to.
+pub enum PathEntry {
+    Dir(DirEntry),
+    File(FileEntry),
+}
+
+pub async fn ls(context: &ProgramContext, args: &LsArgs) -> CommandResult {
+    let tree = match (&args.snapshot, &args.tree, &args.before) {
+        (Some(snapshot), _, _) => {
+            let snapshot_name = resolve_snapshot_name(context, args.archive.as_deref(), snapshot);
+            get_snapshot(context, &snapshot_name).await?.root_hash
+        }
+        (None, Some(tree), _) => tree.clone(),
+        (None, None, Some(before)) => {
+            let snapshot_name =
+                resolve_snapshot_before(context, args.archive.as_deref(), before).await?;
+            get_snapshot(context, &snapshot_name).await?.root_hash
+        }
+        (None, None, None) => {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                "Nothing to list, pass a snapshot, --tree or --before".to_string(),
+            ))
+        }
+    };
+    let dir_entry = get_dir_entry(context, &tree).await?;
+    let path = args.path.as_deref().unwrap_or(Path::new(""));
+    match resolve_path(context, dir_entry, path).await? {
+        PathEntry::Dir(dir_entry) => print_dir_entry(context, &dir_entry).await?,
+        PathEntry::File(file) => print_file(&file),
+    }
+    Ok(())
+}
+
+/// Look up `path` within `dir_entry`, loading the directories on the way
+/// that are stored separately.
+pub async fn resolve_path(
+    context: &ProgramContext,
+    dir_entry: DirEntry,
+    path: &Path,
+) -> CommandResult<PathEntry> {
+    let mut entry = PathEntry::Dir(dir_entry);
+    let mut current = PathBuf::new();
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy(),
+            Component::RootDir | Component::CurDir => continue,
+            _ => {
+                return Err(CommandError::new(
+                    CommandErrorKind::User,
+                    format!("Unsupported path: {}", path.display()),
+                ))
+            }
+        };
+        let PathEntry::Dir(dir_entry) = entry else {
+            return Err(CommandError::new(
+                CommandErrorKind::NotFound,
+                format!("Not a directory: {}", current.display()),
+            ));
+        };
+        current.push(name.as_ref());
+        entry = if let Some(sub_dir) = dir_entry.sub_dir.into_iter().find(|d| d.name == name) {
+            match sub_dir.content {
+                Some(Content::Inline(dir_entry)) => PathEntry::Dir(dir_entry),
+                Some(Content::Hash(hash)) => PathEntry::Dir(get_dir_entry(context, &hash).await?),
+                None => {
+                    return Err(CommandError::new(
+                        CommandErrorKind::Corrupt,
+                        format!("Directory has no content: {}", current.display()),
+                    ))
+                }
+            }
+        } else if let Some(file) = dir_entry.file.into_iter().find(|f| f.name == name) {
+            PathEntry::File(file)
+        } else {
+            return Err(CommandError::new(
+                CommandErrorKind::NotFound,
+                format!("No such file or directory: {}", current.display()),
+            ));
+        };
+    }
+    Ok(entry)
+}
+
+async fn print_dir_entry(context: &ProgramContext, dir_entry: &DirEntry) -> CommandResult {
+    for sub_dir in dir_entry.sub_dir.iter() {
+        let (size, location) = match sub_dir.content {
+            Some(Content::Inline(ref dir_entry)) => (dir_entry.size, "inline".to_string()),
+            Some(Content::Hash(ref hash)) => {
+                (get_dir_entry(context, hash).await?.size, hash.clone())
+            }
+            None => (0, "missing".to_string()),
+        };
+        println!(
+            "d {:>14} {:>20} {}/ {}",
+            format_size(size),
+            "",
+            sub_dir.name,
+            location
+        );
+    }
+
+    for file in dir_entry.file.iter() {
+        print_file(file);
+    }
+    Ok(())
+}
+
+fn print_file(file: &FileEntry) {
+    println!(
+        "- {:>14} {:>20} {} {}",
+        format_size(file.size),
+        format_short_time(file.modified),
+        file.name,
+        file.content_hash
+    );
+}
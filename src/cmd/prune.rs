@@ -0,0 +1,236 @@
+use std::collections::{BTreeMap, HashSet};
+
+use clap::Args;
+use tracing::{debug, info};
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry},
+    storage::Collection,
+    util::{
+        hooks::{fire_hook, HookEvent},
+        lock::with_lock,
+        size::format_size,
+    },
+};
+
+use super::common::*;
+
+#[derive(Debug, Default, Args)]
+pub struct PruneArgs {
+    /// Show which data would no longer be used if this snapshot were
+    /// forgotten, without changing anything. Either a number in the
+    /// configured archive or "archive/number".
+    #[arg(long)]
+    pub explain: Option<String>,
+    /// Only show how many blobs would be deleted and how much space that
+    /// would free.
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Data used only by one snapshot, which pruning would delete once the
+/// snapshot is forgotten.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ForgetExplanation {
+    /// Blobs the snapshot uses, including directory entries.
+    pub blobs: usize,
+    /// Blobs that no other snapshot uses.
+    pub unique_blobs: Vec<String>,
+    /// Total size of the unique file chunks.
+    pub unique_bytes: u64,
+    /// Files with unique chunks, and the size of those chunks.
+    pub files: BTreeMap<String, u64>,
+}
+
+/// Delete the blobs that no snapshot uses. Blobs of a backup that is still
+/// running aren't used by a snapshot yet, so this holds an exclusive lock of
+/// the repository.
+pub async fn prune(context: &ProgramContext, args: &PruneArgs) -> CommandResult {
+    if let Some(ref snapshot) = args.explain {
+        return print_explanation(context, snapshot).await;
+    }
+
+    if args.dry_run {
+        return print_unused(context).await;
+    }
+    // Running backups rely on the blobs they found in the repository, so
+    // none may run while blobs are deleted.
+    with_lock(context, true, delete_unused(context)).await
+}
+
+async fn print_unused(context: &ProgramContext) -> CommandResult {
+    let unused = find_unused_blobs(context).await?;
+    {
+        // Listings have no sizes, so the blobs are read to measure them.
+        let mut bytes = 0;
+        let mut buffer = Vec::new();
+        for hash in &unused {
+            context
+                .storage
+                .read(Collection::Blob, hash, &mut buffer)
+                .await
+                .into_io_command_result(format!("Failed to read blob {}", hash).as_str())?;
+            bytes += buffer.len() as u64;
+        }
+        println!(
+            "{} blobs are not used by any snapshot, pruning would free {}.",
+            unused.len(),
+            format_size(bytes)
+        );
+    }
+    Ok(())
+}
+
+async fn delete_unused(context: &ProgramContext) -> CommandResult {
+    let unused = find_unused_blobs(context).await?;
+    for hash in &unused {
+        debug!("Deleting blob {}", hash);
+        context
+            .storage
+            .delete(Collection::Blob, hash)
+            .await
+            .into_io_command_result(format!("Failed to delete blob {}", hash).as_str())?;
+    }
+    // Packs with deleted blobs are written again without them on flush.
+    context
+        .storage
+        .flush()
+        .await
+        .into_io_command_result("Failed to flush storage")?;
+    info!("Deleted {} blobs that no snapshot uses", unused.len());
+    fire_hook(
+        context,
+        HookEvent::PruneCompleted {
+            deleted_blobs: unused.len(),
+        },
+    )
+    .await;
+    Ok(())
+}
+
+/// Blobs in the storage that no snapshot uses, sorted. Fails if the blobs of
+/// any snapshot can't be found, as its blobs would then look unused.
+pub async fn find_unused_blobs(context: &ProgramContext) -> CommandResult<Vec<String>> {
+    let snapshots = context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let mut reachable = HashSet::new();
+    for snapshot_name in snapshots {
+        info!("Reading snapshot {}", snapshot_name);
+        let snapshot = get_snapshot(context, &snapshot_name).await?;
+        collect_reachable_blobs(context, &snapshot.root_hash, &mut reachable).await?;
+    }
+
+    let mut unused: Vec<String> = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?
+        .into_iter()
+        .filter(|hash| !reachable.contains(hash))
+        .collect();
+    unused.sort();
+    Ok(unused)
+}
+
+async fn print_explanation(context: &ProgramContext, snapshot: &str) -> CommandResult {
+    let snapshot_name = resolve_snapshot_name(context, None, snapshot);
+    let explanation = explain_forget(context, &snapshot_name).await?;
+
+    println!(
+        "Snapshot {} uses {} blobs, {} of them are not used by any other snapshot.",
+        snapshot_name,
+        explanation.blobs,
+        explanation.unique_blobs.len()
+    );
+    println!(
+        "Forgetting it would free {} of file data in {} files:",
+        format_size(explanation.unique_bytes),
+        explanation.files.len()
+    );
+    for (path, bytes) in &explanation.files {
+        println!("  {} ({})", path, format_size(*bytes));
+    }
+    Ok(())
+}
+
+pub async fn explain_forget(
+    context: &ProgramContext,
+    snapshot_name: &str,
+) -> CommandResult<ForgetExplanation> {
+    let snapshot = get_snapshot(context, snapshot_name).await?;
+
+    let snapshots = context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let mut other_blobs = HashSet::new();
+    for other in snapshots.iter().filter(|other| *other != snapshot_name) {
+        info!("Reading snapshot {}", other);
+        let other = get_snapshot(context, other).await?;
+        collect_reachable_blobs(context, &other.root_hash, &mut other_blobs).await?;
+    }
+
+    let mut explanation = ForgetExplanation::default();
+    let mut seen = HashSet::new();
+    let mut unique = |hash: &str, explanation: &mut ForgetExplanation| {
+        if hash.is_empty() || !seen.insert(hash.to_string()) {
+            return false;
+        }
+        explanation.blobs += 1;
+        if other_blobs.contains(hash) {
+            return false;
+        }
+        explanation.unique_blobs.push(hash.to_string());
+        true
+    };
+
+    unique(&snapshot.root_hash, &mut explanation);
+    let mut pending = vec![(
+        String::new(),
+        get_dir_entry(context, &snapshot.root_hash).await?,
+    )];
+    while let Some((path, dir_entry)) = pending.pop() {
+        let join = |name: &str| {
+            if path.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", path, name)
+            }
+        };
+
+        for file_entry in &dir_entry.file {
+            let mut offset = 0;
+            let mut file_bytes = 0;
+            for (index, hash) in file_entry.chunk_hash.iter().enumerate() {
+                let length = chunk_length(file_entry, index, offset)?;
+                offset += length;
+                if unique(hash, &mut explanation) {
+                    file_bytes += length;
+                }
+            }
+            if file_bytes > 0 {
+                explanation.unique_bytes += file_bytes;
+                explanation.files.insert(join(&file_entry.name), file_bytes);
+            }
+        }
+
+        for sub_dir in dir_entry.sub_dir {
+            let sub_dir_entry: DirEntry = match sub_dir.content {
+                Some(Content::Inline(dir_entry)) => dir_entry,
+                Some(Content::Hash(hash)) => {
+                    unique(&hash, &mut explanation);
+                    get_dir_entry(context, &hash).await?
+                }
+                None => continue,
+            };
+            pending.push((join(&sub_dir.name), sub_dir_entry));
+        }
+    }
+
+    explanation.unique_blobs.sort();
+    Ok(explanation)
+}
@@ -0,0 +1,415 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry},
+    storage::{Collection, Storage},
+    util::{
+        hash::{blob_key_matches, sha256_hex, SHA256_KEY_PREFIX},
+        lock::with_lock,
+    },
+};
+use async_recursion::async_recursion;
+use clap::Args;
+use prost::Message;
+use tracing::{debug, info, warn};
+
+use super::{
+    check::{read_damaged_blobs, write_damaged_blobs},
+    common::*,
+    snapshots::{list_snapshots, SnapshotsArgs},
+};
+
+#[derive(Debug, Default, Args)]
+pub struct RepairArgs {
+    /// Copy good versions of damaged and missing blobs from the mirror
+    /// storage in the config. Damaged blobs are the ones found by check.
+    #[arg(long)]
+    pub from_mirror: bool,
+    /// Rewrite the snapshots that use damaged or missing blobs without the
+    /// files and directories whose data is lost, so that the rest of them
+    /// stays restorable. Runs after --from-mirror when both are given.
+    #[arg(long)]
+    pub remove_damaged: bool,
+    /// Only show what --remove-damaged would remove.
+    #[arg(long, requires = "remove_damaged")]
+    pub dry_run: bool,
+}
+
+/// Repair the repository. The blobs written aren't used by a snapshot until
+/// the repair is done, so like a backup it holds a shared lock, which
+/// keeps prune from deleting them meanwhile.
+pub async fn repair(
+    context: &ProgramContext,
+    args: &RepairArgs,
+    mirror: Option<&dyn Storage>,
+) -> CommandResult {
+    if !args.from_mirror && !args.remove_damaged {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Nothing to repair with, pass --from-mirror or --remove-damaged".to_string(),
+        ));
+    }
+    if args.from_mirror && mirror.is_none() {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "No mirror storage in the config".to_string(),
+        ));
+    }
+
+    with_lock(context, false, run_repair(context, args, mirror)).await
+}
+
+async fn run_repair(
+    context: &ProgramContext,
+    args: &RepairArgs,
+    mirror: Option<&dyn Storage>,
+) -> CommandResult {
+    let mut failed = 0;
+    if let (true, Some(mirror)) = (args.from_mirror, mirror) {
+        failed = repair_from_mirror(context, mirror).await?;
+    }
+    if args.remove_damaged {
+        return remove_damaged(context, args.dry_run).await;
+    }
+    if failed > 0 {
+        return Err(CommandError::new(
+            CommandErrorKind::Partial,
+            format!("Failed to repair {} blobs", failed),
+        ));
+    }
+    Ok(())
+}
+
+/// Copy the damaged and missing blobs from the mirror. Returns how many
+/// couldn't be repaired.
+async fn repair_from_mirror(context: &ProgramContext, mirror: &dyn Storage) -> CommandResult<u64> {
+    // Damaged blobs go first, as they may be directory entries that are
+    // needed to find the missing blobs.
+    let mut damaged = read_damaged_blobs(context).await?;
+    let mut repaired = 0;
+    let mut failed = 0;
+    for hash in damaged.clone() {
+        match repair_blob(context, mirror, &hash, true).await {
+            Ok(()) => {
+                damaged.remove(&hash);
+                repaired += 1;
+            }
+            Err(e) => {
+                warn!("Failed to repair blob {}: {}", hash, e);
+                failed += 1;
+            }
+        }
+    }
+    write_damaged_blobs(context, &damaged).await?;
+
+    for hash in find_missing_blobs(context).await? {
+        match repair_blob(context, mirror, &hash, false).await {
+            Ok(()) => repaired += 1,
+            Err(e) => {
+                warn!("Failed to restore missing blob {}: {}", hash, e);
+                failed += 1;
+            }
+        }
+    }
+
+    context
+        .storage
+        .flush()
+        .await
+        .into_io_command_result("Failed to write the repaired blobs")?;
+    info!("Repaired {} blobs from the mirror", repaired);
+    Ok(failed)
+}
+
+/// Rewrite every snapshot that uses lost blobs without the files and
+/// directories that need them.
+async fn remove_damaged(context: &ProgramContext, dry_run: bool) -> CommandResult {
+    let existing = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?
+        .into_iter()
+        .collect();
+    let mut remover = DamageRemover {
+        context,
+        damaged: read_damaged_blobs(context).await?,
+        existing,
+        dry_run,
+        rewritten: HashMap::new(),
+    };
+
+    let mut repaired = 0;
+    let mut lost = 0;
+    for (snapshot_name, mut snapshot) in list_snapshots(context, &SnapshotsArgs::default()).await? {
+        let rewritten = remover.rewrite_stored(&snapshot.root_hash).await?;
+        let Some(root_hash) = rewritten.key else {
+            warn!(
+                "The root directory of {} is lost, forget the snapshot",
+                snapshot_name
+            );
+            lost += 1;
+            continue;
+        };
+        if rewritten.removed.is_empty() {
+            continue;
+        }
+        for path in &rewritten.removed {
+            info!("Removing {} from {}", path, snapshot_name);
+        }
+        repaired += 1;
+        if dry_run {
+            continue;
+        }
+        snapshot.root_hash = root_hash;
+        snapshot.removed_path.extend(rewritten.removed);
+        context
+            .storage
+            .replace(
+                Collection::Snapshot,
+                &snapshot_name,
+                &snapshot.encode_to_vec(),
+            )
+            .await
+            .into_io_command_result(
+                format!("Failed to write the repaired snapshot {}", snapshot_name).as_str(),
+            )?;
+    }
+
+    if dry_run {
+        info!("Would repair {} snapshots", repaired);
+    } else {
+        context
+            .storage
+            .flush()
+            .await
+            .into_io_command_result("Failed to write the repaired snapshots")?;
+        info!(
+            "Repaired {} snapshots, the damaged data stays until it is pruned",
+            repaired
+        );
+    }
+    if lost > 0 {
+        return Err(CommandError::new(
+            CommandErrorKind::Partial,
+            format!("{} snapshots can't be repaired", lost),
+        ));
+    }
+    Ok(())
+}
+
+/// A stored directory entry without its lost data.
+#[derive(Clone)]
+struct Rewritten {
+    /// Key of the rewritten entry, the original key if nothing was removed
+    /// and None if the entry itself is lost.
+    key: Option<String>,
+    size: u64,
+    /// Paths removed below the directory, relative to it.
+    removed: Vec<String>,
+}
+
+struct DamageRemover<'a> {
+    context: &'a ProgramContext,
+    damaged: BTreeSet<String>,
+    existing: HashSet<String>,
+    dry_run: bool,
+    /// Directory entries by their original key, as snapshots share them.
+    rewritten: HashMap<String, Rewritten>,
+}
+
+impl DamageRemover<'_> {
+    fn is_lost(&self, key: &str) -> bool {
+        self.damaged.contains(key) || !self.existing.contains(key)
+    }
+
+    #[async_recursion]
+    async fn rewrite_stored(&mut self, key: &str) -> CommandResult<Rewritten> {
+        if let Some(rewritten) = self.rewritten.get(key) {
+            return Ok(rewritten.clone());
+        }
+
+        let dir_entry = if self.is_lost(key) {
+            None
+        } else {
+            match get_dir_entry(self.context, key).await {
+                Ok(dir_entry) => Some(dir_entry),
+                // Entries check hasn't seen yet may still fail to decode.
+                Err(e) if e.kind() == CommandErrorKind::Corrupt => {
+                    warn!("Directory entry {} is damaged: {}", key, e);
+                    None
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let rewritten = match dir_entry {
+            None => Rewritten {
+                key: None,
+                size: 0,
+                removed: Vec::new(),
+            },
+            Some(mut dir_entry) => {
+                let removed = self.rewrite_dir(&mut dir_entry).await?;
+                let key = if removed.is_empty() {
+                    key.to_string()
+                } else {
+                    self.write_dir_entry(key, &dir_entry).await?
+                };
+                Rewritten {
+                    key: Some(key),
+                    size: dir_entry.size,
+                    removed,
+                }
+            }
+        };
+        self.rewritten.insert(key.to_string(), rewritten.clone());
+        Ok(rewritten)
+    }
+
+    /// Remove the files and directories below `dir_entry` whose data is
+    /// lost. Returns their paths relative to it, empty if it is unchanged.
+    #[async_recursion]
+    async fn rewrite_dir(&mut self, dir_entry: &mut DirEntry) -> CommandResult<Vec<String>> {
+        let mut removed = Vec::new();
+        let mut size = 0;
+        dir_entry.file.retain(|file| {
+            // Empty chunk hashes stand for blocks of zeros that aren't stored.
+            let lost = file
+                .chunk_hash
+                .iter()
+                .any(|hash| !hash.is_empty() && self.is_lost(hash));
+            if lost {
+                removed.push(file.name.clone());
+            } else {
+                size += file.size;
+            }
+            !lost
+        });
+
+        let mut sub_dirs = Vec::with_capacity(dir_entry.sub_dir.len());
+        for mut sub_dir in std::mem::take(&mut dir_entry.sub_dir) {
+            let sub_removed = match sub_dir.content {
+                Some(Content::Hash(ref key)) => {
+                    let rewritten = self.rewrite_stored(key).await?;
+                    let Some(key) = rewritten.key else {
+                        removed.push(sub_dir.name);
+                        continue;
+                    };
+                    size += rewritten.size;
+                    sub_dir.content = Some(Content::Hash(key));
+                    rewritten.removed
+                }
+                Some(Content::Inline(ref mut inline)) => {
+                    let sub_removed = self.rewrite_dir(inline).await?;
+                    size += inline.size;
+                    sub_removed
+                }
+                None => Vec::new(),
+            };
+            removed.extend(
+                sub_removed
+                    .into_iter()
+                    .map(|path| format!("{}/{}", sub_dir.name, path)),
+            );
+            sub_dirs.push(sub_dir);
+        }
+        dir_entry.sub_dir = sub_dirs;
+
+        if !removed.is_empty() {
+            dir_entry.size = size;
+            // The next backup must not reuse the directory as unchanged.
+            dir_entry.metadata_hash.clear();
+        }
+        Ok(removed)
+    }
+
+    /// Store a rewritten directory entry with a key of the same form as the
+    /// original's.
+    async fn write_dir_entry(
+        &mut self,
+        original_key: &str,
+        dir_entry: &DirEntry,
+    ) -> CommandResult<String> {
+        let (encoded, hash) = sha256_hex(dir_entry.encode_to_vec())
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to encode dir entry")?;
+        let key = if original_key.starts_with(SHA256_KEY_PREFIX) {
+            format!("{}{}", SHA256_KEY_PREFIX, hash)
+        } else {
+            hash
+        };
+        if !self.dry_run {
+            self.context
+                .storage
+                .write(Collection::Blob, &key, &encoded)
+                .await
+                .ignore_already_exists()
+                .into_io_command_result("Failed to write the repaired dir entry")?;
+        }
+        self.existing.insert(key.clone());
+        Ok(key)
+    }
+}
+
+/// Blobs that some snapshot uses but the storage doesn't have. Snapshots
+/// whose directory entries can't be read are left out with a warning.
+async fn find_missing_blobs(context: &ProgramContext) -> CommandResult<Vec<String>> {
+    let snapshots = context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let mut reachable = HashSet::new();
+    for snapshot_name in snapshots {
+        let snapshot = get_snapshot(context, &snapshot_name).await?;
+        if let Err(e) = collect_reachable_blobs(context, &snapshot.root_hash, &mut reachable).await
+        {
+            warn!(
+                "Failed to find the blobs of {}, run repair again after check: {}",
+                snapshot_name, e
+            );
+        }
+    }
+
+    let existing: HashSet<String> = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?
+        .into_iter()
+        .collect();
+    let mut missing: Vec<String> = reachable.difference(&existing).cloned().collect();
+    missing.sort();
+    Ok(missing)
+}
+
+/// Copy a blob from the mirror after checking that the copy is good.
+async fn repair_blob(
+    context: &ProgramContext,
+    mirror: &dyn Storage,
+    hash: &str,
+    exists: bool,
+) -> CommandResult {
+    let mut buffer = Vec::new();
+    mirror
+        .read(Collection::Blob, hash, &mut buffer)
+        .await
+        .into_io_command_result("Failed to read the mirror copy")?;
+    if !blob_key_matches(hash, &buffer) {
+        return Err(CommandError::new(
+            CommandErrorKind::Corrupt,
+            "The mirror copy is damaged too".to_string(),
+        ));
+    }
+
+    debug!("Repairing blob {}", hash);
+    let result = if exists {
+        context
+            .storage
+            .replace(Collection::Blob, hash, &buffer)
+            .await
+    } else {
+        context.storage.write(Collection::Blob, hash, &buffer).await
+    };
+    result.into_io_command_result("Failed to write the good copy")
+}
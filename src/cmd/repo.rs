@@ -0,0 +1,498 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
+
+use clap::{Args, Subcommand};
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::{
+    fs::File,
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    data::backup::Snapshot,
+    storage::{
+        file::{init_repository, FileStorage},
+        Collection, Storage,
+    },
+    util::hash::blob_key_matches,
+};
+
+use super::{
+    common::*,
+    snapshots::{list_snapshots, SnapshotsArgs},
+};
+
+// Export files start with this line. The rest of the file is a sequence of
+// records: a kind byte, the key length as a big-endian u16, the key, the data
+// length as a big-endian u64 and the data. An end record closes the file so
+// that truncated exports are detected.
+const EXPORT_HEADER: &[u8] = b"freebck-export 1\n";
+const RECORD_BLOB: u8 = b'B';
+const RECORD_SNAPSHOT: u8 = b'S';
+const RECORD_END: u8 = b'E';
+
+#[derive(Debug, Args)]
+pub struct RepoArgs {
+    #[command(subcommand)]
+    pub command: RepoCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RepoCommand {
+    /// Create a new repository with a repository ID.
+    Init(InitArgs),
+    /// Write the repository, or selected snapshots, to a single file.
+    Export(ExportArgs),
+    /// Add the objects of an exported file to the repository.
+    Import(ImportArgs),
+    /// Copy the repository, or its latest snapshots, to a new directory.
+    Clone(CloneArgs),
+}
+
+#[derive(Debug, Default, Args)]
+pub struct InitArgs {
+    /// Directory to create the repository in. Creates it in the configured
+    /// storage if not given, which must not have any data yet.
+    pub path: Option<PathBuf>,
+    /// Repository ID to use instead of a random one.
+    #[arg(long)]
+    pub id: Option<String>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ExportArgs {
+    /// File to write, "-" for stdout.
+    pub file: PathBuf,
+    /// Only export these snapshots and the data they use. Either a number in
+    /// the configured archive or "archive/number". Exports everything by
+    /// default.
+    #[arg(long)]
+    pub snapshot: Vec<String>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ImportArgs {
+    /// File to read, "-" for stdin.
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct CloneArgs {
+    /// Directory to create the new repository in.
+    pub destination: PathBuf,
+    /// Only copy the newest this many snapshots of each archive and the data
+    /// they use. Copies everything by default.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub depth: Option<u32>,
+}
+
+pub async fn repo(context: &ProgramContext, args: &RepoArgs) -> CommandResult {
+    match args.command {
+        RepoCommand::Init(ref init_args) if init_args.path.is_none() => {
+            init_storage(context, init_args).await
+        }
+        RepoCommand::Init(ref init_args) => init(init_args).await,
+        RepoCommand::Export(ref export_args) => export(context, export_args).await,
+        RepoCommand::Import(ref import_args) => import(context, import_args).await,
+        RepoCommand::Clone(ref clone_args) => clone(context, clone_args).await,
+    }
+}
+
+fn new_repo_id(args: &InitArgs) -> CommandResult<String> {
+    let repo_id = match args.id {
+        Some(ref id) => id.clone(),
+        None => Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+    };
+    if repo_id.is_empty() || repo_id.contains(char::is_whitespace) {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            format!("Invalid repository ID {:?}", repo_id),
+        ));
+    }
+    Ok(repo_id)
+}
+
+/// Create a repository in the directory given in `args`. This doesn't need a
+/// context, so that it can be used before the configured storage exists.
+pub async fn init(args: &InitArgs) -> CommandResult {
+    let Some(ref path) = args.path else {
+        return Err(CommandError::new(
+            CommandErrorKind::Program,
+            "init needs a path, use init_storage for the configured storage".to_string(),
+        ));
+    };
+    let repo_id = new_repo_id(args)?;
+
+    init_repository(path, &repo_id)
+        .await
+        .into_io_command_result(
+            format!("Failed to create repository in {}", path.display()).as_str(),
+        )?;
+    info!("Created repository {} with ID {}", path.display(), repo_id);
+    Ok(())
+}
+
+/// Create a repository in the configured storage. Fails if the storage
+/// already has data, such as when its prefix is shared with another
+/// repository.
+pub async fn init_storage(context: &ProgramContext, args: &InitArgs) -> CommandResult {
+    let repo_id = new_repo_id(args)?;
+    for collection in [Collection::Snapshot, Collection::Blob, Collection::Pack] {
+        let items = match context.storage.get_collection_items(collection).await {
+            Ok(items) => items,
+            // Servers of many repositories don't know the new one yet.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).into_io_command_result("Failed to list the storage"),
+        };
+        if !items.is_empty() {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                format!(
+                    "The storage already has {} items, use another prefix",
+                    collection.name()
+                ),
+            ));
+        }
+    }
+
+    context
+        .storage
+        .init(&repo_id)
+        .await
+        .into_io_command_result("Failed to create repository")?;
+    info!("Created repository with ID {}", repo_id);
+    Ok(())
+}
+
+async fn list_all_objects(context: &ProgramContext) -> CommandResult<(Vec<String>, Vec<String>)> {
+    let snapshots = context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let blobs = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?;
+    Ok((snapshots, blobs))
+}
+
+/// The given snapshots and the blobs reachable from them.
+async fn list_snapshot_objects(
+    context: &ProgramContext,
+    snapshots: Vec<String>,
+) -> CommandResult<(Vec<String>, Vec<String>)> {
+    let mut blobs = HashSet::new();
+    for snapshot_name in &snapshots {
+        let snapshot = get_snapshot(context, snapshot_name).await?;
+        collect_reachable_blobs(context, &snapshot.root_hash, &mut blobs).await?;
+    }
+    let mut blobs: Vec<_> = blobs.into_iter().collect();
+    blobs.sort();
+    Ok((snapshots, blobs))
+}
+
+pub async fn export(context: &ProgramContext, args: &ExportArgs) -> CommandResult {
+    let (snapshots, blobs) = if args.snapshot.is_empty() {
+        list_all_objects(context).await?
+    } else {
+        let snapshots = args
+            .snapshot
+            .iter()
+            .map(|snapshot| resolve_snapshot_name(context, None, snapshot))
+            .collect();
+        list_snapshot_objects(context, snapshots).await?
+    };
+
+    let mut output: BufWriter<Box<dyn AsyncWrite + Unpin + Send>> =
+        BufWriter::new(if args.file.as_os_str() == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(&args.file).await.into_command_result(
+                CommandErrorKind::System,
+                format!("Failed to create {}", args.file.display()).as_str(),
+            )?)
+        });
+    write_export(context, &mut output, &snapshots, &blobs)
+        .await
+        .into_io_command_result("Failed to write export")?;
+
+    info!(
+        "Exported {} snapshots and {} blobs",
+        snapshots.len(),
+        blobs.len()
+    );
+    Ok(())
+}
+
+async fn write_record(
+    output: &mut (impl AsyncWrite + Unpin),
+    kind: u8,
+    key: &str,
+    data: &[u8],
+) -> io::Result<()> {
+    let key_length = u16::try_from(key.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Key too long"))?;
+    output.write_u8(kind).await?;
+    output.write_u16(key_length).await?;
+    output.write_all(key.as_bytes()).await?;
+    output.write_u64(data.len() as u64).await?;
+    output.write_all(data).await
+}
+
+async fn write_export(
+    context: &ProgramContext,
+    output: &mut (impl AsyncWrite + Unpin),
+    snapshots: &[String],
+    blobs: &[String],
+) -> io::Result<()> {
+    output.write_all(EXPORT_HEADER).await?;
+
+    // Blobs go first, so that an interrupted import never leaves behind a
+    // snapshot with missing data.
+    let mut buffer = Vec::new();
+    for blob in blobs {
+        debug!("Exporting blob {}", blob);
+        context
+            .storage
+            .read(Collection::Blob, blob, &mut buffer)
+            .await?;
+        write_record(output, RECORD_BLOB, blob, &buffer).await?;
+    }
+    for snapshot in snapshots {
+        debug!("Exporting snapshot {}", snapshot);
+        context
+            .storage
+            .read(Collection::Snapshot, snapshot, &mut buffer)
+            .await?;
+        write_record(output, RECORD_SNAPSHOT, snapshot, &buffer).await?;
+    }
+
+    write_record(output, RECORD_END, "", &[]).await?;
+    output.flush().await
+}
+
+fn invalid_export(message: &str) -> CommandError {
+    CommandError::new(
+        CommandErrorKind::Corrupt,
+        format!("Invalid export file: {}", message),
+    )
+}
+
+pub async fn import(context: &ProgramContext, args: &ImportArgs) -> CommandResult {
+    let mut input: BufReader<Box<dyn AsyncRead + Unpin + Send>> =
+        BufReader::new(if args.file.as_os_str() == "-" {
+            Box::new(io::stdin())
+        } else {
+            Box::new(File::open(&args.file).await.into_command_result(
+                CommandErrorKind::User,
+                format!("Failed to open {}", args.file.display()).as_str(),
+            )?)
+        });
+
+    let mut header = vec![0; EXPORT_HEADER.len()];
+    input
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| invalid_export("missing header"))?;
+    if header != EXPORT_HEADER {
+        return Err(invalid_export("unknown header"));
+    }
+
+    let mut snapshots = 0;
+    let mut blobs = 0;
+    let mut key = Vec::new();
+    let mut data = Vec::new();
+    loop {
+        let kind = input
+            .read_u8()
+            .await
+            .map_err(|_| invalid_export("file is truncated"))?;
+        read_record(&mut input, &mut key, &mut data)
+            .await
+            .map_err(|_| invalid_export("file is truncated"))?;
+        let key = String::from_utf8(key.clone()).map_err(|_| invalid_export("invalid key"))?;
+
+        match kind {
+            RECORD_BLOB => {
+                if !blob_key_matches(&key, &data) {
+                    return Err(CommandError::new(
+                        CommandErrorKind::Corrupt,
+                        format!("Blob {} does not match its hash", key),
+                    ));
+                }
+                debug!("Importing blob {}", key);
+                context
+                    .storage
+                    .write(Collection::Blob, &key, &data)
+                    .await
+                    .ignore_already_exists()
+                    .into_command_result(CommandErrorKind::System, "Failed to write blob")?;
+                blobs += 1;
+            }
+            RECORD_SNAPSHOT => {
+                // The blobs of the snapshot must be stored before it.
+                context
+                    .storage
+                    .flush()
+                    .await
+                    .into_io_command_result("Failed to write blobs")?;
+                debug!("Importing snapshot {}", key);
+                if let Err(e) = context
+                    .storage
+                    .write(Collection::Snapshot, &key, &data)
+                    .await
+                {
+                    if e.kind() != io::ErrorKind::AlreadyExists {
+                        return Err(e.into_command_error(
+                            CommandErrorKind::System,
+                            "Failed to write snapshot",
+                        ));
+                    }
+                    let mut existing = Vec::new();
+                    context
+                        .storage
+                        .read(Collection::Snapshot, &key, &mut existing)
+                        .await
+                        .into_io_command_result("Failed to read snapshot")?;
+                    if existing != data {
+                        warn!("Snapshot {} already exists with different content", key);
+                    }
+                }
+                snapshots += 1;
+            }
+            RECORD_END => break,
+            _ => return Err(invalid_export("unknown record")),
+        }
+    }
+
+    context
+        .storage
+        .flush()
+        .await
+        .into_io_command_result("Failed to write blobs")?;
+    info!("Imported {} snapshots and {} blobs", snapshots, blobs);
+    Ok(())
+}
+
+async fn read_record(
+    input: &mut (impl AsyncRead + Unpin),
+    key: &mut Vec<u8>,
+    data: &mut Vec<u8>,
+) -> io::Result<()> {
+    let key_length = input.read_u16().await?;
+    key.resize(key_length as usize, 0);
+    input.read_exact(key).await?;
+
+    let data_length = input.read_u64().await?;
+    data.clear();
+    let read = input.take(data_length).read_to_end(data).await?;
+    if read as u64 != data_length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// The newest `depth` snapshots of each archive, given `snapshots` by archive
+/// and then oldest first as list_snapshots returns them.
+fn newest_snapshots(snapshots: Vec<(String, Snapshot)>, depth: u32) -> Vec<String> {
+    let mut archives: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (snapshot_name, _) in snapshots {
+        archives
+            .entry(snapshot_archive(&snapshot_name).to_string())
+            .or_default()
+            .push(snapshot_name);
+    }
+
+    archives
+        .into_values()
+        .flat_map(|snapshots| {
+            let skip = snapshots.len().saturating_sub(depth as usize);
+            snapshots.into_iter().skip(skip)
+        })
+        .collect()
+}
+
+pub async fn clone(context: &ProgramContext, args: &CloneArgs) -> CommandResult {
+    let (snapshots, blobs) = match args.depth {
+        Some(depth) => {
+            let all_snapshots = list_snapshots(context, &SnapshotsArgs::default()).await?;
+            list_snapshot_objects(context, newest_snapshots(all_snapshots, depth)).await?
+        }
+        None => list_all_objects(context).await?,
+    };
+
+    let destination = FileStorage::new(args.destination.clone())
+        .await
+        .into_command_result(
+            CommandErrorKind::System,
+            format!(
+                "Failed to create repository in {}",
+                args.destination.display()
+            )
+            .as_str(),
+        )?;
+
+    // As with exports, blobs are copied first so that an interrupted clone
+    // never has snapshots with missing data.
+    let mut buffer = Vec::new();
+    for blob in &blobs {
+        debug!("Copying blob {}", blob);
+        context
+            .storage
+            .read(Collection::Blob, blob, &mut buffer)
+            .await
+            .into_io_command_result("Failed to read blob")?;
+        destination
+            .write(Collection::Blob, blob, &buffer)
+            .await
+            .ignore_already_exists()
+            .into_command_result(CommandErrorKind::System, "Failed to write blob")?;
+    }
+    for snapshot in &snapshots {
+        debug!("Copying snapshot {}", snapshot);
+        context
+            .storage
+            .read(Collection::Snapshot, snapshot, &mut buffer)
+            .await
+            .into_io_command_result("Failed to read snapshot")?;
+        destination
+            .write(Collection::Snapshot, snapshot, &buffer)
+            .await
+            .ignore_already_exists()
+            .into_command_result(CommandErrorKind::System, "Failed to write snapshot")?;
+    }
+
+    info!(
+        "Copied {} snapshots and {} blobs to {}",
+        snapshots.len(),
+        blobs.len(),
+        args.destination.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_newest_snapshots() {
+        // The recorded sequence orders each archive, not the key.
+        let snapshots = [("a/9", 1), ("a/10", 2), ("a/1", 3), ("b/3", 1)]
+            .iter()
+            .map(|(name, sequence)| {
+                let snapshot = Snapshot {
+                    sequence: *sequence,
+                    ..Default::default()
+                };
+                (name.to_string(), snapshot)
+            })
+            .collect();
+        assert_eq!(newest_snapshots(snapshots, 2), ["a/10", "a/1", "b/3"]);
+    }
+}
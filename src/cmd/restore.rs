@@ -1,105 +1,864 @@
-use std::{io::Cursor, os::unix::prelude::MetadataExt, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    error::Error,
+    os::unix::{fs::FileTypeExt, prelude::MetadataExt},
+    path::{Component, Path, PathBuf},
+    pin::pin,
+    sync::Mutex,
+};
 
 use crate::{
-    cmd::common::{get_dir_entry, IntoCommandError, IntoCommandResult},
-    data::backup::{sub_dir_entry, DirEntry, FileEntry, Snapshot, SubDirEntry},
+    cmd::common::{
+        chunk_length, filter_path, get_dir_entry, get_snapshot, resolve_snapshot_before,
+        resolve_snapshot_name, IntoCommandError, IntoCommandResult, IntoIoCommandResult,
+    },
+    data::backup::{sub_dir_entry, DirEntry, FileEntry, SubDirEntry},
     storage::Collection,
-    util::time::{as_unix_timestamp, system_time_from_unix_timestamp},
+    util::{
+        cache::ChunkCache,
+        glob::PathFilter,
+        hash::{blob_key_matches, read_hash, split_blob_key, HashingReader},
+        rate::RateLimiter,
+        sampled_log::SampledLog,
+        size::parse_size,
+        time::{as_unix_timestamp_nanos, format_time, modified_matches},
+    },
 };
 
 use super::common::{
-    CommandError, CommandErrorKind, CommandResult, KeepGoingOrErr, ProgramContext,
+    CommandError, CommandErrorKind, CommandResult, KeepGoingOrErr, MultiError, ProgramContext,
 };
 use async_recursion::async_recursion;
-use clap::Args;
+use clap::{Args, ValueEnum};
 use futures::future::{try_join_all, BoxFuture};
-use log::{debug, info};
-use prost::Message;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use tokio::{
-    fs::{self, File, OpenOptions},
-    io::AsyncWriteExt,
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
 };
+use tracing::{debug, info, instrument, warn};
+
+pub mod target;
 
-#[derive(Debug, Args)]
+use target::{CreateMode, LocalRestoreTarget, RestoreFile, RestoreTarget};
+
+#[derive(Debug, Default, Args)]
 pub struct RestoreArgs {
-    pub snapshot: String,
+    /// Snapshot number, or "archive/number" to restore from another archive.
+    #[arg(required_unless_present_any = ["resume", "before"])]
+    pub snapshot: Option<String>,
+    /// Restore the newest snapshot started before this time, e.g.
+    /// "2024-05-01 12:00".
+    #[arg(long, conflicts_with_all = ["snapshot", "resume"])]
+    pub before: Option<String>,
+    /// Archive to restore from instead of the configured one.
+    #[arg(long)]
+    pub archive: Option<String>,
     /// Keep going on errors.
     #[arg(long)]
     pub keep_going: bool,
-    /// Don't override existing files.
+    /// When to replace files that already exist in the target. Defaults to
+    /// never, or if-different with --undo-dir, which keeps the replaced files.
+    #[arg(long, value_enum)]
+    pub overwrite: Option<Overwrite>,
+    /// Allow restoring files onto existing block devices.
+    #[arg(long)]
+    pub block_devices: bool,
+    /// Move files replaced by the restore into this directory, so that the
+    /// restore can be undone.
+    #[arg(long)]
+    pub undo_dir: Option<PathBuf>,
+    /// Write what can be restored of files with missing or corrupt chunks,
+    /// filling the gaps with zeros, and report the damaged ranges.
+    #[arg(long)]
+    pub salvage: bool,
+    /// Limit download speed in bytes per second, e.g. "10M". Applies on top
+    /// of bandwidth_limit in the config, which all transfers share.
+    #[arg(long, value_parser = parse_size)]
+    pub limit_download: Option<u64>,
+    /// Restore files directly into the target directory, without the
+    /// directories they are in.
     #[arg(long)]
-    pub no_override_files: bool,
+    pub flatten: bool,
+    /// With --flatten, only restore files matching this glob. Can be repeated.
+    #[arg(long, requires = "flatten")]
+    pub include: Vec<String>,
+    /// With --flatten, rename files whose names collide to "name (1).ext"
+    /// instead of failing.
+    #[arg(long, requires = "flatten")]
+    pub rename_collisions: bool,
+    /// Record progress in a session with this name, so that an interrupted
+    /// restore can be continued with --resume.
+    #[arg(long, conflicts_with = "resume")]
+    pub session: Option<String>,
+    /// Continue an interrupted restore session, skipping the files it already
+    /// restored. Other options must match the original restore.
+    #[arg(long, conflicts_with = "snapshot")]
+    pub resume: Option<String>,
+    /// Restore into a target directory that already has files in it.
+    #[arg(long)]
+    pub into_nonempty: bool,
+    /// Always write file contents, instead of cloning files restored earlier
+    /// in the run with the same content on file systems with reflinks.
+    #[arg(long)]
+    pub no_reflink: bool,
+    /// Memory for chunks kept for other files with the same content, e.g.
+    /// "256M". Defaults to 64 MiB, 0 disables the cache.
+    #[arg(long, value_parser = parse_size)]
+    pub chunk_cache: Option<u64>,
+    /// Write a JSON report of every file restored, skipped, salvaged or
+    /// failed to this path, with the reasons and content hashes.
+    #[arg(long)]
+    pub report: Option<PathBuf>,
+    /// Restore paths matching this glob before everything else, e.g. "etc"
+    /// to get a system going again early. Can be repeated.
+    #[arg(long, conflicts_with = "flatten")]
+    pub priority_path: Vec<String>,
+    /// Only restore this file or directory of the snapshot, e.g. "etc/nginx",
+    /// at the same place under the target. Can be repeated.
+    #[arg(long)]
+    pub path: Vec<PathBuf>,
+    /// Don't create directories that have no files below them.
+    #[arg(long)]
+    pub prune_empty_dirs: bool,
+    /// Check each chunk against its hash as it is read, and each restored
+    /// file against the hash of its content, failing on a mismatch. Files
+    /// that look up to date are hashed too instead of being trusted.
+    #[arg(long)]
+    pub verify: bool,
+    /// Delete files and directories in the target that aren't in the
+    /// snapshot, once everything else is restored. Those in the way of
+    /// restored ones go first. With --undo-dir they are moved there instead.
+    #[arg(long, conflicts_with_all = ["flatten", "path"])]
+    pub delete: bool,
+    /// Only show what --delete would delete, without restoring anything.
+    #[arg(long, requires = "delete")]
+    pub dry_run: bool,
 }
 
-pub async fn restore(context: &ProgramContext, args: &RestoreArgs) -> CommandResult {
-    info!("Restore starting");
+/// When restore replaces a file that already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Overwrite {
+    /// Replace the file even if it looks up to date.
+    Always,
+    /// Skip the file if it looks up to date and fail otherwise.
+    Never,
+    /// Replace the file if it differs and is older than the one in the
+    /// snapshot.
+    IfNewer,
+    /// Replace the file unless it has the size and modified time of the one
+    /// in the snapshot, or with --verify, its content.
+    IfDifferent,
+}
 
-    let snapshot_name = format!("{}/{}", context.archive_name, args.snapshot);
+impl RestoreArgs {
+    fn overwrite(&self) -> Overwrite {
+        self.overwrite.unwrap_or(match self.undo_dir {
+            Some(_) => Overwrite::IfDifferent,
+            None => Overwrite::Never,
+        })
+    }
+}
 
-    let mut snapshot_buf = Vec::new();
-    if let Err(e) = context
-        .storage
-        .read(Collection::Snapshot, &snapshot_name, &mut snapshot_buf)
-        .await
-    {
-        if e.kind() == std::io::ErrorKind::NotFound {
+/// Which part of the tree a pass of `restore_dir` restores.
+#[derive(Clone, Copy)]
+enum Pass {
+    All,
+    /// Only the paths matching --priority-path.
+    Priority,
+    /// Everything but the paths matching --priority-path.
+    Rest,
+}
+
+// Directory in the state directory for restore session files.
+pub const RESTORE_SESSIONS_DIR: &str = "restore_sessions";
+// Default memory for chunks shared between files.
+const DEFAULT_CHUNK_CACHE: u64 = 64 * 1024 * 1024;
+// Progress within a file is recorded at most once per this many bytes.
+const SESSION_RECORD_INTERVAL: u64 = 64 * 1024 * 1024;
+
+/// Progress of a restore, kept in an append-only file with one record per
+/// line: the snapshot and target, then "done <path>" for finished files and
+/// "partial <chunks> <offset> <path>" for files written up to a chunk.
+/// Paths are relative to the restore target.
+struct RestoreSession {
+    path: PathBuf,
+    log: tokio::sync::Mutex<fs::File>,
+    done: HashSet<String>,
+    partial: HashMap<String, (usize, u64)>,
+}
+
+impl RestoreSession {
+    fn session_path(context: &ProgramContext, name: &str) -> CommandResult<PathBuf> {
+        if name.is_empty() || name.contains('/') || name.starts_with('.') {
             return Err(CommandError::new(
                 CommandErrorKind::User,
-                format!("Snapshot not found {}", snapshot_name),
+                format!("Invalid session name: {}", name),
             ));
-        } else {
-            return Err(
-                e.into_command_error(CommandErrorKind::System, "Failed to download snapshot")
-            );
         }
+        Ok(context.state_dir.join(RESTORE_SESSIONS_DIR).join(name))
     }
-    let snapshot = Snapshot::decode(Cursor::new(snapshot_buf)).map_err(|e| {
-        CommandError::with_source(
-            CommandErrorKind::Corrupt,
-            format!("Error decoding snapshot"),
-            Box::new(e),
+
+    async fn start(
+        context: &ProgramContext,
+        name: &str,
+        snapshot_name: &str,
+    ) -> CommandResult<Self> {
+        let path = Self::session_path(context, name)?;
+        fs::create_dir_all(path.parent().unwrap())
+            .await
+            .into_command_result(
+                CommandErrorKind::System,
+                "Failed to create session directory",
+            )?;
+        let log = fs::File::create(&path).await.into_command_result(
+            CommandErrorKind::System,
+            format!("Failed to create session file: {}", path.display()).as_str(),
+        )?;
+        let session = Self {
+            path,
+            log: tokio::sync::Mutex::new(log),
+            done: HashSet::new(),
+            partial: HashMap::new(),
+        };
+        session
+            .record(&format!(
+                "snapshot {}\ntarget {}",
+                snapshot_name,
+                context.backup_target.display()
+            ))
+            .await?;
+        Ok(session)
+    }
+
+    /// Open an existing session, returning it along with its snapshot.
+    async fn resume(context: &ProgramContext, name: &str) -> CommandResult<(Self, String)> {
+        let path = Self::session_path(context, name)?;
+        let content = fs::read_to_string(&path).await.map_err(|e| {
+            if e.kind() == std::io::ErrorKind::NotFound {
+                CommandError::new(
+                    CommandErrorKind::NotFound,
+                    format!("No restore session named {}", name),
+                )
+            } else {
+                e.into_command_error(CommandErrorKind::System, "Failed to read session file")
+            }
+        })?;
+
+        let mut snapshot_name = None;
+        let mut done = HashSet::new();
+        let mut partial = HashMap::new();
+        for line in content.lines() {
+            let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+            match kind {
+                "snapshot" => snapshot_name = Some(rest.to_string()),
+                "target" if Path::new(rest) != context.backup_target => {
+                    return Err(CommandError::new(
+                        CommandErrorKind::User,
+                        format!("Session {} restores to {}", name, rest),
+                    ));
+                }
+                "target" => {}
+                "done" => {
+                    partial.remove(rest);
+                    done.insert(rest.to_string());
+                }
+                "partial" => {
+                    let mut parts = rest.splitn(3, ' ');
+                    let chunks = parts.next().and_then(|c| c.parse().ok());
+                    let offset = parts.next().and_then(|o| o.parse().ok());
+                    match (chunks, offset, parts.next()) {
+                        (Some(chunks), Some(offset), Some(file)) => {
+                            partial.insert(file.to_string(), (chunks, offset));
+                        }
+                        // A record cut short by the interruption.
+                        _ => debug!("Ignoring session record: {}", line),
+                    }
+                }
+                _ => debug!("Ignoring session record: {}", line),
+            }
+        }
+        let snapshot_name = snapshot_name.ok_or_else(|| {
+            CommandError::new(
+                CommandErrorKind::Corrupt,
+                format!("Session file without snapshot: {}", path.display()),
+            )
+        })?;
+
+        let log = fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to open session file")?;
+        info!(
+            "Resuming restore of {}, {} files already restored",
+            snapshot_name,
+            done.len()
+        );
+
+        Ok((
+            Self {
+                path,
+                log: tokio::sync::Mutex::new(log),
+                done,
+                partial,
+            },
+            snapshot_name,
+        ))
+    }
+
+    async fn record(&self, record: &str) -> CommandResult {
+        let mut log = self.log.lock().await;
+        log.write_all(format!("{}\n", record).as_bytes())
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to write session file")?;
+        // Tokio writes files in the background until they are flushed.
+        log.flush()
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to write session file")
+    }
+
+    async fn finish(self) -> CommandResult {
+        fs::remove_file(&self.path)
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to remove session file")
+    }
+}
+
+/// Key of a restored file in the session, `None` for paths that can't be
+/// recorded. Those are checked again on resume like in a normal restore.
+fn session_key(state: &RestoreState<'_>, target_path: &Path) -> Option<String> {
+    let relative_path = target_path.strip_prefix(&state.root).ok()?;
+    let key = relative_path.to_str()?;
+    if key.contains('\n') {
+        return None;
+    }
+    Some(key.to_string())
+}
+
+/// What happened to one path, for --report.
+struct ReportEntry {
+    path: PathBuf,
+    status: &'static str,
+    reason: String,
+    content_hash: String,
+}
+
+struct DamagedFile {
+    path: PathBuf,
+    ranges: Vec<(u64, u64)>,
+}
+
+/// State shared by all tasks of a restore run.
+struct RestoreState<'a> {
+    target: &'a dyn RestoreTarget,
+    /// Paths are kept under the local path of the target, so that they can
+    /// be shown as they are. Without one they are relative.
+    root: PathBuf,
+    damaged_files: Mutex<Vec<DamagedFile>>,
+    /// Paths skipped over with --keep-going.
+    failures: Mutex<MultiError>,
+    download_limiter: Option<RateLimiter>,
+    session: Option<RestoreSession>,
+    /// Recently downloaded chunks, so that files with the same content don't
+    /// download them again.
+    chunk_cache: ChunkCache,
+    /// Outcome of each file, collected with --report.
+    report: Option<Mutex<Vec<ReportEntry>>>,
+    priority_paths: PathFilter,
+    /// Debug lines about the entries gone through.
+    path_log: SampledLog,
+}
+
+impl RestoreState<'_> {
+    /// Path relative to the target, as given to it.
+    fn target_relative<'p>(&self, path: &'p Path) -> &'p Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    fn report(&self, path: &Path, status: &'static str, reason: String, content_hash: &str) {
+        if let Some(ref report) = self.report {
+            report.lock().unwrap().push(ReportEntry {
+                path: path.to_path_buf(),
+                status,
+                reason,
+                content_hash: content_hash.to_string(),
+            });
+        }
+    }
+}
+
+pub async fn restore(context: &ProgramContext, args: &RestoreArgs) -> CommandResult {
+    let target = LocalRestoreTarget::new(context.backup_target.clone(), !args.no_reflink);
+    restore_to(context, args, &target).await
+}
+
+/// Restore into `target` instead of the backup target directory. Sessions
+/// need a target with a local path.
+pub async fn restore_to(
+    context: &ProgramContext,
+    args: &RestoreArgs,
+    target: &dyn RestoreTarget,
+) -> CommandResult {
+    info!("Restore starting");
+    let root = target.local_path().map(Path::to_path_buf);
+    if root.is_none() && (args.session.is_some() || args.resume.is_some()) {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Restore sessions need a target directory".to_string(),
+        ));
+    }
+    if root.is_none() && args.delete {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "--delete needs a target directory".to_string(),
+        ));
+    }
+
+    let (session, snapshot_name) = match args.resume {
+        Some(ref resume) => {
+            let (session, snapshot_name) = RestoreSession::resume(context, resume).await?;
+            (Some(session), snapshot_name)
+        }
+        None => {
+            let snapshot_name = match (&args.snapshot, &args.before) {
+                (Some(snapshot), _) => {
+                    resolve_snapshot_name(context, args.archive.as_deref(), snapshot)
+                }
+                (None, Some(before)) => {
+                    resolve_snapshot_before(context, args.archive.as_deref(), before).await?
+                }
+                (None, None) => {
+                    return Err(CommandError::new(
+                        CommandErrorKind::User,
+                        "No snapshot to restore".to_string(),
+                    ))
+                }
+            };
+            let session = match args.session {
+                Some(ref session) => {
+                    Some(RestoreSession::start(context, session, &snapshot_name).await?)
+                }
+                None => None,
+            };
+            (session, snapshot_name)
+        }
+    };
+    let snapshot = get_snapshot(context, &snapshot_name).await?;
+    // A resumed session continues its own partial restore, and --delete is
+    // meant for targets with files in them.
+    if let Some(ref root) = root {
+        if !args.into_nonempty && !args.delete && args.resume.is_none() {
+            check_target_empty(context, root).await?;
+        }
+    }
+    info!(
+        "Restoring snapshot {} from {}",
+        snapshot_name,
+        format_time(snapshot.started)
+    );
+    if !snapshot.client_id.is_empty() {
+        info!(
+            "Snapshot {} was created by client {}",
+            snapshot_name, snapshot.client_id
+        );
+    }
+    let mut root_dir_entry = get_dir_entry(context, &snapshot.root_hash).await?;
+    if !args.path.is_empty() {
+        let paths = args
+            .path
+            .iter()
+            .map(|path| path_names(path))
+            .collect::<CommandResult<Vec<_>>>()?;
+        let paths: Vec<&[String]> = paths.iter().map(Vec::as_slice).collect();
+        root_dir_entry = select_paths(context, root_dir_entry, &paths, "").await?;
+    }
+    if args.prune_empty_dirs {
+        root_dir_entry = prune_empty_dirs(context, root_dir_entry).await?;
+    }
+
+    let state = RestoreState {
+        target,
+        root: root.unwrap_or_default(),
+        damaged_files: Mutex::new(Vec::new()),
+        failures: Mutex::new(MultiError::default()),
+        download_limiter: args.limit_download.map(RateLimiter::new),
+        session,
+        chunk_cache: ChunkCache::new(args.chunk_cache.unwrap_or(DEFAULT_CHUNK_CACHE)),
+        report: args.report.as_ref().map(|_| Mutex::new(Vec::new())),
+        priority_paths: PathFilter::new(&args.priority_path),
+        path_log: SampledLog::new("entries"),
+    };
+    if args.dry_run {
+        let deleted = delete_extra(context, args, &state, root_dir_entry, &state.root).await?;
+        info!("Would delete {} paths", deleted);
+        return Ok(());
+    }
+    // Deleting comes last, so that a restore that fails leaves the target
+    // with everything it had.
+    let delete_dir_entry = args.delete.then(|| root_dir_entry.clone());
+    let mut result = if args.flatten {
+        restore_flattened(context, args, &state, root_dir_entry).await
+    } else if !state.priority_paths.is_empty() {
+        info!("Restoring priority paths");
+        async {
+            restore_dir(
+                context,
+                args,
+                &state,
+                root_dir_entry.clone(),
+                &state.root,
+                Pass::Priority,
+            )
+            .await?;
+            info!("Priority paths restored, restoring the rest");
+            restore_dir(
+                context,
+                args,
+                &state,
+                root_dir_entry,
+                &state.root,
+                Pass::Rest,
+            )
+            .await
+        }
+        .await
+    } else {
+        restore_dir(
+            context,
+            args,
+            &state,
+            root_dir_entry,
+            &state.root,
+            Pass::All,
         )
-    })?;
-    let root_dir_entry = get_dir_entry(context, &snapshot.root_hash).await?;
+        .await
+    };
+    if let (Ok(()), Some(dir_entry)) = (&result, delete_dir_entry) {
+        info!("Deleting paths that aren't in the snapshot");
+        result = delete_extra(context, args, &state, dir_entry, &state.root)
+            .await
+            .map(|deleted| info!("Deleted {} paths", deleted));
+    }
 
-    restore_dir(context, args, root_dir_entry, &context.backup_target).await?;
+    state.path_log.finish();
+    let failures = state.failures.into_inner().unwrap();
+    if let (Some(path), Some(report)) = (&args.report, state.report) {
+        let report = report.into_inner().unwrap();
+        write_report(
+            path,
+            &state.root,
+            &snapshot_name,
+            report,
+            &failures,
+            &result,
+        )
+        .await?;
+    }
+    result?;
+    if let Some(session) = state.session {
+        // Keep the session around so that the failed paths can be retried.
+        if failures.is_empty() {
+            session.finish().await?;
+        }
+    }
 
+    let damaged_files = state.damaged_files.into_inner().unwrap();
+    if !damaged_files.is_empty() {
+        for damaged_file in damaged_files.iter() {
+            warn!(
+                "Damaged file {}: bytes {}",
+                damaged_file.path.display(),
+                format_ranges(&damaged_file.ranges)
+            );
+        }
+        warn!(
+            "Restore complete, {} files were salvaged with damage",
+            damaged_files.len()
+        );
+        return failures.into_result("Restore");
+    }
+
+    failures.into_result("Restore")?;
     info!("Restore complete");
     Ok(())
 }
 
+/// Names of the components of a --path.
+fn path_names(path: &Path) -> CommandResult<Vec<String>> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+            Component::RootDir | Component::CurDir => continue,
+            _ => {
+                return Err(CommandError::new(
+                    CommandErrorKind::User,
+                    format!("Unsupported path: {}", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The part of `dir_entry` that holds `paths`, given as names relative to
+/// it. Only the directory entries on the way to the paths are fetched, the
+/// selected directories are fetched when they are restored.
+#[async_recursion]
+async fn select_paths(
+    context: &ProgramContext,
+    dir_entry: DirEntry,
+    paths: &[&[String]],
+    parent: &str,
+) -> CommandResult<DirEntry> {
+    if paths.iter().any(|names| names.is_empty()) {
+        return Ok(dir_entry);
+    }
+
+    let mut by_name: BTreeMap<&str, Vec<&[String]>> = BTreeMap::new();
+    for names in paths {
+        by_name.entry(&names[0]).or_default().push(&names[1..]);
+    }
+    let DirEntry {
+        mut file,
+        mut sub_dir,
+        ..
+    } = dir_entry;
+    let mut selected = DirEntry::default();
+    for (name, rest) in by_name {
+        let path = if parent.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", parent, name)
+        };
+        if let Some(index) = sub_dir.iter().position(|sub_dir| sub_dir.name == name) {
+            let mut sub_dir = sub_dir.swap_remove(index);
+            if !rest.iter().any(|names| names.is_empty()) {
+                let dir_entry = match sub_dir.content {
+                    Some(sub_dir_entry::Content::Inline(dir_entry)) => dir_entry,
+                    Some(sub_dir_entry::Content::Hash(hash)) => {
+                        get_dir_entry(context, &hash).await?
+                    }
+                    None => {
+                        return Err(CommandError::new(
+                            CommandErrorKind::Corrupt,
+                            format!("Sub dir entry without content {}", path),
+                        ))
+                    }
+                };
+                let dir_entry = select_paths(context, dir_entry, &rest, &path).await?;
+                sub_dir.content = Some(sub_dir_entry::Content::Inline(dir_entry));
+            }
+            selected.sub_dir.push(sub_dir);
+        } else if let Some(index) = file.iter().position(|file| file.name == name) {
+            if rest.iter().any(|names| !names.is_empty()) {
+                return Err(CommandError::new(
+                    CommandErrorKind::NotFound,
+                    format!("Not a directory in the snapshot: {}", path),
+                ));
+            }
+            selected.file.push(file.swap_remove(index));
+        } else {
+            return Err(CommandError::new(
+                CommandErrorKind::NotFound,
+                format!("No {} in the snapshot", path),
+            ));
+        }
+    }
+    Ok(selected)
+}
+
+/// `dir_entry` without the directories that have no files below them. The
+/// directories that are kept are fetched and inlined.
+#[async_recursion]
+async fn prune_empty_dirs(
+    context: &ProgramContext,
+    mut dir_entry: DirEntry,
+) -> CommandResult<DirEntry> {
+    let mut sub_dirs = Vec::with_capacity(dir_entry.sub_dir.len());
+    for mut sub_dir in std::mem::take(&mut dir_entry.sub_dir) {
+        let sub_dir_entry = match sub_dir.content {
+            Some(sub_dir_entry::Content::Inline(dir_entry)) => dir_entry,
+            Some(sub_dir_entry::Content::Hash(hash)) => get_dir_entry(context, &hash).await?,
+            None => {
+                return Err(CommandError::new(
+                    CommandErrorKind::Corrupt,
+                    format!("Sub dir entry without content {}", sub_dir.name),
+                ))
+            }
+        };
+        let sub_dir_entry = prune_empty_dirs(context, sub_dir_entry).await?;
+        if sub_dir_entry.file.is_empty() && sub_dir_entry.sub_dir.is_empty() {
+            continue;
+        }
+        sub_dir.content = Some(sub_dir_entry::Content::Inline(sub_dir_entry));
+        sub_dirs.push(sub_dir);
+    }
+    dir_entry.sub_dir = sub_dirs;
+    Ok(dir_entry)
+}
+
+fn format_ranges(ranges: &[(u64, u64)]) -> String {
+    ranges
+        .iter()
+        .map(|(start, end)| format!("{}-{}", start, end))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    snapshot: &'a str,
+    target: Cow<'a, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    files: Vec<JsonReportFile<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonReportFile<'a> {
+    path: Cow<'a, str>,
+    status: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    reason: &'a str,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    hash: &'a str,
+}
+
+/// Write the --report file: the snapshot, the target and an entry for each
+/// file sorted by path, with paths relative to the target. A restore that
+/// stopped at an error has it in "error", and its files may be missing.
+async fn write_report(
+    path: &Path,
+    root: &Path,
+    snapshot_name: &str,
+    mut entries: Vec<ReportEntry>,
+    failures: &MultiError,
+    result: &CommandResult,
+) -> CommandResult {
+    for failure in failures.errors() {
+        let mut reason = failure.error.to_string();
+        if let Some(source) = failure.error.source() {
+            reason = format!("{}: {}", reason, source);
+        }
+        entries.push(ReportEntry {
+            path: failure.path.clone(),
+            status: "failed",
+            reason,
+            content_hash: String::new(),
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let report = JsonReport {
+        snapshot: snapshot_name,
+        target: root.to_string_lossy(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        files: entries
+            .iter()
+            .map(|entry| JsonReportFile {
+                path: entry
+                    .path
+                    .strip_prefix(root)
+                    .unwrap_or(&entry.path)
+                    .to_string_lossy(),
+                status: entry.status,
+                reason: &entry.reason,
+                hash: &entry.content_hash,
+            })
+            .collect(),
+    };
+    let mut report = serde_json::to_string_pretty(&report).into_command_result(
+        CommandErrorKind::System,
+        "Failed to encode the restore report",
+    )?;
+    report.push('\n');
+
+    fs::write(path, report).await.into_command_result(
+        CommandErrorKind::System,
+        "Failed to write the restore report",
+    )
+}
+
+/// Device and inode numbers of the repository, if it is local, and the state
+/// directory. With the default layout they are inside the target, and
+/// restores must leave them alone.
+async fn own_dirs(context: &ProgramContext) -> Vec<(u64, u64)> {
+    let mut own_dirs = Vec::new();
+    for dir in [context.storage.local_path(), Some(&context.state_dir)]
+        .into_iter()
+        .flatten()
+    {
+        if let Ok(metadata) = fs::metadata(dir).await {
+            own_dirs.push((metadata.dev(), metadata.ino()));
+        }
+    }
+    own_dirs
+}
+
+async fn check_target_empty(context: &ProgramContext, root: &Path) -> CommandResult {
+    let mut entries = match fs::read_dir(root).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(
+                e.into_command_error(CommandErrorKind::System, "Failed to list restore target")
+            )
+        }
+    };
+    let own_dirs = own_dirs(context).await;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to list restore target")?
+    {
+        let metadata = entry
+            .metadata()
+            .await
+            .into_io_command_result("Failed to get metadata")?;
+        if !own_dirs.contains(&(metadata.dev(), metadata.ino())) {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                format!(
+                    "Restore target {} is not empty, pass --into-nonempty to restore into it",
+                    root.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[async_recursion]
 async fn restore_dir(
     context: &ProgramContext,
     args: &RestoreArgs,
+    state: &RestoreState<'_>,
     root_dir_entry: DirEntry,
-    target: &PathBuf,
+    target: &Path,
+    pass: Pass,
 ) -> CommandResult {
-    debug!("Restoring dir {}", target.display());
-
-    match fs::metadata(target).await {
-        Ok(metadata) => {
-            if !metadata.file_type().is_dir() {
-                Err(CommandError::new(
-                    CommandErrorKind::FileSystemConflict,
-                    "Target exists and is not a directory".to_string(),
-                ))
-            } else {
-                Ok(())
+    state
+        .path_log
+        .log(format_args!("Restoring dir {}", target.display()));
+    // With --delete, anything but a directory in the way goes first,
+    // symbolic links included so that the restore doesn't write through them.
+    // The root is left alone, it is the target given by the user.
+    if args.delete && target != state.root {
+        if let Ok(metadata) = fs::symlink_metadata(target).await {
+            if !metadata.is_dir() {
+                delete_path(args, state, target).await?;
             }
         }
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::NotFound => {
-                fs::create_dir(target)
-                    .await
-                    .into_command_result(CommandErrorKind::System, "Failed to create directory")?;
-                Ok(())
-            }
-            _ => Err(
-                e.into_command_error(CommandErrorKind::System, "Failed to get directory metadata")
-            ),
-        },
-    }?;
+    }
+    state
+        .target
+        .create_dir(state.target_relative(target))
+        .await
+        .into_io_command_result("Failed to create directory")?;
 
     let DirEntry {
         file: files,
@@ -110,6 +869,29 @@ async fn restore_dir(
     let mut results: Vec<BoxFuture<CommandResult>> = Vec::new();
     for SubDirEntry { name, content, .. } in sub_dirs.into_iter() {
         let dir_target = target.join(&name);
+        let dir_pass = match pass {
+            Pass::All => Pass::All,
+            Pass::Priority => {
+                let path = filter_path(state.target_relative(&dir_target))?;
+                if state.priority_paths.includes(&path) {
+                    Pass::All
+                } else if state.priority_paths.should_descend(&path) {
+                    pass
+                } else {
+                    continue;
+                }
+            }
+            Pass::Rest => {
+                let path = filter_path(state.target_relative(&dir_target))?;
+                if state.priority_paths.includes(&path) {
+                    continue;
+                } else if state.priority_paths.should_descend(&path) {
+                    pass
+                } else {
+                    Pass::All
+                }
+            }
+        };
         let content = content.ok_or_else(|| {
             CommandError::new(
                 CommandErrorKind::Corrupt,
@@ -123,9 +905,9 @@ async fn restore_dir(
                 sub_dir_entry::Content::Hash(hash) => get_dir_entry(context, &hash).await,
             }?;
 
-            restore_dir(context, args, dir_entry, &dir_target)
+            restore_dir(context, args, state, dir_entry, &dir_target, dir_pass)
                 .await
-                .keep_going_or_err(args.keep_going, |e| {
+                .keep_going_or_err(args.keep_going, &state.failures, &dir_target, |e| {
                     e.with_message(format!("Failed to restore dir {}", dir_target.display()))
                 })?;
             Ok(())
@@ -133,11 +915,21 @@ async fn restore_dir(
     }
 
     for file_entry in files.into_iter() {
+        let file_target = target.join(&file_entry.name);
+        let included = match pass {
+            Pass::All => true,
+            Pass::Priority | Pass::Rest => {
+                let path = filter_path(state.target_relative(&file_target))?;
+                state.priority_paths.includes(&path) == matches!(pass, Pass::Priority)
+            }
+        };
+        if !included {
+            continue;
+        }
         results.push(Box::pin(async move {
-            let file_target = target.join(&file_entry.name);
-            restore_file(context, args, file_entry, &file_target)
+            restore_file(context, args, state, file_entry, &file_target)
                 .await
-                .keep_going_or_err(args.keep_going, |e| {
+                .keep_going_or_err(args.keep_going, &state.failures, &file_target, |e| {
                     e.with_message(format!("Failed to restore file {}", file_target.display()))
                 })?;
             Ok(())
@@ -149,116 +941,831 @@ async fn restore_dir(
     Ok(())
 }
 
+/// Delete what is in the `target` directory but not in `dir_entry`, for
+/// --delete, or only log it with --dry-run. Entries of the wrong type are
+/// deleted too, and the undo directory, the repository and the state
+/// directory are always left alone. Returns the
+/// number of paths deleted, counting each deleted directory once.
+async fn delete_extra(
+    context: &ProgramContext,
+    args: &RestoreArgs,
+    state: &RestoreState<'_>,
+    dir_entry: DirEntry,
+    target: &Path,
+) -> CommandResult<u64> {
+    // The undo directory may be inside the target. It is created up front
+    // so that it can be recognized by its device and inode.
+    let mut kept_dirs = own_dirs(context).await;
+    match args.undo_dir {
+        Some(ref undo_dir) if !args.dry_run => {
+            fs::create_dir_all(undo_dir)
+                .await
+                .into_command_result(CommandErrorKind::System, "Failed to create undo directory")?;
+            let metadata = fs::metadata(undo_dir)
+                .await
+                .into_command_result(CommandErrorKind::System, "Failed to read undo directory")?;
+            kept_dirs.push((metadata.dev(), metadata.ino()));
+        }
+        _ => {}
+    }
+    delete_extra_in(context, args, state, &kept_dirs, dir_entry, target).await
+}
+
+#[async_recursion]
+async fn delete_extra_in(
+    context: &ProgramContext,
+    args: &RestoreArgs,
+    state: &RestoreState<'_>,
+    kept_dirs: &[(u64, u64)],
+    dir_entry: DirEntry,
+    target: &Path,
+) -> CommandResult<u64> {
+    let mut entries = match fs::read_dir(target).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => {
+            return Err(e.into_command_error(
+                CommandErrorKind::System,
+                format!("Failed to list {}", target.display()).as_str(),
+            ))
+        }
+    };
+    let DirEntry {
+        file: files,
+        sub_dir: mut sub_dirs,
+        ..
+    } = dir_entry;
+    let mut deleted = 0;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to list restore target")?
+    {
+        let path = entry.path();
+        // Symbolic links are never followed, so that nothing outside of the
+        // target is deleted. A link is only kept if a file has its name.
+        let metadata = entry
+            .metadata()
+            .await
+            .into_io_command_result("Failed to get metadata")?;
+        if kept_dirs.contains(&(metadata.dev(), metadata.ino())) {
+            continue;
+        }
+
+        let name = entry.file_name();
+        let name = name.to_str();
+        if metadata.is_dir() {
+            if let Some(index) = sub_dirs
+                .iter()
+                .position(|sub_dir| Some(sub_dir.name.as_str()) == name)
+            {
+                let sub_dir = sub_dirs.swap_remove(index);
+                let result = async {
+                    let sub_dir_entry = match sub_dir.content {
+                        Some(sub_dir_entry::Content::Inline(dir_entry)) => dir_entry,
+                        Some(sub_dir_entry::Content::Hash(hash)) => {
+                            get_dir_entry(context, &hash).await?
+                        }
+                        None => {
+                            return Err(CommandError::new(
+                                CommandErrorKind::Corrupt,
+                                format!("Sub dir entry without content {}", path.display()),
+                            ))
+                        }
+                    };
+                    delete_extra_in(context, args, state, kept_dirs, sub_dir_entry, &path).await
+                }
+                .await;
+                match result {
+                    Ok(sub_deleted) => deleted += sub_deleted,
+                    Err(e) => Err::<(), _>(e).keep_going_or_err(
+                        args.keep_going,
+                        &state.failures,
+                        &path,
+                        |e| e.with_message(format!("Failed to delete in {}", path.display())),
+                    )?,
+                }
+                continue;
+            }
+        } else if files.iter().any(|file| Some(file.name.as_str()) == name) {
+            continue;
+        }
+
+        deleted += 1;
+        if args.dry_run {
+            info!("Would delete {}", path.display());
+            continue;
+        }
+        delete_path(args, state, &path).await.keep_going_or_err(
+            args.keep_going,
+            &state.failures,
+            &path,
+            |e| e.with_message(format!("Failed to delete {}", path.display())),
+        )?;
+    }
+
+    Ok(deleted)
+}
+
+/// Delete `path` for --delete, or move it to the undo directory if there is
+/// one. A symbolic link is deleted itself, not what it points to.
+async fn delete_path(args: &RestoreArgs, state: &RestoreState<'_>, path: &Path) -> CommandResult {
+    info!("Deleting {}", path.display());
+    let metadata = fs::symlink_metadata(path)
+        .await
+        .into_io_command_result("Failed to get metadata")?;
+    match args.undo_dir {
+        Some(ref undo_dir) => move_to_undo_dir(state, undo_dir, path).await,
+        None if metadata.is_dir() => fs::remove_dir_all(path)
+            .await
+            .into_io_command_result("Failed to delete directory"),
+        None => fs::remove_file(path)
+            .await
+            .into_io_command_result("Failed to delete file"),
+    }
+}
+
+/// Collect the files in a tree that match the filter, along with their paths
+/// relative to the tree root.
+#[async_recursion]
+async fn collect_files(
+    context: &ProgramContext,
+    filter: &PathFilter,
+    dir_entry: DirEntry,
+    relative_path: &str,
+    included: bool,
+    files: &mut Vec<(String, FileEntry)>,
+) -> CommandResult {
+    let join = |name: &str| {
+        if relative_path.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", relative_path, name)
+        }
+    };
+
+    for file_entry in dir_entry.file.into_iter() {
+        let file_path = join(&file_entry.name);
+        if included || filter.includes(&file_path) {
+            files.push((file_path, file_entry));
+        }
+    }
+
+    for SubDirEntry { name, content } in dir_entry.sub_dir.into_iter() {
+        let sub_dir_path = join(&name);
+        let sub_dir_included = included || filter.includes(&sub_dir_path);
+        if !sub_dir_included && !filter.should_descend(&sub_dir_path) {
+            continue;
+        }
+
+        let sub_dir_entry = match content {
+            Some(sub_dir_entry::Content::Inline(dir_entry)) => dir_entry,
+            Some(sub_dir_entry::Content::Hash(hash)) => get_dir_entry(context, &hash).await?,
+            None => {
+                return Err(CommandError::new(
+                    CommandErrorKind::Corrupt,
+                    format!("Sub dir entry without content {}", sub_dir_path),
+                ))
+            }
+        };
+        collect_files(
+            context,
+            filter,
+            sub_dir_entry,
+            &sub_dir_path,
+            sub_dir_included,
+            files,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Name for the `n`th file restored with the same name, e.g. "report (2).docx".
+fn collision_name(name: &str, n: usize) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{} ({}).{}", stem, n, extension)
+        }
+        _ => format!("{} ({})", name, n),
+    }
+}
+
+async fn restore_flattened(
+    context: &ProgramContext,
+    args: &RestoreArgs,
+    state: &RestoreState<'_>,
+    root_dir_entry: DirEntry,
+) -> CommandResult {
+    let filter = PathFilter::new(&args.include);
+    let mut files = Vec::new();
+    collect_files(
+        context,
+        &filter,
+        root_dir_entry,
+        "",
+        filter.is_empty(),
+        &mut files,
+    )
+    .await?;
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    info!("Restoring {} files", files.len());
+
+    state
+        .target
+        .create_dir(Path::new(""))
+        .await
+        .into_io_command_result("Failed to create directory")?;
+
+    let mut used_names = HashSet::new();
+    let mut results: Vec<BoxFuture<CommandResult>> = Vec::new();
+    for (path, file_entry) in files.into_iter() {
+        let mut name = file_entry.name.clone();
+        if used_names.contains(&name) {
+            if !args.rename_collisions {
+                let error = CommandError::new(
+                    CommandErrorKind::FileSystemConflict,
+                    format!(
+                        "{} has the same name as another restored file, pass --rename-collisions to rename it",
+                        path
+                    ),
+                );
+                if !args.keep_going {
+                    return Err(error);
+                }
+                warn!("{}", error);
+                state
+                    .failures
+                    .lock()
+                    .unwrap()
+                    .push(state.root.join(&name), error);
+                continue;
+            }
+            name = (1..)
+                .map(|n| collision_name(&file_entry.name, n))
+                .find(|name| !used_names.contains(name))
+                .unwrap();
+            info!("Restoring {} as {}", path, name);
+        }
+        used_names.insert(name.clone());
+
+        results.push(Box::pin(async move {
+            let file_target = state.root.join(&name);
+            restore_file(context, args, state, file_entry, &file_target)
+                .await
+                .keep_going_or_err(args.keep_going, &state.failures, &file_target, |e| {
+                    e.with_message(format!("Failed to restore file {}", path))
+                })
+        }));
+    }
+
+    try_join_all(results).await?;
+
+    Ok(())
+}
+
+#[instrument(level = "debug", skip_all, fields(path = %target_path.display(), size = file_entry.size))]
 async fn restore_file(
     context: &ProgramContext,
     args: &RestoreArgs,
+    state: &RestoreState<'_>,
     file_entry: FileEntry,
     target_path: &PathBuf,
 ) -> CommandResult {
     let FileEntry {
-        name: _,
-        chunk_hash: chunk_hashes,
+        ref content_hash,
+        chunk_hash: ref chunk_hashes,
         size,
         modified,
+        modified_nanos,
+        block_size,
         ..
     } = file_entry;
 
-    debug!("Restoring file {}", target_path.display());
+    state
+        .path_log
+        .log(format_args!("Restoring file {}", target_path.display()));
 
-    enum Matches {
-        DoesNotExist,
-        Matches,
-        DoesNotMatch,
+    let session_key = match state.session {
+        Some(_) => session_key(state, target_path),
+        None => None,
+    };
+    let mut resume_from: Option<(usize, u64)> = None;
+    if let (Some(session), Some(key)) = (&state.session, &session_key) {
+        if session.done.contains(key) {
+            state
+                .path_log
+                .log(format_args!("Already restored {}", target_path.display()));
+            state.report(
+                target_path,
+                "skipped",
+                "Restored earlier in the session".to_string(),
+                content_hash,
+            );
+            return Ok(());
+        }
+        resume_from = session.partial.get(key).copied();
     }
-    let existing_matches = match fs::metadata(target_path).await {
-        Ok(metadata) => 'matches: {
-            let existing_size = metadata.size();
-            let existing_modified = match metadata.modified() {
-                Ok(m) => as_unix_timestamp(m),
-                Err(e) => {
+
+    let mut mode = CreateMode::New;
+    if state.target.local_path().is_some() {
+        enum Matches {
+            DoesNotExist,
+            Matches,
+            DoesNotMatch,
+            /// Differs, but is no older than the file in the snapshot.
+            Newer,
+            /// A directory, which only --delete or --undo-dir moves aside.
+            Dir,
+            BlockDevice,
+            /// Partially written by an interrupted restore session.
+            Partial,
+        }
+        let existing_matches = match fs::metadata(target_path).await {
+            // The file was partially written by this restore session.
+            Ok(metadata) if resume_from.is_some() && metadata.is_file() => Matches::Partial,
+            Ok(metadata) if metadata.file_type().is_block_device() => {
+                if !args.block_devices {
+                    return Err(CommandError::new(
+                        CommandErrorKind::FileSystemConflict,
+                        format!(
+                            "{} is a block device, pass --block-devices to restore onto it",
+                            target_path.display()
+                        ),
+                    ));
+                }
+                Matches::BlockDevice
+            }
+            Ok(metadata) if metadata.is_dir() => Matches::Dir,
+            Ok(_) if args.overwrite() == Overwrite::Always => Matches::DoesNotMatch,
+            Ok(metadata) => 'matches: {
+                let existing_size = metadata.size();
+                let existing_modified = match metadata.modified() {
+                    Ok(m) => m,
+                    Err(e) => {
+                        debug!(
+                            "Failed to get modified time for {}: {}",
+                            target_path.display(),
+                            e
+                        );
+                        break 'matches Matches::DoesNotMatch;
+                    }
+                };
+
+                if existing_size != size
+                    || !modified_matches(modified, modified_nanos, existing_modified)
+                {
+                    if args.overwrite() == Overwrite::IfNewer
+                        && as_unix_timestamp_nanos(existing_modified) >= (modified, modified_nanos)
+                    {
+                        break 'matches Matches::Newer;
+                    }
+                    break 'matches Matches::DoesNotMatch;
+                }
+
+                if args.verify && !content_hash.is_empty() {
+                    let existing_hash = match fs::File::open(target_path).await {
+                        Ok(file) => read_hash(pin!(file), context.tuning.read_buffer_size).await,
+                        Err(e) => Err(e),
+                    };
+                    match existing_hash {
+                        Ok(existing_hash) if existing_hash == *content_hash => {}
+                        Ok(_) => break 'matches Matches::DoesNotMatch,
+                        Err(e) => {
+                            debug!("Failed to hash {}: {}", target_path.display(), e);
+                            break 'matches Matches::DoesNotMatch;
+                        }
+                    }
+                }
+                Matches::Matches
+            }
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
                     debug!(
-                        "Failed to get modified time for {}: {}",
+                        "Failed to get metadata for {}: {}",
                         target_path.display(),
                         e
                     );
-                    break 'matches Matches::DoesNotMatch;
                 }
-            };
-
-            if existing_size != size && existing_modified != modified {
-                break 'matches Matches::DoesNotMatch;
+                Matches::DoesNotExist
             }
-
-            // TODO: Check hash if requested.
-            Matches::Matches
-        }
-        Err(e) => {
-            if e.kind() != std::io::ErrorKind::NotFound {
-                debug!(
-                    "Failed to get metadata for {}: {}",
-                    target_path.display(),
-                    e
+        };
+        match existing_matches {
+            Matches::Matches => {
+                state.report(
+                    target_path,
+                    "skipped",
+                    "Already up to date".to_string(),
+                    content_hash,
                 );
+                return Ok(());
             }
-            Matches::DoesNotExist
-        }
-    };
-    match existing_matches {
-        Matches::Matches => return Ok(()),
-        Matches::DoesNotMatch => {
-            if args.no_override_files {
-                return Err(CommandError::new(
-                    CommandErrorKind::FileSystemConflict,
-                    format!("{} already exists", target_path.display()),
-                ));
+            Matches::Newer => {
+                state.report(
+                    target_path,
+                    "skipped",
+                    "Existing file is newer".to_string(),
+                    content_hash,
+                );
+                return Ok(());
+            }
+            Matches::DoesNotMatch => {
+                if args.overwrite() == Overwrite::Never {
+                    return Err(CommandError::new(
+                        CommandErrorKind::FileSystemConflict,
+                        format!("{} already exists", target_path.display()),
+                    ));
+                }
+                if let Some(ref undo_dir) = args.undo_dir {
+                    move_to_undo_dir(state, undo_dir, target_path).await?;
+                }
             }
+            Matches::Dir => {
+                if !args.delete && (args.undo_dir.is_none() || args.overwrite() == Overwrite::Never)
+                {
+                    return Err(CommandError::new(
+                        CommandErrorKind::FileSystemConflict,
+                        format!("{} is a directory", target_path.display()),
+                    ));
+                }
+                delete_path(args, state, target_path).await?;
+            }
+            Matches::DoesNotExist => {}
+            Matches::BlockDevice => {}
+            Matches::Partial => {}
+        }
+        mode = match existing_matches {
+            Matches::Partial => CreateMode::InPlace {
+                offset: resume_from.map_or(0, |(_, offset)| offset),
+            },
+            // Write over the existing contents in place.
+            Matches::BlockDevice => CreateMode::InPlace { offset: 0 },
+            // Moved away to the undo directory otherwise.
+            Matches::DoesNotMatch if args.undo_dir.is_none() => CreateMode::Truncate,
+            _ => CreateMode::New,
+        };
+        if !matches!(existing_matches, Matches::Partial) {
+            resume_from = None;
         }
-        Matches::DoesNotExist => {}
-    }
-
-    let mut open_options = OpenOptions::new();
-
-    open_options.write(true);
-    if args.no_override_files {
-        open_options.create(true).truncate(true);
-    } else {
-        open_options.create_new(true);
     }
 
-    let mut target_file = open_options
-        .open(target_path)
+    let mut target_file = state
+        .target
+        .create_file(state.target_relative(target_path), &file_entry, mode)
         .await
-        .into_command_result(CommandErrorKind::System, "Failed to open file for writing")?;
+        .into_io_command_result("Failed to open file for writing")?;
+    let (skip_chunks, mut written) = match target_file.is_complete() {
+        true => (chunk_hashes.len(), size),
+        false => resume_from.unwrap_or((0, 0)),
+    };
 
     let mut buffer = Vec::new(); // TODO: Setup a pool of buffers.
-    for chunk_hash in chunk_hashes.into_iter() {
-        context
-            .storage
-            .read(Collection::Blob, &chunk_hash, &mut buffer)
-            .await
-            .keep_going_or_err(args.keep_going, |e| {
+
+    // Only a file written from the start can be checked as a whole.
+    let mut hasher =
+        (args.verify && skip_chunks == 0 && !content_hash.is_empty()).then(Sha256::new);
+    let mut recorded = written;
+    let mut damaged_ranges = Vec::new();
+    let mut complete = true;
+    for (index, chunk_hash) in chunk_hashes.iter().enumerate().skip(skip_chunks) {
+        if let (Some(session), Some(key)) = (&state.session, &session_key) {
+            if written - recorded >= SESSION_RECORD_INTERVAL {
+                // The data has to be on disk before the session says it is.
+                target_file
+                    .sync()
+                    .await
+                    .into_io_command_result("Failed to sync changes")?;
+                session
+                    .record(&format!("partial {} {} {}", index, written, key))
+                    .await?;
+                recorded = written;
+            }
+        }
+
+        if block_size != 0 && chunk_hash.is_empty() {
+            // Zero block that was elided in fixed-block mode.
+            let zeros = chunk_length(&file_entry, index, written)?;
+            if let Some(ref mut hasher) = hasher {
+                hasher.update(vec![0; zeros as usize]);
+            }
+            target_file
+                .write_zeros(zeros)
+                .await
+                .into_io_command_result("Failed to write chunk")?;
+            written += zeros;
+            continue;
+        }
+
+        let cached = state.chunk_cache.get(chunk_hash);
+        let length = chunk_length(&file_entry, index, written)?;
+        if cached.is_none() && !args.salvage && length > context.tuning.stream_chunk_size as u64 {
+            let (streamed, read_result) = stream_chunk(
+                context,
+                args,
+                state,
+                chunk_hash,
+                length,
+                &mut *target_file,
+                &mut hasher,
+            )
+            .await?;
+            written += streamed;
+            complete &= read_result.is_ok();
+            read_result.keep_going_or_err(args.keep_going, &state.failures, target_path, |e| {
                 CommandError::with_source(
                     CommandErrorKind::Corrupt,
                     format!("Failed to read chunk {}", chunk_hash),
                     Box::new(e),
                 )
             })?;
+            if !complete {
+                // The rest of the chunk is missing, so later chunks would
+                // land at the wrong offsets.
+                break;
+            }
+            continue;
+        }
+
+        let read_result = match cached {
+            Some(cached) => {
+                buffer.clear();
+                buffer.extend_from_slice(&cached);
+                Ok(())
+            }
+            None => {
+                let read_result = context
+                    .storage
+                    .read(Collection::Blob, chunk_hash, &mut buffer)
+                    .await
+                    .and_then(|_| match file_entry.chunk_size.get(index) {
+                        Some(length) if *length != buffer.len() as u64 => Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Chunk is {} bytes, expected {}", buffer.len(), length),
+                        )),
+                        _ => Ok(()),
+                    })
+                    .and_then(|_| {
+                        if args.verify && !blob_key_matches(chunk_hash, &buffer) {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                "Chunk does not match its hash",
+                            ));
+                        }
+                        Ok(())
+                    });
+                if let Some(ref download_limiter) = state.download_limiter {
+                    download_limiter.acquire(buffer.len() as u64).await;
+                }
+                if read_result.is_ok() {
+                    state.chunk_cache.insert(chunk_hash, &buffer);
+                }
+                read_result
+            }
+        };
+        if args.salvage {
+            let damaged = match read_result {
+                Ok(()) => !blob_key_matches(chunk_hash, &buffer),
+                Err(ref e) => {
+                    debug!("Failed to read chunk {}: {}", chunk_hash, e);
+                    true
+                }
+            };
+            if damaged {
+                buffer.clear();
+                buffer.resize(length as usize, 0);
+                damaged_ranges.push((written, written + length));
+            }
+        } else {
+            complete &= read_result.is_ok();
+            read_result.keep_going_or_err(args.keep_going, &state.failures, target_path, |e| {
+                CommandError::with_source(
+                    CommandErrorKind::Corrupt,
+                    format!("Failed to read chunk {}", chunk_hash),
+                    Box::new(e),
+                )
+            })?;
+            if !complete {
+                // The buffer doesn't hold the chunk, and without it later
+                // chunks would land at the wrong offsets.
+                break;
+            }
+        }
+        if let Some(ref mut hasher) = hasher {
+            hasher.update(&buffer);
+        }
         target_file
-            .write_all(&buffer)
+            .write(&buffer)
             .await
-            .into_command_result(CommandErrorKind::System, "Failed to write chunk")?;
+            .into_io_command_result("Failed to write chunk")?;
+        written += buffer.len() as u64;
     }
 
-    let target_file = target_file.into_std().await;
-    target_file
-        .set_modified(system_time_from_unix_timestamp(modified)?)
-        .into_command_result(CommandErrorKind::System, "Failed to set modified time")?;
+    let mut intact = complete && damaged_ranges.is_empty();
+    let mut mismatch = false;
+    if let Some(hasher) = hasher.filter(|_| intact) {
+        mismatch = format!("{:x}", hasher.finalize()) != *content_hash;
+        intact = !mismatch;
+    }
+    if !damaged_ranges.is_empty() {
+        state.report(
+            target_path,
+            "salvaged",
+            format!("Damaged bytes {}", format_ranges(&damaged_ranges)),
+            content_hash,
+        );
+        state.damaged_files.lock().unwrap().push(DamagedFile {
+            path: target_path.clone(),
+            ranges: damaged_ranges,
+        });
+    }
 
-    let target_file = File::from_std(target_file);
     target_file
-        .sync_all()
+        .finish(intact)
+        .await
+        .into_io_command_result("Failed to finish writing file")?;
+    if mismatch {
+        return Err(CommandError::new(
+            CommandErrorKind::Corrupt,
+            format!(
+                "Restored {} does not match its content hash",
+                target_path.display()
+            ),
+        ));
+    }
+    if intact {
+        state.report(target_path, "restored", String::new(), content_hash);
+    }
+    if !complete {
+        // Left for a resumed session to restore again.
+        return Ok(());
+    }
+    record_done(state, &session_key).await
+}
+
+/// Copy a large chunk from the storage to `target_file` a piece at a time
+/// instead of reading it whole, checking its length and with --verify its
+/// hash once it is through. Returns the bytes written along with the result
+/// of reading the chunk, as the pieces before a failure are written already.
+async fn stream_chunk(
+    context: &ProgramContext,
+    args: &RestoreArgs,
+    state: &RestoreState<'_>,
+    chunk_hash: &str,
+    length: u64,
+    target_file: &mut dyn RestoreFile,
+    hasher: &mut Option<Sha256>,
+) -> CommandResult<(u64, std::io::Result<()>)> {
+    let mut reader = match context
+        .storage
+        .read_stream(Collection::Blob, chunk_hash)
         .await
-        .into_command_result(CommandErrorKind::System, "Failed to sync changes")?;
+    {
+        Ok(reader) => HashingReader::new(reader),
+        Err(e) => return Ok((0, Err(e))),
+    };
+    let mut buffer = vec![0; context.tuning.read_buffer_size];
+    let mut written = 0;
+    loop {
+        let bytes_read = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(bytes_read) => bytes_read,
+            Err(e) => return Ok((written, Err(e))),
+        };
+        if reader.bytes() > length {
+            let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Chunk is more than the expected {} bytes", length),
+            );
+            return Ok((written, Err(error)));
+        }
+        if let Some(ref download_limiter) = state.download_limiter {
+            download_limiter.acquire(bytes_read as u64).await;
+        }
+        let piece = &buffer[..bytes_read];
+        if let Some(ref mut hasher) = hasher {
+            hasher.update(piece);
+        }
+        target_file
+            .write(piece)
+            .await
+            .into_io_command_result("Failed to write chunk")?;
+        written += bytes_read as u64;
+    }
+
+    if written != length {
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Chunk is {} bytes, expected {}", written, length),
+        );
+        return Ok((written, Err(error)));
+    }
+    let (algorithm, hash) = split_blob_key(chunk_hash);
+    if args.verify && (algorithm != "sha256" || reader.finish() != hash) {
+        let error = std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Chunk does not match its hash",
+        );
+        return Ok((written, Err(error)));
+    }
+    Ok((written, Ok(())))
+}
+
+async fn record_done(state: &RestoreState<'_>, session_key: &Option<String>) -> CommandResult {
+    match (&state.session, session_key) {
+        (Some(session), Some(key)) => session.record(&format!("done {}", key)).await,
+        _ => Ok(()),
+    }
+}
+
+async fn move_to_undo_dir(
+    state: &RestoreState<'_>,
+    undo_dir: &Path,
+    target_path: &Path,
+) -> CommandResult {
+    let relative_path = target_path
+        .strip_prefix(&state.root)
+        .into_command_result(CommandErrorKind::Program, "Restore target outside of root")?;
+    let undo_path = undo_dir.join(relative_path);
+    debug!(
+        "Moving {} to {}",
+        target_path.display(),
+        undo_path.display()
+    );
 
+    if let Some(parent) = undo_path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to create undo directory")?;
+    }
+
+    match fs::rename(target_path, &undo_path).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {}
+        Err(e) => {
+            return Err(e.into_command_error(
+                CommandErrorKind::System,
+                "Failed to move file to undo directory",
+            ))
+        }
+    }
+
+    // The undo directory is on another file system, fall back to copying.
+    copy_to_undo_dir(target_path, &undo_path).await?;
+    let metadata = fs::symlink_metadata(target_path)
+        .await
+        .into_io_command_result("Failed to get metadata")?;
+    if metadata.is_dir() {
+        fs::remove_dir_all(target_path).await.into_command_result(
+            CommandErrorKind::System,
+            "Failed to remove replaced directory",
+        )
+    } else {
+        fs::remove_file(target_path)
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to remove replaced file")
+    }
+}
+
+/// Copy a file, symbolic link or directory tree to `undo_path`, for when it
+/// can't be moved there.
+#[async_recursion]
+async fn copy_to_undo_dir(target_path: &Path, undo_path: &Path) -> CommandResult {
+    let metadata = fs::symlink_metadata(target_path)
+        .await
+        .into_io_command_result("Failed to get metadata")?;
+    if metadata.is_symlink() {
+        let link = fs::read_link(target_path)
+            .await
+            .into_io_command_result("Failed to read symbolic link")?;
+        return fs::symlink(link, undo_path).await.into_command_result(
+            CommandErrorKind::System,
+            "Failed to copy symbolic link to undo directory",
+        );
+    }
+    if !metadata.is_dir() {
+        return fs::copy(target_path, undo_path)
+            .await
+            .map(|_| ())
+            .into_command_result(
+                CommandErrorKind::System,
+                "Failed to copy file to undo directory",
+            );
+    }
+
+    fs::create_dir(undo_path).await.into_command_result(
+        CommandErrorKind::System,
+        "Failed to create directory in undo directory",
+    )?;
+    let mut entries = fs::read_dir(target_path)
+        .await
+        .into_io_command_result("Failed to read directory")?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .into_io_command_result("Failed to read directory")?
+    {
+        copy_to_undo_dir(&entry.path(), &undo_path.join(entry.file_name())).await?;
+    }
     Ok(())
 }
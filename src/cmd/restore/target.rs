@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::debug;
+
+use crate::{
+    data::backup::FileEntry,
+    util::{fs::reflink, time::system_time_from_unix_timestamp_nanos},
+};
+
+/// How `RestoreTarget::create_file` should treat what is already at the path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateMode {
+    /// Nothing is there yet.
+    New,
+    /// Replace whatever is there.
+    Truncate,
+    /// Write over the existing content in place from `offset` on, e.g. onto a
+    /// block device or to continue an interrupted restore. Only used with
+    /// targets that have a local path.
+    InPlace { offset: u64 },
+}
+
+/// Destination of a restore. Paths are relative to the root of the restore,
+/// which is the empty path.
+#[async_trait]
+pub trait RestoreTarget: Send + Sync {
+    // Create a directory, its parent has been created before. It is fine if
+    // it exists already.
+    async fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    // Start writing the content of `file` to `path`.
+    async fn create_file(
+        &self,
+        path: &Path,
+        file: &FileEntry,
+        mode: CreateMode,
+    ) -> io::Result<Box<dyn RestoreFile>>;
+
+    // Directory on the local file system that the target writes into. Only
+    // then does the restore look at files already there, to skip those that
+    // are up to date, move replaced ones to the undo directory and resume
+    // sessions.
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// File being written by a restore.
+#[async_trait]
+pub trait RestoreFile: Send {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()>;
+
+    // Write `length` zero bytes, which are left out of sparse files where the
+    // target supports them.
+    async fn write_zeros(&mut self, length: u64) -> io::Result<()> {
+        self.write(&vec![0; length as usize]).await
+    }
+
+    // Make what has been written durable, before a session records it.
+    async fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Whether the content is in place already, e.g. cloned from a file with
+    // the same content, so that no chunks need to be written.
+    fn is_complete(&self) -> bool {
+        false
+    }
+
+    // Finish the file. `intact` is false if any content is missing or
+    // damaged.
+    async fn finish(self: Box<Self>, intact: bool) -> io::Result<()>;
+}
+
+/// Restore into a directory on the local file system, the default.
+pub struct LocalRestoreTarget {
+    root: PathBuf,
+    reflink: bool,
+    /// Fully restored files by content hash, to clone files with the same
+    /// content from.
+    restored_contents: Arc<Mutex<HashMap<String, PathBuf>>>,
+    /// Set once cloning has failed, as the file system likely can't do it.
+    reflink_failed: AtomicBool,
+}
+
+impl LocalRestoreTarget {
+    /// With `reflink`, files with the same content as a file restored earlier
+    /// are cloned from it on file systems that support it.
+    pub fn new(root: PathBuf, reflink: bool) -> Self {
+        Self {
+            root,
+            reflink,
+            restored_contents: Arc::new(Mutex::new(HashMap::new())),
+            reflink_failed: AtomicBool::new(false),
+        }
+    }
+
+    /// Clone the data of a file restored earlier with the same content into
+    /// `target_file`. Returns false if there is none or cloning failed, in
+    /// which case the content has to be written.
+    async fn clone_restored_content(
+        &self,
+        content_hash: &str,
+        target_file: &File,
+        target_path: &Path,
+    ) -> bool {
+        if !self.reflink || content_hash.is_empty() || self.reflink_failed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Some(source_path) = self
+            .restored_contents
+            .lock()
+            .unwrap()
+            .get(content_hash)
+            .cloned()
+        else {
+            return false;
+        };
+
+        let result = match File::open(&source_path).await {
+            Ok(source) => reflink(&source, target_file),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => {
+                debug!(
+                    "Cloned {} from {}",
+                    target_path.display(),
+                    source_path.display()
+                );
+                true
+            }
+            Err(e) => {
+                debug!(
+                    "Failed to clone {} from {}, writing it instead: {}",
+                    target_path.display(),
+                    source_path.display(),
+                    e
+                );
+                self.reflink_failed.store(true, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl RestoreTarget for LocalRestoreTarget {
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let path = self.root.join(path);
+        match fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Target exists and is not a directory",
+            )),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir_all(&path).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn create_file(
+        &self,
+        path: &Path,
+        file: &FileEntry,
+        mode: CreateMode,
+    ) -> io::Result<Box<dyn RestoreFile>> {
+        let path = self.root.join(path);
+        let mut open_options = OpenOptions::new();
+        open_options.write(true);
+        match mode {
+            CreateMode::New => open_options.create_new(true),
+            CreateMode::Truncate => open_options.create(true).truncate(true),
+            CreateMode::InPlace { .. } => &mut open_options,
+        };
+        let mut target_file = open_options.open(&path).await?;
+        let block_device = target_file.metadata().await?.file_type().is_block_device();
+
+        let mut complete = false;
+        match mode {
+            CreateMode::InPlace { offset } if offset > 0 => {
+                debug!("Resuming {} at offset {}", path.display(), offset);
+                target_file.seek(SeekFrom::Start(offset)).await?;
+                if !block_device {
+                    target_file.set_len(offset).await?;
+                }
+            }
+            CreateMode::InPlace { .. } => {}
+            CreateMode::New | CreateMode::Truncate => {
+                complete = self
+                    .clone_restored_content(&file.content_hash, &target_file, &path)
+                    .await;
+            }
+        }
+
+        Ok(Box::new(LocalRestoreFile {
+            file: target_file,
+            path,
+            block_device,
+            complete,
+            sparse: file.block_size != 0,
+            size: file.size,
+            modified: file.modified,
+            modified_nanos: file.modified_nanos,
+            content_hash: file.content_hash.clone(),
+            restored_contents: self.restored_contents.clone(),
+        }))
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
+
+struct LocalRestoreFile {
+    file: File,
+    path: PathBuf,
+    block_device: bool,
+    complete: bool,
+    /// Zero blocks were elided in fixed-block mode and are skipped over.
+    sparse: bool,
+    size: u64,
+    modified: i64,
+    modified_nanos: u32,
+    content_hash: String,
+    restored_contents: Arc<Mutex<HashMap<String, PathBuf>>>,
+}
+
+#[async_trait]
+impl RestoreFile for LocalRestoreFile {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data).await
+    }
+
+    async fn write_zeros(&mut self, length: u64) -> io::Result<()> {
+        if self.block_device {
+            self.file.write_all(&vec![0; length as usize]).await
+        } else {
+            self.file.seek(SeekFrom::Current(length as i64)).await?;
+            Ok(())
+        }
+    }
+
+    async fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data().await
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    async fn finish(self: Box<Self>, intact: bool) -> io::Result<()> {
+        if self.block_device {
+            return self.file.sync_all().await;
+        }
+        if self.sparse {
+            // Trailing zero blocks were skipped over, extend the file to its
+            // size.
+            self.file.set_len(self.size).await?;
+        }
+
+        let modified = system_time_from_unix_timestamp_nanos(self.modified, self.modified_nanos)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        let file = self.file.into_std().await;
+        file.set_modified(modified)?;
+        File::from_std(file).sync_all().await?;
+
+        if intact && !self.content_hash.is_empty() {
+            self.restored_contents
+                .lock()
+                .unwrap()
+                .entry(self.content_hash)
+                .or_insert(self.path);
+        }
+        Ok(())
+    }
+}
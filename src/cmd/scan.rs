@@ -0,0 +1,208 @@
+use std::{collections::HashMap, path::Path};
+
+use async_recursion::async_recursion;
+use clap::Args;
+use tracing::info;
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry, FileEntry, SubDirEntry},
+    util::{fs::sanitize_os_string, size::format_size, time::modified_matches},
+};
+
+use super::{
+    backup::{
+        source::{EntryType, LocalBackupSource},
+        BackupArgs, BackupState, SpecialType, Walk,
+    },
+    common::*,
+};
+
+#[derive(Debug, Default, Args)]
+pub struct ScanArgs {
+    /// Only scan paths matching this glob, as with backup --include.
+    #[arg(long)]
+    pub include: Vec<String>,
+    /// Leave out entries of this type, as with backup --exclude-type.
+    #[arg(long, value_enum)]
+    pub exclude_type: Vec<SpecialType>,
+    /// Count other freebck repositories, as with backup --include-repos.
+    #[arg(long)]
+    pub include_repos: bool,
+    /// Count block devices, as with backup --block-devices.
+    #[arg(long)]
+    pub block_devices: bool,
+}
+
+impl ScanArgs {
+    /// Arguments of the backup that the scan estimates.
+    fn backup_args(&self) -> BackupArgs {
+        BackupArgs {
+            include: self.include.clone(),
+            exclude_type: self.exclude_type.clone(),
+            include_repos: self.include_repos,
+            block_devices: self.block_devices,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScanTotals {
+    pub files: u64,
+    pub dirs: u64,
+    pub bytes: u64,
+    /// Files that are new or changed since the previous snapshot.
+    pub changed_files: u64,
+    /// Upper bound for the data a backup would upload, before deduplication.
+    pub changed_bytes: u64,
+}
+
+pub async fn scan(context: &ProgramContext, args: &ScanArgs) -> CommandResult {
+    let previous_root = match get_latest_snapshot(context, None).await? {
+        Some((snapshot_name, snapshot)) => {
+            info!("Comparing against snapshot {}", snapshot_name);
+            Some(get_dir_entry(context, &snapshot.root_hash).await?)
+        }
+        None => None,
+    };
+
+    let totals = scan_source(context, args, previous_root.as_ref()).await?;
+
+    println!("Files:              {}", totals.files);
+    println!("Directories:        {}", totals.dirs);
+    println!("Total size:         {}", format_size(totals.bytes));
+    println!("Changed files:      {}", totals.changed_files);
+    println!("Estimated new data: {}", format_size(totals.changed_bytes));
+    Ok(())
+}
+
+/// Walk the backup target as a backup with the same arguments would, leaving
+/// out the same entries, without reading or writing any file contents.
+pub async fn scan_source(
+    context: &ProgramContext,
+    args: &ScanArgs,
+    previous_root: Option<&DirEntry>,
+) -> CommandResult<ScanTotals> {
+    let args = args.backup_args();
+    let source = LocalBackupSource::new(context.backup_target.clone());
+    let mut state = BackupState::new(context, &args, &source)?;
+    state.skip_own_dirs(context).await;
+    let mut totals = ScanTotals::default();
+    scan_dir(
+        context,
+        &args,
+        &state,
+        &state.root,
+        state.filter.is_empty(),
+        previous_root,
+        &mut totals,
+    )
+    .await?;
+    state.path_log.finish();
+    state.warn_skipped();
+    Ok(totals)
+}
+
+#[async_recursion]
+async fn scan_dir(
+    context: &ProgramContext,
+    args: &BackupArgs,
+    state: &BackupState<'_>,
+    path: &Path,
+    included: bool,
+    previous: Option<&'async_recursion DirEntry>,
+    totals: &mut ScanTotals,
+) -> CommandResult {
+    state
+        .path_log
+        .log(format_args!("Scanning directory: {}", path.display()));
+
+    let mut previous_sub_dirs: HashMap<&String, &SubDirEntry> = HashMap::new();
+    let mut previous_files: HashMap<&String, &FileEntry> = HashMap::new();
+    if let Some(previous) = previous {
+        for entry in &previous.sub_dir {
+            previous_sub_dirs.insert(&entry.name, entry);
+        }
+        for entry in &previous.file {
+            previous_files.insert(&entry.name, entry);
+        }
+    }
+
+    let dir_entries = state
+        .source
+        .read_dir(state.source_relative(path))
+        .await
+        .into_command_result(
+            CommandErrorKind::System,
+            format!("Failed to list directory entries in: {}", path.display()).as_str(),
+        )?;
+    for dir_entry in dir_entries {
+        let path = path.join(&dir_entry.name);
+        let name = sanitize_os_string(dir_entry.name)?;
+        let entry_type = dir_entry.entry_type;
+
+        match state
+            .walk_entry(context, args, &path, entry_type, included)
+            .await?
+        {
+            Walk::Skip => {}
+            Walk::File => {
+                let metadata = state
+                    .source
+                    .metadata(state.source_relative(&path))
+                    .await
+                    .into_command_result(
+                        CommandErrorKind::System,
+                        format!("Failed to get file metadata: {}", path.display()).as_str(),
+                    )?;
+                let size = metadata.size;
+
+                totals.files += 1;
+                totals.bytes += size;
+                // Block devices are always read, as backup_file does.
+                let unchanged = entry_type == EntryType::File
+                    && previous_files.get(&name).is_some_and(|previous| {
+                        modified_matches(
+                            previous.modified,
+                            previous.modified_nanos,
+                            metadata.modified,
+                        ) && previous.size == size
+                    });
+                if !unchanged {
+                    totals.changed_files += 1;
+                    totals.changed_bytes += size;
+                }
+            }
+            Walk::Dir {
+                included: entry_included,
+            } => {
+                let fetched_sub_dir: DirEntry;
+                let previous_sub_dir = match previous_sub_dirs
+                    .get(&name)
+                    .and_then(|sub_dir| sub_dir.content.as_ref())
+                {
+                    Some(Content::Inline(dir_entry)) => Some(dir_entry),
+                    Some(Content::Hash(hash)) => {
+                        fetched_sub_dir = get_dir_entry(context, hash).await?;
+                        Some(&fetched_sub_dir)
+                    }
+                    None => None,
+                };
+
+                totals.dirs += 1;
+                scan_dir(
+                    context,
+                    args,
+                    state,
+                    &path,
+                    entry_included,
+                    previous_sub_dir,
+                    totals,
+                )
+                .await?;
+            }
+        }
+    }
+
+    Ok(())
+}
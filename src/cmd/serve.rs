@@ -0,0 +1,105 @@
+use std::{env, path::PathBuf, sync::Arc};
+
+use clap::Args;
+use tokio::{fs, net::TcpListener};
+use tracing::{info, warn};
+
+use crate::{
+    storage::rest::{serve_storage, RepositoryDirectory, ServeOptions},
+    util::{http::tls_acceptor, size::parse_size},
+};
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct ServeArgs {
+    /// Directory to serve. It may be a repository itself, and repositories
+    /// in its subdirectories are served at their relative path, which
+    /// clients set as their prefix. Clients can create new ones with repo
+    /// init.
+    pub path: PathBuf,
+    /// Address and port to listen on.
+    #[arg(long, default_value = "127.0.0.1:8000")]
+    pub listen: String,
+    /// File with a token that clients have to send. Taken from
+    /// FREEBCK_REST_TOKEN if not given, and anyone who can connect may read
+    /// and add data without either.
+    #[arg(long)]
+    pub token_file: Option<PathBuf>,
+    /// Let clients remove items, as pruning needs. Without this the
+    /// repository is append-only, and a compromised client can't destroy
+    /// existing backups.
+    #[arg(long)]
+    pub allow_delete: bool,
+    /// PEM file with the certificate chain to serve https:// with. Without
+    /// it the server speaks plain http://, and the token can be read by
+    /// anyone on the network.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Largest item clients may write, e.g. "512MiB". Items are held in
+    /// memory while they are received, and larger ones are refused.
+    #[arg(long, value_parser = parse_size, default_value = "2GiB")]
+    pub max_item_size: u64,
+}
+
+/// Serve the repositories in a directory to `RestStorage` clients. This
+/// doesn't need a context, as the server usually has no archive of its own.
+pub async fn serve(args: &ServeArgs) -> CommandResult {
+    let is_dir = fs::metadata(&args.path)
+        .await
+        .is_ok_and(|metadata| metadata.is_dir());
+    if !is_dir {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            format!("{} is not a directory", args.path.display()),
+        ));
+    }
+    let token = match args.token_file {
+        Some(ref token_file) => Some(
+            fs::read_to_string(token_file)
+                .await
+                .into_io_command_result("Failed to read token file")?
+                .trim()
+                .to_string(),
+        ),
+        None => env::var("FREEBCK_REST_TOKEN").ok(),
+    };
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(tls_acceptor(cert, key).into_io_command_result("Failed to load TLS certificate")?)
+        }
+        _ => None,
+    };
+    if tls.is_none() && token.is_some() {
+        warn!("Serving without --tls-cert, the token is sent unencrypted");
+    }
+
+    let listener = TcpListener::bind(&args.listen)
+        .await
+        .into_io_command_result(format!("Failed to listen on {}", args.listen).as_str())?;
+    info!(
+        "Serving {} on {}://{}{}",
+        args.path.display(),
+        if tls.is_some() { "https" } else { "http" },
+        args.listen,
+        if args.allow_delete {
+            ""
+        } else {
+            ", append-only"
+        }
+    );
+    let options = ServeOptions {
+        token,
+        allow_delete: args.allow_delete,
+        max_item_size: args.max_item_size,
+        tls,
+    };
+    let repositories = RepositoryDirectory::new(args.path.clone());
+    serve_storage(listener, Arc::new(repositories), options)
+        .await
+        .into_io_command_result("Failed to accept connections")
+}
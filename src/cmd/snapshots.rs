@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use clap::Args;
+use serde::Serialize;
+
+use crate::{
+    data::backup::Snapshot,
+    storage::Collection,
+    util::{size::format_size, time::format_short_time},
+};
+
+use super::{backup::parse_meta, common::*};
+
+#[derive(Debug, Default, Args)]
+pub struct SnapshotsArgs {
+    /// Only list snapshots of this archive. All archives are listed by
+    /// default.
+    #[arg(long)]
+    pub archive: Option<String>,
+    /// Only list snapshots with this metadata, e.g. "commit=1a2b3c". Can be
+    /// repeated, snapshots must then have all of it.
+    #[arg(long, value_parser = parse_meta)]
+    pub meta: Vec<(String, String)>,
+    /// Print the snapshots as a JSON array, with times as Unix timestamps and
+    /// sizes in bytes.
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// One snapshot as listed by `freebck snapshots`.
+pub struct SnapshotSummary {
+    pub archive: String,
+    pub number: u32,
+    pub snapshot: Snapshot,
+    /// Size of the backed up files, if the backup recorded its stats.
+    pub size: Option<u64>,
+}
+
+impl SnapshotSummary {
+    pub fn name(&self) -> String {
+        format!("{}/{}", self.archive, self.number)
+    }
+}
+
+pub async fn snapshots(context: &ProgramContext, args: &SnapshotsArgs) -> CommandResult {
+    let summaries = summarize_snapshots(context, args).await?;
+    if args.json {
+        println!("{}", format_json(&summaries)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<16} {:<16} {:>11} {:<16} Meta",
+        "Snapshot", "Started", "Finished", "Size", "Client"
+    );
+    for summary in &summaries {
+        let snapshot = &summary.snapshot;
+        let mut meta: Vec<_> = snapshot
+            .meta
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        meta.sort();
+        println!(
+            "{:<20} {:<16} {:<16} {:>11} {:<16} {}",
+            summary.name(),
+            format_short_time(snapshot.started),
+            format_short_time(snapshot.finished),
+            summary.size.map_or("-".to_string(), format_size),
+            match snapshot.client_id.as_str() {
+                "" => "-",
+                client_id => client_id,
+            },
+            meta.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Snapshots selected by `args` with their sizes, by archive and then oldest
+/// first.
+pub async fn summarize_snapshots(
+    context: &ProgramContext,
+    args: &SnapshotsArgs,
+) -> CommandResult<Vec<SnapshotSummary>> {
+    let snapshots = list_snapshots(context, args).await?;
+    let mut summaries = Vec::with_capacity(snapshots.len());
+    for (name, snapshot) in snapshots {
+        let Some((archive, number)) = name
+            .rsplit_once('/')
+            .and_then(|(archive, number)| Some((archive, number.parse::<u32>().ok()?)))
+        else {
+            continue;
+        };
+        summaries.push(SnapshotSummary {
+            archive: archive.to_string(),
+            number,
+            snapshot,
+            size: None,
+        });
+    }
+
+    let archives: BTreeSet<&str> = summaries
+        .iter()
+        .map(|summary| summary.archive.as_str())
+        .collect();
+    let mut sizes = HashMap::new();
+    for archive in archives {
+        for (name, stats) in get_stats_history(context, Some(archive)).await? {
+            sizes.insert(name, stats.size);
+        }
+    }
+    for summary in summaries.iter_mut() {
+        summary.size = sizes.get(&summary.name()).copied();
+    }
+    Ok(summaries)
+}
+
+/// One snapshot as printed by `freebck snapshots --json`.
+#[derive(Serialize)]
+struct JsonSnapshot<'a> {
+    name: String,
+    archive: &'a str,
+    number: u32,
+    sequence: u64,
+    started: i64,
+    finished: i64,
+    size: Option<u64>,
+    client_id: &'a str,
+    meta: BTreeMap<&'a str, &'a str>,
+}
+
+fn format_json(summaries: &[SnapshotSummary]) -> CommandResult<String> {
+    let snapshots: Vec<JsonSnapshot> = summaries
+        .iter()
+        .map(|summary| {
+            let snapshot = &summary.snapshot;
+            JsonSnapshot {
+                name: summary.name(),
+                archive: &summary.archive,
+                number: summary.number,
+                sequence: snapshot.sequence,
+                started: snapshot.started,
+                finished: snapshot.finished,
+                size: summary.size,
+                client_id: &snapshot.client_id,
+                meta: snapshot
+                    .meta
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str()))
+                    .collect(),
+            }
+        })
+        .collect();
+    serde_json::to_string_pretty(&snapshots)
+        .into_command_result(CommandErrorKind::System, "Failed to encode the snapshots")
+}
+
+/// Snapshots selected by `args` by name, by archive and then oldest first.
+pub async fn list_snapshots(
+    context: &ProgramContext,
+    args: &SnapshotsArgs,
+) -> CommandResult<Vec<(String, Snapshot)>> {
+    let prefix = match args.archive {
+        Some(ref archive) => format!("{}/", archive),
+        None => String::new(),
+    };
+    let snapshot_names = context
+        .storage
+        .get_collection_items_with_prefix(Collection::Snapshot, &prefix)
+        .await
+        .into_io_command_result("Failed to list snapshots")?;
+    let mut snapshots = Vec::new();
+    for snapshot_name in snapshot_names {
+        let snapshot = get_snapshot(context, &snapshot_name).await?;
+        if args
+            .meta
+            .iter()
+            .all(|(key, value)| snapshot.meta.get(key) == Some(value))
+        {
+            snapshots.push((snapshot_name, snapshot));
+        }
+    }
+    snapshots.sort_by(|(a_name, a), (b_name, b)| {
+        (snapshot_archive(a_name), snapshot_order(a_name, a))
+            .cmp(&(snapshot_archive(b_name), snapshot_order(b_name, b)))
+    });
+    Ok(snapshots)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        let summaries = [
+            SnapshotSummary {
+                archive: "home".to_string(),
+                number: 2,
+                snapshot: Snapshot {
+                    started: 1_700_000_000,
+                    finished: 1_700_000_060,
+                    sequence: 2,
+                    meta: [("commit".to_string(), "1a2b".to_string())].into(),
+                    ..Default::default()
+                },
+                size: Some(1234),
+            },
+            SnapshotSummary {
+                archive: "home".to_string(),
+                number: 3,
+                snapshot: Snapshot::default(),
+                size: None,
+            },
+        ];
+
+        let json: serde_json::Value =
+            serde_json::from_str(&format_json(&summaries).unwrap()).unwrap();
+        let snapshots = json.as_array().unwrap();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0]["name"].as_str(), Some("home/2"));
+        assert_eq!(snapshots[0]["sequence"].as_i64(), Some(2));
+        assert_eq!(snapshots[0]["finished"].as_i64(), Some(1_700_000_060));
+        assert_eq!(snapshots[0]["size"].as_i64(), Some(1234));
+        assert_eq!(snapshots[0]["meta"]["commit"].as_str(), Some("1a2b"));
+        assert!(snapshots[1]["size"].is_null());
+        assert_eq!(format_json(&[]).unwrap(), "[]");
+    }
+}
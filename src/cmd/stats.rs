@@ -0,0 +1,217 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use clap::Args;
+use prost::Message;
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, BackupStats},
+    storage::Collection,
+    util::{size::format_size, time::format_short_time},
+};
+
+use super::{
+    common::*,
+    snapshots::{list_snapshots, SnapshotsArgs},
+};
+
+// Width of the bars drawn for the stored size in --history.
+const BAR_WIDTH: u64 = 30;
+
+#[derive(Debug, Args)]
+pub struct StatsArgs {
+    /// Show every backup recorded for the archive, oldest first, with a bar
+    /// for the growth of the stored data.
+    #[arg(long)]
+    pub history: bool,
+    /// Archive to show, the configured archive by default.
+    #[arg(long)]
+    pub archive: Option<String>,
+    /// Show the size and deduplication of the whole repository and of each
+    /// archive in it, found by walking all snapshots.
+    #[arg(long, conflicts_with_all = ["history", "archive"])]
+    pub repository: bool,
+}
+
+/// Size of the repository as found by walking all snapshots.
+#[derive(Debug, Default)]
+pub struct RepositoryStats {
+    pub snapshots: u64,
+    /// Blobs in the storage, referenced or not.
+    pub blobs: u64,
+    /// Blobs referenced by snapshots, and their total size.
+    pub referenced_blobs: u64,
+    pub stored_bytes: u64,
+    /// Size of the files of all snapshots together.
+    pub logical_bytes: u64,
+    pub archives: BTreeMap<String, ArchiveStats>,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveStats {
+    pub snapshots: u64,
+    /// Size of the files of the newest snapshot.
+    pub latest_bytes: u64,
+    /// Size of the files of all snapshots of the archive together.
+    pub logical_bytes: u64,
+}
+
+impl RepositoryStats {
+    /// How many times over deduplication fits the snapshots into the stored
+    /// data.
+    pub fn dedup_ratio(&self) -> f64 {
+        self.logical_bytes as f64 / self.stored_bytes.max(1) as f64
+    }
+}
+
+pub async fn stats(context: &ProgramContext, args: &StatsArgs) -> CommandResult {
+    if args.repository {
+        let stats = repository_stats(context).await?;
+        println!("Snapshots:      {}", stats.snapshots);
+        println!(
+            "Blobs:          {} ({} not referenced)",
+            stats.blobs,
+            stats.blobs.saturating_sub(stats.referenced_blobs)
+        );
+        println!("Stored:         {}", format_size(stats.stored_bytes));
+        println!("Logical:        {}", format_size(stats.logical_bytes));
+        println!("Deduplication:  {:.2}x", stats.dedup_ratio());
+        println!();
+        println!(
+            "{:<20} {:>9} {:>11} {:>11}",
+            "Archive", "Snapshots", "Latest", "Logical"
+        );
+        for (name, archive) in &stats.archives {
+            println!(
+                "{:<20} {:>9} {:>11} {:>11}",
+                name,
+                archive.snapshots,
+                format_size(archive.latest_bytes),
+                format_size(archive.logical_bytes)
+            );
+        }
+        return Ok(());
+    }
+
+    let history = get_stats_history(context, args.archive.as_deref()).await?;
+    let Some((latest_name, latest)) = history.last() else {
+        return Err(CommandError::new(
+            CommandErrorKind::NotFound,
+            format!(
+                "No backup stats for archive {}",
+                args.archive.as_deref().unwrap_or(&context.archive_name)
+            ),
+        ));
+    };
+
+    if !args.history {
+        println!("Snapshot:  {}", latest_name);
+        println!("Started:   {}", format_short_time(latest.started));
+        println!("Duration:  {}", format_duration(latest));
+        println!("Size:      {}", format_size(latest.size));
+        println!("New data:  {}", format_size(latest.new_bytes));
+        println!("Stored:    {}", format_size(latest.stored_bytes));
+        return Ok(());
+    }
+
+    let max_stored_bytes = history
+        .iter()
+        .map(|(_, stats)| stats.stored_bytes)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    println!(
+        "{:<20} {:<16} {:>10} {:>11} {:>11} {:>11}",
+        "Snapshot", "Started", "Duration", "Size", "New data", "Stored"
+    );
+    for (name, stats) in &history {
+        let bar = (stats.stored_bytes * BAR_WIDTH).div_ceil(max_stored_bytes);
+        println!(
+            "{:<20} {:<16} {:>10} {:>11} {:>11} {:>11} {}",
+            name,
+            format_short_time(stats.started),
+            format_duration(stats),
+            format_size(stats.size),
+            format_size(stats.new_bytes),
+            format_size(stats.stored_bytes),
+            "#".repeat(bar as usize)
+        );
+    }
+    Ok(())
+}
+
+fn format_duration(stats: &BackupStats) -> String {
+    let seconds = Duration::from_secs(stats.duration_millis.div_ceil(1000));
+    humantime::format_duration(seconds).to_string()
+}
+
+/// Walk all snapshots for the size of the repository. Sizes of blobs are
+/// taken from the trees that reference them, so only directories are read.
+pub async fn repository_stats(context: &ProgramContext) -> CommandResult<RepositoryStats> {
+    let mut stats = RepositoryStats::default();
+    let mut sizes = HashMap::new();
+    // Oldest first, so that the newest snapshot of an archive comes last.
+    for (name, snapshot) in list_snapshots(context, &SnapshotsArgs::default()).await? {
+        let logical_bytes = collect_blob_sizes(context, &snapshot.root_hash, &mut sizes).await?;
+        let archive_name = name.rsplit_once('/').map_or(name.as_str(), |(a, _)| a);
+        let archive = stats.archives.entry(archive_name.to_string()).or_default();
+        archive.snapshots += 1;
+        archive.latest_bytes = logical_bytes;
+        archive.logical_bytes += logical_bytes;
+        stats.snapshots += 1;
+        stats.logical_bytes += logical_bytes;
+    }
+    stats.referenced_blobs = sizes.len() as u64;
+    stats.stored_bytes = sizes.values().sum();
+    stats.blobs = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await
+        .into_io_command_result("Failed to list blobs")?
+        .len() as u64;
+    Ok(stats)
+}
+
+/// Add the sizes of the blobs reachable from the directory entry stored as
+/// `root_hash` to `sizes` by hash. Returns the size of the files in the tree.
+async fn collect_blob_sizes(
+    context: &ProgramContext,
+    root_hash: &str,
+    sizes: &mut HashMap<String, u64>,
+) -> CommandResult<u64> {
+    let root = get_dir_entry(context, root_hash).await?;
+    let logical_bytes = root.size;
+    if sizes.contains_key(root_hash) {
+        return Ok(logical_bytes);
+    }
+    sizes.insert(root_hash.to_string(), root.encoded_len() as u64);
+
+    let mut dir_entries = vec![root];
+    while let Some(dir_entry) = dir_entries.pop() {
+        for file in dir_entry.file {
+            let mut offset = 0;
+            for (index, hash) in file.chunk_hash.iter().enumerate() {
+                let length = chunk_length(&file, index, offset)?;
+                offset += length;
+                // All-zero blocks aren't stored.
+                if !hash.is_empty() {
+                    sizes.insert(hash.clone(), length);
+                }
+            }
+        }
+        for sub_dir in dir_entry.sub_dir {
+            match sub_dir.content {
+                Some(Content::Hash(hash)) if !sizes.contains_key(&hash) => {
+                    let dir_entry = get_dir_entry(context, &hash).await?;
+                    sizes.insert(hash, dir_entry.encoded_len() as u64);
+                    dir_entries.push(dir_entry);
+                }
+                Some(Content::Inline(dir_entry)) => dir_entries.push(dir_entry),
+                _ => {}
+            }
+        }
+    }
+    Ok(logical_bytes)
+}
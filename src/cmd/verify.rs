@@ -0,0 +1,335 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    fs::FileType,
+    path::{Path, PathBuf},
+    pin::pin,
+};
+
+use async_recursion::async_recursion;
+use clap::Args;
+use tokio::{
+    fs::{self, read_dir, File},
+    io::AsyncReadExt,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    data::backup::{sub_dir_entry::Content, DirEntry, FileEntry},
+    storage::Collection,
+    util::{fs::sanitize_os_string, hash::read_hash, time::modified_matches},
+};
+
+use super::common::*;
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Snapshot to verify. Defaults to the latest snapshot of the archive.
+    pub snapshot: Option<String>,
+    /// Compare the snapshot against the backup target on disk.
+    #[arg(long)]
+    pub compare_source: bool,
+    /// Compare file contents byte by byte instead of by hash.
+    #[arg(long)]
+    pub bitwise: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DifferenceKind {
+    /// The entry is in the snapshot but not in the compared data.
+    OnlyInSnapshot,
+    /// The entry is in the compared data but not in the snapshot.
+    NotInSnapshot,
+    /// The entry is a file in one and a directory in the other.
+    TypeMismatch,
+    Size,
+    Modified,
+    Content,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub path: PathBuf,
+    pub kind: DifferenceKind,
+}
+
+impl Display for Difference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let description = match self.kind {
+            DifferenceKind::OnlyInSnapshot => "only in snapshot",
+            DifferenceKind::NotInSnapshot => "not in snapshot",
+            DifferenceKind::TypeMismatch => "type differs",
+            DifferenceKind::Size => "size differs",
+            DifferenceKind::Modified => "modified time differs",
+            DifferenceKind::Content => "content differs",
+        };
+        write!(f, "{}: {}", self.path.display(), description)
+    }
+}
+
+pub async fn verify(context: &ProgramContext, args: &VerifyArgs) -> CommandResult {
+    if !args.compare_source {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Nothing to verify, pass --compare-source".to_string(),
+        ));
+    }
+
+    let snapshot_name = match args.snapshot {
+        Some(ref snapshot) => resolve_snapshot_name(context, None, snapshot),
+        None => match get_latest_snapshot(context, None).await? {
+            Some((snapshot_name, _)) => snapshot_name,
+            None => {
+                return Err(CommandError::new(
+                    CommandErrorKind::User,
+                    format!("No snapshots in archive {}", context.archive_name),
+                ))
+            }
+        },
+    };
+    info!("Verifying snapshot {}", snapshot_name);
+
+    let snapshot = get_snapshot(context, &snapshot_name).await?;
+    let root_dir_entry = get_dir_entry(context, &snapshot.root_hash).await?;
+    let differences = compare_with_dir(
+        context,
+        root_dir_entry,
+        &context.backup_target,
+        args.bitwise,
+    )
+    .await?;
+
+    for difference in differences.iter() {
+        warn!("{}", difference);
+    }
+
+    if !differences.is_empty() {
+        return Err(CommandError::new(
+            CommandErrorKind::Mismatch,
+            format!(
+                "Snapshot {} does not match the source, {} differences found",
+                snapshot_name,
+                differences.len()
+            ),
+        ));
+    }
+
+    info!("Snapshot {} matches the source", snapshot_name);
+    Ok(())
+}
+
+/// Compare a snapshot tree against a directory on disk and return every
+/// difference found. Paths in the result are relative to `path`.
+pub async fn compare_with_dir(
+    context: &ProgramContext,
+    dir_entry: DirEntry,
+    path: &Path,
+    bitwise: bool,
+) -> CommandResult<Vec<Difference>> {
+    let mut differences = Vec::new();
+    compare_dir(
+        context,
+        bitwise,
+        dir_entry,
+        path,
+        Path::new(""),
+        &mut differences,
+    )
+    .await?;
+    Ok(differences)
+}
+
+#[async_recursion]
+async fn compare_dir(
+    context: &ProgramContext,
+    bitwise: bool,
+    dir_entry: DirEntry,
+    path: &Path,
+    relative_path: &Path,
+    differences: &mut Vec<Difference>,
+) -> CommandResult {
+    debug!("Comparing directory: {}", path.display());
+
+    let mut on_disk: HashMap<String, FileType> = HashMap::new();
+    let mut dir_entries = read_dir(path).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to list directory entries in: {}", path.display()).as_str(),
+    )?;
+    while let Some(dir_entry) = dir_entries.next_entry().await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to iterate directory entries in: {}", path.display()).as_str(),
+    )? {
+        let name = sanitize_os_string(dir_entry.file_name())?;
+        let file_type = dir_entry.file_type().await.into_command_result(
+            CommandErrorKind::System,
+            format!("Failed to get file type: {}", dir_entry.path().display()).as_str(),
+        )?;
+        on_disk.insert(name, file_type);
+    }
+
+    for sub_dir in dir_entry.sub_dir.into_iter() {
+        let sub_dir_relative_path = relative_path.join(&sub_dir.name);
+        match on_disk.remove(&sub_dir.name) {
+            None => differences.push(Difference {
+                path: sub_dir_relative_path,
+                kind: DifferenceKind::OnlyInSnapshot,
+            }),
+            Some(file_type) if !file_type.is_dir() => differences.push(Difference {
+                path: sub_dir_relative_path,
+                kind: DifferenceKind::TypeMismatch,
+            }),
+            Some(_) => {
+                let sub_dir_entry = match sub_dir.content {
+                    Some(Content::Inline(dir_entry)) => dir_entry,
+                    Some(Content::Hash(hash)) => get_dir_entry(context, &hash).await?,
+                    None => {
+                        return Err(CommandError::new(
+                            CommandErrorKind::Corrupt,
+                            format!(
+                                "Sub dir entry without content {}",
+                                sub_dir_relative_path.display()
+                            ),
+                        ))
+                    }
+                };
+                compare_dir(
+                    context,
+                    bitwise,
+                    sub_dir_entry,
+                    &path.join(&sub_dir.name),
+                    &sub_dir_relative_path,
+                    differences,
+                )
+                .await?;
+            }
+        }
+    }
+
+    for file_entry in dir_entry.file.iter() {
+        let file_relative_path = relative_path.join(&file_entry.name);
+        match on_disk.remove(&file_entry.name) {
+            None => differences.push(Difference {
+                path: file_relative_path,
+                kind: DifferenceKind::OnlyInSnapshot,
+            }),
+            Some(file_type) if !file_type.is_file() => differences.push(Difference {
+                path: file_relative_path,
+                kind: DifferenceKind::TypeMismatch,
+            }),
+            Some(_) => {
+                let file_path = path.join(&file_entry.name);
+                for kind in compare_file(context, bitwise, file_entry, &file_path).await? {
+                    differences.push(Difference {
+                        path: file_relative_path.clone(),
+                        kind,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut not_in_snapshot: Vec<_> = on_disk.into_keys().collect();
+    not_in_snapshot.sort();
+    for name in not_in_snapshot {
+        differences.push(Difference {
+            path: relative_path.join(name),
+            kind: DifferenceKind::NotInSnapshot,
+        });
+    }
+
+    Ok(())
+}
+
+async fn compare_file(
+    context: &ProgramContext,
+    bitwise: bool,
+    file_entry: &FileEntry,
+    path: &Path,
+) -> CommandResult<Vec<DifferenceKind>> {
+    let mut differences = Vec::new();
+
+    let metadata = fs::metadata(path).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to get file metadata: {}", path.display()).as_str(),
+    )?;
+    let modified = metadata
+        .modified()
+        .into_command_result(CommandErrorKind::System, "Failed to get file modified time")?;
+    if !modified_matches(file_entry.modified, file_entry.modified_nanos, modified) {
+        differences.push(DifferenceKind::Modified);
+    }
+    if metadata.len() != file_entry.size {
+        // Contents can't match if the sizes differ, no need to read them.
+        differences.push(DifferenceKind::Size);
+        return Ok(differences);
+    }
+
+    let mut file = pin!(File::open(path).await.into_command_result(
+        CommandErrorKind::System,
+        format!("Failed to open file: {}", path.display()).as_str()
+    )?);
+    let content_matches = if bitwise {
+        compare_file_content(context, file_entry, file.as_mut(), path).await?
+    } else {
+        let content_hash = read_hash(file.as_mut(), context.tuning.read_buffer_size)
+            .await
+            .into_command_result(CommandErrorKind::System, "Failed to calculate file hash")?;
+        content_hash == file_entry.content_hash
+    };
+    if !content_matches {
+        differences.push(DifferenceKind::Content);
+    }
+
+    Ok(differences)
+}
+
+async fn compare_file_content(
+    context: &ProgramContext,
+    file_entry: &FileEntry,
+    mut file: std::pin::Pin<&mut File>,
+    path: &Path,
+) -> CommandResult<bool> {
+    let mut chunk_buffer = Vec::new();
+    let mut file_buffer = Vec::new();
+    let mut offset: u64 = 0;
+    for (index, chunk_hash) in file_entry.chunk_hash.iter().enumerate() {
+        if file_entry.block_size != 0 && chunk_hash.is_empty() {
+            let zeros = chunk_length(file_entry, index, offset)?;
+            chunk_buffer.clear();
+            chunk_buffer.resize(zeros as usize, 0);
+        } else {
+            context
+                .storage
+                .read(Collection::Blob, chunk_hash, &mut chunk_buffer)
+                .await
+                .into_command_result(
+                    CommandErrorKind::Corrupt,
+                    format!("Failed to read chunk {}", chunk_hash).as_str(),
+                )?;
+        }
+        offset += chunk_buffer.len() as u64;
+
+        file_buffer.resize(chunk_buffer.len(), 0);
+        match file.read_exact(&mut file_buffer).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(e) => {
+                return Err(e.into_command_error(
+                    CommandErrorKind::System,
+                    format!("Failed to read file: {}", path.display()).as_str(),
+                ))
+            }
+        }
+
+        if chunk_buffer != file_buffer {
+            return Ok(false);
+        }
+    }
+
+    // The file must not have any data beyond the last chunk.
+    let trailing = file
+        .read(&mut [0u8; 1])
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to read file")?;
+    Ok(trailing == 0)
+}
@@ -1 +0,0 @@
-pub const CHUNK_SIZE: usize = 1024 * 1024 * 1024;
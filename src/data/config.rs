@@ -1,13 +1,371 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum StorageConfig {
     File(FileStorageConfig),
+    S3(S3StorageConfig),
+    Sftp(SshStorageConfig),
+    SshExec(SshStorageConfig),
+    Rest(RestStorageConfig),
+    B2(B2StorageConfig),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileStorageConfig {
     pub path: String,
+    /// Treat `path` as a mount root, such as /media/user, and use the
+    /// repository found up to two levels below it by its repo-id marker.
+    /// Repositories are never created implicitly in this mode.
+    #[serde(default)]
+    pub discover: bool,
+    /// IDs of the repositories that may be used, e.g. one per rotated drive.
+    /// Any repository is accepted if empty.
+    #[serde(default)]
+    pub repo_ids: Vec<String>,
+    /// Move deleted items to trash/ in the repository instead of removing
+    /// them, e.g. "30days". They are removed from there by gc --maintenance
+    /// once they have been in the trash this long.
+    #[serde(default, with = "optional_duration")]
+    pub trash: Option<Duration>,
+}
+
+/// Bucket in AWS S3 or a compatible object store. Backblaze B2 buckets can
+/// also be used through their S3-compatible endpoint, such as
+/// https://s3.us-west-004.backblazeb2.com, with path_style set.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct S3StorageConfig {
+    pub bucket: String,
+    /// Prefix for all object names, to share a bucket between repositories.
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_region")]
+    pub region: String,
+    /// Endpoint URL such as "http://localhost:9000". Defaults to the AWS
+    /// endpoint of the region.
+    pub endpoint: Option<String>,
+    /// Address the bucket as part of the path instead of the host name, as
+    /// MinIO does by default.
+    #[serde(default)]
+    pub path_style: bool,
+    /// Credentials, taken from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY if
+    /// not set.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+}
+
+fn default_region() -> String {
+    "us-east-1".to_string()
+}
+
+/// Directory on a remote machine reached over ssh, with SFTP or with plain
+/// shell commands. The ssh client does the authentication, so ~/.ssh/config,
+/// known_hosts and the agent apply.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SshStorageConfig {
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    /// Remote user name, defaults to the one from the ssh config.
+    pub user: Option<String>,
+    /// Private key to log in with, relative to the config file.
+    pub key_path: Option<String>,
+    /// Directory of the repository on the remote machine, relative to the
+    /// remote user's home directory unless absolute.
+    pub path: String,
+    /// Subdirectory of `path` for the repository, to keep several
+    /// repositories under one directory.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+/// Bucket in Backblaze B2, used through its native API.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct B2StorageConfig {
+    pub bucket: String,
+    /// Prefix for all file names, to share a bucket between repositories.
+    #[serde(default)]
+    pub prefix: String,
+    /// Application key ID, taken from B2_APPLICATION_KEY_ID if not set.
+    pub key_id: Option<String>,
+    /// Application key, taken from B2_APPLICATION_KEY if not set.
+    pub application_key: Option<String>,
+    /// URL to authorize the account with, https://api.backblazeb2.com by
+    /// default.
+    pub endpoint: Option<String>,
+}
+
+/// Repository served by `freebck serve` on another machine.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RestStorageConfig {
+    /// Server URL such as "https://backup.lan:8000". Use https:// unless
+    /// the network is trusted, as the token is sent with every request.
+    pub url: String,
+    /// Token the server was started with, taken from FREEBCK_REST_TOKEN if
+    /// not set.
+    pub token: Option<String>,
+    /// PEM file with the certificate of the CA that signed the server's
+    /// certificate, or the certificate itself if it is self-signed.
+    pub ca_cert: Option<PathBuf>,
+    /// Repository on the server, such as "hosts/laptop", when the server
+    /// serves a directory of repositories.
+    #[serde(default)]
+    pub prefix: String,
+}
+
+/// Bounds for the number of concurrent storage operations. The actual
+/// concurrency is adjusted within them based on the observed latency.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConcurrencyConfig {
+    pub min: usize,
+    pub max: usize,
+}
+
+/// Limits for how long a single storage operation may take, written as
+/// durations like "30s" or "5m". Unset operations have no limit.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TimeoutConfig {
+    #[serde(default, with = "optional_duration")]
+    pub read: Option<Duration>,
+    #[serde(default, with = "optional_duration")]
+    pub write: Option<Duration>,
+    #[serde(default, with = "optional_duration")]
+    pub list: Option<Duration>,
+}
+
+/// Compression of blobs with zstd. Repositories with blobs from before
+/// compression keep storing them uncompressed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionConfig {
+    /// zstd level from 1 to 22, or 0 to store blobs uncompressed. Higher
+    /// levels are smaller and slower.
+    #[serde(default = "default_compression_level")]
+    pub level: i32,
+    /// Extensions of files that are compressed already, such as "jpg" or
+    /// "zip", which backup stores without trying to compress them. Common
+    /// image, video, audio and archive formats by default. Other data that
+    /// doesn't compress is detected from its first 64 KiB.
+    #[serde(default = "default_skip_extensions")]
+    pub skip_extensions: Vec<String>,
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_skip_extensions() -> Vec<String> {
+    [
+        "jpg", "jpeg", "png", "gif", "webp", "heic", "avif", "mp4", "m4v", "mkv", "mov", "webm",
+        "mp3", "m4a", "aac", "ogg", "opus", "flac", "zip", "gz", "tgz", "bz2", "xz", "zst", "7z",
+        "rar",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            level: default_compression_level(),
+            skip_extensions: default_skip_extensions(),
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Whether the contents of `path` may compress, going by its extension.
+    pub fn may_compress(&self, path: &Path) -> bool {
+        let Some(extension) = path.extension().and_then(|extension| extension.to_str()) else {
+            return true;
+        };
+        !self
+            .skip_extensions
+            .iter()
+            .any(|skipped| skipped.eq_ignore_ascii_case(extension))
+    }
+}
+
+/// Encryption of the repository with a master key, which is stored encrypted
+/// with a passphrase. With this set, `repo init` creates the master key and
+/// repositories without one are refused. Repositories with a master key need
+/// the passphrase whether this is set or not. The passphrase can also be
+/// given with --passphrase-file or FREEBCK_PASSPHRASE.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    /// File with the passphrase, relative to the config file.
+    pub passphrase_file: Option<String>,
+    /// Shell command that prints the passphrase, e.g. a password manager
+    /// lookup.
+    pub passphrase_command: Option<String>,
+    /// Memory for deriving a key from a new passphrase, "64M" by default.
+    #[serde(default, with = "optional_size")]
+    pub kdf_memory: Option<u64>,
+    /// Passes over that memory, 3 by default.
+    pub kdf_iterations: Option<u32>,
+}
+
+/// Buffer sizes and pipeline depths, for tuning memory use and throughput
+/// without recompiling. Sizes are written like "64M". Unset values keep the
+/// defaults, and can also be given for one run with --set, e.g.
+/// "tuning.chunk_uploads=4".
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TuningConfig {
+    /// Largest chunk that files are split into outside of fixed-block mode.
+    #[serde(default, with = "optional_size")]
+    pub chunk_size: Option<u64>,
+    /// Block size of fixed-block mode when --block-size isn't given.
+    #[serde(default, with = "optional_size")]
+    pub block_size: Option<u64>,
+    /// Buffer for reading files while hashing them.
+    #[serde(default, with = "optional_size")]
+    pub read_buffer_size: Option<u64>,
+    /// Chunks read ahead of the uploads per file.
+    pub chunk_queue_depth: Option<usize>,
+    /// Chunks uploaded concurrently per file.
+    pub chunk_uploads: Option<usize>,
+    /// Files a backup keeps open at the same time.
+    pub open_files: Option<usize>,
+    /// Largest encoded directory that is stored inside its parent.
+    #[serde(default, with = "optional_size")]
+    pub inline_dir_size: Option<u64>,
+    /// Chunks larger than this are streamed instead of held in memory.
+    #[serde(default, with = "optional_size")]
+    pub stream_chunk_size: Option<u64>,
+}
+
+mod optional_duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => {
+                serializer.serialize_str(&humantime::format_duration(*duration).to_string())
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        let value: Option<String> = Option::deserialize(deserializer)?;
+        value
+            .map(|value| humantime::parse_duration(&value).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+mod optional_size {
+    use super::*;
+    use crate::util::size::parse_size;
+
+    pub fn serialize<S: Serializer>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match size {
+            Some(size) => serializer.serialize_str(&size.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        let value: Option<String> = Option::deserialize(deserializer)?;
+        value
+            .map(|value| parse_size(&value).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Shell commands run on repository events. Each gets a JSON description of
+/// the event on stdin and the event name in FREEBCK_EVENT.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HooksConfig {
+    pub snapshot_created: Option<String>,
+    pub check_failed: Option<String>,
+    /// Run once for each snapshot that `forget` removes.
+    pub snapshot_forgotten: Option<String>,
+    pub prune_completed: Option<String>,
+}
+
+/// Which snapshots `forget` keeps. Each rule keeps the newest snapshot of
+/// that many of the most recent periods that have one, and a snapshot is kept
+/// if any rule keeps it. The rules apply to the snapshots of each retention
+/// class separately, so that e.g. daily backups don't push out the monthly
+/// ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Number of most recent snapshots to keep.
+    pub keep_last: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    /// Number of most recent snapshots to keep of each retention class, on
+    /// top of the other rules, e.g. `monthly = 12`.
+    #[serde(default)]
+    pub keep_class: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FsSnapshotKind {
+    Btrfs,
+    Lvm,
+    Zfs,
+}
+
+/// Back up from a point-in-time snapshot of the file system instead of the
+/// live files, which may change while they are read. The snapshot is made
+/// before the backup and removed after it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FsSnapshotConfig {
+    pub kind: FsSnapshotKind,
+    /// What to snapshot: the Btrfs subvolume path, the LVM logical volume as
+    /// "group/volume" or the ZFS dataset.
+    pub volume: String,
+    /// Path of the Btrfs snapshot or mount point of the LVM snapshot. ZFS
+    /// snapshots are read from the .zfs directory of the dataset.
+    pub mount: Option<String>,
+    /// Copy-on-write space of an LVM snapshot, e.g. "5G".
+    pub size: Option<String>,
+    /// Options for mounting an LVM snapshot, "ro" by default.
+    pub mount_options: Option<String>,
+    /// Name of the snapshot, "freebck" by default.
+    pub name: Option<String>,
+    /// Directory within the snapshot to back up, its root by default.
+    pub subdir: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DatabaseKind {
+    Postgres,
+    Mysql,
+    Sqlite,
+}
+
+/// Back up a dump of a database made with its own tools instead of the files
+/// at `path`. The versions of the tools are recorded in the snapshot
+/// metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    pub kind: DatabaseKind,
+    /// Name of the database, or the path of the database file for SQLite.
+    pub database: String,
+    /// Further arguments for pg_dump or mysqldump, such as the host and the
+    /// user. Passwords are best kept in ~/.pgpass or ~/.my.cnf.
+    #[serde(default)]
+    pub args: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -15,10 +373,327 @@ pub struct ArchiveConfig {
     #[serde(default = "default_path")]
     pub path: String,
 
+    /// Name of the archive. The special value "auto" derives the name from
+    /// the hostname and `label`.
     pub name: String,
+    /// Suffix appended to the hostname when `name` is "auto".
+    pub label: Option<String>,
     pub storage: StorageConfig,
+    /// Second repository with the same data, used by `repair --from-mirror`
+    /// to replace damaged blobs in `storage`.
+    pub mirror: Option<StorageConfig>,
+    pub concurrency: Option<ConcurrencyConfig>,
+    pub timeouts: Option<TimeoutConfig>,
+    pub tuning: Option<TuningConfig>,
+    /// Bytes per second, e.g. "10M", shared by all transfers of the process:
+    /// every archive of backup --all and the mirror storage too.
+    #[serde(default, with = "optional_size")]
+    pub bandwidth_limit: Option<u64>,
+    /// Store blobs smaller than 1 MiB together in packs of about this size,
+    /// e.g. "32M", instead of one object each. Packs are built in memory.
+    #[serde(default, with = "optional_size")]
+    pub pack_size: Option<u64>,
+    /// Read every object back after writing it and fail the command if it
+    /// differs, for storage that may silently truncate or corrupt writes.
+    /// Doubles the traffic of backups.
+    #[serde(default)]
+    pub verify_writes: bool,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    pub encryption: Option<EncryptionConfig>,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    pub fs_snapshot: Option<FsSnapshotConfig>,
+    pub database: Option<DatabaseConfig>,
+    /// Default policy for `forget`, used when no --keep flags are given.
+    pub retention: Option<RetentionConfig>,
+
+    /// Stable identifier of this client. Generated and stored next to the
+    /// config file if not set.
+    pub client_id: Option<String>,
+
+    /// Further archives in the same repository, backed up by `backup --all`
+    /// along with this one.
+    #[serde(default)]
+    pub archives: Vec<ExtraArchiveConfig>,
+    /// How many archives `backup --all` backs up at the same time.
+    pub parallel_archives: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtraArchiveConfig {
+    pub name: String,
+    /// Backup target, relative to the config file like `path`.
+    pub path: String,
+    pub fs_snapshot: Option<FsSnapshotConfig>,
+    pub database: Option<DatabaseConfig>,
 }
 
 fn default_path() -> String {
     "..".to_string()
 }
+
+/// Default location of the global config file shared by all archives:
+/// `$XDG_CONFIG_HOME/freebck/config.toml`, falling back to
+/// `~/.config/freebck/config.toml`.
+pub fn default_global_config_path() -> Option<PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("freebck").join("config.toml"))
+}
+
+/// Recursively merge `overlay` into `base`. Tables are merged key by key,
+/// any other value in `overlay` replaces the one in `base`.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Resolve the `profile` key of an archive config. The named profile from
+/// the `[profiles]` table of the global config provides defaults that the
+/// archive config overrides. Profiles can name a further profile to inherit
+/// from in the same way.
+pub fn apply_profiles(
+    mut config: toml::Table,
+    global_config: &toml::Table,
+) -> Result<toml::Table, String> {
+    let profiles = match global_config.get("profiles") {
+        Some(toml::Value::Table(profiles)) => Some(profiles),
+        Some(_) => return Err("\"profiles\" in the global config must be a table".to_string()),
+        None => None,
+    };
+
+    let mut seen = Vec::new();
+    while let Some(profile) = config.remove("profile") {
+        let toml::Value::String(name) = profile else {
+            return Err("\"profile\" must be a string".to_string());
+        };
+        if seen.contains(&name) {
+            return Err(format!("Profile {} inherits from itself", name));
+        }
+
+        let mut resolved = match profiles.and_then(|profiles| profiles.get(&name)) {
+            Some(toml::Value::Table(profile)) => profile.clone(),
+            Some(_) => return Err(format!("Profile {} must be a table", name)),
+            None => return Err(format!("Profile {} is not defined", name)),
+        };
+        merge_tables(&mut resolved, config);
+        config = resolved;
+        seen.push(name);
+    }
+    Ok(config)
+}
+
+/// Parse a config as a TOML table. `format` is the extension of the file:
+/// "json", "yaml" and "yml" are parsed as JSON or YAML with the same
+/// structure, anything else as TOML. TOML has no null, so JSON and YAML
+/// members set to null are left out as if they were not set.
+pub fn parse_config(
+    text: &str,
+    format: Option<&str>,
+) -> Result<toml::Table, Box<dyn Error + Send + Sync>> {
+    let mut value: serde_json::Value = match format {
+        Some("json") => serde_json::from_str(text)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(text)?,
+        _ => return Ok(toml::from_str(text)?),
+    };
+    remove_nulls(&mut value);
+    Ok(serde_json::from_value(value)?)
+}
+
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(members) => {
+            members.retain(|_, member| !member.is_null());
+            members.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
+/// Apply a "key=value" override to a config, where the key is a dotted path
+/// like "storage.file.path". Existing keys match case-insensitively. The
+/// value is parsed as a TOML value if possible, otherwise it is a string.
+pub fn set_config_value(config: &mut toml::Table, assignment: &str) -> Result<(), String> {
+    let (path, raw_value) = assignment
+        .split_once('=')
+        .ok_or_else(|| format!("Expected key=value, got {}", assignment))?;
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", raw_value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw_value.to_string()));
+
+    let keys: Vec<&str> = path.trim().split('.').collect();
+    if keys.iter().any(|key| key.is_empty()) {
+        return Err(format!("Invalid key {}", path));
+    }
+
+    let mut table = config;
+    for (i, key) in keys.iter().enumerate() {
+        let key = table
+            .keys()
+            .find(|existing| existing.eq_ignore_ascii_case(key))
+            .cloned()
+            .unwrap_or_else(|| key.to_string());
+        if i == keys.len() - 1 {
+            table.insert(key, value);
+            return Ok(());
+        }
+
+        let entry = table
+            .entry(key.clone())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        table = match entry {
+            toml::Value::Table(table) => table,
+            _ => return Err(format!("{} is not a table", key)),
+        };
+    }
+    unreachable!()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compression_skip_extensions() {
+        let config = CompressionConfig::default();
+        assert!(!config.may_compress(Path::new("photos/IMG_0001.JPG")));
+        assert!(!config.may_compress(Path::new("backup.tar.zst")));
+        assert!(config.may_compress(Path::new("notes.txt")));
+        assert!(config.may_compress(Path::new("Makefile")));
+
+        let config: CompressionConfig = toml::from_str("skip_extensions = [\"iso\"]").unwrap();
+        assert_eq!(config.level, 3);
+        assert!(!config.may_compress(Path::new("image.iso")));
+        assert!(config.may_compress(Path::new("photo.jpg")));
+    }
+
+    #[test]
+    fn test_set_config_value() {
+        let mut config: toml::Table = toml::from_str(
+            r#"
+            name = "laptop"
+            storage = { File = { path = "../repo" } }
+            "#,
+        )
+        .unwrap();
+        set_config_value(&mut config, "storage.file.path=/mnt/usb").unwrap();
+        set_config_value(&mut config, "concurrency.min=2").unwrap();
+        set_config_value(&mut config, "concurrency.max = 8").unwrap();
+        set_config_value(&mut config, "label=work laptop").unwrap();
+        set_config_value(&mut config, "bandwidth_limit=10M").unwrap();
+
+        let config: ArchiveConfig = config.try_into().unwrap();
+        let StorageConfig::File(storage) = config.storage else {
+            panic!("Expected file storage");
+        };
+        assert_eq!(storage.path, "/mnt/usb");
+        assert_eq!(config.concurrency.as_ref().unwrap().min, 2);
+        assert_eq!(config.concurrency.as_ref().unwrap().max, 8);
+        assert_eq!(config.label.as_deref(), Some("work laptop"));
+        assert_eq!(config.bandwidth_limit, Some(10 << 20));
+
+        let mut config = toml::Table::new();
+        assert!(set_config_value(&mut config, "name").is_err());
+        assert!(set_config_value(&mut config, "name..x=1").is_err());
+        set_config_value(&mut config, "name=x").unwrap();
+        assert!(set_config_value(&mut config, "name.inner=1").is_err());
+    }
+
+    #[test]
+    fn test_apply_profiles() {
+        let global: toml::Table = toml::from_str(
+            r#"
+            [profiles.base]
+            concurrency = { min = 1, max = 4 }
+            timeouts = { read = "30s", write = "1m" }
+
+            [profiles.offsite]
+            profile = "base"
+            storage = { File = { path = "/mnt/offsite" } }
+            "#,
+        )
+        .unwrap();
+        let local: toml::Table = toml::from_str(
+            r#"
+            profile = "offsite"
+            name = "laptop"
+            timeouts = { write = "5m" }
+            "#,
+        )
+        .unwrap();
+
+        let config: ArchiveConfig = apply_profiles(local, &global).unwrap().try_into().unwrap();
+        assert_eq!(config.name, "laptop");
+        let StorageConfig::File(storage) = config.storage else {
+            panic!("Expected file storage");
+        };
+        assert_eq!(storage.path, "/mnt/offsite");
+        assert_eq!(config.concurrency.unwrap().max, 4);
+        let timeouts = config.timeouts.unwrap();
+        assert_eq!(timeouts.read, Some(Duration::from_secs(30)));
+        assert_eq!(timeouts.write, Some(Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_apply_profiles_errors() {
+        let global: toml::Table = toml::from_str(
+            r#"
+            [profiles.a]
+            profile = "b"
+            [profiles.b]
+            profile = "a"
+            "#,
+        )
+        .unwrap();
+        let local: toml::Table = toml::from_str(r#"profile = "a""#).unwrap();
+        assert!(apply_profiles(local, &global).is_err());
+
+        let local: toml::Table = toml::from_str(r#"profile = "missing""#).unwrap();
+        assert!(apply_profiles(local, &global).is_err());
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let toml = r#"
+            name = "laptop"
+            storage = { File = { path = "../repo" } }
+            concurrency = { min = 1, max = 4 }
+            "#;
+        let json = r#"{
+            "name": "laptop",
+            "label": null,
+            "storage": { "File": { "path": "../repo" } },
+            "concurrency": { "min": 1, "max": 4 }
+        }"#;
+        let yaml = "
+name: laptop
+label:
+storage:
+  File:
+    path: ../repo
+concurrency: { min: 1, max: 4 }
+";
+        let expected = parse_config(toml, Some("toml")).unwrap();
+        assert_eq!(parse_config(json, Some("json")).unwrap(), expected);
+        assert_eq!(parse_config(yaml, Some("yml")).unwrap(), expected);
+        assert_eq!(parse_config(yaml, Some("yaml")).unwrap(), expected);
+        let config: ArchiveConfig = expected.try_into().unwrap();
+        assert_eq!(config.concurrency.unwrap().max, 4);
+
+        assert!(parse_config("[1, 2]", Some("json")).is_err());
+        assert!(parse_config("name: [", Some("yaml")).is_err());
+    }
+}
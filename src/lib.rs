@@ -1,7 +1,26 @@
 pub mod cmd {
     pub mod backup;
+    pub mod cat;
+    pub mod check;
     pub mod common;
+    pub mod completions;
+    pub mod diff;
+    pub mod doctor;
+    pub mod find;
+    pub mod forget;
+    pub mod gc;
+    pub mod history;
+    pub mod key;
+    pub mod ls;
+    pub mod prune;
+    pub mod repair;
+    pub mod repo;
     pub mod restore;
+    pub mod scan;
+    pub mod serve;
+    pub mod snapshots;
+    pub mod stats;
+    pub mod verify;
 }
 
 pub mod data {
@@ -14,9 +33,21 @@ pub mod data {
 pub mod storage;
 
 pub mod util {
+    pub mod cache;
+    pub mod database;
     pub mod fs;
+    pub mod fs_snapshot;
+    pub mod glob;
     pub mod hash;
+    pub mod hooks;
+    pub mod host;
+    pub mod http;
+    pub mod lock;
+    pub mod process;
+    pub mod rate;
+    pub mod sampled_log;
+    pub mod size;
+    pub mod tar;
     pub mod time;
+    pub mod trace;
 }
-
-pub mod constants;
@@ -1,36 +1,132 @@
 use std::{
+    env,
     error::Error,
+    io,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 
 use freebck::{
     cmd::{
-        backup::{backup, BackupArgs},
+        backup::{backup, backup_all, BackupArgs},
+        cat::{cat, CatArgs},
+        check::{check, CheckArgs},
         common::{
-            CommandError, CommandErrorKind, CommandResult, IntoCommandResult, ProgramContext,
+            CommandError, CommandErrorKind, CommandResult, IntoCommandError, IntoCommandResult,
+            IntoIoCommandError, IntoIoCommandResult, ProgramContext, RuntimeTuning,
         },
+        completions::{completions, manpages, CompletionsArgs, ManpagesArgs},
+        diff::{diff, DiffArgs},
+        doctor::{doctor, DoctorArgs},
+        find::{find, FindArgs},
+        forget::{forget, ForgetArgs},
+        gc::{gc, GcArgs},
+        history::{history, record_run, HistoryArgs, RunRecord},
+        key::{key, KeyArgs},
+        ls::{ls, LsArgs},
+        prune::{prune, PruneArgs},
+        repair::{repair, RepairArgs},
+        repo::{init, repo, RepoArgs, RepoCommand},
         restore::{restore, RestoreArgs},
+        scan::{scan, ScanArgs},
+        serve::{serve, ServeArgs},
+        snapshots::{snapshots, SnapshotsArgs},
+        stats::{stats, StatsArgs},
+        verify::{verify, VerifyArgs},
+    },
+    data::config::{
+        apply_profiles, default_global_config_path, parse_config, set_config_value, ArchiveConfig,
+        StorageConfig,
+    },
+    storage::{
+        adaptive::AdaptiveStorage,
+        b2::B2Storage,
+        compressed::CompressedStorage,
+        encrypted::{read_keys, EncryptedStorage, KdfParams},
+        file::FileStorage,
+        limited::LimitedStorage,
+        pack::PackStorage,
+        rest::RestStorage,
+        s3::S3Storage,
+        sftp::SftpStorage,
+        ssh_exec::SshExecStorage,
+        timeout::TimeoutStorage,
+        traced::TracedStorage,
+        verified::VerifiedStorage,
+        Storage,
+    },
+    util::{
+        host::hostname,
+        process::run_command,
+        rate::RateLimiter,
+        size::{set_display_units, SizeUnits},
+        time::set_display_utc,
+        trace::{finish_trace, init_tracing},
     },
-    data::config::{ArchiveConfig, StorageConfig},
-    storage::{file::FileStorage, Storage},
 };
-use log::error;
+use rand::distributions::{Alphanumeric, DistString};
 use tokio::fs;
+use tracing::{error, info, warn};
 
 /// freebck - The free backup tool
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Path to the config file to use.
-    #[arg(long, default_value = ".freebck/config.toml")]
-    config: String,
+    /// Path to the config file to use, in TOML, JSON or YAML. Defaults to
+    /// .freebck/config.toml, config.json, config.yaml or config.yml.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Path to the global config file with the profiles that archive configs
+    /// can inherit from. Defaults to ~/.config/freebck/config.toml.
+    #[arg(long)]
+    global_config: Option<PathBuf>,
+
+    /// Override a config value for this run, e.g. "storage.file.path=/mnt/usb".
+    /// Can be repeated.
+    #[arg(long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+
+    /// Archive name to use instead of the one in the config file. "auto"
+    /// derives the name from the hostname.
+    #[arg(long)]
+    archive_name: Option<String>,
+
+    /// File with the passphrase of an encrypted repository. FREEBCK_PASSPHRASE
+    /// gives it too, and encryption in the config can name a file or a
+    /// command.
+    #[arg(long, value_name = "FILE")]
+    passphrase_file: Option<PathBuf>,
 
-    /// Enable verbose logging.
+    /// Enable verbose logging. On large trees only some of the paths are
+    /// logged, set FREEBCK_LOG_LEVEL=trace to log all of them.
     #[arg(long, short)]
     verbose: bool,
 
+    /// Display times in UTC instead of the local time zone.
+    #[arg(long)]
+    utc: bool,
+
+    /// Display sizes in powers of 1000, e.g. "1.6 GB".
+    #[arg(long, conflicts_with_all = ["binary", "bytes"])]
+    si: bool,
+
+    /// Display sizes in powers of 1024, e.g. "1.5 GiB". This is the default.
+    #[arg(long, conflicts_with = "bytes")]
+    binary: bool,
+
+    /// Display sizes as exact byte counts, for scripts.
+    #[arg(long)]
+    bytes: bool,
+
+    /// Write the spans of file and storage operations to this file as they
+    /// end, one JSON object per line with their busy and idle time, to find
+    /// out where a slow run spends its time.
+    #[arg(long, value_name = "FILE")]
+    trace_json: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -41,30 +137,205 @@ enum Commands {
     Backup(BackupArgs),
     /// Restore from a snapshot.
     Restore(RestoreArgs),
+    /// Verify a snapshot.
+    Verify(VerifyArgs),
+    /// Write the raw contents of an object to stdout.
+    Cat(CatArgs),
+    /// List a directory in a snapshot without restoring it.
+    Ls(LsArgs),
+    /// List the snapshots in the repository.
+    Snapshots(SnapshotsArgs),
+    /// Find the snapshots and paths of files with the given contents.
+    Find(FindArgs),
+    /// Clean up temporary data left behind by interrupted operations.
+    Gc(GcArgs),
+    /// Compare a snapshot against a directory or tar archive.
+    Diff(DiffArgs),
+    /// Estimate the size of the next backup without writing anything.
+    Scan(ScanArgs),
+    /// Check the integrity of the stored data.
+    Check(CheckArgs),
+    /// Export or import the whole repository.
+    Repo(RepoArgs),
+    /// Manage the passphrases of an encrypted repository.
+    Key(KeyArgs),
+    /// Check the environment for common problems.
+    Doctor(DoctorArgs),
+    /// Remove data that no snapshot uses.
+    Prune(PruneArgs),
+    /// Forget snapshots that a retention policy doesn't keep or that expired.
+    Forget(ForgetArgs),
+    /// Show statistics recorded by backups.
+    Stats(StatsArgs),
+    /// Show the outcome of recent runs of freebck with this config.
+    History(HistoryArgs),
+    /// Repair damaged data in the repository.
+    Repair(RepairArgs),
+    /// Serve repositories over HTTP for rest storage on other machines.
+    Serve(ServeArgs),
+    /// Write a shell completion script to stdout.
+    Completions(CompletionsArgs),
+    /// Write man pages for all commands to a directory.
+    Manpages(ManpagesArgs),
 }
 
-async fn parse_archive_config(path: &Path) -> CommandResult<ArchiveConfig> {
-    let raw_toml = fs::read_to_string(path).await.map_err(|e| {
+impl Commands {
+    /// Name of the command as recorded in the history.
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Backup(_) => "backup",
+            Commands::Restore(_) => "restore",
+            Commands::Verify(_) => "verify",
+            Commands::Cat(_) => "cat",
+            Commands::Ls(_) => "ls",
+            Commands::Snapshots(_) => "snapshots",
+            Commands::Find(_) => "find",
+            Commands::Gc(_) => "gc",
+            Commands::Diff(_) => "diff",
+            Commands::Scan(_) => "scan",
+            Commands::Check(_) => "check",
+            Commands::Repo(_) => "repo",
+            Commands::Key(_) => "key",
+            Commands::Doctor(_) => "doctor",
+            Commands::Prune(_) => "prune",
+            Commands::Forget(_) => "forget",
+            Commands::Stats(_) => "stats",
+            Commands::History(_) => "history",
+            Commands::Repair(_) => "repair",
+            Commands::Serve(_) => "serve",
+            Commands::Completions(_) => "completions",
+            Commands::Manpages(_) => "manpages",
+        }
+    }
+}
+
+/// Read a config file as a TOML table, in the format given by its extension.
+async fn parse_config_file(path: &Path, description: &str) -> CommandResult<toml::Table> {
+    let raw_config = fs::read_to_string(path).await.map_err(|e| {
         CommandError::with_source(
             CommandErrorKind::User,
-            format!("Error reading archive config file: {}", path.display()),
+            format!("Error reading {} file: {}", description, path.display()),
             Box::new(e),
         )
     })?;
-    return toml::from_str(&raw_toml).map_err(|e| {
+
+    let format = path.extension().and_then(|extension| extension.to_str());
+    parse_config(&raw_config, format).map_err(|e| {
+        CommandError::with_source(
+            CommandErrorKind::User,
+            format!("Error parsing {}", description),
+            e,
+        )
+    })
+}
+
+/// The archive config in the default location, which may be in any of the
+/// supported formats.
+fn default_config_path() -> PathBuf {
+    let dir = Path::new(".freebck");
+    ["config.toml", "config.json", "config.yaml", "config.yml"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+        .unwrap_or_else(|| dir.join("config.toml"))
+}
+
+async fn parse_archive_config(
+    path: &Path,
+    global_config_path: Option<&Path>,
+    overrides: &[String],
+) -> CommandResult<ArchiveConfig> {
+    let mut config = parse_config_file(path, "archive config").await?;
+    if config.contains_key("profile") {
+        let global_config_path = global_config_path.ok_or_else(|| {
+            CommandError::new(
+                CommandErrorKind::User,
+                "Archive config uses a profile, but there is no global config".to_string(),
+            )
+        })?;
+        let global_config = parse_config_file(global_config_path, "global config").await?;
+        config = apply_profiles(config, &global_config)
+            .map_err(|message| CommandError::new(CommandErrorKind::User, message))?;
+    }
+    for assignment in overrides {
+        set_config_value(&mut config, assignment)
+            .map_err(|message| CommandError::new(CommandErrorKind::User, message))?;
+    }
+
+    config.try_into().map_err(|e| {
         CommandError::with_source(
             CommandErrorKind::User,
-            format!("Error parsing archive config"),
+            "Error parsing archive config".to_string(),
             Box::new(e),
         )
+    })
+}
+
+/// Passphrase of an encrypted repository, from FREEBCK_PASSPHRASE,
+/// `passphrase_file` or else the file or command of the encryption config.
+async fn get_passphrase(
+    config_path: &Path,
+    config: &ArchiveConfig,
+    passphrase_file: Option<&Path>,
+) -> CommandResult<String> {
+    if let Ok(passphrase) = env::var("FREEBCK_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+
+    let encryption = config.encryption.clone().unwrap_or_default();
+    let passphrase_file = passphrase_file.map(Path::to_path_buf).or_else(|| {
+        encryption
+            .passphrase_file
+            .map(|file| config_path.parent().unwrap().join(file))
     });
+    let passphrase = match (passphrase_file, encryption.passphrase_command) {
+        (Some(path), _) => fs::read_to_string(&path).await.into_command_result(
+            CommandErrorKind::User,
+            format!("Failed to read passphrase file {}", path.display()).as_str(),
+        )?,
+        (None, Some(command)) => run_command(vec!["sh".to_string(), "-c".to_string(), command]).await?,
+        (None, None) => {
+            return Err(CommandError::new(
+                CommandErrorKind::User,
+                "Repository is encrypted, give its passphrase with --passphrase-file or FREEBCK_PASSPHRASE".to_string(),
+            ))
+        }
+    };
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return Err(CommandError::new(
+            CommandErrorKind::User,
+            "Passphrase is empty".to_string(),
+        ));
+    }
+    Ok(passphrase.to_string())
 }
 
+fn kdf_params(config: &ArchiveConfig) -> KdfParams {
+    let default = KdfParams::default();
+    let Some(ref encryption) = config.encryption else {
+        return default;
+    };
+    KdfParams {
+        memory_kib: encryption.kdf_memory.map_or(default.memory_kib, |memory| {
+            (memory / 1024).try_into().unwrap_or(u32::MAX)
+        }),
+        iterations: encryption.kdf_iterations.unwrap_or(default.iterations),
+    }
+}
+
+/// Create the storage for `storage_config`, which is either the storage or
+/// the mirror of `config`. Encrypted repositories are opened with the
+/// passphrase.
 async fn create_storage(
     config_path: &Path,
     config: &ArchiveConfig,
+    storage_config: &StorageConfig,
+    limiter: Option<&Arc<RateLimiter>>,
+    passphrase_file: Option<&Path>,
+    client_id: &str,
 ) -> CommandResult<Box<dyn Storage>> {
-    let storage = match config.storage {
+    let mut storage: Box<dyn Storage> = match *storage_config {
         StorageConfig::File(ref file_config) => Box::new(
             FileStorage::from_config(config_path, &file_config)
                 .await
@@ -73,50 +344,319 @@ async fn create_storage(
                     "Failed to initialize file storage",
                 )?,
         ),
+        StorageConfig::S3(ref s3_config) => Box::new(
+            S3Storage::from_config(s3_config)
+                .into_command_result(CommandErrorKind::User, "Failed to initialize S3 storage")?,
+        ),
+        StorageConfig::Sftp(ref sftp_config) => Box::new(
+            SftpStorage::from_config(config_path, sftp_config)
+                .await
+                .into_command_result(
+                    CommandErrorKind::System,
+                    "Failed to initialize SFTP storage",
+                )?,
+        ),
+        StorageConfig::Rest(ref rest_config) => Box::new(
+            RestStorage::from_config(rest_config)
+                .into_command_result(CommandErrorKind::User, "Failed to initialize rest storage")?,
+        ),
+        StorageConfig::B2(ref b2_config) => Box::new(
+            B2Storage::from_config(b2_config)
+                .into_command_result(CommandErrorKind::User, "Failed to initialize B2 storage")?,
+        ),
+        StorageConfig::SshExec(ref ssh_config) => Box::new(
+            SshExecStorage::from_config(config_path, ssh_config)
+                .into_command_result(CommandErrorKind::User, "Failed to initialize ssh storage")?,
+        ),
     };
 
-    Ok(storage)
+    // Innermost, so that the spans time the backend alone.
+    storage = Box::new(TracedStorage::new(storage));
+
+    // Right outside of the backend, so that the other wrappers deal with
+    // plaintext and only ciphertext is stored.
+    let keys = read_keys(&*storage)
+        .await
+        .into_io_command_result("Failed to read the repository keys")?;
+    if !keys.is_empty() || config.encryption.is_some() {
+        let passphrase = get_passphrase(config_path, config, passphrase_file).await?;
+        let encrypted =
+            EncryptedStorage::open(storage, &keys, &passphrase, kdf_params(config), client_id)
+                .await
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::PermissionDenied => e.into_command_error(
+                        CommandErrorKind::User,
+                        "Failed to open the encrypted repository",
+                    ),
+                    _ => e.into_io_command_error("Failed to open the encrypted repository"),
+                })?;
+        storage = Box::new(encrypted);
+    }
+
+    if let Some(ref timeout_config) = config.timeouts {
+        storage = Box::new(TimeoutStorage::new(storage, timeout_config));
+    }
+
+    if let Some(ref concurrency_config) = config.concurrency {
+        storage = Box::new(AdaptiveStorage::new(storage, concurrency_config));
+    }
+
+    // Outside of the others, so that waiting for bandwidth doesn't count as
+    // latency or towards timeouts.
+    if let Some(limiter) = limiter {
+        storage = Box::new(LimitedStorage::new(storage, limiter.clone()));
+    }
+
+    // Inside of pack, so that the packs are checked as they are written, and
+    // outside of the rest, so that the read back is limited and timed too.
+    if config.verify_writes {
+        storage = Box::new(VerifiedStorage::new(storage));
+    }
+
+    // Outside of the others, so that only whole packs reach the storage.
+    if let Some(pack_size) = config.pack_size {
+        storage = Box::new(PackStorage::new(storage, pack_size));
+    }
+
+    // Outermost, so that blobs are compressed one at a time before they are
+    // packed.
+    Ok(Box::new(CompressedStorage::new(
+        storage,
+        config.compression.level,
+    )))
+}
+
+async fn get_client_id(config_path: &Path, config: &ArchiveConfig) -> CommandResult<String> {
+    if let Some(ref client_id) = config.client_id {
+        return Ok(client_id.clone());
+    }
+
+    let client_id_path = config_path.parent().unwrap().join("client_id");
+    match fs::read_to_string(&client_id_path).await {
+        Ok(client_id) => return Ok(client_id.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => {
+            return Err(e.into_command_error(
+                CommandErrorKind::System,
+                format!("Failed to read client id: {}", client_id_path.display()).as_str(),
+            ))
+        }
+    }
+
+    let client_id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    fs::write(&client_id_path, &client_id)
+        .await
+        .into_command_result(CommandErrorKind::System, "Failed to write client id")?;
+    info!("Assigned client id: {}", client_id);
+
+    Ok(client_id)
+}
+
+fn get_archive_name(args: &Cli, config: &ArchiveConfig) -> CommandResult<String> {
+    let name = args.archive_name.as_ref().unwrap_or(&config.name);
+    if name != "auto" {
+        return Ok(name.clone());
+    }
+
+    let hostname = hostname()?;
+    Ok(match config.label {
+        Some(ref label) => format!("{}-{}", hostname, label),
+        None => hostname,
+    })
+}
+
+/// Contexts for the configured archive and the further archives in the
+/// config, all sharing the storage of `context`.
+fn archive_contexts(
+    context: ProgramContext,
+    config_path: &Path,
+    config: &ArchiveConfig,
+) -> Vec<ProgramContext> {
+    let mut contexts = Vec::new();
+    for archive in &config.archives {
+        contexts.push(ProgramContext {
+            archive_name: archive.name.clone(),
+            backup_target: config_path.parent().unwrap().join(&archive.path),
+            fs_snapshot: archive.fs_snapshot.clone(),
+            database: archive.database.clone(),
+            ..context.clone()
+        });
+    }
+    contexts.insert(0, context);
+    contexts
 }
 
 async fn run(args: Cli) -> CommandResult {
-    let config_path = PathBuf::from(&args.config);
+    set_display_utc(args.utc);
+    set_display_units(if args.si {
+        SizeUnits::Si
+    } else if args.bytes {
+        SizeUnits::Bytes
+    } else {
+        SizeUnits::Binary
+    });
+    if let Commands::Repo(RepoArgs {
+        command: RepoCommand::Init(ref init_args),
+    }) = args.command
+    {
+        if init_args.path.is_some() {
+            return init(init_args).await;
+        }
+    }
+    match args.command {
+        Commands::Completions(ref completions_args) => {
+            return completions(&mut Cli::command(), completions_args)
+        }
+        Commands::Manpages(ref manpages_args) => {
+            return manpages(&mut Cli::command(), manpages_args).await
+        }
+        Commands::Serve(ref serve_args) => return serve(serve_args).await,
+        _ => {}
+    }
+
+    let config_path = args.config.clone().unwrap_or_else(default_config_path);
+    let state_dir = config_path.parent().unwrap().to_path_buf();
+    if let Commands::History(ref history_args) = args.command {
+        return history(&state_dir, history_args).await;
+    }
 
-    let archive_config = parse_archive_config(&config_path).await?;
+    let global_config_path = args
+        .global_config
+        .clone()
+        .or_else(default_global_config_path);
+    let archive_config =
+        parse_archive_config(&config_path, global_config_path.as_deref(), &args.set).await?;
     let backup_target = config_path.parent().unwrap().join(&archive_config.path);
-    let storage = create_storage(&config_path, &archive_config).await?;
+    let limiter = archive_config
+        .bandwidth_limit
+        .map(|bytes_per_second| Arc::new(RateLimiter::new(bytes_per_second)));
+    let client_id = get_client_id(&config_path, &archive_config).await?;
+    let storage = create_storage(
+        &config_path,
+        &archive_config,
+        &archive_config.storage,
+        limiter.as_ref(),
+        args.passphrase_file.as_deref(),
+        &client_id,
+    )
+    .await?;
+    let archive_name = get_archive_name(&args, &archive_config)?;
 
     let context = ProgramContext {
-        archive_name: archive_config.name,
-        storage,
+        archive_name,
+        client_id,
+        storage: storage.into(),
         backup_target,
+        state_dir: state_dir.clone(),
+        hooks: archive_config.hooks.clone(),
+        fs_snapshot: archive_config.fs_snapshot.clone(),
+        database: archive_config.database.clone(),
+        compression: archive_config.compression.clone(),
+        tuning: match archive_config.tuning {
+            Some(ref tuning_config) => RuntimeTuning::from_config(tuning_config)?,
+            None => RuntimeTuning::default(),
+        },
     };
 
-    match args.command {
-        Commands::Backup(backup_args) => backup(&context, &backup_args).await,
+    let mut run = RunRecord::start(args.command.name(), &context.archive_name);
+    let result = match args.command {
+        Commands::Backup(backup_args) if backup_args.all => {
+            let parallel = backup_args
+                .parallel
+                .or(archive_config.parallel_archives)
+                .unwrap_or(1);
+            let contexts = archive_contexts(context, &config_path, &archive_config);
+            let result = backup_all(&contexts, &backup_args, parallel).await;
+            for context in &contexts {
+                run.add_snapshots(context).await;
+            }
+            result
+        }
+        Commands::Backup(backup_args) => {
+            let result = backup(&context, &backup_args).await;
+            run.add_snapshots(&context).await;
+            result
+        }
         Commands::Restore(restore_args) => restore(&context, &restore_args).await,
+        Commands::Verify(verify_args) => verify(&context, &verify_args).await,
+        Commands::Cat(cat_args) => cat(&context, &cat_args).await,
+        Commands::Ls(ls_args) => ls(&context, &ls_args).await,
+        Commands::Snapshots(snapshots_args) => snapshots(&context, &snapshots_args).await,
+        Commands::Find(find_args) => find(&context, &find_args).await,
+        Commands::Gc(gc_args) => gc(&context, &gc_args).await,
+        Commands::Diff(diff_args) => diff(&context, &diff_args).await,
+        Commands::Scan(scan_args) => scan(&context, &scan_args).await,
+        Commands::Check(check_args) => check(&context, &check_args).await,
+        Commands::Repo(repo_args) => repo(&context, &repo_args).await,
+        Commands::Key(key_args) => {
+            let passphrase = get_passphrase(
+                &config_path,
+                &archive_config,
+                args.passphrase_file.as_deref(),
+            );
+            key(&context, &key_args, passphrase, kdf_params(&archive_config)).await
+        }
+        Commands::Doctor(doctor_args) => doctor(&context, &doctor_args).await,
+        Commands::Prune(prune_args) => prune(&context, &prune_args).await,
+        Commands::Forget(forget_args) => {
+            forget(&context, &forget_args, archive_config.retention.as_ref()).await
+        }
+        Commands::Stats(stats_args) => stats(&context, &stats_args).await,
+        Commands::Repair(repair_args) => {
+            let mirror = match archive_config.mirror {
+                Some(ref mirror_config) => Some(
+                    create_storage(
+                        &config_path,
+                        &archive_config,
+                        mirror_config,
+                        limiter.as_ref(),
+                        args.passphrase_file.as_deref(),
+                        &context.client_id,
+                    )
+                    .await?,
+                ),
+                None => None,
+            };
+            repair(&context, &repair_args, mirror.as_deref()).await
+        }
+        Commands::Completions(_)
+        | Commands::Manpages(_)
+        | Commands::Serve(_)
+        | Commands::History(_) => unreachable!(),
+    };
+
+    run.finish(&result);
+    if let Err(e) = record_run(&state_dir, &run).await {
+        warn!("Failed to record the run in the history: {}", e);
     }
+    result
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
 
-    let log_env = env_logger::Env::default()
-        .filter_or(
-            "FREEBCK_LOG_LEVEL",
-            if args.verbose { "debug" } else { "info" },
-        )
-        .write_style("FREEBCK_LOG_STYLE");
-    env_logger::Builder::from_env(log_env)
-        .format_level(false)
-        .format_target(false)
-        .format_indent(None)
-        .format_timestamp(None)
-        .init();
-
-    if let Err(e) = run(args).await {
+    if let Err(e) = init_tracing(args.verbose, args.trace_json.as_deref()) {
+        eprintln!("Failed to set up logging: {}", e);
+        std::process::exit(1);
+    }
+
+    let result = run(args).await;
+    if let Err(e) = finish_trace() {
+        warn!("Failed to write trace: {}", e);
+    }
+    if let Err(e) = result {
         error!("{}", e);
 
+        if let Some(failures) = e.failures() {
+            for (kind, errors) in failures.grouped() {
+                error!("\n{:?} errors ({}):", kind, errors.len());
+                for path_error in errors {
+                    error!("  {}: {}", path_error.path.display(), path_error.error);
+                }
+            }
+        }
+
         let mut source = e.source();
         while source.is_some() {
             let e = source.unwrap();
@@ -1,21 +1,82 @@
-use std::io;
+use std::{collections::HashSet, io, path::Path, sync::Arc, time::SystemTime};
 
 use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 #[macro_use]
 mod test;
 
+pub mod adaptive;
+pub mod b2;
+pub mod compressed;
+pub mod encrypted;
 pub mod file;
+pub mod limited;
+pub mod memory;
+pub mod pack;
+pub mod rest;
+pub mod s3;
+pub mod sftp;
+pub mod ssh_exec;
+pub mod timeout;
+pub mod traced;
 mod util;
+pub mod verified;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Collection {
     Snapshot,
     Blob,
+    /// Statistics recorded for each backup, by snapshot name.
+    Stats,
+    /// Small blobs stored together, by hash of the pack.
+    Pack,
+    /// Blob locations of each pack, under the name of the pack.
+    PackIndex,
+    /// Locks of the operations running on the repository.
+    Lock,
+    /// Settings of the repository itself, such as its format.
+    Config,
+    /// Master keys of an encrypted repository, each encrypted with a
+    /// passphrase. Not encrypted themselves.
+    Key,
+}
+
+impl Collection {
+    /// Name of the collection as used in storage paths.
+    pub fn name(self) -> &'static str {
+        match self {
+            Collection::Snapshot => "snapshot",
+            Collection::Blob => "blob",
+            Collection::Stats => "stats",
+            Collection::Pack => "pack",
+            Collection::PackIndex => "pack_index",
+            Collection::Lock => "lock",
+            Collection::Config => "config",
+            Collection::Key => "key",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            Collection::Snapshot,
+            Collection::Blob,
+            Collection::Stats,
+            Collection::Pack,
+            Collection::PackIndex,
+            Collection::Lock,
+            Collection::Config,
+            Collection::Key,
+        ]
+        .into_iter()
+        .find(|collection| collection.name() == name)
+    }
 }
 
 pub type StorageWrite = io::Result<()>;
 pub type StorageRead = io::Result<()>;
 pub type StorageItems = io::Result<Vec<String>>;
+pub type StorageReader = Box<dyn AsyncRead + Send + Unpin>;
 
 #[async_trait]
 pub trait Storage: Sync + Send {
@@ -27,4 +88,193 @@ pub trait Storage: Sync + Send {
 
     // Get an iterator over all items in the collection. Collection should be alphanumeric.
     async fn get_collection_items(&self, collection: Collection) -> StorageItems;
+
+    // Write a new item whose data is known not to compress, such as a chunk
+    // of a JPEG image. Storage that compresses stores it as it is.
+    async fn write_uncompressed(
+        &self,
+        collection: Collection,
+        key: &str,
+        data: &[u8],
+    ) -> StorageWrite {
+        self.write(collection, key, data).await
+    }
+
+    // Write a new item with the content of `reader`. The default reads it all
+    // into memory first, storages that can take the data in pieces should
+    // override it.
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        self.write(collection, key, &data).await
+    }
+
+    // Open an item for reading. The default reads it all into memory first.
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        let mut buffer = Vec::new();
+        self.read(collection, key, &mut buffer).await?;
+        Ok(Box::new(io::Cursor::new(buffer)))
+    }
+
+    // Items of the collection whose keys start with `prefix`, such as the
+    // snapshots of one archive. The default filters the full listing, which
+    // storages that can filter on their side should override.
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        let mut items = self.get_collection_items(collection).await?;
+        items.retain(|key| key.starts_with(prefix));
+        Ok(items)
+    }
+
+    // Replace an item that exists but is damaged. Items are otherwise never
+    // overwritten, so this is only meant for repairs.
+    async fn replace(&self, _collection: Collection, _key: &str, _data: &[u8]) -> StorageWrite {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This storage can't replace items",
+        ))
+    }
+
+    // Remove an item from the collection. Removing an item that doesn't
+    // exist succeeds, so that an interrupted removal can be retried.
+    async fn delete(&self, _collection: Collection, _key: &str) -> StorageWrite {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This storage can't delete items",
+        ))
+    }
+
+    // Whether each of `keys` exists in the collection. The default lists the
+    // whole collection, which is cheaper than a request per key.
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        let items: HashSet<String> = self
+            .get_collection_items(collection)
+            .await?
+            .into_iter()
+            .collect();
+        Ok(keys.iter().map(|key| items.contains(key)).collect())
+    }
+
+    // Finish writes that the storage has buffered. Items written before are
+    // only guaranteed to be stored after this returns.
+    async fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Remove temporary objects of writes that were started before
+    // `older_than` and never finished, returning how many were removed.
+    // Storage that keeps deleted items for a while removes expired ones too.
+    async fn clean_temporary(&self, _older_than: SystemTime) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    // Mark the storage as a repository with ID `repo_id`, by writing the ID
+    // next to the collections. Fails with AlreadyExists if it already has an
+    // ID. Whether anything else is stored is up to the caller to check.
+    async fn init(&self, _repo_id: &str) -> StorageWrite {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "This storage can't create repositories",
+        ))
+    }
+
+    // Directory the items are stored in, if the storage is on the local file
+    // system.
+    fn local_path(&self) -> Option<&Path> {
+        None
+    }
+
+    // Largest object the backend accepts in one write, if it has a limit.
+    fn max_object_size(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Lets several contexts use the same storage, and so the same connections
+/// and limits, e.g. when backing up archives in parallel.
+#[async_trait]
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        (**self).write(collection, key, data).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        (**self).read(collection, key, buffer).await
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        (**self).get_collection_items(collection).await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        (**self)
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    async fn write_uncompressed(
+        &self,
+        collection: Collection,
+        key: &str,
+        data: &[u8],
+    ) -> StorageWrite {
+        (**self).write_uncompressed(collection, key, data).await
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        (**self).replace(collection, key, data).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        (**self).delete(collection, key).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        (**self).has_many(collection, keys).await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        (**self).write_stream(collection, key, reader).await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        (**self).read_stream(collection, key).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        (**self).flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        (**self).clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        (**self).init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        (**self).local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        (**self).max_object_size()
+    }
 }
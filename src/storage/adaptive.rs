@@ -0,0 +1,322 @@
+use std::{
+    path::Path,
+    pin::Pin,
+    sync::Mutex,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{self, AsyncRead, ReadBuf},
+    sync::Semaphore,
+};
+use tracing::debug;
+
+use crate::data::config::ConcurrencyConfig;
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+// Latencies are normalized per this many bytes, so that large chunks don't
+// look like a slow backend.
+const LATENCY_UNIT_BYTES: f64 = 1024.0 * 1024.0;
+// Recent latency above this multiple of the baseline is treated as overload.
+const OVERLOAD_FACTOR: f64 = 2.0;
+const DECREASE_FACTOR: f64 = 0.9;
+const RECENT_LATENCY_WEIGHT: f64 = 0.2;
+// The baseline creeps up slowly so the controller recovers from a lucky
+// early sample.
+const BASELINE_DRIFT: f64 = 1.01;
+
+struct ControllerState {
+    limit: f64,
+    permits: usize,
+    permits_to_forget: usize,
+    baseline_latency: Option<f64>,
+    recent_latency: Option<f64>,
+}
+
+/// Adjusts the number of concurrent storage operations based on their
+/// latency: additive increase while latency stays close to the best seen,
+/// multiplicative decrease once it rises.
+pub struct ConcurrencyController {
+    min: usize,
+    max: usize,
+    semaphore: Semaphore,
+    state: Mutex<ControllerState>,
+}
+
+impl ConcurrencyController {
+    pub fn new(config: &ConcurrencyConfig) -> Self {
+        let min = config.min.max(1);
+        let max = config.max.max(min);
+
+        Self {
+            min,
+            max,
+            semaphore: Semaphore::new(min),
+            state: Mutex::new(ControllerState {
+                limit: min as f64,
+                permits: min,
+                permits_to_forget: 0,
+                baseline_latency: None,
+                recent_latency: None,
+            }),
+        }
+    }
+
+    async fn run<T, F>(&self, operation: F) -> io::Result<T>
+    where
+        F: std::future::Future<Output = io::Result<(T, usize)>>,
+    {
+        let permit = self.semaphore.acquire().await.map_err(io::Error::other)?;
+
+        let started = Instant::now();
+        let result = operation.await;
+        let elapsed = started.elapsed();
+
+        let forget = {
+            let mut state = self.state.lock().unwrap();
+            if let Ok((_, bytes)) = result {
+                self.record(&mut state, elapsed, bytes);
+            }
+
+            if state.permits_to_forget > 0 {
+                state.permits_to_forget -= 1;
+                state.permits -= 1;
+                true
+            } else {
+                false
+            }
+        };
+        if forget {
+            permit.forget();
+        }
+
+        result.map(|(value, _)| value)
+    }
+
+    fn record(&self, state: &mut ControllerState, elapsed: Duration, bytes: usize) {
+        let latency = elapsed.as_secs_f64() / (1.0 + bytes as f64 / LATENCY_UNIT_BYTES);
+
+        let baseline = match state.baseline_latency {
+            Some(baseline) => (baseline * BASELINE_DRIFT).min(latency),
+            None => latency,
+        };
+        let recent = match state.recent_latency {
+            Some(recent) => recent + (latency - recent) * RECENT_LATENCY_WEIGHT,
+            None => latency,
+        };
+        state.baseline_latency = Some(baseline);
+        state.recent_latency = Some(recent);
+
+        let previous_limit = state.limit as usize;
+        if recent > baseline * OVERLOAD_FACTOR {
+            state.limit = (state.limit * DECREASE_FACTOR).max(self.min as f64);
+        } else {
+            // Grows by about one per round of `limit` operations.
+            state.limit = (state.limit + 1.0 / state.limit).min(self.max as f64);
+        }
+
+        let limit = state.limit as usize;
+        if limit != previous_limit {
+            debug!("Storage concurrency limit: {}", limit);
+        }
+
+        let effective_permits = state.permits - state.permits_to_forget;
+        if limit > effective_permits {
+            let mut added = limit - effective_permits;
+            let cancelled = added.min(state.permits_to_forget);
+            state.permits_to_forget -= cancelled;
+            added -= cancelled;
+            state.permits += added;
+            self.semaphore.add_permits(added);
+        } else if limit < effective_permits {
+            state.permits_to_forget += effective_permits - limit;
+        }
+        while state.permits_to_forget > 0 {
+            match self.semaphore.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+            state.permits_to_forget -= 1;
+            state.permits -= 1;
+        }
+    }
+}
+
+/// Reader that counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    bytes: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        self.bytes += buf.filled().len() - filled;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Storage wrapper that limits concurrent reads and writes with a
+/// `ConcurrencyController`. Streamed reads are only limited while they are
+/// opened.
+pub struct AdaptiveStorage {
+    inner: Box<dyn Storage>,
+    controller: ConcurrencyController,
+}
+
+impl AdaptiveStorage {
+    pub fn new(inner: Box<dyn Storage>, config: &ConcurrencyConfig) -> Self {
+        Self {
+            inner,
+            controller: ConcurrencyController::new(config),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for AdaptiveStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.controller
+            .run(async {
+                self.inner.write(collection, key, data).await?;
+                Ok(((), data.len()))
+            })
+            .await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        self.controller
+            .run(async {
+                self.inner.read(collection, key, buffer).await?;
+                Ok(((), buffer.len()))
+            })
+            .await
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.inner.get_collection_items(collection).await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        self.inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let mut reader = CountingReader {
+            inner: reader,
+            bytes: 0,
+        };
+        self.controller
+            .run(async {
+                self.inner
+                    .write_stream(collection, key, &mut reader)
+                    .await?;
+                Ok(((), reader.bytes))
+            })
+            .await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        self.controller
+            .run(async {
+                let reader = self.inner.read_stream(collection, key).await?;
+                Ok((reader, 0))
+            })
+            .await
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.inner.replace(collection, key, data).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.inner.delete(collection, key).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        self.inner.has_many(collection, keys).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::file::FileStorage;
+
+    struct AdaptiveStorageTestState {
+        _tmp_dir: tempfile::TempDir,
+        storage: AdaptiveStorage,
+    }
+
+    impl AdaptiveStorageTestState {
+        async fn new() -> Self {
+            let _tmp_dir = tempfile::tempdir().unwrap();
+            let file_storage = FileStorage::new(_tmp_dir.path().to_owned()).await.unwrap();
+            let storage = AdaptiveStorage::new(
+                Box::new(file_storage),
+                &ConcurrencyConfig { min: 1, max: 4 },
+            );
+
+            Self { _tmp_dir, storage }
+        }
+    }
+
+    storage_tests!(AdaptiveStorageTestState);
+
+    #[test]
+    fn controller_adapts_to_latency() {
+        let controller = ConcurrencyController::new(&ConcurrencyConfig { min: 2, max: 8 });
+        let mut state = controller.state.lock().unwrap();
+
+        for _ in 0..100 {
+            controller.record(&mut state, Duration::from_millis(10), 0);
+        }
+        assert_eq!(state.limit as usize, 8);
+        assert_eq!(controller.semaphore.available_permits(), 8);
+
+        for _ in 0..100 {
+            controller.record(&mut state, Duration::from_millis(100), 0);
+        }
+        assert_eq!(state.limit as usize, 2);
+        assert_eq!(controller.semaphore.available_permits(), 2);
+    }
+}
@@ -0,0 +1,1165 @@
+use std::{
+    env,
+    future::Future,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize,
+};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt},
+    sync::Mutex,
+    time::sleep,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    data::config::B2StorageConfig,
+    util::{
+        http::{client, parse_url, percent_encode, send, Response},
+        time::as_unix_timestamp,
+    },
+};
+
+use super::{
+    file::REPO_ID_FILE, util::normalize_prefix, Collection, Storage, StorageItems, StorageRead,
+    StorageWrite,
+};
+
+const DEFAULT_ENDPOINT: &str = "https://api.backblazeb2.com";
+/// Attempts for a request while B2 is busy, and for an upload while its
+/// upload URLs fail.
+const MAX_ATTEMPTS: u32 = 5;
+/// Wait before the first retry, doubled for each one after it, unless B2
+/// says how long to wait.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait asked for by Retry-After that is followed.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+/// Files listed per request. Listing is billed per 1000 files.
+const LIST_PAGE_SIZE: u32 = 1000;
+/// Most parts a large file can have.
+const MAX_PARTS: usize = 10_000;
+
+/// Storage in a Backblaze B2 bucket, through the native API.
+/// Items are files named "<prefix><collection>/<key>". Items larger than the
+/// part size that B2 recommends for the account are uploaded as large files
+/// in parts. Requests are retried while B2 is busy, and uploads get a new
+/// upload URL whenever one fails, as B2 asks.
+///
+/// B2 can't refuse an upload if the file exists, so writes check first, and
+/// two clients writing the same item at once may both add a version. Items
+/// are named by their content or written by one client, so the versions
+/// are the same.
+pub struct B2Storage {
+    client: reqwest::Client,
+    endpoint: String,
+    key_id: String,
+    application_key: String,
+    bucket: String,
+    prefix: String,
+    session: Mutex<Option<Arc<Session>>>,
+    /// Upload URLs not in use. Each one takes one upload at a time.
+    upload_urls: StdMutex<Vec<UploadUrl>>,
+}
+
+/// Authorization of the account, from b2_authorize_account.
+struct Session {
+    token: String,
+    api_url: String,
+    download_url: String,
+    bucket_id: String,
+    part_size: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizeResponse {
+    account_id: String,
+    authorization_token: String,
+    api_url: String,
+    download_url: String,
+    recommended_part_size: usize,
+    allowed: Option<Allowed>,
+}
+
+/// What an application key may access. Keys restricted to a bucket name it.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Allowed {
+    bucket_id: Option<String>,
+    bucket_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BucketList {
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Bucket {
+    bucket_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadUrl {
+    upload_url: String,
+    authorization_token: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileList {
+    files: Vec<FileInfo>,
+    next_file_name: Option<String>,
+    next_file_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FileInfo {
+    file_name: String,
+    file_id: String,
+    /// Milliseconds since the Unix epoch.
+    #[serde(default)]
+    upload_timestamp: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LargeFile {
+    file_id: String,
+}
+
+/// Whether B2 answers with `status` when it is busy or had a passing
+/// failure, so that the request may succeed if it is sent again.
+fn is_busy(status: u16) -> bool {
+    matches!(status, 408 | 429 | 500 | 503)
+}
+
+/// Wait before retrying, as long as the Retry-After header of `response`
+/// asks or else `delay`, and return the delay for the next retry.
+async fn wait_to_retry(response: Option<&Response>, delay: Duration) -> Duration {
+    let retry_after = response
+        .and_then(|response| response.header("Retry-After"))
+        .and_then(|seconds| seconds.trim().parse().ok())
+        .map(|seconds| Duration::from_secs(seconds).min(MAX_RETRY_AFTER));
+    sleep(retry_after.unwrap_or(delay)).await;
+    delay * 2
+}
+
+/// Send the request `build` makes, again while B2 is busy.
+async fn send_retrying(build: impl Fn() -> reqwest::RequestBuilder) -> io::Result<Response> {
+    let mut delay = RETRY_DELAY;
+    for _ in 1..MAX_ATTEMPTS {
+        let response = send(build()).await?;
+        if !is_busy(response.status) {
+            return Ok(response);
+        }
+        debug!("B2 answered HTTP {}, retrying", response.status);
+        delay = wait_to_retry(Some(&response), delay).await;
+    }
+    send(build()).await
+}
+
+/// The JSON body of a successful response.
+fn parse_response<T: DeserializeOwned>(response: &Response, operation: &str) -> io::Result<T> {
+    if !response.is_success() {
+        return Err(response.error(operation));
+    }
+    serde_json::from_slice(&response.body).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid response to {}: {}", operation, e),
+        )
+    })
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha1::digest(data))
+}
+
+/// Read up to `size` bytes, fewer only at the end of `reader`.
+async fn read_part(
+    reader: &mut (dyn AsyncRead + Send + Unpin),
+    size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut part = Vec::new();
+    reader.take(size as u64).read_to_end(&mut part).await?;
+    Ok(part)
+}
+
+impl B2Storage {
+    pub fn from_config(config: &B2StorageConfig) -> io::Result<Self> {
+        let credential = |value: &Option<String>, variable: &str| {
+            value
+                .clone()
+                .or_else(|| env::var(variable).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("No B2 credentials, set them in the config or {}", variable),
+                    )
+                })
+        };
+        let endpoint = parse_url(config.endpoint.as_deref().unwrap_or(DEFAULT_ENDPOINT))?;
+        Ok(Self {
+            client: client(None)?,
+            endpoint: endpoint.as_str().trim_end_matches('/').to_string(),
+            key_id: credential(&config.key_id, "B2_APPLICATION_KEY_ID")?,
+            application_key: credential(&config.application_key, "B2_APPLICATION_KEY")?,
+            bucket: config.bucket.clone(),
+            prefix: normalize_prefix(&config.prefix)?,
+            session: Mutex::new(None),
+            upload_urls: StdMutex::new(Vec::new()),
+        })
+    }
+
+    fn item_name(&self, collection: Collection, key: &str) -> String {
+        format!("{}{}/{}", self.prefix, collection.name(), key)
+    }
+
+    /// The current authorization, authorizing first if there is none.
+    async fn session(&self) -> io::Result<Arc<Session>> {
+        let mut session = self.session.lock().await;
+        if let Some(ref session) = *session {
+            return Ok(session.clone());
+        }
+        let authorized = Arc::new(self.authorize().await?);
+        *session = Some(authorized.clone());
+        Ok(authorized)
+    }
+
+    /// Forget `expired`, so that the next request authorizes again.
+    async fn expire(&self, expired: &Arc<Session>) {
+        let mut session = self.session.lock().await;
+        if session
+            .as_ref()
+            .is_some_and(|session| Arc::ptr_eq(session, expired))
+        {
+            *session = None;
+        }
+    }
+
+    async fn authorize(&self) -> io::Result<Session> {
+        let url = format!("{}/b2api/v2/b2_authorize_account", self.endpoint);
+        let response = send_retrying(|| {
+            self.client
+                .get(&url)
+                .basic_auth(&self.key_id, Some(&self.application_key))
+        })
+        .await?;
+        let account: AuthorizeResponse = parse_response(&response, "b2_authorize_account")?;
+        let mut session = Session {
+            token: account.authorization_token,
+            api_url: account.api_url,
+            download_url: account.download_url,
+            bucket_id: String::new(),
+            part_size: account.recommended_part_size.max(1),
+        };
+
+        session.bucket_id = match account.allowed {
+            Some(Allowed {
+                bucket_id: Some(bucket_id),
+                bucket_name,
+            }) => {
+                if bucket_name.as_deref() != Some(self.bucket.as_str()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("The B2 application key can't access {}", self.bucket),
+                    ));
+                }
+                bucket_id
+            }
+            _ => {
+                let body = json!({
+                    "accountId": account.account_id,
+                    "bucketName": self.bucket,
+                });
+                let response = self.send_api(&session, "b2_list_buckets", &body).await?;
+                let list: BucketList = parse_response(&response, "b2_list_buckets")?;
+                match list.buckets.into_iter().next() {
+                    Some(bucket) => bucket.bucket_id,
+                    None => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("No B2 bucket named {}", self.bucket),
+                        ))
+                    }
+                }
+            }
+        };
+        Ok(session)
+    }
+
+    async fn send_api(
+        &self,
+        session: &Session,
+        name: &str,
+        body: &serde_json::Value,
+    ) -> io::Result<Response> {
+        let url = format!("{}/b2api/v2/{}", session.api_url, name);
+        send_retrying(|| {
+            self.client
+                .post(&url)
+                .header(AUTHORIZATION, &session.token)
+                .body(body.to_string())
+        })
+        .await
+    }
+
+    /// Call the API operation `name` with `body`, authorizing again if the
+    /// authorization expired.
+    async fn call<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        body: serde_json::Value,
+    ) -> io::Result<T> {
+        let session = self.session().await?;
+        let mut response = self.send_api(&session, name, &body).await?;
+        // Authorizations are valid for a day at most.
+        if response.status == 401 {
+            self.expire(&session).await;
+            response = self.send_api(&*self.session().await?, name, &body).await?;
+        }
+        parse_response(&response, name)
+    }
+
+    /// Fail with AlreadyExists if there is a file called `name`.
+    async fn check_missing(&self, name: &str) -> StorageWrite {
+        let bucket_id = self.session().await?.bucket_id.clone();
+        let list: FileList = self
+            .call(
+                "b2_list_file_names",
+                json!({
+                    "bucketId": bucket_id,
+                    "startFileName": name,
+                    "prefix": name,
+                    "maxFileCount": 1,
+                }),
+            )
+            .await?;
+        if list.files.iter().any(|file| file.file_name == name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File already exists: {}", name),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send an upload with the request that `build` makes for an upload URL.
+    /// Whenever a URL fails, the upload is sent again to a new one from
+    /// `new_url`, as B2 asks. Returns the URL that worked, which can take
+    /// more uploads.
+    async fn send_upload<F, U>(
+        &self,
+        operation: &str,
+        mut upload_url: Option<UploadUrl>,
+        new_url: F,
+        build: impl Fn(&UploadUrl) -> reqwest::RequestBuilder,
+    ) -> io::Result<UploadUrl>
+    where
+        F: Fn() -> U,
+        U: Future<Output = io::Result<UploadUrl>>,
+    {
+        let mut delay = RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let url = match upload_url.take() {
+                Some(url) => url,
+                None => new_url().await?,
+            };
+            let response = match send(build(&url)).await {
+                Ok(response) if response.is_success() => return Ok(url),
+                Ok(response) if response.status != 401 && !is_busy(response.status) => {
+                    return Err(response.error(operation))
+                }
+                result => result,
+            };
+            if attempt == MAX_ATTEMPTS {
+                return Err(match response {
+                    Ok(response) => response.error(operation),
+                    Err(e) => e,
+                });
+            }
+            debug!("{} failed, retrying with a new upload URL", operation);
+            delay = wait_to_retry(response.as_ref().ok(), delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Upload `data` as a new version of the file `name`.
+    async fn upload(&self, name: &str, data: &[u8]) -> StorageWrite {
+        let pooled = self.upload_urls.lock().unwrap().pop();
+        let sha1 = sha1_hex(data);
+        let new_url = || async {
+            let bucket_id = self.session().await?.bucket_id.clone();
+            self.call("b2_get_upload_url", json!({ "bucketId": bucket_id }))
+                .await
+        };
+        let upload_url = self
+            .send_upload(&format!("Uploading {}", name), pooled, new_url, |url| {
+                self.client
+                    .post(&url.upload_url)
+                    .header(AUTHORIZATION, &url.authorization_token)
+                    .header("X-Bz-File-Name", percent_encode(name, false))
+                    .header(CONTENT_TYPE, "b2/x-auto")
+                    .header("X-Bz-Content-Sha1", &sha1)
+                    .body(data.to_vec())
+            })
+            .await?;
+        self.upload_urls.lock().unwrap().push(upload_url);
+        Ok(())
+    }
+
+    /// Upload `data`, as a large file if it is larger than a part.
+    async fn put(&self, name: &str, data: &[u8]) -> StorageWrite {
+        if data.len() > self.session().await?.part_size {
+            return self.upload_stream(name, &mut &data[..]).await;
+        }
+        self.upload(name, data).await
+    }
+
+    /// Upload the content of `reader`, as a large file if it has more than
+    /// one part. At most two parts are held in memory.
+    async fn upload_stream(
+        &self,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let part_size = self.session().await?.part_size;
+        let first = read_part(reader, part_size).await?;
+        if first.len() < part_size {
+            return self.upload(name, &first).await;
+        }
+        // Large files need at least two parts.
+        let second = read_part(reader, part_size).await?;
+        if second.is_empty() {
+            return self.upload(name, &first).await;
+        }
+
+        let bucket_id = self.session().await?.bucket_id.clone();
+        let large_file: LargeFile = self
+            .call(
+                "b2_start_large_file",
+                json!({
+                    "bucketId": bucket_id,
+                    "fileName": name,
+                    "contentType": "b2/x-auto",
+                }),
+            )
+            .await?;
+        let file_id = large_file.file_id;
+        let result = match self
+            .upload_parts(name, &file_id, [first, second], reader, part_size)
+            .await
+        {
+            Ok(sha1s) => self
+                .call::<IgnoredAny>(
+                    "b2_finish_large_file",
+                    json!({ "fileId": file_id, "partSha1Array": sha1s }),
+                )
+                .await
+                .map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            // Parts are billed until the large file is cancelled.
+            if let Err(e) = self
+                .call::<IgnoredAny>("b2_cancel_large_file", json!({ "fileId": file_id }))
+                .await
+            {
+                warn!("Failed to cancel the upload of {}: {}", name, e);
+            }
+        }
+        result
+    }
+
+    /// Upload the parts of large file `file_id`, the first two already read,
+    /// and return their SHA-1 hashes.
+    async fn upload_parts(
+        &self,
+        name: &str,
+        file_id: &str,
+        [first, second]: [Vec<u8>; 2],
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+        part_size: usize,
+    ) -> io::Result<Vec<String>> {
+        let new_url = || async {
+            self.call("b2_get_upload_part_url", json!({ "fileId": file_id }))
+                .await
+        };
+        let mut upload_url = None;
+        let mut sha1s: Vec<String> = Vec::new();
+        let mut part = first;
+        let mut next = Some(second);
+        while !part.is_empty() {
+            if sha1s.len() == MAX_PARTS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} has more than {} parts", name, MAX_PARTS),
+                ));
+            }
+            let sha1 = sha1_hex(&part);
+            let number = sha1s.len() + 1;
+            let operation = format!("Uploading part {} of {}", number, name);
+            upload_url = Some(
+                self.send_upload(&operation, upload_url, new_url, |url| {
+                    self.client
+                        .post(&url.upload_url)
+                        .header(AUTHORIZATION, &url.authorization_token)
+                        .header("X-Bz-Part-Number", number)
+                        .header("X-Bz-Content-Sha1", &sha1)
+                        .body(part.clone())
+                })
+                .await?,
+            );
+            sha1s.push(sha1);
+            part = match next.take() {
+                Some(next) => next,
+                None => read_part(reader, part_size).await?,
+            };
+        }
+        Ok(sha1s)
+    }
+
+    /// Download the latest version of the file `name`.
+    async fn download(&self, name: &str) -> io::Result<Response> {
+        let operation = format!("Reading {}", name);
+        let mut session = self.session().await?;
+        let mut expired = false;
+        loop {
+            let url = format!(
+                "{}/file/{}/{}",
+                session.download_url,
+                percent_encode(&self.bucket, true),
+                percent_encode(name, false)
+            );
+            let response =
+                send_retrying(|| self.client.get(&url).header(AUTHORIZATION, &session.token))
+                    .await?;
+            if response.status == 401 && !expired {
+                self.expire(&session).await;
+                session = self.session().await?;
+                expired = true;
+                continue;
+            }
+            if !response.is_success() {
+                return Err(response.error(&operation));
+            }
+
+            // Large files have no hash of their whole content.
+            let sha1 = response
+                .header("X-Bz-Content-Sha1")
+                .map(|sha1| sha1.trim_start_matches("unverified:"))
+                .filter(|sha1| sha1.len() == 40);
+            if sha1.is_some_and(|sha1| sha1 != sha1_hex(&response.body)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: the content doesn't match its SHA-1", operation),
+                ));
+            }
+            return Ok(response);
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for B2Storage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let name = self.item_name(collection, key);
+        self.check_missing(&name).await?;
+        self.put(&name, data).await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let name = self.item_name(collection, key);
+        self.check_missing(&name).await?;
+        self.upload_stream(&name, reader).await
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        // Reads get the latest version, and the damaged one stays until
+        // the bucket's lifecycle rules remove it.
+        self.put(&self.item_name(collection, key), data).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        *buffer = self.download(&self.item_name(collection, key)).await?.body;
+        Ok(())
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        let name = self.item_name(collection, key);
+        let bucket_id = self.session().await?.bucket_id.clone();
+        // Every version is removed, as older ones would still be billed.
+        loop {
+            let list: FileList = self
+                .call(
+                    "b2_list_file_versions",
+                    json!({
+                        "bucketId": bucket_id,
+                        "startFileName": name,
+                        "prefix": name,
+                        "maxFileCount": 100,
+                    }),
+                )
+                .await?;
+            let versions: Vec<FileInfo> = list
+                .files
+                .into_iter()
+                .filter(|file| file.file_name == name)
+                .collect();
+            if versions.is_empty() {
+                return Ok(());
+            }
+            for version in versions {
+                let result = self
+                    .call::<IgnoredAny>(
+                        "b2_delete_file_version",
+                        json!({ "fileName": name, "fileId": version.file_id }),
+                    )
+                    .await;
+                match result {
+                    // Removed by someone else in the meantime.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => {
+                        result?;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        let name = format!("{}{}", self.prefix, REPO_ID_FILE);
+        self.check_missing(&name).await?;
+        self.upload(&name, format!("{}\n", repo_id).as_bytes())
+            .await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        // Parts of large files never finished are billed until the file is
+        // cancelled.
+        let older_than = as_unix_timestamp(older_than) * 1000;
+        let bucket_id = self.session().await?.bucket_id.clone();
+        let mut removed = 0;
+        let mut start_file_id: Option<String> = None;
+        loop {
+            let mut body = json!({
+                "bucketId": bucket_id,
+                "namePrefix": self.prefix,
+                "maxFileCount": 100,
+            });
+            if let Some(ref start_file_id) = start_file_id {
+                body["startFileId"] = json!(start_file_id);
+            }
+            let list: FileList = self.call("b2_list_unfinished_large_files", body).await?;
+            for file in list.files {
+                if file.upload_timestamp >= older_than {
+                    continue;
+                }
+                debug!("Cancelling the upload of {}", file.file_name);
+                self.call::<IgnoredAny>("b2_cancel_large_file", json!({ "fileId": file.file_id }))
+                    .await?;
+                removed += 1;
+            }
+            match list.next_file_id {
+                Some(next_file_id) => start_file_id = Some(next_file_id),
+                None => return Ok(removed),
+            }
+        }
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.get_collection_items_with_prefix(collection, "").await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        key_prefix: &str,
+    ) -> StorageItems {
+        let prefix = format!("{}{}/", self.prefix, collection.name());
+        let list_prefix = format!("{}{}", prefix, key_prefix);
+        let bucket_id = self.session().await?.bucket_id.clone();
+        let mut items = Vec::new();
+        let mut start_file_name: Option<String> = None;
+        loop {
+            let mut body = json!({
+                "bucketId": bucket_id,
+                "prefix": list_prefix,
+                "maxFileCount": LIST_PAGE_SIZE,
+            });
+            if let Some(ref start_file_name) = start_file_name {
+                body["startFileName"] = json!(start_file_name);
+            }
+            let list: FileList = self.call("b2_list_file_names", body).await?;
+            items.extend(
+                list.files
+                    .iter()
+                    .filter_map(|file| file.file_name.strip_prefix(&prefix))
+                    .map(|key| key.to_string()),
+            );
+            match list.next_file_name {
+                Some(next_file_name) => start_file_name = Some(next_file_name),
+                None => return Ok(items),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use hyper::body::Incoming;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::util::http::{percent_decode, read_body, response, serve, ServerResponse};
+
+    /// Large file that was started but not finished.
+    struct PendingLargeFile {
+        name: String,
+        /// Start time in milliseconds.
+        started: i64,
+        /// Uploaded parts by number.
+        parts: BTreeMap<u64, Vec<u8>>,
+    }
+
+    /// Files of the bucket and unfinished large files, with just enough of
+    /// the B2 API for the storage.
+    #[derive(Default)]
+    struct Bucket {
+        url: String,
+        /// Versions of each file, oldest first, as IDs and contents.
+        files: BTreeMap<String, Vec<(String, Vec<u8>)>>,
+        /// Large files by ID.
+        large_files: BTreeMap<String, PendingLargeFile>,
+        next_id: u64,
+        /// Token that API requests must carry, changed to expire it.
+        token: String,
+        /// Requests to answer with 503 before serving any.
+        busy: usize,
+        /// Large files that were finished.
+        finished_large_files: usize,
+    }
+
+    fn json_response(status: u16, value: serde_json::Value) -> ServerResponse {
+        response(status, value.to_string())
+    }
+
+    impl Bucket {
+        fn new_id(&mut self) -> String {
+            self.next_id += 1;
+            format!("id_{}", self.next_id)
+        }
+
+        fn list(&self, request: &serde_json::Value, versions: bool) -> serde_json::Value {
+            let prefix = request["prefix"].as_str().unwrap_or("");
+            let start = request["startFileName"].as_str().unwrap_or("");
+            let count = request["maxFileCount"].as_u64().unwrap_or(100) as usize;
+            let mut files = Vec::new();
+            let mut next_file_name = None;
+            for (name, file_versions) in self.files.range(start.to_string()..) {
+                if !name.starts_with(prefix) {
+                    continue;
+                }
+                let shown = match versions {
+                    true => &file_versions[..],
+                    false => &file_versions[file_versions.len() - 1..],
+                };
+                if files.len() + shown.len() > count {
+                    next_file_name = Some(name.clone());
+                    break;
+                }
+                for (id, _) in shown.iter().rev() {
+                    files.push(json!({ "fileName": name, "fileId": id }));
+                }
+            }
+            json!({ "files": files, "nextFileName": next_file_name })
+        }
+
+        fn handle_api(&mut self, operation: &str, request: serde_json::Value) -> ServerResponse {
+            let field = |name: &str| request[name].as_str().unwrap_or_default().to_string();
+            match operation {
+                "b2_list_buckets" if field("bucketName") == "bucket" => json_response(
+                    200,
+                    json!({ "buckets": [{ "bucketId": "bucket_id", "bucketName": "bucket" }] }),
+                ),
+                "b2_list_buckets" => json_response(200, json!({ "buckets": [] })),
+                "b2_get_upload_url" => json_response(
+                    200,
+                    json!({
+                        "uploadUrl": format!("{}/upload", self.url),
+                        "authorizationToken": "upload_token",
+                    }),
+                ),
+                "b2_list_file_names" => json_response(200, self.list(&request, false)),
+                "b2_list_file_versions" => json_response(200, self.list(&request, true)),
+                "b2_delete_file_version" => {
+                    let name = field("fileName");
+                    let id = field("fileId");
+                    let Some(versions) = self.files.get_mut(&name) else {
+                        return json_response(404, json!({ "code": "file_not_present" }));
+                    };
+                    versions.retain(|(version, _)| *version != id);
+                    if versions.is_empty() {
+                        self.files.remove(&name);
+                    }
+                    json_response(200, json!({ "fileId": id, "fileName": name }))
+                }
+                "b2_start_large_file" => {
+                    let id = self.new_id();
+                    let started = as_unix_timestamp(SystemTime::now()) * 1000;
+                    self.large_files.insert(
+                        id.clone(),
+                        PendingLargeFile {
+                            name: field("fileName"),
+                            started,
+                            parts: BTreeMap::new(),
+                        },
+                    );
+                    json_response(200, json!({ "fileId": id }))
+                }
+                "b2_get_upload_part_url" => json_response(
+                    200,
+                    json!({
+                        "fileId": field("fileId"),
+                        "uploadUrl": format!("{}/upload_part/{}", self.url, field("fileId")),
+                        "authorizationToken": "upload_token",
+                    }),
+                ),
+                "b2_finish_large_file" => {
+                    let id = field("fileId");
+                    let Some(file) = self.large_files.remove(&id) else {
+                        return json_response(400, json!({ "code": "bad_request" }));
+                    };
+                    let sha1s: Vec<String> =
+                        file.parts.values().map(|part| sha1_hex(part)).collect();
+                    if request["partSha1Array"] != json!(sha1s) || file.parts.len() < 2 {
+                        return json_response(400, json!({ "code": "bad_request" }));
+                    }
+                    let data = file.parts.into_values().flatten().collect();
+                    self.files
+                        .entry(file.name)
+                        .or_default()
+                        .push((id.clone(), data));
+                    self.finished_large_files += 1;
+                    json_response(200, json!({ "fileId": id }))
+                }
+                "b2_cancel_large_file" => {
+                    self.large_files.remove(&field("fileId"));
+                    json_response(200, json!({ "fileId": field("fileId") }))
+                }
+                "b2_list_unfinished_large_files" => {
+                    let files: Vec<_> = self
+                        .large_files
+                        .iter()
+                        .map(|(id, file)| {
+                            json!({
+                                "fileId": id,
+                                "fileName": file.name,
+                                "uploadTimestamp": file.started,
+                            })
+                        })
+                        .collect();
+                    json_response(200, json!({ "files": files, "nextFileId": null }))
+                }
+                _ => json_response(400, json!({ "code": "bad_request" })),
+            }
+        }
+    }
+
+    async fn handle_request(
+        bucket: Arc<StdMutex<Bucket>>,
+        request: hyper::Request<Incoming>,
+    ) -> ServerResponse {
+        let path = request.uri().path().to_string();
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let (authorization, file_name, sha1, part_number) = (
+            header("Authorization"),
+            percent_decode(&header("X-Bz-File-Name")),
+            header("X-Bz-Content-Sha1"),
+            header("X-Bz-Part-Number")
+                .parse::<u64>()
+                .unwrap_or_default(),
+        );
+        let body = match read_body(request, u64::MAX).await {
+            Ok(body) => body.to_vec(),
+            Err(response) => return response,
+        };
+
+        let mut bucket = bucket.lock().unwrap();
+        if bucket.busy > 0 {
+            bucket.busy -= 1;
+            let mut response = json_response(503, json!({ "code": "service_unavailable" }));
+            response
+                .headers_mut()
+                .insert("Retry-After", "0".parse().unwrap());
+            return response;
+        }
+        if path == "/b2api/v2/b2_authorize_account" {
+            if authorization != "Basic a2V5X2lkOmtleQ==" {
+                return json_response(401, json!({ "code": "unauthorized" }));
+            }
+            bucket.token = format!("token_{}", bucket.new_id());
+            return json_response(
+                200,
+                json!({
+                    "accountId": "account",
+                    "authorizationToken": bucket.token,
+                    "apiUrl": bucket.url,
+                    "downloadUrl": bucket.url,
+                    "recommendedPartSize": 1000,
+                    "allowed": { "bucketId": null, "bucketName": null },
+                }),
+            );
+        }
+        if let Some(operation) = path.strip_prefix("/b2api/v2/") {
+            if authorization != bucket.token {
+                return json_response(401, json!({ "code": "expired_auth_token" }));
+            }
+            let request = serde_json::from_slice(&body).unwrap_or_default();
+            return bucket.handle_api(operation, request);
+        }
+        if let Some(name) = path.strip_prefix("/file/bucket/") {
+            if authorization != bucket.token {
+                return json_response(401, json!({ "code": "expired_auth_token" }));
+            }
+            return match bucket.files.get(&percent_decode(name)) {
+                Some(versions) => {
+                    let data = versions.last().unwrap().1.clone();
+                    let mut response = response(200, data.clone());
+                    response
+                        .headers_mut()
+                        .insert("X-Bz-Content-Sha1", sha1_hex(&data).parse().unwrap());
+                    response
+                }
+                None => json_response(404, json!({ "code": "not_found" })),
+            };
+        }
+        if authorization != "upload_token" || sha1 != sha1_hex(&body) {
+            return json_response(400, json!({ "code": "bad_request" }));
+        }
+        if path == "/upload" {
+            let id = bucket.new_id();
+            bucket.files.entry(file_name).or_default().push((id, body));
+            return json_response(200, json!({}));
+        }
+        if let Some(id) = path.strip_prefix("/upload_part/") {
+            return match bucket.large_files.get_mut(id) {
+                Some(file) => {
+                    file.parts.insert(part_number, body);
+                    json_response(200, json!({}))
+                }
+                None => json_response(400, json!({ "code": "bad_request" })),
+            };
+        }
+        response(404, "")
+    }
+
+    struct B2StorageTestState {
+        storage: B2Storage,
+        bucket: Arc<StdMutex<Bucket>>,
+    }
+
+    impl B2StorageTestState {
+        async fn new() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let bucket = Arc::new(StdMutex::new(Bucket {
+                url: url.clone(),
+                ..Default::default()
+            }));
+            let served = bucket.clone();
+            tokio::spawn(serve(listener, None, move |request| {
+                handle_request(served.clone(), request)
+            }));
+
+            let storage = B2Storage::from_config(&B2StorageConfig {
+                bucket: "bucket".to_string(),
+                prefix: "backups".to_string(),
+                key_id: Some("key_id".to_string()),
+                application_key: Some("key".to_string()),
+                endpoint: Some(url),
+            })
+            .unwrap();
+            Self { storage, bucket }
+        }
+    }
+
+    storage_tests!(B2StorageTestState);
+
+    #[tokio::test]
+    async fn write_existing_returns_already_exists() {
+        let state = B2StorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        let error = state
+            .storage
+            .write(Collection::Blob, "key_1", b"2")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn large_items_are_uploaded_in_parts() {
+        let state = B2StorageTestState::new().await;
+        let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        state
+            .storage
+            .write_stream(Collection::Blob, "large", &mut &data[..])
+            .await
+            .unwrap();
+        // Exactly one part is uploaded as a normal file.
+        state
+            .storage
+            .write(Collection::Blob, "one_part", &data[..1000])
+            .await
+            .unwrap();
+        {
+            let bucket = state.bucket.lock().unwrap();
+            assert_eq!(bucket.finished_large_files, 1);
+            assert!(bucket.large_files.is_empty());
+            assert_eq!(bucket.files["backups/blob/large"][0].1, data);
+        }
+
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "large", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, data);
+    }
+
+    #[tokio::test]
+    async fn busy_requests_are_retried() {
+        let state = B2StorageTestState::new().await;
+        state.bucket.lock().unwrap().busy = 3;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        state.bucket.lock().unwrap().busy = 2;
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"1");
+    }
+
+    #[tokio::test]
+    async fn expired_authorization_is_renewed() {
+        let state = B2StorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Snapshot, "key_1", b"1")
+            .await
+            .unwrap();
+        state.bucket.lock().unwrap().token = "expired".to_string();
+        assert_eq!(
+            state
+                .storage
+                .get_collection_items(Collection::Snapshot)
+                .await
+                .unwrap(),
+            ["key_1"]
+        );
+        state.bucket.lock().unwrap().token = "expired".to_string();
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Snapshot, "key_1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"1");
+    }
+
+    #[tokio::test]
+    async fn delete_removes_every_version() {
+        let state = B2StorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Snapshot, "key_1", b"1")
+            .await
+            .unwrap();
+        state
+            .storage
+            .replace(Collection::Snapshot, "key_1", b"2")
+            .await
+            .unwrap();
+        state
+            .storage
+            .delete(Collection::Snapshot, "key_1")
+            .await
+            .unwrap();
+        assert!(state.bucket.lock().unwrap().files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn init_writes_repo_id_once() {
+        let state = B2StorageTestState::new().await;
+        state.storage.init("repo-1").await.unwrap();
+        let error = state.storage.init("repo-2").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            state.bucket.lock().unwrap().files["backups/repo-id"][0].1,
+            b"repo-1\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn clean_temporary_cancels_unfinished_large_files() {
+        let state = B2StorageTestState::new().await;
+        let started: LargeFile = state
+            .storage
+            .call(
+                "b2_start_large_file",
+                json!({ "bucketId": "bucket_id", "fileName": "backups/blob/large" }),
+            )
+            .await
+            .unwrap();
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        assert_eq!(state.storage.clean_temporary(hour_ago).await.unwrap(), 0);
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        assert_eq!(state.storage.clean_temporary(later).await.unwrap(), 1);
+        assert!(!state
+            .bucket
+            .lock()
+            .unwrap()
+            .large_files
+            .contains_key(&started.file_id));
+    }
+}
@@ -0,0 +1,488 @@
+use std::{io::Cursor, path::Path, time::SystemTime};
+
+use async_compression::{
+    tokio::bufread::{ZstdDecoder, ZstdEncoder},
+    Level,
+};
+use async_trait::async_trait;
+use prost::Message;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, BufReader},
+    sync::OnceCell,
+};
+use tracing::debug;
+
+use crate::{data::backup::RepositoryFormat, util::hash::run_blocking};
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+/// Key of the repository format in the config collection.
+const FORMAT_KEY: &str = "format";
+/// Blobs are stored as they are, as in repositories from before compression.
+const BLOB_FORMAT_PLAIN: u32 = 0;
+/// Blobs start with one of the codec bytes below.
+const BLOB_FORMAT_CODEC: u32 = 1;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_ZSTD: u8 = 1;
+
+/// Larger blobs are only compressed if this much of their start compresses
+/// by at least a thirtieth, so that compressed media isn't compressed again.
+const SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Storage wrapper that compresses blobs with zstd, leaving the other
+/// collections as they are. Blob keys stay the hashes of the uncompressed
+/// data, so compressed and uncompressed blobs deduplicate against each other.
+///
+/// Blobs written with `write_uncompressed`, and larger ones whose start
+/// doesn't compress, are stored as they are. Streamed blobs larger than two
+/// samples are compressed as they are read, and stay compressed even if that
+/// doesn't make them smaller.
+///
+/// Each blob starts with a byte naming its codec. Repositories with blobs
+/// from before compression have no format recorded, and their blobs are
+/// read and written as they are. Empty repositories are given the codec
+/// format by their first blob write.
+pub struct CompressedStorage {
+    inner: Box<dyn Storage>,
+    /// zstd level, or 0 to store blobs uncompressed.
+    level: i32,
+    blob_format: OnceCell<u32>,
+}
+
+impl CompressedStorage {
+    pub fn new(inner: Box<dyn Storage>, level: i32) -> Self {
+        Self {
+            inner,
+            level,
+            blob_format: OnceCell::new(),
+        }
+    }
+
+    /// Blob format of the repository. Repositories without a recorded format
+    /// are from before compression if they have blobs, and are recorded as
+    /// such so that the blobs are listed only once. Empty ones are given the
+    /// codec format when `writing`.
+    async fn blob_format(&self, writing: bool) -> io::Result<u32> {
+        if let Some(format) = self.blob_format.get() {
+            return Ok(*format);
+        }
+
+        let mut buffer = Vec::new();
+        let format = loop {
+            match self
+                .inner
+                .read(Collection::Config, FORMAT_KEY, &mut buffer)
+                .await
+            {
+                Ok(()) => {
+                    let format = RepositoryFormat::decode(buffer.as_slice())
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    break format.blob_format;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+
+            let has_blobs = !self
+                .inner
+                .get_collection_items(Collection::Blob)
+                .await?
+                .is_empty();
+            let blob_format = match (has_blobs, writing) {
+                (true, _) => BLOB_FORMAT_PLAIN,
+                (false, true) => BLOB_FORMAT_CODEC,
+                // Nothing to read yet, and a later write decides the format.
+                (false, false) => return Ok(BLOB_FORMAT_PLAIN),
+            };
+            let format = RepositoryFormat { blob_format };
+            match self
+                .inner
+                .write(Collection::Config, FORMAT_KEY, &format.encode_to_vec())
+                .await
+            {
+                Ok(()) => break blob_format,
+                // Another client recorded the format first.
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                // Recording the format of an old repository only saves a
+                // listing, so e.g. read-only credentials can do without.
+                Err(e) if has_blobs => {
+                    debug!("Failed to record the repository format: {}", e);
+                    break blob_format;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        if format > BLOB_FORMAT_CODEC {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Repository has blob format {}, which this version can't read",
+                    format
+                ),
+            ));
+        }
+        Ok(*self.blob_format.get_or_init(|| async { format }).await)
+    }
+
+    async fn encode(&self, data: &[u8], compress: bool) -> io::Result<Vec<u8>> {
+        if self.blob_format(true).await? == BLOB_FORMAT_PLAIN {
+            return Ok(data.to_vec());
+        }
+        let level = if compress { self.level } else { 0 };
+        if level == 0 {
+            return encode_blob(data, 0);
+        }
+        let data = data.to_vec();
+        run_blocking(move || encode_blob(&data, level)).await?
+    }
+}
+
+/// Whether compressing the start of `data` saves enough to be worth
+/// compressing all of it.
+fn sample_compresses(data: &[u8], level: i32) -> io::Result<bool> {
+    if data.len() <= SAMPLE_SIZE * 2 {
+        return Ok(true);
+    }
+    let sample = zstd::bulk::compress(&data[..SAMPLE_SIZE], level)?;
+    Ok(sample.len() < SAMPLE_SIZE - SAMPLE_SIZE / 30)
+}
+
+/// Blob as stored in the codec format: a codec byte and the data, which is
+/// compressed at `level` unless that doesn't make it smaller.
+fn encode_blob(data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    if level != 0 && sample_compresses(data, level)? {
+        let mut encoded = vec![CODEC_ZSTD];
+        zstd::stream::copy_encode(data, &mut encoded, level)?;
+        if encoded.len() <= data.len() {
+            return Ok(encoded);
+        }
+    }
+    let mut encoded = Vec::with_capacity(data.len() + 1);
+    encoded.push(CODEC_NONE);
+    encoded.extend_from_slice(data);
+    Ok(encoded)
+}
+
+/// Data of a blob stored in the codec format.
+fn decode_blob(stored: &[u8]) -> io::Result<Vec<u8>> {
+    match stored.split_first() {
+        Some((&CODEC_NONE, data)) => Ok(data.to_vec()),
+        Some((&CODEC_ZSTD, data)) => zstd::stream::decode_all(data),
+        Some((codec, _)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Blob has unknown codec {}", codec),
+        )),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Blob has no codec",
+        )),
+    }
+}
+
+#[async_trait]
+impl Storage for CompressedStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection != Collection::Blob {
+            return self.inner.write(collection, key, data).await;
+        }
+        let encoded = self.encode(data, true).await?;
+        self.inner.write(collection, key, &encoded).await
+    }
+
+    async fn write_uncompressed(
+        &self,
+        collection: Collection,
+        key: &str,
+        data: &[u8],
+    ) -> StorageWrite {
+        if collection != Collection::Blob {
+            return self.inner.write(collection, key, data).await;
+        }
+        let encoded = self.encode(data, false).await?;
+        self.inner.write(collection, key, &encoded).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        self.inner.read(collection, key, buffer).await?;
+        if collection != Collection::Blob || self.blob_format(false).await? == BLOB_FORMAT_PLAIN {
+            return Ok(());
+        }
+        let stored = std::mem::take(buffer);
+        *buffer = run_blocking(move || decode_blob(&stored)).await??;
+        Ok(())
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.inner.get_collection_items(collection).await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        self.inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        if collection != Collection::Blob || self.blob_format(true).await? == BLOB_FORMAT_PLAIN {
+            return self.inner.write_stream(collection, key, reader).await;
+        }
+        // Small blobs are compressed as a whole, so that they are stored
+        // as they are when compressing doesn't help.
+        let mut start = Vec::with_capacity(SAMPLE_SIZE * 2 + 1);
+        (&mut *reader)
+            .take(SAMPLE_SIZE as u64 * 2 + 1)
+            .read_to_end(&mut start)
+            .await?;
+        if start.len() <= SAMPLE_SIZE * 2 {
+            return self.write(collection, key, &start).await;
+        }
+
+        let level = self.level;
+        let (start, compress) = run_blocking(move || {
+            let compress = level != 0 && sample_compresses(&start, level)?;
+            Ok::<_, io::Error>((start, compress))
+        })
+        .await??;
+        let data = Cursor::new(start).chain(reader);
+        if !compress {
+            let mut stored = Cursor::new([CODEC_NONE]).chain(data);
+            return self.inner.write_stream(collection, key, &mut stored).await;
+        }
+        let encoder = ZstdEncoder::with_quality(BufReader::new(data), Level::Precise(level));
+        let mut stored = Cursor::new([CODEC_ZSTD]).chain(encoder);
+        self.inner.write_stream(collection, key, &mut stored).await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        let mut stored = self.inner.read_stream(collection, key).await?;
+        if collection != Collection::Blob || self.blob_format(false).await? == BLOB_FORMAT_PLAIN {
+            return Ok(stored);
+        }
+        let codec = match stored.read_u8().await {
+            Ok(codec) => codec,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Blob has no codec",
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        match codec {
+            CODEC_NONE => Ok(stored),
+            CODEC_ZSTD => Ok(Box::new(ZstdDecoder::new(BufReader::new(stored)))),
+            codec => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Blob has unknown codec {}", codec),
+            )),
+        }
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection != Collection::Blob {
+            return self.inner.replace(collection, key, data).await;
+        }
+        let encoded = self.encode(data, true).await?;
+        self.inner.replace(collection, key, &encoded).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.inner.delete(collection, key).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        self.inner.has_many(collection, keys).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    struct CompressedStorageTestState {
+        storage: CompressedStorage,
+    }
+
+    impl CompressedStorageTestState {
+        async fn new() -> Self {
+            Self {
+                storage: CompressedStorage::new(Box::new(MemoryStorage::new()), 3),
+            }
+        }
+    }
+
+    storage_tests!(CompressedStorageTestState);
+
+    #[tokio::test]
+    async fn blobs_are_compressed() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = CompressedStorage::new(Box::new(inner.clone()), 3);
+        let text = b"Hello World! ".repeat(1000);
+        storage
+            .write(Collection::Blob, "text", &text)
+            .await
+            .unwrap();
+        // Random data doesn't get smaller, so it is stored as it is.
+        let random: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+        storage
+            .write_uncompressed(Collection::Blob, "skipped", &text)
+            .await
+            .unwrap();
+        // Only the start of larger blobs is tried.
+        let mut mixed: Vec<u8> = (0..SAMPLE_SIZE * 2).map(|_| rand::random()).collect();
+        mixed.extend_from_slice(&text);
+        storage
+            .write(Collection::Blob, "mixed", &mixed)
+            .await
+            .unwrap();
+        storage
+            .write(Collection::Blob, "random", &random)
+            .await
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        inner
+            .read(Collection::Blob, "text", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer[0], CODEC_ZSTD);
+        assert!(buffer.len() < text.len() / 10);
+        inner
+            .read(Collection::Blob, "random", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer[0], CODEC_NONE);
+        assert_eq!(buffer.len(), random.len() + 1);
+        for (key, data) in [("skipped", &text), ("mixed", &mixed)] {
+            inner
+                .read(Collection::Blob, key, &mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(buffer[0], CODEC_NONE);
+            assert_eq!(&buffer[1..], data.as_slice());
+        }
+
+        // A new instance reads the format from the storage.
+        let storage = CompressedStorage::new(Box::new(inner), 0);
+        storage
+            .read(Collection::Blob, "text", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, text);
+        storage
+            .read(Collection::Blob, "random", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, random);
+    }
+
+    #[tokio::test]
+    async fn old_repositories_stay_uncompressed() {
+        let inner = Arc::new(MemoryStorage::new());
+        inner
+            .write(Collection::Blob, "old", b"Old blob")
+            .await
+            .unwrap();
+
+        let storage = CompressedStorage::new(Box::new(inner.clone()), 3);
+        let mut buffer = Vec::new();
+        storage
+            .read(Collection::Blob, "old", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"Old blob");
+        let text = b"Hello World! ".repeat(1000);
+        storage.write(Collection::Blob, "new", &text).await.unwrap();
+        inner
+            .read(Collection::Blob, "new", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, text);
+
+        // The format is recorded, so the next instance doesn't list blobs.
+        inner
+            .read(Collection::Config, FORMAT_KEY, &mut buffer)
+            .await
+            .unwrap();
+        let format = RepositoryFormat::decode(buffer.as_slice()).unwrap();
+        assert_eq!(format.blob_format, BLOB_FORMAT_PLAIN);
+    }
+
+    #[tokio::test]
+    async fn large_streams_are_compressed_as_read() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = CompressedStorage::new(Box::new(inner.clone()), 3);
+        let text = b"Hello World! ".repeat(SAMPLE_SIZE);
+        let random: Vec<u8> = (0..SAMPLE_SIZE * 3).map(|_| rand::random()).collect();
+        for (key, data) in [("text", &text), ("random", &random)] {
+            storage
+                .write_stream(Collection::Blob, key, &mut data.as_slice())
+                .await
+                .unwrap();
+        }
+
+        let mut buffer = Vec::new();
+        inner
+            .read(Collection::Blob, "text", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer[0], CODEC_ZSTD);
+        assert!(buffer.len() < text.len() / 10);
+        inner
+            .read(Collection::Blob, "random", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer[0], CODEC_NONE);
+
+        for (key, data) in [("text", &text), ("random", &random)] {
+            storage
+                .read(Collection::Blob, key, &mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(&buffer, data);
+            buffer.clear();
+            storage
+                .read_stream(Collection::Blob, key)
+                .await
+                .unwrap()
+                .read_to_end(&mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(&buffer, data);
+        }
+    }
+}
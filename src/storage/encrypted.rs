@@ -0,0 +1,469 @@
+use std::{io::Cursor, path::Path, time::SystemTime};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use async_trait::async_trait;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use prost::Message;
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    RngCore,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt},
+    sync::OnceCell,
+};
+
+use crate::{
+    data::backup::Key,
+    util::{hash::run_blocking, time::as_unix_timestamp},
+};
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// Version byte that starts every encrypted item.
+const ITEM_VERSION: u8 = 1;
+/// Bytes that encryption adds to an item.
+const OVERHEAD: u64 = (1 + NONCE_SIZE + TAG_SIZE) as u64;
+
+/// Cost of deriving a key from a passphrase with Argon2id.
+#[derive(Debug, Clone, Copy)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+        }
+    }
+}
+
+/// Master key of a repository, which encrypts every item but the keys.
+#[derive(Clone)]
+pub struct MasterKey([u8; 32]);
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new((&self.0).into())
+    }
+}
+
+/// Key derived from `passphrase` with the salt and cost of `key`.
+fn derive_key(passphrase: &str, key: &Key) -> io::Result<XChaCha20Poly1305> {
+    let params = Params::new(key.memory_kib, key.iterations, key.parallelism, Some(32))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut derived = [0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), &key.salt, &mut derived)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(XChaCha20Poly1305::new(&derived.into()))
+}
+
+fn encrypt(cipher: &XChaCha20Poly1305, data: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| io::Error::other("Failed to encrypt"))?;
+    let mut item = Vec::with_capacity(data.len() + OVERHEAD as usize);
+    item.push(ITEM_VERSION);
+    item.extend_from_slice(&nonce);
+    item.extend_from_slice(&ciphertext);
+    Ok(item)
+}
+
+fn decrypt(cipher: &XChaCha20Poly1305, item: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let Some((&version, rest)) = item.split_first() else {
+        return Err(invalid("Encrypted item is empty"));
+    };
+    if version != ITEM_VERSION {
+        return Err(invalid(&format!(
+            "Encrypted item has unknown version {}",
+            version
+        )));
+    }
+    if rest.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(invalid("Encrypted item is truncated"));
+    }
+    let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| invalid("Encrypted item is damaged or was moved"))
+}
+
+/// Key item that opens `master_key` with `passphrase`.
+pub async fn wrap_master_key(
+    master_key: &MasterKey,
+    passphrase: &str,
+    params: KdfParams,
+    client_id: &str,
+) -> io::Result<Key> {
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut key = Key {
+        salt,
+        memory_kib: params.memory_kib,
+        iterations: params.iterations,
+        parallelism: 1,
+        encrypted_key: Vec::new(),
+        created: as_unix_timestamp(SystemTime::now()),
+        client_id: client_id.to_string(),
+    };
+    let master_key = master_key.clone();
+    let passphrase = passphrase.to_string();
+    run_blocking(move || {
+        let cipher = derive_key(&passphrase, &key)?;
+        key.encrypted_key = encrypt(&cipher, &master_key.0, b"key")?;
+        Ok(key)
+    })
+    .await?
+}
+
+/// Master key in `key` if `passphrase` opens it.
+pub async fn unwrap_master_key(key: &Key, passphrase: &str) -> io::Result<Option<MasterKey>> {
+    let key = key.clone();
+    let passphrase = passphrase.to_string();
+    run_blocking(move || {
+        let cipher = derive_key(&passphrase, &key)?;
+        let Ok(master_key) = decrypt(&cipher, &key.encrypted_key, b"key") else {
+            return Ok(None);
+        };
+        let master_key = master_key.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "Master key has the wrong size")
+        })?;
+        Ok(Some(MasterKey(master_key)))
+    })
+    .await?
+}
+
+/// Keys of the repository by their names, none if it isn't encrypted.
+pub async fn read_keys(storage: &dyn Storage) -> io::Result<Vec<(String, Key)>> {
+    let names = match storage.get_collection_items(Collection::Key).await {
+        Ok(names) => names,
+        // Servers of many repositories don't know a new one yet.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut keys = Vec::with_capacity(names.len());
+    let mut buffer = Vec::new();
+    for name in names {
+        storage.read(Collection::Key, &name, &mut buffer).await?;
+        let key = Key::decode(buffer.as_slice())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        keys.push((name, key));
+    }
+    Ok(keys)
+}
+
+/// Master key that one of `keys` opens with `passphrase`.
+pub async fn unlock(keys: &[(String, Key)], passphrase: &str) -> io::Result<MasterKey> {
+    for (_, key) in keys {
+        if let Some(master_key) = unwrap_master_key(key, passphrase).await? {
+            return Ok(master_key);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "No key of the repository opens with the passphrase",
+    ))
+}
+
+/// Store `key` under a new random name, which is returned.
+pub async fn add_key(storage: &dyn Storage, key: &Key) -> io::Result<String> {
+    let name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+    storage
+        .write(Collection::Key, &name, &key.encode_to_vec())
+        .await?;
+    Ok(name)
+}
+
+/// Storage wrapper that encrypts every item with XChaCha20-Poly1305 under
+/// the master key of the repository, except the keys themselves. Each item
+/// is bound to its collection and key, so that items can't be swapped
+/// unnoticed. The names of items, which include the hashes of blobs, are
+/// not encrypted.
+///
+/// A repository without keys gets its master key when it is created with
+/// `init`, and nothing can be read or written before that.
+pub struct EncryptedStorage {
+    inner: Box<dyn Storage>,
+    cipher: OnceCell<XChaCha20Poly1305>,
+    /// Passphrase and cost for the key of a new repository.
+    passphrase: String,
+    kdf: KdfParams,
+    client_id: String,
+}
+
+impl EncryptedStorage {
+    /// Open the repository in `inner`, which has `keys`, with `passphrase`.
+    /// Fails if it has keys but none opens with the passphrase.
+    pub async fn open(
+        inner: Box<dyn Storage>,
+        keys: &[(String, Key)],
+        passphrase: &str,
+        kdf: KdfParams,
+        client_id: &str,
+    ) -> io::Result<Self> {
+        let cipher = OnceCell::new();
+        if !keys.is_empty() {
+            _ = cipher.set(unlock(keys, passphrase).await?.cipher());
+        }
+        Ok(Self {
+            inner,
+            cipher,
+            passphrase: passphrase.to_string(),
+            kdf,
+            client_id: client_id.to_string(),
+        })
+    }
+
+    fn cipher(&self) -> io::Result<&XChaCha20Poly1305> {
+        self.cipher.get().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Repository has no keys although the config asks for encryption, create it with repo init",
+            )
+        })
+    }
+
+    fn encrypt(&self, collection: Collection, key: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        encrypt(self.cipher()?, data, item_aad(collection, key).as_bytes())
+    }
+}
+
+fn item_aad(collection: Collection, key: &str) -> String {
+    format!("{}/{}", collection.name(), key)
+}
+
+#[async_trait]
+impl Storage for EncryptedStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection == Collection::Key {
+            return self.inner.write(collection, key, data).await;
+        }
+        let item = self.encrypt(collection, key, data)?;
+        self.inner.write(collection, key, &item).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        self.inner.read(collection, key, buffer).await?;
+        if collection == Collection::Key {
+            return Ok(());
+        }
+        let cipher = self.cipher()?.clone();
+        let aad = item_aad(collection, key);
+        let item = std::mem::take(buffer);
+        *buffer = run_blocking(move || decrypt(&cipher, &item, aad.as_bytes())).await??;
+        Ok(())
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.inner.get_collection_items(collection).await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        self.inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    // Items are encrypted as a whole, so their streams are held in memory.
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await?;
+        self.write(collection, key, &data).await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        let mut buffer = Vec::new();
+        self.read(collection, key, &mut buffer).await?;
+        Ok(Box::new(Cursor::new(buffer)))
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection == Collection::Key {
+            return self.inner.replace(collection, key, data).await;
+        }
+        let item = self.encrypt(collection, key, data)?;
+        self.inner.replace(collection, key, &item).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.inner.delete(collection, key).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        self.inner.has_many(collection, keys).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    // A new repository gets its master key along with its ID. The key is
+    // written first, so that no repository exists without one.
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        if self.cipher.get().is_none() {
+            if !read_keys(&*self.inner).await?.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "Repository already has keys",
+                ));
+            }
+            let master_key = MasterKey::generate();
+            let key =
+                wrap_master_key(&master_key, &self.passphrase, self.kdf, &self.client_id).await?;
+            add_key(&*self.inner, &key).await?;
+            _ = self.cipher.set(master_key.cipher());
+        }
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner
+            .max_object_size()
+            .map(|max| max.saturating_sub(OVERHEAD))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    /// Cheap enough for debug builds.
+    const TEST_KDF: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+    };
+
+    struct EncryptedStorageTestState {
+        storage: EncryptedStorage,
+    }
+
+    impl EncryptedStorageTestState {
+        async fn new() -> Self {
+            let storage = EncryptedStorage::open(
+                Box::new(MemoryStorage::new()),
+                &[],
+                "passphrase",
+                TEST_KDF,
+                "test_client",
+            )
+            .await
+            .unwrap();
+            storage.init("test").await.unwrap();
+            Self { storage }
+        }
+    }
+
+    storage_tests!(EncryptedStorageTestState);
+
+    #[tokio::test]
+    async fn items_are_encrypted() {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = EncryptedStorage::open(
+            Box::new(inner.clone()),
+            &[],
+            "passphrase",
+            TEST_KDF,
+            "client",
+        )
+        .await
+        .unwrap();
+        // Nothing is stored before the repository has a key.
+        let error = storage
+            .write(Collection::Snapshot, "test/1", b"Hello World!")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        storage.init("test").await.unwrap();
+        storage
+            .write(Collection::Snapshot, "test/1", b"Hello World!")
+            .await
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        inner
+            .read(Collection::Snapshot, "test/1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer.len(), 12 + OVERHEAD as usize);
+        assert!(!buffer.windows(5).any(|window| window == b"Hello"));
+
+        // An item moved to another key doesn't decrypt.
+        inner
+            .write(Collection::Snapshot, "test/2", &buffer)
+            .await
+            .unwrap();
+        let error = storage
+            .read(Collection::Snapshot, "test/2", &mut buffer)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // The key is read back with the passphrase.
+        let keys = read_keys(&*inner).await.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].1.client_id, "client");
+        let storage = EncryptedStorage::open(
+            Box::new(inner.clone()),
+            &keys,
+            "passphrase",
+            TEST_KDF,
+            "client",
+        )
+        .await
+        .unwrap();
+        storage
+            .read(Collection::Snapshot, "test/1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"Hello World!");
+
+        let error = EncryptedStorage::open(Box::new(inner), &keys, "wrong", TEST_KDF, "client")
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -2,13 +2,14 @@ use std::mem::ManuallyDrop;
 use std::ops::DerefMut;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::time::{Duration, SystemTime};
 
 use async_recursion::async_recursion;
-use log::warn;
 use tokio::{
     fs::{self, read_dir, File, OpenOptions},
-    io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 };
+use tracing::warn;
 
 use async_trait::async_trait;
 
@@ -16,13 +17,20 @@ use rand::distributions::{Alphanumeric, DistString};
 
 use crate::data::config::FileStorageConfig;
 use crate::storage::util::base16_decode;
+use crate::util::time::as_unix_timestamp;
 
 use super::util::{base16_encode, xor_byte_hash};
-use super::{Collection, Storage, StorageItems, StorageRead, StorageWrite};
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+// File in the repository root holding the repository ID.
+pub const REPO_ID_FILE: &str = "repo-id";
+// Directory in the repository root that deleted items are moved to.
+const TRASH_DIR: &str = "trash";
 
 pub struct FileStorage {
     root: PathBuf,
     tmp_dir: PathBuf,
+    trash: Option<Duration>,
 }
 
 impl FileStorage {
@@ -30,22 +38,191 @@ impl FileStorage {
         let tmp_dir = root.join("tmp");
         fs::create_dir_all(&tmp_dir).await?;
 
-        Ok(Self { root, tmp_dir })
+        Ok(Self {
+            root,
+            tmp_dir,
+            trash: None,
+        })
     }
 
     pub async fn from_config(config_path: &Path, config: &FileStorageConfig) -> io::Result<Self> {
-        let root = config_path.parent().unwrap().join(&config.path);
-        return Self::new(root).await;
+        let path = config_path.parent().unwrap().join(&config.path);
+        if config.discover {
+            let root = discover_repository(&path, &config.repo_ids).await?;
+            return Ok(Self::new(root).await?.with_trash(config.trash));
+        }
+
+        if !config.repo_ids.is_empty() {
+            match read_repo_id(&path).await? {
+                Some(repo_id) if config.repo_ids.contains(&repo_id) => {}
+                Some(repo_id) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Repository {} has ID {}, which is not in repo_ids",
+                            path.display(),
+                            repo_id
+                        ),
+                    ))
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No repository ID in {}", path.display()),
+                    ))
+                }
+            }
+        }
+        Ok(Self::new(path).await?.with_trash(config.trash))
+    }
+
+    /// Move deleted items to the trash directory instead of removing them,
+    /// and remove them from there in clean_temporary once they have been in
+    /// the trash for `retention`.
+    pub fn with_trash(mut self, retention: Option<Duration>) -> Self {
+        self.trash = retention;
+        self
+    }
+
+    async fn move_to_trash(&self, collection: Collection, key: &str) -> StorageWrite {
+        let path = get_item_path(&self.root, collection, key)?;
+        let trash_dir = self.root.join(TRASH_DIR).join(collection.name());
+        fs::create_dir_all(&trash_dir).await?;
+        // The deletion time is part of the name, as renaming keeps the
+        // modification time of the item.
+        let deleted = as_unix_timestamp(SystemTime::now());
+        let trash_path = trash_dir.join(format!("{}-{}", deleted, base16_encode(key)));
+        match fs::rename(path, trash_path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Remove the items that were moved to the trash before `deleted_before`.
+    async fn purge_trash(&self, deleted_before: SystemTime) -> io::Result<u64> {
+        let deleted_before = as_unix_timestamp(deleted_before);
+        let mut removed = 0;
+        let mut collections = match read_dir(self.root.join(TRASH_DIR)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            result => result?,
+        };
+        while let Some(collection) = collections.next_entry().await? {
+            let mut entries = read_dir(collection.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let name = entry.file_name();
+                let deleted = name
+                    .to_str()
+                    .and_then(|name| name.split_once('-'))
+                    .and_then(|(deleted, _)| deleted.parse::<i64>().ok());
+                match deleted {
+                    Some(deleted) if deleted < deleted_before => {
+                        fs::remove_file(entry.path()).await?;
+                        removed += 1;
+                    }
+                    Some(_) => {}
+                    None => warn!("Unexpected file in trash: {}", entry.path().display()),
+                }
+            }
+        }
+        Ok(removed)
     }
 }
 
-fn get_collection_path(root: &Path, collection: Collection) -> PathBuf {
-    let name = match collection {
-        Collection::Snapshot => "snapshot",
-        Collection::Blob => "blob",
-    };
+/// ID of the repository at `root`, None if it has no repo-id marker.
+pub async fn read_repo_id(root: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(root.join(REPO_ID_FILE)).await {
+        Ok(repo_id) => Ok(Some(repo_id.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
-    root.join(name)
+/// Mark `root` as a repository with the given ID, creating the directory if
+/// needed. Fails if it already has an ID.
+pub async fn init_repository(root: &Path, repo_id: &str) -> io::Result<()> {
+    fs::create_dir_all(root).await?;
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(root.join(REPO_ID_FILE))
+        .await?;
+    file.write_all(format!("{}\n", repo_id).as_bytes()).await?;
+    file.sync_all().await
+}
+
+/// Find the one repository at or up to two levels below `mount_root` whose
+/// ID is accepted.
+async fn discover_repository(mount_root: &Path, repo_ids: &[String]) -> io::Result<PathBuf> {
+    let mut candidates = vec![mount_root.to_path_buf()];
+    let mut level = vec![mount_root.to_path_buf()];
+    for _ in 0..2 {
+        let mut next_level = Vec::new();
+        for dir in level {
+            let Ok(mut entries) = read_dir(&dir).await else {
+                continue;
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                    next_level.push(entry.path());
+                }
+            }
+        }
+        candidates.extend(next_level.iter().cloned());
+        level = next_level;
+    }
+
+    let mut found = Vec::new();
+    for candidate in candidates {
+        if let Ok(Some(repo_id)) = read_repo_id(&candidate).await {
+            if repo_ids.is_empty() || repo_ids.contains(&repo_id) {
+                found.push(candidate);
+            }
+        }
+    }
+
+    match found.len() {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No repository found under {}", mount_root.display()),
+        )),
+        1 => Ok(found.pop().unwrap()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Multiple repositories found under {}: {}",
+                mount_root.display(),
+                found
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )),
+    }
+}
+
+/// Whether `path` looks like the root of a file repository: it has a repo-id
+/// marker, or the directories of both collections.
+pub async fn is_repository(path: &Path) -> bool {
+    if fs::try_exists(path.join(REPO_ID_FILE))
+        .await
+        .unwrap_or(false)
+    {
+        return true;
+    }
+    for collection in [Collection::Snapshot, Collection::Blob] {
+        let is_dir = fs::metadata(get_collection_path(path, collection))
+            .await
+            .is_ok_and(|metadata| metadata.is_dir());
+        if !is_dir {
+            return false;
+        }
+    }
+    true
+}
+
+fn get_collection_path(root: &Path, collection: Collection) -> PathBuf {
+    root.join(collection.name())
 }
 
 // Get the path to an item in a collection.
@@ -86,6 +263,18 @@ impl RenameOnFinishFile {
         })
     }
 
+    /// Remove the temporary file without renaming it into place.
+    async fn abort(mut self) {
+        unsafe {
+            ManuallyDrop::drop(&mut self.file);
+        }
+
+        self.cleaned_up = true;
+        if let Err(e) = fs::remove_file(&self.tmp_path).await {
+            warn!("Failed to remove temp file: {}", e);
+        }
+    }
+
     /// Structural pin projection. This is safe because we never move the
     /// `File` out of the `ManuallyDrop`.
     fn pin_get_file(self: Pin<&mut Self>) -> Pin<&mut File> {
@@ -164,6 +353,15 @@ impl Drop for RenameOnFinishFile {
 #[async_trait]
 impl Storage for FileStorage {
     async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.write_stream(collection, key, &mut &data[..]).await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
         let path = get_item_path(&self.root, collection, key)?;
         match fs::metadata(&path).await {
             Ok(_) => {
@@ -185,12 +383,60 @@ impl Storage for FileStorage {
         }
 
         let mut file = RenameOnFinishFile::new(tmp_path, path).await?;
-        file.write_all(data).await?;
+        if let Err(e) = io::copy(reader, &mut file).await {
+            file.abort().await;
+            return Err(e);
+        }
         file.finish().await?;
 
         Ok(())
     }
 
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let path = get_item_path(&self.root, collection, key)?;
+        let random_name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let tmp_path = self.tmp_dir.join(random_name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        // Renaming replaces the damaged file atomically.
+        let mut file = RenameOnFinishFile::new(tmp_path, path).await?;
+        if let Err(e) = file.write_all(data).await {
+            file.abort().await;
+            return Err(e);
+        }
+        file.finish().await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        if self.trash.is_some() {
+            return self.move_to_trash(collection, key).await;
+        }
+        let path = get_item_path(&self.root, collection, key)?;
+        match fs::remove_file(path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        let mut removed = 0;
+        let mut entries = read_dir(&self.tmp_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // Files still being written have a recent modification time.
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() && metadata.modified()? < older_than {
+                fs::remove_file(entry.path()).await?;
+                removed += 1;
+            }
+        }
+        if let Some(retention) = self.trash {
+            removed += self.purge_trash(SystemTime::now() - retention).await?;
+        }
+        Ok(removed)
+    }
+
     async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
         let path = get_item_path(&self.root, collection, key)?;
         let mut file = File::open(path).await?;
@@ -199,6 +445,11 @@ impl Storage for FileStorage {
         Ok(())
     }
 
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        let path = get_item_path(&self.root, collection, key)?;
+        Ok(Box::new(File::open(path).await?))
+    }
+
     /// Iterate over directory structure like
     ///
     /// collection:
@@ -251,6 +502,14 @@ impl Storage for FileStorage {
         iterate_dir(&mut items, path).await?;
         Ok(items)
     }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        init_repository(&self.root, repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
 }
 
 #[cfg(test)]
@@ -272,4 +531,140 @@ mod test {
     }
 
     storage_tests!(FileStorageTestState);
+
+    #[tokio::test]
+    async fn replace_overwrites_item() {
+        let state = FileStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"damaged")
+            .await
+            .unwrap();
+        state
+            .storage
+            .replace(Collection::Blob, "key_1", b"good")
+            .await
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"good");
+    }
+
+    #[tokio::test]
+    async fn clean_temporary_removes_old_files() {
+        let state = FileStorageTestState::new().await;
+        let tmp_dir = state._tmp_dir.path().join("tmp");
+        let old = std::fs::File::create(tmp_dir.join("old")).unwrap();
+        old.set_modified(SystemTime::now() - std::time::Duration::from_secs(3600))
+            .unwrap();
+        std::fs::write(tmp_dir.join("new"), b"").unwrap();
+
+        let older_than = SystemTime::now() - std::time::Duration::from_secs(60);
+        assert_eq!(state.storage.clean_temporary(older_than).await.unwrap(), 1);
+        assert!(!tmp_dir.join("old").exists());
+        assert!(tmp_dir.join("new").exists());
+    }
+
+    #[tokio::test]
+    async fn delete_moves_to_trash() {
+        let mut state = FileStorageTestState::new().await;
+        let retention = std::time::Duration::from_secs(3600);
+        state.storage = state.storage.with_trash(Some(retention));
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"data")
+            .await
+            .unwrap();
+        state
+            .storage
+            .delete(Collection::Blob, "key_1")
+            .await
+            .unwrap();
+        // Deleting again finds nothing to move.
+        state
+            .storage
+            .delete(Collection::Blob, "key_1")
+            .await
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        let error = state
+            .storage
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let trash_dir = state._tmp_dir.path().join("trash/blob");
+        let trashed: Vec<PathBuf> = std::fs::read_dir(&trash_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(trashed.len(), 1);
+        assert_eq!(std::fs::read(&trashed[0]).unwrap(), b"data");
+
+        // Items are only purged once they have been in the trash long enough.
+        let older_than = SystemTime::now() - std::time::Duration::from_secs(60);
+        assert_eq!(state.storage.clean_temporary(older_than).await.unwrap(), 0);
+        assert!(trashed[0].exists());
+        let deleted = as_unix_timestamp(SystemTime::now() - 2 * retention);
+        let old = trash_dir.join(format!("{}-{}", deleted, base16_encode("key_2")));
+        std::fs::write(&old, b"old").unwrap();
+        assert_eq!(state.storage.clean_temporary(older_than).await.unwrap(), 1);
+        assert!(!old.exists());
+        assert!(trashed[0].exists());
+    }
+
+    fn discover_config(path: &Path, repo_ids: &[&str]) -> FileStorageConfig {
+        FileStorageConfig {
+            path: path.to_str().unwrap().to_string(),
+            discover: true,
+            repo_ids: repo_ids.iter().map(|id| id.to_string()).collect(),
+            trash: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn discover_repository_by_id() {
+        let mount_root = tempfile::tempdir().unwrap();
+        let config_path = mount_root.path().join("config.toml");
+        let config = discover_config(mount_root.path(), &["drive-a", "drive-b"]);
+
+        // Nothing is created when no drive is attached.
+        assert_eq!(
+            FileStorage::from_config(&config_path, &config)
+                .await
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(!mount_root.path().join("tmp").exists());
+
+        let other = mount_root.path().join("other/freebck");
+        init_repository(&other, "drive-c").await.unwrap();
+        let drive = mount_root.path().join("usb/freebck");
+        init_repository(&drive, "drive-b").await.unwrap();
+        assert!(init_repository(&drive, "drive-b").await.is_err());
+
+        let storage = FileStorage::from_config(&config_path, &config)
+            .await
+            .unwrap();
+        assert_eq!(storage.root, drive);
+
+        // Without repo_ids any repository is accepted, which is ambiguous here.
+        let config = discover_config(mount_root.path(), &[]);
+        assert_eq!(
+            FileStorage::from_config(&config_path, &config)
+                .await
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
 }
@@ -0,0 +1,191 @@
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use tokio::io::{self, AsyncRead, ReadBuf};
+
+use crate::util::rate::RateLimiter;
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+/// Storage wrapper that takes the bytes of every read and write from a
+/// shared `RateLimiter`. Giving every storage of the process the same
+/// limiter makes concurrent commands and archives share one bandwidth
+/// budget, with transfers served in the order they ask for it.
+pub struct LimitedStorage {
+    inner: Box<dyn Storage>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl LimitedStorage {
+    pub fn new(inner: Box<dyn Storage>, limiter: Arc<RateLimiter>) -> Self {
+        Self { inner, limiter }
+    }
+}
+
+/// Reader that takes the bytes it reads from a `RateLimiter`. Like buffered
+/// reads, it waits after each read and holds back the next one.
+struct LimitedReader<R> {
+    inner: R,
+    limiter: Arc<RateLimiter>,
+    wait: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl<R> LimitedReader<R> {
+    fn new(inner: R, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            inner,
+            limiter,
+            wait: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(ref mut wait) = self.wait {
+            ready!(wait.as_mut().poll(cx));
+            self.wait = None;
+        }
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let bytes = (buf.filled().len() - filled) as u64;
+        if bytes > 0 {
+            let limiter = self.limiter.clone();
+            self.wait = Some(Box::pin(async move { limiter.acquire(bytes).await }));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait]
+impl Storage for LimitedStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.limiter.acquire(data.len() as u64).await;
+        self.inner.write(collection, key, data).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        // The size is only known afterwards, so the wait comes after the
+        // read and holds back the next transfer instead.
+        self.inner.read(collection, key, buffer).await?;
+        self.limiter.acquire(buffer.len() as u64).await;
+        Ok(())
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.inner.get_collection_items(collection).await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        self.inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let mut reader = LimitedReader::new(reader, self.limiter.clone());
+        self.inner.write_stream(collection, key, &mut reader).await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        let reader = self.inner.read_stream(collection, key).await?;
+        Ok(Box::new(LimitedReader::new(reader, self.limiter.clone())))
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.limiter.acquire(data.len() as u64).await;
+        self.inner.replace(collection, key, data).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.inner.delete(collection, key).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        self.inner.has_many(collection, keys).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    struct LimitedStorageTestState {
+        storage: LimitedStorage,
+    }
+
+    impl LimitedStorageTestState {
+        async fn new() -> Self {
+            let storage = LimitedStorage::new(
+                Box::new(MemoryStorage::new()),
+                Arc::new(RateLimiter::new(1_000_000)),
+            );
+            Self { storage }
+        }
+    }
+
+    storage_tests!(LimitedStorageTestState);
+
+    #[tokio::test]
+    async fn storages_share_the_limit() {
+        let limiter = Arc::new(RateLimiter::new(10_000));
+        let first = LimitedStorage::new(Box::new(MemoryStorage::new()), limiter.clone());
+        let second = LimitedStorage::new(Box::new(MemoryStorage::new()), limiter);
+
+        let started = Instant::now();
+        first
+            .write(Collection::Blob, "key_1", &[0; 10_000])
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        second
+            .write(Collection::Blob, "key_1", &[0; 2_000])
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+}
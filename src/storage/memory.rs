@@ -0,0 +1,107 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use tokio::io;
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageWrite};
+
+/// Storage that keeps items in memory, for tests and for library users that
+/// don't want to touch the disk. Everything is lost when it is dropped.
+#[derive(Default)]
+pub struct MemoryStorage {
+    items: Mutex<HashMap<(Collection, String), Vec<u8>>>,
+    repo_id: Mutex<Option<String>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let mut items = self.items.lock().unwrap();
+        let item_key = (collection, key.to_string());
+        if items.contains_key(&item_key) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Item already exists: {}", key),
+            ));
+        }
+        items.insert(item_key, data.to_vec());
+        Ok(())
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        let items = self.items.lock().unwrap();
+        let Some(data) = items.get(&(collection, key.to_string())) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No such item: {}", key),
+            ));
+        };
+        buffer.clear();
+        buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.items
+            .lock()
+            .unwrap()
+            .insert((collection, key.to_string()), data.to_vec());
+        Ok(())
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.items
+            .lock()
+            .unwrap()
+            .remove(&(collection, key.to_string()));
+        Ok(())
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        let mut current = self.repo_id.lock().unwrap();
+        if current.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "Storage already has a repository ID",
+            ));
+        }
+        *current = Some(repo_id.to_string());
+        Ok(())
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        Ok(self
+            .items
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(item_collection, _)| *item_collection == collection)
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct MemoryStorageTestState {
+        storage: MemoryStorage,
+    }
+
+    impl MemoryStorageTestState {
+        async fn new() -> Self {
+            Self {
+                storage: MemoryStorage::new(),
+            }
+        }
+    }
+
+    storage_tests!(MemoryStorageTestState);
+}
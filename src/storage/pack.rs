@@ -0,0 +1,534 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+    ops::Range,
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use prost::Message;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt},
+    sync::{Mutex, OnceCell},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    data::backup::{PackIndex, PackedBlob},
+    util::hash::sha256_hex,
+};
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+/// Blobs smaller than this are packed, larger ones are stored on their own.
+pub const MAX_PACKED_BLOB_SIZE: usize = 1024 * 1024;
+
+/// Blobs waiting for their pack to be written.
+#[derive(Default)]
+struct PendingPack {
+    data: Vec<u8>,
+    blobs: HashMap<String, Range<usize>>,
+}
+
+#[derive(Clone)]
+struct PackLocation {
+    pack: String,
+    offset: u64,
+    size: u64,
+}
+
+/// Storage wrapper that groups small blobs into packs of about `pack_size`
+/// bytes, so that they take one object and one request instead of one each.
+/// Each pack has an index in the pack_index collection, written after the
+/// pack so that an index never refers to a missing pack. Blobs are kept in
+/// memory until their pack is full or `flush` is called.
+///
+/// Deleting a packed blob only forgets its location. On `flush`, the blobs
+/// still used in packs that had blobs deleted are packed again, and those
+/// packs are deleted once the new ones are written.
+pub struct PackStorage {
+    inner: Box<dyn Storage>,
+    pack_size: usize,
+    pending: Mutex<PendingPack>,
+    /// Locations of the packed blobs by key, read from the indexes on first
+    /// use.
+    index: OnceCell<Mutex<HashMap<String, PackLocation>>>,
+    /// Packs that had blobs deleted since the last flush.
+    deleted_from: Mutex<HashSet<String>>,
+    /// The pack read last, as blobs are often read in the order they were
+    /// written.
+    last_pack: StdMutex<Option<(String, Arc<Vec<u8>>)>>,
+}
+
+fn ignore_already_exists(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+impl PackStorage {
+    pub fn new(inner: Box<dyn Storage>, pack_size: u64) -> Self {
+        Self {
+            inner,
+            pack_size: pack_size as usize,
+            pending: Default::default(),
+            index: OnceCell::new(),
+            deleted_from: Default::default(),
+            last_pack: StdMutex::new(None),
+        }
+    }
+
+    async fn index(&self) -> io::Result<&Mutex<HashMap<String, PackLocation>>> {
+        self.index
+            .get_or_try_init(|| async {
+                let mut index = HashMap::new();
+                let mut buffer = Vec::new();
+                for pack in self
+                    .inner
+                    .get_collection_items(Collection::PackIndex)
+                    .await?
+                {
+                    self.inner
+                        .read(Collection::PackIndex, &pack, &mut buffer)
+                        .await?;
+                    let pack_index = PackIndex::decode(buffer.as_slice()).map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Invalid index of pack {}: {}", pack, e),
+                        )
+                    })?;
+                    for blob in pack_index.blob {
+                        let location = PackLocation {
+                            pack: pack.clone(),
+                            offset: blob.offset,
+                            size: blob.size,
+                        };
+                        index.insert(blob.key, location);
+                    }
+                }
+                debug!("Loaded the locations of {} packed blobs", index.len());
+                Ok(Mutex::new(index))
+            })
+            .await
+    }
+
+    /// Write the pending blobs as a pack. They stay pending if this fails, so
+    /// that the next write or flush tries again.
+    async fn write_pack(&self, pending: &mut PendingPack) -> io::Result<()> {
+        if pending.blobs.is_empty() {
+            return Ok(());
+        }
+
+        let name;
+        (pending.data, name) = sha256_hex(std::mem::take(&mut pending.data)).await?;
+        ignore_already_exists(
+            self.inner
+                .write(Collection::Pack, &name, &pending.data)
+                .await,
+        )?;
+        let pack_index = PackIndex {
+            blob: pending
+                .blobs
+                .iter()
+                .map(|(key, range)| PackedBlob {
+                    key: key.clone(),
+                    offset: range.start as u64,
+                    size: range.len() as u64,
+                })
+                .collect(),
+        };
+        ignore_already_exists(
+            self.inner
+                .write(Collection::PackIndex, &name, &pack_index.encode_to_vec())
+                .await,
+        )?;
+        debug!("Wrote pack {} with {} blobs", name, pending.blobs.len());
+
+        let mut index = self.index().await?.lock().await;
+        for (key, range) in std::mem::take(pending).blobs {
+            let location = PackLocation {
+                pack: name.clone(),
+                offset: range.start as u64,
+                size: range.len() as u64,
+            };
+            index.insert(key, location);
+        }
+        Ok(())
+    }
+
+    async fn read_pack(&self, pack: &str) -> io::Result<Arc<Vec<u8>>> {
+        if let Some((ref name, ref data)) = *self.last_pack.lock().unwrap() {
+            if name == pack {
+                return Ok(data.clone());
+            }
+        }
+        let mut data = Vec::new();
+        self.inner.read(Collection::Pack, pack, &mut data).await?;
+        let data = Arc::new(data);
+        *self.last_pack.lock().unwrap() = Some((pack.to_string(), data.clone()));
+        Ok(data)
+    }
+
+    /// Pack the remaining blobs of the packs that had blobs deleted again, and
+    /// delete the old packs once no blob is located in them. Old packs are
+    /// deleted index first, so that an index never refers to a missing pack.
+    async fn repack(&self, pending: &mut PendingPack) -> io::Result<()> {
+        let packs: Vec<String> = self.deleted_from.lock().await.drain().collect();
+        if packs.is_empty() {
+            return Ok(());
+        }
+
+        for pack in &packs {
+            let data = self.read_pack(pack).await?;
+            let blobs: Vec<(String, PackLocation)> = self
+                .index()
+                .await?
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, location)| location.pack == *pack)
+                .map(|(key, location)| (key.clone(), location.clone()))
+                .collect();
+            debug!("Repacking {} blobs of pack {}", blobs.len(), pack);
+            for (key, location) in blobs {
+                let range = location.offset as usize..(location.offset + location.size) as usize;
+                let Some(blob) = data.get(range) else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Blob {} is past the end of pack {}", key, pack),
+                    ));
+                };
+                let start = pending.data.len();
+                pending.data.extend_from_slice(blob);
+                pending.blobs.insert(key, start..pending.data.len());
+                if pending.data.len() >= self.pack_size {
+                    self.write_pack(pending).await?;
+                }
+            }
+        }
+        self.write_pack(pending).await?;
+
+        let index = self.index().await?.lock().await;
+        for pack in packs {
+            // A new pack may have the same contents and name as an old one.
+            if index.values().any(|location| location.pack == pack) {
+                continue;
+            }
+            self.inner.delete(Collection::PackIndex, &pack).await?;
+            self.inner.delete(Collection::Pack, &pack).await?;
+            debug!("Deleted pack {}", pack);
+        }
+        Ok(())
+    }
+
+    async fn is_packed(&self, key: &str) -> io::Result<bool> {
+        Ok(self.pending.lock().await.blobs.contains_key(key)
+            || self.index().await?.lock().await.contains_key(key))
+    }
+}
+
+impl Drop for PackStorage {
+    fn drop(&mut self) {
+        let pending = self.pending.get_mut();
+        if !pending.blobs.is_empty() {
+            warn!(
+                "Dropped {} blobs that were never flushed to a pack",
+                pending.blobs.len()
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for PackStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection != Collection::Blob || data.len() >= MAX_PACKED_BLOB_SIZE {
+            return self.inner.write(collection, key, data).await;
+        }
+
+        let mut pending = self.pending.lock().await;
+        if pending.blobs.contains_key(key) || self.index().await?.lock().await.contains_key(key) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Blob already exists: {}", key),
+            ));
+        }
+        let start = pending.data.len();
+        pending.data.extend_from_slice(data);
+        let end = pending.data.len();
+        pending.blobs.insert(key.to_string(), start..end);
+        if pending.data.len() >= self.pack_size {
+            self.write_pack(&mut pending).await?;
+        }
+        Ok(())
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        if collection != Collection::Blob {
+            return self.inner.read(collection, key, buffer).await;
+        }
+
+        {
+            let pending = self.pending.lock().await;
+            if let Some(range) = pending.blobs.get(key) {
+                buffer.clear();
+                buffer.extend_from_slice(&pending.data[range.clone()]);
+                return Ok(());
+            }
+        }
+        let location = self.index().await?.lock().await.get(key).cloned();
+        let Some(location) = location else {
+            return self.inner.read(collection, key, buffer).await;
+        };
+
+        let pack = self.read_pack(&location.pack).await?;
+        let range = location.offset as usize..(location.offset + location.size) as usize;
+        let Some(data) = pack.get(range) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Blob {} is past the end of pack {}", key, location.pack),
+            ));
+        };
+        buffer.clear();
+        buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        let items = self.inner.get_collection_items(collection).await?;
+        if collection != Collection::Blob {
+            return Ok(items);
+        }
+
+        // A blob may be both packed and stored on its own.
+        let mut keys: HashSet<String> = items.into_iter().collect();
+        keys.extend(self.index().await?.lock().await.keys().cloned());
+        keys.extend(self.pending.lock().await.blobs.keys().cloned());
+        Ok(keys.into_iter().collect())
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        if collection == Collection::Blob {
+            let mut items = self.get_collection_items(collection).await?;
+            items.retain(|key| key.starts_with(prefix));
+            return Ok(items);
+        }
+        self.inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    // Streams that turn out small enough are packed as with `write`.
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        if collection != Collection::Blob {
+            return self.inner.write_stream(collection, key, reader).await;
+        }
+        let mut start = Vec::new();
+        (&mut *reader)
+            .take(MAX_PACKED_BLOB_SIZE as u64)
+            .read_to_end(&mut start)
+            .await?;
+        if start.len() < MAX_PACKED_BLOB_SIZE {
+            return self.write(collection, key, &start).await;
+        }
+        let mut data = Cursor::new(start).chain(reader);
+        self.inner.write_stream(collection, key, &mut data).await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        if collection == Collection::Blob && self.is_packed(key).await? {
+            let mut buffer = Vec::new();
+            self.read(collection, key, &mut buffer).await?;
+            return Ok(Box::new(Cursor::new(buffer)));
+        }
+        self.inner.read_stream(collection, key).await
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection == Collection::Blob && self.is_packed(key).await? {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Blob {} is packed and can't be replaced", key),
+            ));
+        }
+        self.inner.replace(collection, key, data).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        if collection == Collection::Blob {
+            if self.pending.lock().await.blobs.remove(key).is_some() {
+                return Ok(());
+            }
+            if let Some(location) = self.index().await?.lock().await.remove(key) {
+                self.deleted_from.lock().await.insert(location.pack);
+            }
+        }
+        // A blob may be both packed and stored on its own.
+        self.inner.delete(collection, key).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        let mut pending = self.pending.lock().await;
+        self.repack(&mut pending).await?;
+        self.write_pack(&mut pending).await?;
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    struct PackStorageTestState {
+        storage: PackStorage,
+    }
+
+    impl PackStorageTestState {
+        async fn new() -> Self {
+            Self {
+                storage: PackStorage::new(Box::new(MemoryStorage::new()), 16),
+            }
+        }
+    }
+
+    storage_tests!(PackStorageTestState);
+
+    #[tokio::test]
+    async fn small_blobs_are_packed() -> TestResult {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = PackStorage::new(Box::new(inner.clone()), 1000);
+        storage.write(Collection::Blob, "key_1", b"first").await?;
+        storage.write(Collection::Blob, "key_2", b"second").await?;
+        let large = vec![1; MAX_PACKED_BLOB_SIZE];
+        storage.write(Collection::Blob, "key_3", &large).await?;
+        let error = storage
+            .write(Collection::Blob, "key_1", b"again")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        storage.flush().await?;
+
+        assert_eq!(
+            inner.get_collection_items(Collection::Blob).await?,
+            ["key_3"]
+        );
+        assert_eq!(inner.get_collection_items(Collection::Pack).await?.len(), 1);
+
+        // A new instance finds the blobs through the pack index.
+        let storage = PackStorage::new(Box::new(inner), 1000);
+        let mut items = storage.get_collection_items(Collection::Blob).await?;
+        items.sort();
+        assert_eq!(items, ["key_1", "key_2", "key_3"]);
+        let mut buffer = Vec::new();
+        storage.read(Collection::Blob, "key_2", &mut buffer).await?;
+        assert_eq!(buffer, b"second");
+        storage.read(Collection::Blob, "key_1", &mut buffer).await?;
+        assert_eq!(buffer, b"first");
+        storage.read(Collection::Blob, "key_3", &mut buffer).await?;
+        assert_eq!(buffer, large);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_blobs_are_repacked() -> TestResult {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = PackStorage::new(Box::new(inner.clone()), 1000);
+        storage.write(Collection::Blob, "key_1", b"first").await?;
+        storage.write(Collection::Blob, "key_2", b"second").await?;
+        storage.write(Collection::Blob, "key_3", b"third").await?;
+        storage.flush().await?;
+        let old_packs = inner.get_collection_items(Collection::Pack).await?;
+
+        storage.delete(Collection::Blob, "key_2").await?;
+        let mut buffer = Vec::new();
+        let error = storage
+            .read(Collection::Blob, "key_2", &mut buffer)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        storage.flush().await?;
+
+        let packs = inner.get_collection_items(Collection::Pack).await?;
+        assert_eq!(packs.len(), 1);
+        assert_ne!(packs, old_packs);
+        assert_eq!(
+            inner.get_collection_items(Collection::PackIndex).await?,
+            packs
+        );
+        let mut data = Vec::new();
+        inner.read(Collection::Pack, &packs[0], &mut data).await?;
+        assert_eq!(data.len(), b"firstthird".len());
+
+        // A new instance only finds the remaining blobs.
+        let storage = PackStorage::new(Box::new(inner), 1000);
+        let mut items = storage.get_collection_items(Collection::Blob).await?;
+        items.sort();
+        assert_eq!(items, ["key_1", "key_3"]);
+        storage.read(Collection::Blob, "key_3", &mut buffer).await?;
+        assert_eq!(buffer, b"third");
+
+        // Deleting every blob of a pack leaves no pack.
+        storage.delete(Collection::Blob, "key_1").await?;
+        storage.delete(Collection::Blob, "key_3").await?;
+        storage.flush().await?;
+        assert!(storage
+            .get_collection_items(Collection::Blob)
+            .await?
+            .is_empty());
+        assert!(storage
+            .get_collection_items(Collection::Pack)
+            .await?
+            .is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_pack_is_written() -> TestResult {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = PackStorage::new(Box::new(inner.clone()), 10);
+        storage.write(Collection::Blob, "key_1", b"12345").await?;
+        assert!(inner
+            .get_collection_items(Collection::Pack)
+            .await?
+            .is_empty());
+        storage.write(Collection::Blob, "key_2", b"67890").await?;
+        assert_eq!(inner.get_collection_items(Collection::Pack).await?.len(), 1);
+        assert_eq!(
+            inner.get_collection_items(Collection::PackIndex).await?,
+            inner.get_collection_items(Collection::Pack).await?
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,881 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use hyper::{
+    body::Incoming,
+    header::{HeaderValue, AUTHORIZATION, ETAG, IF_NONE_MATCH},
+};
+use sha2::{Digest, Sha256};
+use tokio::{io, net::TcpListener};
+use tokio_rustls::TlsAcceptor;
+use tracing::warn;
+
+use crate::{
+    data::config::RestStorageConfig,
+    util::http::{
+        client, parse_url, percent_decode, percent_encode, read_body, response, send, serve,
+        Response, ServerResponse,
+    },
+};
+
+use super::{
+    file::{init_repository, is_repository, FileStorage, REPO_ID_FILE},
+    util::normalize_prefix,
+    Collection, Storage, StorageItems, StorageRead, StorageWrite,
+};
+
+/// Storage on a `freebck serve` server. Items are at
+/// "<url>/<prefix>/<collection>/<percent-encoded key>", GET reads, PUT writes
+/// and DELETE removes them, and GET on "<url>/<prefix>/<collection>/" lists
+/// the keys one per line, only those starting with the "prefix" query
+/// parameter if given. POST to "<url>/<prefix>/repo-id" creates the
+/// repository with the ID in the body. The prefix selects one of the
+/// repositories of the server, and may be empty.
+///
+/// Items carry a strong ETag, the quoted SHA-256 of their content. Reads and
+/// writes check it, so that data changed on the way is noticed, and items
+/// other than blobs and packs are kept after reading them and revalidated
+/// with If-None-Match, so that reading them again only costs a 304.
+pub struct RestStorage {
+    client: reqwest::Client,
+    /// Server URL with the prefix, without a trailing slash.
+    url: String,
+    token: Option<String>,
+    cache: Mutex<ItemCache>,
+}
+
+/// Largest item that is kept for revalidation.
+const MAX_CACHED_ITEM_SIZE: usize = 64 * 1024;
+/// Total size of the kept items, over which they are all dropped.
+const MAX_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+struct KeptItem {
+    etag: String,
+    data: Arc<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct ItemCache {
+    items: HashMap<(Collection, String), KeptItem>,
+    size: usize,
+}
+
+impl ItemCache {
+    fn insert(&mut self, collection: Collection, key: &str, etag: String, data: &[u8]) {
+        if data.len() > MAX_CACHED_ITEM_SIZE {
+            return;
+        }
+        if self.size + data.len() > MAX_CACHE_SIZE {
+            self.items.clear();
+            self.size = 0;
+        }
+        self.size += data.len();
+        let item = KeptItem {
+            etag,
+            data: Arc::new(data.to_vec()),
+        };
+        if let Some(previous) = self.items.insert((collection, key.to_string()), item) {
+            self.size -= previous.data.len();
+        }
+    }
+
+    fn remove(&mut self, collection: Collection, key: &str) {
+        if let Some(item) = self.items.remove(&(collection, key.to_string())) {
+            self.size -= item.data.len();
+        }
+    }
+}
+
+/// Strong ETag of an item with `data` as its content.
+fn etag(data: &[u8]) -> String {
+    format!("\"{:x}\"", Sha256::digest(data))
+}
+
+impl RestStorage {
+    pub fn from_config(config: &RestStorageConfig) -> io::Result<Self> {
+        let mut url = parse_url(&config.url)?
+            .as_str()
+            .trim_end_matches('/')
+            .to_string();
+        let prefix = normalize_prefix(&config.prefix)?;
+        if !prefix.is_empty() {
+            url = format!(
+                "{}/{}",
+                url,
+                percent_encode(prefix.trim_end_matches('/'), false)
+            );
+        }
+        Ok(Self {
+            client: client(config.ca_cert.as_deref())?,
+            url,
+            token: config
+                .token
+                .clone()
+                .or_else(|| env::var("FREEBCK_REST_TOKEN").ok()),
+            cache: Mutex::new(ItemCache::default()),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, path: String) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, format!("{}{}", self.url, path));
+        match self.token {
+            Some(ref token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn item_path(collection: Collection, key: &str) -> String {
+    format!("/{}/{}", collection.name(), percent_encode(key, true))
+}
+
+/// Check the ETag the server sent for `data`, if it sent one.
+fn check_etag(response: &Response, data: &[u8], operation: &str) -> io::Result<()> {
+    match response.header("ETag") {
+        Some(given) if given != etag(data) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: the content doesn't match its ETag", operation),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl Storage for RestStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let operation = format!("Writing {}", key);
+        let response = send(
+            self.request(reqwest::Method::PUT, item_path(collection, key))
+                .body(data.to_vec()),
+        )
+        .await?;
+        if !response.is_success() {
+            return Err(response.error(&operation));
+        }
+        check_etag(&response, data, &operation)
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        let operation = format!("Reading {}", key);
+        // Blobs and packs are named by their hash, and restores read many
+        // of them once, so keeping them would only cost memory.
+        let keep = !matches!(collection, Collection::Blob | Collection::Pack);
+        let cached = if keep {
+            let cache = self.cache.lock().unwrap();
+            cache.items.get(&(collection, key.to_string())).cloned()
+        } else {
+            None
+        };
+
+        let mut request = self.request(reqwest::Method::GET, item_path(collection, key));
+        if let Some(ref item) = cached {
+            request = request.header(IF_NONE_MATCH, &item.etag);
+        }
+        let response = send(request).await?;
+        if let (304, Some(item)) = (response.status, &cached) {
+            *buffer = item.data.to_vec();
+            return Ok(());
+        }
+        if !response.is_success() {
+            self.cache.lock().unwrap().remove(collection, key);
+            return Err(response.error(&operation));
+        }
+        check_etag(&response, &response.body, &operation)?;
+        if keep {
+            self.cache.lock().unwrap().insert(
+                collection,
+                key,
+                etag(&response.body),
+                &response.body,
+            );
+        }
+        *buffer = response.body;
+        Ok(())
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.cache.lock().unwrap().remove(collection, key);
+        let response =
+            send(self.request(reqwest::Method::DELETE, item_path(collection, key))).await?;
+        if !response.is_success() {
+            return Err(response.error(&format!("Deleting {}", key)));
+        }
+        Ok(())
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        let response = send(
+            self.request(reqwest::Method::POST, format!("/{}", REPO_ID_FILE))
+                .body(repo_id.to_string()),
+        )
+        .await?;
+        if !response.is_success() {
+            return Err(response.error("Creating the repository"));
+        }
+        Ok(())
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.get_collection_items_with_prefix(collection, "").await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        let mut path = format!("/{}/", collection.name());
+        if !prefix.is_empty() {
+            path = format!("{}?prefix={}", path, percent_encode(prefix, true));
+        }
+        let response = send(self.request(reqwest::Method::GET, path)).await?;
+        if !response.is_success() {
+            return Err(response.error("Listing items"));
+        }
+        Ok(String::from_utf8_lossy(&response.body)
+            .lines()
+            .map(|key| key.to_string())
+            .collect())
+    }
+}
+
+/// Settings of a `serve_storage` server.
+pub struct ServeOptions {
+    /// Token that clients have to send. Without one, anyone who can connect
+    /// may read and add items.
+    pub token: Option<String>,
+    /// Let clients remove items.
+    pub allow_delete: bool,
+    /// Largest item a client may write. Larger ones are refused with 413.
+    pub max_item_size: u64,
+    /// Serve https:// instead of http://.
+    pub tls: Option<TlsAcceptor>,
+}
+
+/// Repositories that a `serve_storage` server serves, by prefix.
+#[async_trait]
+pub trait ServedRepositories: Send + Sync {
+    // Open the repository at `prefix`, as returned by normalize_prefix.
+    // Fails with NotFound if there is none.
+    async fn open(&self, prefix: &str) -> io::Result<Arc<dyn Storage>>;
+
+    // Create a repository at `prefix` with ID `repo_id`.
+    async fn create(&self, prefix: &str, repo_id: &str) -> io::Result<()>;
+}
+
+/// One repository, served without a prefix.
+pub struct SingleRepository(pub Arc<dyn Storage>);
+
+#[async_trait]
+impl ServedRepositories for SingleRepository {
+    async fn open(&self, prefix: &str) -> io::Result<Arc<dyn Storage>> {
+        match prefix {
+            "" => Ok(self.0.clone()),
+            _ => Err(no_repository(prefix)),
+        }
+    }
+
+    async fn create(&self, prefix: &str, repo_id: &str) -> io::Result<()> {
+        match prefix {
+            "" => self.0.init(repo_id).await,
+            _ => Err(no_repository(prefix)),
+        }
+    }
+}
+
+/// Repositories in a directory, each in the subdirectory named by its
+/// prefix. The directory itself is the repository without a prefix.
+pub struct RepositoryDirectory {
+    root: PathBuf,
+    opened: Mutex<HashMap<String, Arc<dyn Storage>>>,
+}
+
+impl RepositoryDirectory {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            opened: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl ServedRepositories for RepositoryDirectory {
+    async fn open(&self, prefix: &str) -> io::Result<Arc<dyn Storage>> {
+        if let Some(storage) = self.opened.lock().unwrap().get(prefix) {
+            return Ok(storage.clone());
+        }
+        let path = self.root.join(prefix);
+        if !is_repository(&path).await {
+            return Err(no_repository(prefix));
+        }
+        let storage: Arc<dyn Storage> = Arc::new(FileStorage::new(path).await?);
+        Ok(self
+            .opened
+            .lock()
+            .unwrap()
+            .entry(prefix.to_string())
+            .or_insert(storage)
+            .clone())
+    }
+
+    async fn create(&self, prefix: &str, repo_id: &str) -> io::Result<()> {
+        let path = self.root.join(prefix);
+        if is_repository(&path).await {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("There already is a repository at {:?}", prefix),
+            ));
+        }
+        init_repository(&path, repo_id).await
+    }
+}
+
+fn no_repository(prefix: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No repository at {:?}", prefix),
+    )
+}
+
+/// Serve `repositories` over HTTP for `RestStorage` clients until the
+/// listener fails. Items are never overwritten, and unless `allow_delete` is
+/// set never removed either, other than locks, so a client can't destroy
+/// existing backups.
+/// With a token, requests without it are refused.
+pub async fn serve_storage(
+    listener: TcpListener,
+    repositories: Arc<dyn ServedRepositories>,
+    options: ServeOptions,
+) -> io::Result<()> {
+    let tls = options.tls.clone();
+    let options = Arc::new(options);
+    serve(listener, tls, move |request| {
+        handle_request(repositories.clone(), options.clone(), request)
+    })
+    .await
+}
+
+/// Longest repository ID a client may create a repository with.
+const MAX_REPO_ID_SIZE: u64 = 1024;
+
+/// Compare a token without the time taken telling how much of it was right.
+/// Comparing hashes hides the length of the token too.
+fn token_matches(given: &str, token: &str) -> bool {
+    let given = Sha256::digest(given.as_bytes());
+    let token = Sha256::digest(token.as_bytes());
+    given
+        .iter()
+        .zip(token.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+/// The parts of a request path: the prefix of the repository, and the
+/// collection and key of the item, or None for the repository ID.
+struct ItemPath {
+    prefix: String,
+    item: Option<(Collection, String)>,
+}
+
+fn parse_path(path: &str) -> Option<ItemPath> {
+    let mut segments: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+    let last = segments.pop()?;
+    let item = if last == REPO_ID_FILE {
+        None
+    } else {
+        let collection = Collection::from_name(segments.pop()?)?;
+        Some((collection, percent_decode(last)))
+    };
+    let prefix = segments
+        .iter()
+        .map(|segment| percent_decode(segment))
+        .collect::<Vec<_>>()
+        .join("/");
+    Some(ItemPath {
+        prefix: normalize_prefix(&prefix).ok()?,
+        item,
+    })
+}
+
+async fn handle_request(
+    repositories: Arc<dyn ServedRepositories>,
+    options: Arc<ServeOptions>,
+    request: hyper::Request<Incoming>,
+) -> ServerResponse {
+    // Checked before the body is read, so that clients without the token
+    // can't make the server receive anything.
+    if let Some(ref token) = options.token {
+        let given = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if !given.is_some_and(|given| token_matches(given, token)) {
+            return response(401, "Missing or wrong token");
+        }
+    }
+
+    let method = request.method().as_str().to_string();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let Some(item_path) = parse_path(&path) else {
+        return response(404, "");
+    };
+    let Some((collection, key)) = item_path.item else {
+        if method != "POST" {
+            return response(405, "");
+        }
+        let body = match read_body(request, MAX_REPO_ID_SIZE).await {
+            Ok(body) => body,
+            Err(response) => return response,
+        };
+        let repo_id = String::from_utf8_lossy(&body);
+        if repo_id.trim().is_empty() || repo_id.trim().contains(char::is_whitespace) {
+            return response(400, "Invalid repository ID");
+        }
+        return match repositories.create(&item_path.prefix, repo_id.trim()).await {
+            Ok(()) => response(201, ""),
+            Err(e) => error_response(&method, &path, e),
+        };
+    };
+    let storage = match repositories.open(&item_path.prefix).await {
+        Ok(storage) => storage,
+        Err(e) => return error_response(&method, &path, e),
+    };
+
+    let (result, status) = match (method.as_str(), key.is_empty()) {
+        ("GET", true) => (
+            storage
+                .get_collection_items_with_prefix(collection, &list_prefix(&query))
+                .await
+                .map(|items| items.join("\n").into_bytes()),
+            200,
+        ),
+        ("GET", false) => {
+            let mut buffer = Vec::new();
+            if let Err(e) = storage.read(collection, &key, &mut buffer).await {
+                return error_response(&method, &path, e);
+            }
+            let etag = etag(&buffer);
+            let not_modified = request
+                .headers()
+                .get_all(IF_NONE_MATCH)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|tag| tag.trim() == "*" || tag.trim() == etag);
+            let mut response = if not_modified {
+                response(304, "")
+            } else {
+                response(200, buffer)
+            };
+            if let Ok(etag) = HeaderValue::from_str(&etag) {
+                response.headers_mut().insert(ETAG, etag);
+            }
+            return response;
+        }
+        ("PUT", false) => {
+            let body = match read_body(request, options.max_item_size).await {
+                Ok(body) => body,
+                Err(response) => return response,
+            };
+            if let Err(e) = storage.write(collection, &key, &body).await {
+                return error_response(&method, &path, e);
+            }
+            let mut response = response(201, "");
+            if let Ok(etag) = HeaderValue::from_str(&etag(&body)) {
+                response.headers_mut().insert(ETAG, etag);
+            }
+            return response;
+        }
+        // Locks hold no data, and are removed when the operation ends.
+        ("DELETE", false) if options.allow_delete || collection == Collection::Lock => (
+            storage.delete(collection, &key).await.map(|()| Vec::new()),
+            200,
+        ),
+        _ => return response(405, ""),
+    };
+    match result {
+        Ok(body) => response(status, body),
+        Err(e) => error_response(&method, &path, e),
+    }
+}
+
+fn error_response(method: &str, path: &str, error: io::Error) -> ServerResponse {
+    let status = match error.kind() {
+        io::ErrorKind::NotFound => 404,
+        io::ErrorKind::AlreadyExists => 409,
+        io::ErrorKind::InvalidInput => 400,
+        _ => {
+            warn!("{} {} failed: {}", method, path, error);
+            500
+        }
+    };
+    response(status, error.to_string())
+}
+
+fn list_prefix(query: &str) -> String {
+    query
+        .split('&')
+        .find_map(|parameter| parameter.strip_prefix("prefix="))
+        .map(percent_decode)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{storage::memory::MemoryStorage, util::http::tls_acceptor};
+
+    fn options(token: Option<&str>, allow_delete: bool) -> ServeOptions {
+        ServeOptions {
+            token: token.map(|token| token.to_string()),
+            allow_delete,
+            max_item_size: 64 * 1024,
+            tls: None,
+        }
+    }
+
+    struct RestStorageTestState {
+        storage: RestStorage,
+        url: String,
+    }
+
+    impl RestStorageTestState {
+        async fn new() -> Self {
+            Self::start(options(Some("secret"), true), Some("secret"), None).await
+        }
+
+        async fn start(
+            options: ServeOptions,
+            client_token: Option<&str>,
+            ca_cert: Option<&Path>,
+        ) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let url = match options.tls {
+                Some(_) => format!("https://localhost:{}/", port),
+                None => format!("http://127.0.0.1:{}/", port),
+            };
+            let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+            tokio::spawn(serve_storage(
+                listener,
+                Arc::new(SingleRepository(storage)),
+                options,
+            ));
+
+            let storage = RestStorage::from_config(&RestStorageConfig {
+                url: url.clone(),
+                token: client_token.map(|token| token.to_string()),
+                ca_cert: ca_cert.map(|path| path.to_path_buf()),
+                prefix: String::new(),
+            })
+            .unwrap();
+            Self { storage, url }
+        }
+    }
+
+    storage_tests!(RestStorageTestState);
+
+    #[tokio::test]
+    async fn write_existing_returns_already_exists() {
+        let state = RestStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Snapshot, "archive/1", b"1")
+            .await
+            .unwrap();
+        let error = state
+            .storage
+            .write(Collection::Snapshot, "archive/1", b"2")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            state
+                .storage
+                .get_collection_items(Collection::Snapshot)
+                .await
+                .unwrap(),
+            ["archive/1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_token_is_refused() {
+        let state =
+            RestStorageTestState::start(options(Some("secret"), true), Some("guess"), None).await;
+        let error = state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn delete_is_refused_unless_allowed() {
+        let state = RestStorageTestState::start(options(None, false), None, None).await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        assert!(state
+            .storage
+            .delete(Collection::Blob, "key_1")
+            .await
+            .is_err());
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"1");
+
+        // Locks can always be released.
+        state
+            .storage
+            .write(Collection::Lock, "lock_1", b"")
+            .await
+            .unwrap();
+        state
+            .storage
+            .delete(Collection::Lock, "lock_1")
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_item_is_refused() {
+        let state = RestStorageTestState::new().await;
+        let error = state
+            .storage
+            .write(Collection::Blob, "key_1", &[0; 64 * 1024 + 1])
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("HTTP 413"), "{}", error);
+        state
+            .storage
+            .write(Collection::Blob, "key_1", &[0; 64 * 1024])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn token_is_checked_before_the_body() {
+        let state =
+            RestStorageTestState::start(options(Some("secret"), true), Some("guess"), None).await;
+        let error = state
+            .storage
+            .write(Collection::Blob, "key_1", &[0; 128 * 1024])
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn too_many_headers_are_refused() {
+        let state = RestStorageTestState::new().await;
+        let mut request = client(None)
+            .unwrap()
+            .get(format!("{}blob/", state.url))
+            .bearer_auth("secret");
+        for index in 0..40 {
+            request = request.header(format!("x-header-{}", index), "value");
+        }
+        assert_eq!(send(request).await.unwrap().status, 431);
+    }
+
+    #[tokio::test]
+    async fn serves_https() {
+        let dir = tempfile::tempdir().unwrap();
+        let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        std::fs::write(&cert, certified.cert.pem()).unwrap();
+        std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+        let mut options = options(Some("secret"), true);
+        options.tls = Some(tls_acceptor(&cert, &key).unwrap());
+        let state = RestStorageTestState::start(options, Some("secret"), Some(&cert)).await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"1");
+
+        // A client that doesn't trust the certificate refuses the server.
+        let untrusting = RestStorage::from_config(&RestStorageConfig {
+            url: state.url.clone(),
+            token: Some("secret".to_string()),
+            ca_cert: None,
+            prefix: String::new(),
+        })
+        .unwrap();
+        assert!(untrusting
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_repositories_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(serve_storage(
+            listener,
+            Arc::new(RepositoryDirectory::new(dir.path().to_path_buf())),
+            options(None, false),
+        ));
+        let connect = |prefix: &str| {
+            RestStorage::from_config(&RestStorageConfig {
+                url: url.clone(),
+                token: None,
+                ca_cert: None,
+                prefix: prefix.to_string(),
+            })
+        };
+
+        let laptop = connect("hosts/laptop").unwrap();
+        let desktop = connect("/hosts/desktop/").unwrap();
+        let error = laptop
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        laptop.init("laptop").await.unwrap();
+        desktop.init("desktop").await.unwrap();
+        let error = laptop.init("again").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        laptop.write(Collection::Blob, "key_1", b"1").await.unwrap();
+        desktop
+            .write(Collection::Blob, "key_2", b"2")
+            .await
+            .unwrap();
+        assert_eq!(
+            laptop.get_collection_items(Collection::Blob).await.unwrap(),
+            ["key_1"]
+        );
+        assert_eq!(
+            desktop
+                .get_collection_items(Collection::Blob)
+                .await
+                .unwrap(),
+            ["key_2"]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join("hosts/laptop/repo-id")).unwrap(),
+            "laptop\n"
+        );
+
+        // Prefixes can't reach into the collections of another repository.
+        assert!(connect("hosts/laptop/blob").is_err());
+        let response = send(
+            client(None)
+                .unwrap()
+                .post(format!("{}hosts/laptop/blob/repo-id", url))
+                .body("nested"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn conditional_read_returns_not_modified() {
+        let state = RestStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Stats, "archive/1", b"stats")
+            .await
+            .unwrap();
+
+        let url = format!("{}stats/archive%2F1", state.url);
+        let read = |if_none_match: &str| {
+            send(
+                client(None)
+                    .unwrap()
+                    .get(&url)
+                    .bearer_auth("secret")
+                    .header(IF_NONE_MATCH, if_none_match),
+            )
+        };
+        let response = read("\"other\"").await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("ETag"), Some(etag(b"stats").as_str()));
+        let response = read(&format!("\"other\", {}", etag(b"stats")))
+            .await
+            .unwrap();
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn kept_items_are_revalidated() {
+        let state = RestStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Snapshot, "archive/1", b"1")
+            .await
+            .unwrap();
+        let mut buffer = Vec::new();
+        for _ in 0..2 {
+            state
+                .storage
+                .read(Collection::Snapshot, "archive/1", &mut buffer)
+                .await
+                .unwrap();
+            assert_eq!(buffer, b"1");
+        }
+
+        // Removed by another client, which a kept copy must not hide.
+        let other = RestStorage::from_config(&RestStorageConfig {
+            url: state.url.clone(),
+            token: Some("secret".to_string()),
+            ca_cert: None,
+            prefix: String::new(),
+        })
+        .unwrap();
+        other
+            .delete(Collection::Snapshot, "archive/1")
+            .await
+            .unwrap();
+        let error = state
+            .storage
+            .read(Collection::Snapshot, "archive/1", &mut buffer)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_token_matches() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+}
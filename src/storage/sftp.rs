@@ -0,0 +1,1008 @@
+use std::{
+    collections::HashSet,
+    os::{fd::OwnedFd, unix::net::UnixStream as StdUnixStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::Mutex as StdMutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UnixStream,
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::data::config::SshStorageConfig;
+
+use super::file::REPO_ID_FILE;
+use super::util::{base16_decode, base16_encode, normalize_prefix, xor_byte_hash};
+use super::{Collection, Storage, StorageItems, StorageRead, StorageWrite};
+
+// Packet types of SFTP version 3.
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_WRITE: u8 = 6;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_REMOVE: u8 = 13;
+const FXP_MKDIR: u8 = 14;
+const FXP_STAT: u8 = 17;
+const FXP_RENAME: u8 = 18;
+const FXP_EXTENDED: u8 = 200;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+const FXP_ATTRS: u8 = 105;
+
+const FXF_READ: u32 = 0x01;
+const FXF_WRITE: u32 = 0x02;
+const FXF_CREAT: u32 = 0x08;
+const FXF_EXCL: u32 = 0x20;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+const FX_OP_UNSUPPORTED: u32 = 8;
+
+const ATTR_SIZE: u32 = 0x01;
+const ATTR_UIDGID: u32 = 0x02;
+const ATTR_PERMISSIONS: u32 = 0x04;
+const ATTR_ACMODTIME: u32 = 0x08;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+/// Largest read or write in one request. Servers must support at least this.
+const MAX_IO_LENGTH: usize = 32 * 1024;
+
+/// Storage in a directory on a remote machine, accessed with SFTP through the
+/// ssh client. Items are laid out as in `FileStorage`, and are uploaded to a
+/// temporary file first and renamed into place, so they appear atomically.
+pub struct SftpStorage {
+    channel: Mutex<Channel>,
+    root: String,
+    /// Whether the server can flush files to disk before they are renamed.
+    fsync: bool,
+    /// Directories known to exist, to not create them for every write.
+    created_dirs: StdMutex<HashSet<String>>,
+    ssh: Option<Child>,
+}
+
+/// Outgoing packet, without the length.
+struct PacketWriter(Vec<u8>);
+
+impl PacketWriter {
+    fn new(kind: u8) -> Self {
+        Self(vec![kind])
+    }
+
+    fn u32(mut self, value: u32) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn u64(mut self, value: u64) -> Self {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    fn bytes(self, value: &[u8]) -> Self {
+        let mut packet = self.u32(value.len() as u32);
+        packet.0.extend_from_slice(value);
+        packet
+    }
+
+    fn string(self, value: &str) -> Self {
+        self.bytes(value.as_bytes())
+    }
+}
+
+/// Incoming packet, without the length.
+struct PacketReader {
+    data: Vec<u8>,
+    position: usize,
+}
+
+impl PacketReader {
+    fn take(&mut self, length: usize) -> io::Result<&[u8]> {
+        if self.data.len() - self.position < length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Truncated SFTP packet",
+            ));
+        }
+        self.position += length;
+        Ok(&self.data[self.position - length..self.position])
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// File attributes, keeping the permissions and modification time.
+    fn attributes(&mut self) -> io::Result<Attributes> {
+        let flags = self.u32()?;
+        let mut attributes = Attributes::default();
+        if flags & ATTR_SIZE != 0 {
+            self.u64()?;
+        }
+        if flags & ATTR_UIDGID != 0 {
+            self.u32()?;
+            self.u32()?;
+        }
+        if flags & ATTR_PERMISSIONS != 0 {
+            attributes.permissions = Some(self.u32()?);
+        }
+        if flags & ATTR_ACMODTIME != 0 {
+            self.u32()?; // Access time.
+            attributes.modified = Some(UNIX_EPOCH + Duration::from_secs(self.u32()?.into()));
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.bytes()?;
+                self.bytes()?;
+            }
+        }
+        Ok(attributes)
+    }
+}
+
+/// The file attributes used, when the server sends them.
+#[derive(Default)]
+struct Attributes {
+    permissions: Option<u32>,
+    modified: Option<SystemTime>,
+}
+
+struct Channel {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    next_id: u32,
+}
+
+impl Channel {
+    async fn send(&mut self, packet: PacketWriter) -> io::Result<()> {
+        self.writer
+            .write_all(&(packet.0.len() as u32).to_be_bytes())
+            .await?;
+        self.writer.write_all(&packet.0).await?;
+        self.writer.flush().await
+    }
+
+    async fn receive(&mut self) -> io::Result<PacketReader> {
+        let length = match self.reader.read_u32().await {
+            Ok(length) => length as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "SFTP connection closed",
+                ))
+            }
+            Err(e) => return Err(e),
+        };
+        let mut data = vec![0; length];
+        self.reader.read_exact(&mut data).await?;
+        Ok(PacketReader { data, position: 0 })
+    }
+
+    /// Send a request and wait for its response. `build` gets the packet
+    /// with the type and request ID already written.
+    async fn request(
+        &mut self,
+        kind: u8,
+        build: impl FnOnce(PacketWriter) -> PacketWriter,
+    ) -> io::Result<(u8, PacketReader)> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.send(build(PacketWriter::new(kind).u32(id))).await?;
+
+        let mut response = self.receive().await?;
+        let kind = response.u8()?;
+        if response.u32()? != id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "SFTP response to an unexpected request",
+            ));
+        }
+        Ok((kind, response))
+    }
+}
+
+fn status_error(code: u32, message: &str, operation: &str) -> io::Error {
+    let kind = match code {
+        FX_EOF => io::ErrorKind::UnexpectedEof,
+        FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+        FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+        FX_OP_UNSUPPORTED => io::ErrorKind::Unsupported,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(
+        kind,
+        format!("{} failed: {} (SFTP status {})", operation, message, code),
+    )
+}
+
+/// Error for a response that is not of the `expected` type.
+fn unexpected_response(
+    kind: u8,
+    mut response: PacketReader,
+    expected: u8,
+    operation: &str,
+) -> io::Error {
+    if kind == FXP_STATUS {
+        if let (Ok(code), Ok(message)) = (response.u32(), response.string()) {
+            return status_error(code, &message, operation);
+        }
+    }
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} failed: expected SFTP response {}, got {}",
+            operation, expected, kind
+        ),
+    )
+}
+
+/// Check a response that only carries a status.
+fn expect_ok((kind, mut response): (u8, PacketReader), operation: &str) -> io::Result<()> {
+    if kind != FXP_STATUS {
+        return Err(unexpected_response(kind, response, FXP_STATUS, operation));
+    }
+    match response.u32()? {
+        FX_OK => Ok(()),
+        code => Err(status_error(code, &response.string()?, operation)),
+    }
+}
+
+fn expect_handle((kind, mut response): (u8, PacketReader), operation: &str) -> io::Result<Vec<u8>> {
+    if kind != FXP_HANDLE {
+        return Err(unexpected_response(kind, response, FXP_HANDLE, operation));
+    }
+    response.bytes()
+}
+
+/// Whether the response is a status meaning the end of a file or directory.
+fn is_eof(kind: u8, response: &PacketReader) -> bool {
+    kind == FXP_STATUS && response.data.get(5..9) == Some(&FX_EOF.to_be_bytes())
+}
+
+/// Command running ssh with the connection options from `config`, ending
+/// with the host. `options` go before the host.
+pub(super) fn ssh_command(
+    config_path: &Path,
+    config: &SshStorageConfig,
+    options: &[&str],
+) -> Command {
+    let mut command = Command::new("ssh");
+    command.args(["-o", "BatchMode=yes", "-p", &config.port.to_string()]);
+    if let Some(ref key_path) = config.key_path {
+        command
+            .arg("-i")
+            .arg(config_path.parent().unwrap().join(key_path));
+    }
+    if let Some(ref user) = config.user {
+        command.args(["-l", user]);
+    }
+    command.args(options).arg(&config.host);
+    command
+}
+
+/// Directory of the repository on the remote machine: `path` with the
+/// prefix below it.
+pub(super) fn remote_root(config: &SshStorageConfig) -> io::Result<String> {
+    let prefix = normalize_prefix(&config.prefix)?;
+    Ok(match config.path.trim_end_matches('/') {
+        _ if prefix.is_empty() => config.path.clone(),
+        "" if config.path.starts_with('/') => format!("/{}", prefix),
+        "" => prefix,
+        path => format!("{}/{}", path, prefix),
+    })
+}
+
+fn is_dir(attributes: &Attributes) -> bool {
+    attributes
+        .permissions
+        .is_some_and(|mode| mode & libc::S_IFMT == libc::S_IFDIR)
+}
+
+impl SftpStorage {
+    /// Start an SFTP session with `ssh`, using the user's ssh config, known
+    /// hosts and agent. Password prompts are disabled, so the key has to be
+    /// usable without one.
+    pub async fn from_config(config_path: &Path, config: &SshStorageConfig) -> io::Result<Self> {
+        let root = remote_root(config)?;
+        let (local, remote) = StdUnixStream::pair()?;
+        let mut command = ssh_command(config_path, config, &["-s"]);
+        command
+            .arg("sftp")
+            .stdin(Stdio::from(OwnedFd::from(remote.try_clone()?)))
+            .stdout(Stdio::from(OwnedFd::from(remote)))
+            .stderr(Stdio::inherit());
+        let ssh = command
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to start ssh: {}", e)))?;
+
+        local.set_nonblocking(true)?;
+        let (reader, writer) = UnixStream::from_std(local)?.into_split();
+        let mut storage = Self::new(Box::new(reader), Box::new(writer), &root).await?;
+        storage.ssh = Some(ssh);
+        Ok(storage)
+    }
+
+    /// Start an SFTP session over an established connection.
+    async fn new(
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+        root: &str,
+    ) -> io::Result<Self> {
+        let mut channel = Channel {
+            reader,
+            writer,
+            next_id: 0,
+        };
+        channel.send(PacketWriter::new(FXP_INIT).u32(3)).await?;
+        let mut version = channel.receive().await?;
+        if version.u8()? != FXP_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected response to SFTP init",
+            ));
+        }
+        version.u32()?;
+        let mut fsync = false;
+        while version.position < version.data.len() {
+            let name = version.string()?;
+            let data = version.string()?;
+            fsync |= name == "fsync@openssh.com" && data == "1";
+        }
+
+        let root = match root.trim_end_matches('/') {
+            "" if root.starts_with('/') => "/".to_string(),
+            "" => ".".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+        let storage = Self {
+            channel: Mutex::new(channel),
+            root,
+            fsync,
+            created_dirs: StdMutex::new(HashSet::new()),
+            ssh: None,
+        };
+        storage.create_dirs(&storage.root).await?;
+        storage.create_dirs(&storage.tmp_dir()).await?;
+        Ok(storage)
+    }
+
+    fn tmp_dir(&self) -> String {
+        format!("{}/tmp", self.root)
+    }
+
+    fn collection_path(&self, collection: Collection) -> String {
+        format!("{}/{}", self.root, collection.name())
+    }
+
+    /// The path is: <collection>/<xor hash of key>/<base16 of key>
+    fn item_path(&self, collection: Collection, key: &str) -> io::Result<String> {
+        if key.len() <= 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Key must be at least 3 characters long",
+            ));
+        }
+        Ok(format!(
+            "{}/{}/{}",
+            self.collection_path(collection),
+            xor_byte_hash(key.as_bytes()),
+            base16_encode(key)
+        ))
+    }
+
+    async fn request(
+        &self,
+        kind: u8,
+        build: impl FnOnce(PacketWriter) -> PacketWriter,
+    ) -> io::Result<(u8, PacketReader)> {
+        self.channel.lock().await.request(kind, build).await
+    }
+
+    /// Create `path` and its missing parents.
+    async fn create_dirs(&self, path: &str) -> io::Result<()> {
+        if self.created_dirs.lock().unwrap().contains(path) {
+            return Ok(());
+        }
+        let mut prefix = String::new();
+        for component in path.split_inclusive('/') {
+            prefix.push_str(component);
+            let dir = prefix.trim_end_matches('/');
+            if dir.is_empty() || dir == "." || self.created_dirs.lock().unwrap().contains(dir) {
+                continue;
+            }
+            match self.stat(dir).await? {
+                Some(attributes) if is_dir(&attributes) => {}
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} is not a directory", dir),
+                    ))
+                }
+                None => {
+                    let response = self.request(FXP_MKDIR, |p| p.string(dir).u32(0)).await?;
+                    // Another writer may have created it in the meantime.
+                    if let Err(e) = expect_ok(response, &format!("Creating {}", dir)) {
+                        if !self
+                            .stat(dir)
+                            .await?
+                            .is_some_and(|attributes| is_dir(&attributes))
+                        {
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            self.created_dirs.lock().unwrap().insert(dir.to_string());
+        }
+        Ok(())
+    }
+
+    /// Attributes of `path`, None if it doesn't exist.
+    async fn stat(&self, path: &str) -> io::Result<Option<Attributes>> {
+        let (kind, mut response) = self.request(FXP_STAT, |p| p.string(path)).await?;
+        if kind != FXP_ATTRS {
+            return match expect_ok((kind, response), &format!("Checking {}", path)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+                Ok(()) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Unexpected response to SFTP stat",
+                )),
+            };
+        }
+        Ok(Some(response.attributes()?))
+    }
+
+    async fn close(&self, handle: &[u8]) -> io::Result<()> {
+        expect_ok(
+            self.request(FXP_CLOSE, |p| p.bytes(handle)).await?,
+            "Closing file",
+        )
+    }
+
+    async fn upload(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        let handle = expect_handle(
+            self.request(FXP_OPEN, |p| {
+                p.string(path).u32(FXF_WRITE | FXF_CREAT | FXF_EXCL).u32(0)
+            })
+            .await?,
+            &format!("Creating {}", path),
+        )?;
+
+        let mut result = Ok(());
+        for (index, chunk) in data.chunks(MAX_IO_LENGTH).enumerate() {
+            let offset = (index * MAX_IO_LENGTH) as u64;
+            result = match self
+                .request(FXP_WRITE, |p| p.bytes(&handle).u64(offset).bytes(chunk))
+                .await
+            {
+                Ok(response) => expect_ok(response, &format!("Writing {}", path)),
+                Err(e) => Err(e),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() && self.fsync {
+            result = match self
+                .request(FXP_EXTENDED, |p| {
+                    p.string("fsync@openssh.com").bytes(&handle)
+                })
+                .await
+            {
+                Ok(response) => expect_ok(response, &format!("Syncing {}", path)),
+                Err(e) => Err(e),
+            };
+        }
+        let closed = self.close(&handle).await;
+        result.and(closed)
+    }
+
+    async fn remove(&self, path: &str) -> io::Result<()> {
+        expect_ok(
+            self.request(FXP_REMOVE, |p| p.string(path)).await?,
+            &format!("Removing {}", path),
+        )
+    }
+
+    /// Names and attributes of the entries in a directory, except "." and
+    /// "..".
+    async fn read_dir(&self, path: &str) -> io::Result<Vec<(String, Attributes)>> {
+        let handle = expect_handle(
+            self.request(FXP_OPENDIR, |p| p.string(path)).await?,
+            &format!("Listing {}", path),
+        )?;
+        let mut entries = Vec::new();
+        let result = loop {
+            let (kind, mut response) = match self.request(FXP_READDIR, |p| p.bytes(&handle)).await {
+                Ok(response) => response,
+                Err(e) => break Err(e),
+            };
+            if is_eof(kind, &response) {
+                break Ok(());
+            }
+            if kind != FXP_NAME {
+                break Err(unexpected_response(
+                    kind,
+                    response,
+                    FXP_NAME,
+                    &format!("Listing {}", path),
+                ));
+            }
+            let parsed: io::Result<()> = (|| {
+                for _ in 0..response.u32()? {
+                    let name = response.string()?;
+                    response.bytes()?; // Long name, as shown by ls -l.
+                    let attributes = response.attributes()?;
+                    if name != "." && name != ".." {
+                        entries.push((name, attributes));
+                    }
+                }
+                Ok(())
+            })();
+            if parsed.is_err() {
+                break parsed;
+            }
+        };
+        let closed = self.close(&handle).await;
+        result.and(closed)?;
+        Ok(entries)
+    }
+}
+
+impl Drop for SftpStorage {
+    fn drop(&mut self) {
+        if let Some(ref mut ssh) = self.ssh {
+            _ = ssh.kill();
+            _ = ssh.wait();
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for SftpStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let path = self.item_path(collection, key)?;
+        if self.stat(&path).await?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("File already exists: {}", path),
+            ));
+        }
+        self.create_dirs(path.rsplit_once('/').unwrap().0).await?;
+
+        let random_name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let tmp_path = format!("{}/{}", self.tmp_dir(), random_name);
+        let mut result = self.upload(&tmp_path, data).await;
+        if result.is_ok() {
+            // Renaming fails if the target exists, so items are never
+            // overwritten.
+            result = match self
+                .request(FXP_RENAME, |p| p.string(&tmp_path).string(&path))
+                .await
+            {
+                Ok(response) => expect_ok(response, &format!("Renaming to {}", path)),
+                Err(e) => Err(e),
+            };
+        }
+        if result.is_err() {
+            if let Err(e) = self.remove(&tmp_path).await {
+                warn!("Failed to remove temp file: {}", e);
+            }
+        }
+        result
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        let path = self.item_path(collection, key)?;
+        let handle = expect_handle(
+            self.request(FXP_OPEN, |p| p.string(&path).u32(FXF_READ).u32(0))
+                .await?,
+            &format!("Opening {}", path),
+        )?;
+
+        buffer.clear();
+        let result = loop {
+            let offset = buffer.len() as u64;
+            let (kind, mut response) = match self
+                .request(FXP_READ, |p| {
+                    p.bytes(&handle).u64(offset).u32(MAX_IO_LENGTH as u32)
+                })
+                .await
+            {
+                Ok(response) => response,
+                Err(e) => break Err(e),
+            };
+            if is_eof(kind, &response) {
+                break Ok(());
+            }
+            if kind != FXP_DATA {
+                break Err(unexpected_response(
+                    kind,
+                    response,
+                    FXP_DATA,
+                    &format!("Reading {}", path),
+                ));
+            }
+            match response.bytes() {
+                Ok(data) => buffer.extend_from_slice(&data),
+                Err(e) => break Err(e),
+            }
+        };
+        let closed = self.close(&handle).await;
+        result.and(closed)
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        let path = self.item_path(collection, key)?;
+        match self.remove(&path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        let path = format!("{}/{}", self.root, REPO_ID_FILE);
+        // Uploads are exclusive, but SFTP has no distinct error for that.
+        if self.stat(&path).await?.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Repository ID already exists: {}", path),
+            ));
+        }
+        self.upload(&path, format!("{}\n", repo_id).as_bytes())
+            .await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        let tmp_dir = self.tmp_dir();
+        let entries = match self.read_dir(&tmp_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let mut removed = 0;
+        for (name, attributes) in entries {
+            // Without a modification time the file may still be written to.
+            if is_dir(&attributes) || attributes.modified.is_none_or(|time| time >= older_than) {
+                continue;
+            }
+            self.remove(&format!("{}/{}", tmp_dir, name)).await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        let path = self.collection_path(collection);
+        let dirs = match self.read_dir(&path).await {
+            Ok(dirs) => dirs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut items = Vec::new();
+        for (dir, attributes) in dirs {
+            if !is_dir(&attributes) {
+                continue;
+            }
+            for (file_name, _) in self.read_dir(&format!("{}/{}", path, dir)).await? {
+                items.push(base16_decode(&file_name).map_err(|e| {
+                    io::Error::other(format!("Invalid filename {:?}: {}", file_name, e))
+                })?);
+            }
+        }
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    use tokio::io::{DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    /// Files and directories of the in-memory server.
+    #[derive(Default)]
+    struct Files {
+        files: BTreeMap<String, Vec<u8>>,
+        dirs: BTreeSet<String>,
+        /// Open files and directory listings by handle. Listings are
+        /// returned in one batch.
+        handles: BTreeMap<Vec<u8>, (String, bool)>,
+    }
+
+    fn status(code: u32) -> PacketWriter {
+        PacketWriter::new(FXP_STATUS)
+            .u32(code)
+            .string("")
+            .string("")
+    }
+
+    fn parent(path: &str) -> &str {
+        path.rsplit_once('/').map_or(".", |(parent, _)| parent)
+    }
+
+    impl Files {
+        fn handle(&mut self, kind: u8, request: &mut PacketReader) -> io::Result<PacketWriter> {
+            Ok(match kind {
+                FXP_OPEN => {
+                    let path = request.string()?;
+                    let flags = request.u32()?;
+                    let exists = self.files.contains_key(&path);
+                    if flags & FXF_CREAT != 0 {
+                        if exists && flags & FXF_EXCL != 0 {
+                            return Ok(status(4));
+                        }
+                        if !self.dirs.contains(parent(&path)) {
+                            return Ok(status(FX_NO_SUCH_FILE));
+                        }
+                        self.files.entry(path.clone()).or_default();
+                    } else if !exists {
+                        return Ok(status(FX_NO_SUCH_FILE));
+                    }
+                    let handle = (self.handles.len() as u32).to_be_bytes().to_vec();
+                    self.handles.insert(handle.clone(), (path, false));
+                    PacketWriter::new(FXP_HANDLE).bytes(&handle)
+                }
+                FXP_OPENDIR => {
+                    let path = request.string()?;
+                    if !self.dirs.contains(&path) {
+                        return Ok(status(FX_NO_SUCH_FILE));
+                    }
+                    let handle = (self.handles.len() as u32).to_be_bytes().to_vec();
+                    self.handles.insert(handle.clone(), (path, false));
+                    PacketWriter::new(FXP_HANDLE).bytes(&handle)
+                }
+                FXP_CLOSE => {
+                    self.handles.remove(&request.bytes()?);
+                    status(FX_OK)
+                }
+                FXP_WRITE => {
+                    let (path, _) = &self.handles[&request.bytes()?];
+                    let offset = request.u64()? as usize;
+                    let data = request.bytes()?;
+                    let file = self.files.get_mut(path).unwrap();
+                    file.resize(file.len().max(offset + data.len()), 0);
+                    file[offset..offset + data.len()].copy_from_slice(&data);
+                    status(FX_OK)
+                }
+                FXP_READ => {
+                    let (path, _) = &self.handles[&request.bytes()?];
+                    let offset = request.u64()? as usize;
+                    let length = request.u32()? as usize;
+                    let file = &self.files[path];
+                    if offset >= file.len() {
+                        return Ok(status(FX_EOF));
+                    }
+                    let end = file.len().min(offset + length);
+                    PacketWriter::new(FXP_DATA).bytes(&file[offset..end])
+                }
+                FXP_READDIR => {
+                    let (path, done) = self.handles.get_mut(&request.bytes()?).unwrap();
+                    if *done {
+                        return Ok(status(FX_EOF));
+                    }
+                    *done = true;
+                    let prefix = format!("{}/", path);
+                    let mut entries = vec![(".".to_string(), libc::S_IFDIR)];
+                    for (names, mode) in [
+                        (self.dirs.iter().collect::<Vec<_>>(), libc::S_IFDIR),
+                        (self.files.keys().collect(), libc::S_IFREG),
+                    ] {
+                        entries.extend(
+                            names
+                                .into_iter()
+                                .filter_map(|name| name.strip_prefix(&prefix))
+                                .filter(|name| !name.contains('/'))
+                                .map(|name| (name.to_string(), mode)),
+                        );
+                    }
+                    let mut packet = PacketWriter::new(FXP_NAME).u32(entries.len() as u32);
+                    for (name, mode) in entries {
+                        packet = packet
+                            .string(&name)
+                            .string(&name)
+                            .u32(ATTR_PERMISSIONS)
+                            .u32(mode | 0o755);
+                    }
+                    packet
+                }
+                FXP_REMOVE => match self.files.remove(&request.string()?) {
+                    Some(_) => status(FX_OK),
+                    None => status(FX_NO_SUCH_FILE),
+                },
+                FXP_MKDIR => {
+                    let path = request.string()?;
+                    if self.dirs.contains(&path) || !self.dirs.contains(parent(&path)) {
+                        return Ok(status(4));
+                    }
+                    self.dirs.insert(path);
+                    status(FX_OK)
+                }
+                FXP_STAT => {
+                    let path = request.string()?;
+                    let mode = if self.dirs.contains(&path) {
+                        libc::S_IFDIR
+                    } else if self.files.contains_key(&path) {
+                        libc::S_IFREG
+                    } else {
+                        return Ok(status(FX_NO_SUCH_FILE));
+                    };
+                    PacketWriter::new(FXP_ATTRS)
+                        .u32(ATTR_PERMISSIONS)
+                        .u32(mode | 0o755)
+                }
+                FXP_RENAME => {
+                    let from = request.string()?;
+                    let to = request.string()?;
+                    if self.files.contains_key(&to) || !self.dirs.contains(parent(&to)) {
+                        return Ok(status(4));
+                    }
+                    match self.files.remove(&from) {
+                        Some(data) => {
+                            self.files.insert(to, data);
+                            status(FX_OK)
+                        }
+                        None => status(FX_NO_SUCH_FILE),
+                    }
+                }
+                _ => status(FX_OP_UNSUPPORTED),
+            })
+        }
+    }
+
+    /// Serve a file system from memory, with just enough of SFTP for the
+    /// storage. Only the current directory "." exists at the start.
+    async fn serve_files(reader: ReadHalf<DuplexStream>, writer: WriteHalf<DuplexStream>) {
+        let mut channel = Channel {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            next_id: 0,
+        };
+        let mut files = Files::default();
+        files.dirs.insert(".".to_string());
+        while let Ok(mut request) = channel.receive().await {
+            let kind = request.u8().unwrap();
+            if kind == FXP_INIT {
+                channel
+                    .send(PacketWriter::new(FXP_VERSION).u32(3))
+                    .await
+                    .unwrap();
+                continue;
+            }
+            let id = request.u32().unwrap();
+            let mut response = files.handle(kind, &mut request).unwrap();
+            response.0.splice(1..1, id.to_be_bytes());
+            channel.send(response).await.unwrap();
+        }
+    }
+
+    struct SftpStorageTestState {
+        storage: SftpStorage,
+    }
+
+    impl SftpStorageTestState {
+        async fn new() -> Self {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            let (server_reader, server_writer) = tokio::io::split(server);
+            tokio::spawn(serve_files(server_reader, server_writer));
+
+            let (reader, writer) = tokio::io::split(client);
+            let storage = SftpStorage::new(Box::new(reader), Box::new(writer), "backups/repo/")
+                .await
+                .unwrap();
+            Self { storage }
+        }
+    }
+
+    storage_tests!(SftpStorageTestState);
+
+    #[tokio::test]
+    async fn write_existing_returns_already_exists() {
+        let state = SftpStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        let error = state
+            .storage
+            .write(Collection::Blob, "key_1", b"2")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[tokio::test]
+    async fn init_writes_repo_id_once() {
+        let state = SftpStorageTestState::new().await;
+        state.storage.init("repo-1").await.unwrap();
+        let error = state.storage.init("repo-2").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_remote_root() {
+        let config = |path: &str, prefix: &str| SshStorageConfig {
+            host: "host".to_string(),
+            port: 22,
+            user: None,
+            key_path: None,
+            path: path.to_string(),
+            prefix: prefix.to_string(),
+        };
+        assert_eq!(remote_root(&config("backups/", "")).unwrap(), "backups/");
+        assert_eq!(
+            remote_root(&config("backups/", "/laptop/")).unwrap(),
+            "backups/laptop/"
+        );
+        assert_eq!(remote_root(&config("/", "laptop")).unwrap(), "/laptop/");
+        assert_eq!(remote_root(&config("", "laptop")).unwrap(), "laptop/");
+        assert!(remote_root(&config("backups", "blob")).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_write_large_item() {
+        let state = SftpStorageTestState::new().await;
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        state
+            .storage
+            .write(Collection::Blob, "large", &data)
+            .await
+            .unwrap();
+
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "large", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, data);
+    }
+
+    #[tokio::test]
+    async fn read_missing_returns_not_found() {
+        let state = SftpStorageTestState::new().await;
+        let error = state
+            .storage
+            .read(Collection::Blob, "missing", &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
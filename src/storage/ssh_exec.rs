@@ -0,0 +1,348 @@
+use std::{
+    ffi::OsString,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::io;
+
+use crate::{data::config::SshStorageConfig, util::hash::run_blocking};
+
+use super::file::REPO_ID_FILE;
+use super::sftp::{remote_root, ssh_command};
+use super::util::{base16_decode, base16_encode, xor_byte_hash};
+use super::{Collection, Storage, StorageItems, StorageRead, StorageWrite};
+
+// Exit codes the scripts use for missing and existing items.
+const EXIT_NOT_FOUND: i32 = 2;
+const EXIT_EXISTS: i32 = 17;
+
+/// Storage in a directory on a remote machine that only offers a shell over
+/// ssh. Each operation runs a small POSIX shell script with `cat`, `dd`,
+/// `ln` and `find`, so it works with busybox too. Items are laid out as in
+/// `FileStorage`, and are written to a temporary file first and hard linked
+/// into place, which fails if the item already exists.
+///
+/// Every operation starts a new ssh connection, so ControlMaster in the ssh
+/// config helps a lot.
+pub struct SshExecStorage {
+    /// Command that runs the script given as its last argument.
+    program: OsString,
+    args: Vec<OsString>,
+    root: String,
+}
+
+/// Quote `text` as a single word for the shell.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+impl SshExecStorage {
+    pub fn from_config(config_path: &Path, config: &SshStorageConfig) -> io::Result<Self> {
+        let command = ssh_command(config_path, config, &[]);
+        Ok(Self::new(
+            command.get_program().to_owned(),
+            command.get_args().map(|arg| arg.to_owned()).collect(),
+            &remote_root(config)?,
+        ))
+    }
+
+    fn new(program: OsString, args: Vec<OsString>, root: &str) -> Self {
+        let root = match root.trim_end_matches('/') {
+            "" if root.starts_with('/') => "/".to_string(),
+            "" => ".".to_string(),
+            trimmed => trimmed.to_string(),
+        };
+        Self {
+            program,
+            args,
+            root,
+        }
+    }
+
+    fn collection_path(&self, collection: Collection) -> String {
+        format!("{}/{}", self.root, collection.name())
+    }
+
+    /// The path is: <collection>/<xor hash of key>/<base16 of key>
+    fn item_path(&self, collection: Collection, key: &str) -> io::Result<(String, String)> {
+        if key.len() <= 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Key must be at least 3 characters long",
+            ));
+        }
+        let dir = format!(
+            "{}/{}",
+            self.collection_path(collection),
+            xor_byte_hash(key.as_bytes())
+        );
+        let path = format!("{}/{}", dir, base16_encode(key));
+        Ok((dir, path))
+    }
+
+    /// Run `script` remotely with `input` as its standard input, returning
+    /// its standard output.
+    async fn run(&self, script: String, input: Vec<u8>, operation: &str) -> io::Result<Vec<u8>> {
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let output = run_blocking(move || {
+            let mut child = command.spawn()?;
+            let mut stdin = child.stdin.take().unwrap();
+            // Written from another thread so that a full output pipe can't
+            // block the script while it waits for more input.
+            let writer = std::thread::spawn(move || stdin.write_all(&input));
+            let output = child.wait_with_output()?;
+            // The script may exit without reading everything, which breaks
+            // the pipe, so only its exit status counts.
+            _ = writer.join();
+            Ok::<_, io::Error>(output)
+        })
+        .await?
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to start ssh: {}", e)))?;
+
+        let kind = match output.status.code() {
+            Some(0) => return Ok(output.stdout),
+            Some(EXIT_NOT_FOUND) => io::ErrorKind::NotFound,
+            Some(EXIT_EXISTS) => io::ErrorKind::AlreadyExists,
+            _ => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(
+            kind,
+            format!(
+                "{} failed with {}: {}",
+                operation,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ))
+    }
+}
+
+#[async_trait]
+impl Storage for SshExecStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let (dir, path) = self.item_path(collection, key)?;
+        let tmp_dir = format!("{}/tmp", self.root);
+        let random_name = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+        let script = format!(
+            "set -e
+p={path}
+t={tmp}
+test ! -e \"$p\" || exit {exists}
+mkdir -p {dir} {tmp_dir}
+if ! dd of=\"$t\" bs=65536 conv=fsync 2>/dev/null; then
+    rm -f \"$t\"
+    echo \"Failed to write $t\" >&2
+    exit 1
+fi
+if ! ln \"$t\" \"$p\"; then
+    rm -f \"$t\"
+    test ! -e \"$p\" || exit {exists}
+    exit 1
+fi
+rm -f \"$t\"",
+            path = quote(&path),
+            tmp = quote(&format!("{}/{}", tmp_dir, random_name)),
+            exists = EXIT_EXISTS,
+            dir = quote(&dir),
+            tmp_dir = quote(&tmp_dir),
+        );
+        self.run(script, data.to_vec(), &format!("Writing {}", path))
+            .await?;
+        Ok(())
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        let (_, path) = self.item_path(collection, key)?;
+        let script = format!(
+            "test -e {path} || exit {not_found}
+exec cat {path}",
+            path = quote(&path),
+            not_found = EXIT_NOT_FOUND,
+        );
+        *buffer = self
+            .run(script, Vec::new(), &format!("Reading {}", path))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        let (_, path) = self.item_path(collection, key)?;
+        let script = format!("exec rm -f {}", quote(&path));
+        self.run(script, Vec::new(), &format!("Deleting {}", path))
+            .await?;
+        Ok(())
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        let path = format!("{}/{}", self.root, REPO_ID_FILE);
+        // With noclobber, the redirection fails if the file exists.
+        let script = format!(
+            "set -e
+test ! -e {path} || exit {exists}
+mkdir -p {root}
+set -C
+exec cat > {path}",
+            path = quote(&path),
+            exists = EXIT_EXISTS,
+            root = quote(&self.root),
+        );
+        self.run(
+            script,
+            format!("{}\n", repo_id).into_bytes(),
+            "Writing the repository ID",
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        // find only knows ages in whole minutes, so this may keep files up
+        // to a minute older than asked.
+        let minutes = SystemTime::now()
+            .duration_since(older_than)
+            .unwrap_or_default()
+            .as_secs()
+            / 60;
+        let tmp_dir = format!("{}/tmp", self.root);
+        let script = format!(
+            "cd {} 2>/dev/null || exit 0
+exec find . -type f -mmin +{} -print -exec rm -f {{}} \\;",
+            quote(&tmp_dir),
+            minutes
+        );
+        let output = self
+            .run(script, Vec::new(), &format!("Cleaning {}", tmp_dir))
+            .await?;
+        Ok(String::from_utf8_lossy(&output).lines().count() as u64)
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        let path = self.collection_path(collection);
+        let script = format!(
+            "cd {} 2>/dev/null || exit 0
+exec find . -type f",
+            quote(&path)
+        );
+        let output = self
+            .run(script, Vec::new(), &format!("Listing {}", path))
+            .await?;
+
+        String::from_utf8_lossy(&output)
+            .lines()
+            .map(|line| {
+                let file_name = line.rsplit('/').next().unwrap_or(line);
+                base16_decode(file_name).map_err(|e| {
+                    io::Error::other(format!("Invalid filename {:?}: {}", file_name, e))
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct SshExecStorageTestState {
+        _tmp_dir: tempfile::TempDir,
+        storage: SshExecStorage,
+    }
+
+    impl SshExecStorageTestState {
+        /// Run the scripts with the local shell, as ssh would remotely.
+        async fn new() -> Self {
+            let _tmp_dir = tempfile::tempdir().unwrap();
+            let storage = SshExecStorage::new(
+                "sh".into(),
+                vec!["-c".into()],
+                &_tmp_dir.path().join("it's a repo").to_string_lossy(),
+            );
+            Self { _tmp_dir, storage }
+        }
+    }
+
+    storage_tests!(SshExecStorageTestState);
+
+    #[tokio::test]
+    async fn write_existing_returns_already_exists() {
+        let state = SshExecStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        let error = state
+            .storage
+            .write(Collection::Blob, "key_1", b"2")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        let mut buffer = Vec::new();
+        state
+            .storage
+            .read(Collection::Blob, "key_1", &mut buffer)
+            .await
+            .unwrap();
+        assert_eq!(buffer, b"1");
+    }
+
+    #[tokio::test]
+    async fn init_writes_repo_id_once() {
+        let state = SshExecStorageTestState::new().await;
+        state.storage.init("repo-1").await.unwrap();
+        let error = state.storage.init("repo-2").await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        let path = state._tmp_dir.path().join("it's a repo").join(REPO_ID_FILE);
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "repo-1\n");
+    }
+
+    #[tokio::test]
+    async fn read_missing_returns_not_found() {
+        let state = SshExecStorageTestState::new().await;
+        let error = state
+            .storage
+            .read(Collection::Blob, "missing", &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn clean_temporary_removes_old_files() {
+        let state = SshExecStorageTestState::new().await;
+        state
+            .storage
+            .write(Collection::Blob, "key_1", b"1")
+            .await
+            .unwrap();
+        let tmp_dir = Path::new(&state.storage.root).join("tmp");
+        let old = std::fs::File::create(tmp_dir.join("old")).unwrap();
+        old.set_modified(SystemTime::now() - std::time::Duration::from_secs(3600))
+            .unwrap();
+        std::fs::write(tmp_dir.join("new"), b"").unwrap();
+
+        let older_than = SystemTime::now() - std::time::Duration::from_secs(600);
+        assert_eq!(state.storage.clean_temporary(older_than).await.unwrap(), 1);
+        assert!(!tmp_dir.join("old").exists());
+        assert!(tmp_dir.join("new").exists());
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+}
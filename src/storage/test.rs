@@ -25,6 +25,30 @@ macro_rules! storage_tests {
             Ok(())
         }
 
+        #[tokio::test]
+        async fn write_stream_read_stream_returns_content_back() -> TestResult {
+            use tokio::io::AsyncReadExt;
+
+            let state = <$type>::new().await;
+
+            let data = b"Hello World!".repeat(1000);
+            state
+                .storage
+                .write_stream(Collection::Blob, "key_1", &mut &data[..])
+                .await?;
+
+            let mut buffer = Vec::new();
+            state
+                .storage
+                .read_stream(Collection::Blob, "key_1")
+                .await?
+                .read_to_end(&mut buffer)
+                .await?;
+            assert_eq!(buffer, data);
+
+            Ok(())
+        }
+
         #[tokio::test]
         async fn get_collection_items_returns_correct_files() -> TestResult {
             let state = <$type>::new().await;
@@ -75,6 +99,60 @@ macro_rules! storage_tests {
             Ok(())
         }
 
+        #[tokio::test]
+        async fn get_collection_items_with_prefix_filters_items() -> TestResult {
+            let state = <$type>::new().await;
+
+            for key in ["archive/1", "archive/2", "archive_2/1", "other/1"] {
+                state.storage.write(Collection::Snapshot, key, b"").await?;
+            }
+            state
+                .storage
+                .write(Collection::Blob, "archive/3", b"")
+                .await?;
+
+            let mut items = state
+                .storage
+                .get_collection_items_with_prefix(Collection::Snapshot, "archive/")
+                .await?;
+            items.sort();
+            assert_eq!(items, ["archive/1", "archive/2"]);
+
+            Ok(())
+        }
+
+        #[tokio::test]
+        async fn delete_removes_item() -> TestResult {
+            let state = <$type>::new().await;
+
+            state
+                .storage
+                .write(Collection::Snapshot, "key_1", b"1")
+                .await?;
+            state
+                .storage
+                .write(Collection::Snapshot, "key_2", b"2")
+                .await?;
+            state.storage.delete(Collection::Snapshot, "key_1").await?;
+            // Deleting again is not an error.
+            state.storage.delete(Collection::Snapshot, "key_1").await?;
+
+            let items = state
+                .storage
+                .get_collection_items(Collection::Snapshot)
+                .await?;
+            assert_eq!(items, ["key_2"]);
+            let mut buffer = Vec::new();
+            let error = state
+                .storage
+                .read(Collection::Snapshot, "key_1", &mut buffer)
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+            Ok(())
+        }
+
         #[tokio::test]
         async fn read_unknown_returns_not_found() -> TestResult {
             let state = <$type>::new().await;
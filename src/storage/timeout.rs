@@ -0,0 +1,272 @@
+use std::{
+    future::Future,
+    path::Path,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{self, AsyncRead, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::data::config::TimeoutConfig;
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+/// Storage wrapper that fails operations taking longer than the configured
+/// timeouts with `io::ErrorKind::TimedOut`, so a hung backend can't stall a
+/// command forever.
+pub struct TimeoutStorage {
+    inner: Box<dyn Storage>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+    list: Option<Duration>,
+}
+
+impl TimeoutStorage {
+    pub fn new(inner: Box<dyn Storage>, config: &TimeoutConfig) -> Self {
+        Self {
+            inner,
+            read: config.read,
+            write: config.write,
+            list: config.list,
+        }
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    operation: &str,
+    future: impl Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    let Some(timeout) = timeout else {
+        return future.await;
+    };
+
+    match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result,
+        Err(_) => Err(timed_out(operation, timeout)),
+    }
+}
+
+fn timed_out(operation: &str, timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("Storage {} timed out after {:?}", operation, timeout),
+    )
+}
+
+/// Reader of a stream that has to be read to the end within the read
+/// timeout, as a buffered read would.
+struct TimeoutReader {
+    inner: StorageReader,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl AsyncRead for TimeoutReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Poll::Ready(result) = Pin::new(&mut self.inner).poll_read(cx, buf) {
+            return Poll::Ready(result);
+        }
+        ready!(self.deadline.as_mut().poll(cx));
+        Poll::Ready(Err(timed_out("read", self.timeout)))
+    }
+}
+
+#[async_trait]
+impl Storage for TimeoutStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        with_timeout(self.write, "write", self.inner.write(collection, key, data)).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        with_timeout(self.read, "read", self.inner.read(collection, key, buffer)).await
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        with_timeout(
+            self.list,
+            "list",
+            self.inner.get_collection_items(collection),
+        )
+        .await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        with_timeout(
+            self.list,
+            "list",
+            self.inner
+                .get_collection_items_with_prefix(collection, prefix),
+        )
+        .await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        with_timeout(
+            self.write,
+            "write",
+            self.inner.write_stream(collection, key, reader),
+        )
+        .await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        let Some(timeout) = self.read else {
+            return self.inner.read_stream(collection, key).await;
+        };
+        let deadline = Instant::now() + timeout;
+        let inner = match tokio::time::timeout_at(deadline, self.inner.read_stream(collection, key))
+            .await
+        {
+            Ok(result) => result?,
+            Err(_) => return Err(timed_out("read", timeout)),
+        };
+        Ok(Box::new(TimeoutReader {
+            inner,
+            timeout,
+            deadline: Box::pin(tokio::time::sleep_until(deadline)),
+        }))
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        with_timeout(
+            self.write,
+            "replace",
+            self.inner.replace(collection, key, data),
+        )
+        .await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        with_timeout(self.write, "delete", self.inner.delete(collection, key)).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        with_timeout(self.list, "list", self.inner.has_many(collection, keys)).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::file::FileStorage;
+    use tokio::io::AsyncReadExt;
+
+    struct TimeoutStorageTestState {
+        _tmp_dir: tempfile::TempDir,
+        storage: TimeoutStorage,
+    }
+
+    impl TimeoutStorageTestState {
+        async fn new() -> Self {
+            let _tmp_dir = tempfile::tempdir().unwrap();
+            let file_storage = FileStorage::new(_tmp_dir.path().to_owned()).await.unwrap();
+            let storage = TimeoutStorage::new(
+                Box::new(file_storage),
+                &TimeoutConfig {
+                    read: Some(Duration::from_secs(10)),
+                    write: Some(Duration::from_secs(10)),
+                    list: None,
+                },
+            );
+
+            Self { _tmp_dir, storage }
+        }
+    }
+
+    storage_tests!(TimeoutStorageTestState);
+
+    struct HungStorage;
+
+    #[async_trait]
+    impl Storage for HungStorage {
+        async fn write(&self, _: Collection, _: &str, _: &[u8]) -> StorageWrite {
+            std::future::pending().await
+        }
+
+        async fn read(&self, _: Collection, _: &str, _: &mut Vec<u8>) -> StorageRead {
+            std::future::pending().await
+        }
+
+        async fn get_collection_items(&self, _: Collection) -> StorageItems {
+            std::future::pending().await
+        }
+
+        async fn read_stream(&self, _: Collection, _: &str) -> io::Result<StorageReader> {
+            // Opens, but never gives any data.
+            let (reader, writer) = tokio::io::duplex(1);
+            std::mem::forget(writer);
+            Ok(Box::new(reader))
+        }
+    }
+
+    #[tokio::test]
+    async fn hung_operation_times_out() {
+        let storage = TimeoutStorage::new(
+            Box::new(HungStorage),
+            &TimeoutConfig {
+                read: Some(Duration::from_millis(10)),
+                ..Default::default()
+            },
+        );
+
+        let error = storage
+            .read(Collection::Blob, "key", &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn hung_stream_times_out() {
+        let storage = TimeoutStorage::new(
+            Box::new(HungStorage),
+            &TimeoutConfig {
+                read: Some(Duration::from_millis(10)),
+                ..Default::default()
+            },
+        );
+
+        let mut reader = storage.read_stream(Collection::Blob, "key").await.unwrap();
+        let error = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,188 @@
+use std::{path::Path, time::SystemTime};
+
+use async_trait::async_trait;
+use tokio::io::{self, AsyncRead};
+use tracing::{debug_span, field, Instrument};
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+/// Storage wrapper that runs every operation in a tracing span, with the
+/// collection, key and size as fields.
+pub struct TracedStorage {
+    inner: Box<dyn Storage>,
+}
+
+impl TracedStorage {
+    pub fn new(inner: Box<dyn Storage>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl Storage for TracedStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let span = debug_span!(
+            "storage_write",
+            collection = collection.name(),
+            key,
+            bytes = data.len()
+        );
+        self.inner
+            .write(collection, key, data)
+            .instrument(span)
+            .await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        let span = debug_span!(
+            "storage_read",
+            collection = collection.name(),
+            key,
+            bytes = field::Empty
+        );
+        let result = self
+            .inner
+            .read(collection, key, buffer)
+            .instrument(span.clone())
+            .await;
+        span.record("bytes", buffer.len());
+        result
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        let span = debug_span!(
+            "storage_list",
+            collection = collection.name(),
+            items = field::Empty
+        );
+        let result = self
+            .inner
+            .get_collection_items(collection)
+            .instrument(span.clone())
+            .await;
+        if let Ok(ref items) = result {
+            span.record("items", items.len());
+        }
+        result
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        let span = debug_span!(
+            "storage_list",
+            collection = collection.name(),
+            prefix,
+            items = field::Empty
+        );
+        let result = self
+            .inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .instrument(span.clone())
+            .await;
+        if let Ok(ref items) = result {
+            span.record("items", items.len());
+        }
+        result
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let span = debug_span!("storage_write_stream", collection = collection.name(), key);
+        self.inner
+            .write_stream(collection, key, reader)
+            .instrument(span)
+            .await
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        // Only covers opening, the data is read by the caller afterwards.
+        let span = debug_span!("storage_open", collection = collection.name(), key);
+        self.inner
+            .read_stream(collection, key)
+            .instrument(span)
+            .await
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let span = debug_span!(
+            "storage_replace",
+            collection = collection.name(),
+            key,
+            bytes = data.len()
+        );
+        self.inner
+            .replace(collection, key, data)
+            .instrument(span)
+            .await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        let span = debug_span!("storage_delete", collection = collection.name(), key);
+        self.inner.delete(collection, key).instrument(span).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        let span = debug_span!(
+            "storage_has_many",
+            collection = collection.name(),
+            keys = keys.len()
+        );
+        self.inner.has_many(collection, keys).instrument(span).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner
+            .flush()
+            .instrument(debug_span!("storage_flush"))
+            .await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner
+            .clean_temporary(older_than)
+            .instrument(debug_span!("storage_clean_temporary"))
+            .await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner
+            .init(repo_id)
+            .instrument(debug_span!("storage_init"))
+            .await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    struct TracedStorageTestState {
+        storage: TracedStorage,
+    }
+
+    impl TracedStorageTestState {
+        async fn new() -> Self {
+            Self {
+                storage: TracedStorage::new(Box::new(MemoryStorage::new())),
+            }
+        }
+    }
+
+    storage_tests!(TracedStorageTestState);
+}
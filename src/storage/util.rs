@@ -1,4 +1,6 @@
-use std::string::FromUtf8Error;
+use std::{io, string::FromUtf8Error};
+
+use super::{file::REPO_ID_FILE, Collection};
 
 fn nibble_char(nibble: u8) -> u8 {
     assert!(nibble <= 0xF);
@@ -56,10 +58,44 @@ pub fn base16_decode(str: &str) -> Result<String, FromUtf8Error> {
     return String::from_utf8(output);
 }
 
+/// Check a prefix from a storage config, and return it with a trailing '/'
+/// or empty. Its parts can't be collection names, "tmp" or the repository
+/// ID marker, so that the items of a repository never show up in the
+/// listings of one whose prefix is a part of it.
+pub fn normalize_prefix(prefix: &str) -> io::Result<String> {
+    let prefix = prefix.trim_matches('/');
+    if prefix.is_empty() {
+        return Ok(String::new());
+    }
+    for part in prefix.split('/') {
+        let reserved =
+            Collection::from_name(part).is_some() || part == "tmp" || part == REPO_ID_FILE;
+        if reserved || matches!(part, "" | "." | "..") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid part {:?} in prefix {:?}", part, prefix),
+            ));
+        }
+    }
+    Ok(format!("{}/", prefix))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_normalize_prefix() {
+        assert_eq!(normalize_prefix("").unwrap(), "");
+        assert_eq!(normalize_prefix("/").unwrap(), "");
+        assert_eq!(normalize_prefix("/team/laptop/").unwrap(), "team/laptop/");
+        assert!(normalize_prefix("team//laptop").is_err());
+        assert!(normalize_prefix("team/../laptop").is_err());
+        assert!(normalize_prefix("team/blob").is_err());
+        assert!(normalize_prefix("tmp/laptop").is_err());
+        assert!(normalize_prefix("repo-id").is_err());
+    }
+
     #[test]
     fn test_xor_byte_hash_returns_correct_hash() {
         assert_eq!(xor_byte_hash("Hello World!".as_bytes()), "f2");
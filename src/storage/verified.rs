@@ -0,0 +1,225 @@
+use std::{path::Path, time::SystemTime};
+
+use async_trait::async_trait;
+use tokio::io::{self, AsyncRead};
+use tracing::warn;
+
+use crate::util::hash::HashingReader;
+
+use super::{Collection, Storage, StorageItems, StorageRead, StorageReader, StorageWrite};
+
+/// Storage wrapper that reads every item back after writing it and fails
+/// the write if the storage returns anything else, catching backends that
+/// silently truncate or corrupt writes before a snapshot refers to them.
+///
+/// Streamed writes are compared by their hashes, so they aren't held in
+/// memory.
+pub struct VerifiedStorage {
+    inner: Box<dyn Storage>,
+}
+
+impl VerifiedStorage {
+    pub fn new(inner: Box<dyn Storage>) -> Self {
+        Self { inner }
+    }
+
+    async fn verify(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        let mut buffer = Vec::with_capacity(data.len());
+        self.inner.read(collection, key, &mut buffer).await?;
+        if buffer == data {
+            return Ok(());
+        }
+        Err(self
+            .discard(collection, key, buffer.len() as u64, data.len() as u64)
+            .await)
+    }
+
+    /// Remove the damaged item, so that it isn't taken as already stored
+    /// and the next backup writes it again.
+    async fn discard(
+        &self,
+        collection: Collection,
+        key: &str,
+        stored_bytes: u64,
+        written_bytes: u64,
+    ) -> io::Error {
+        if let Err(e) = self.inner.delete(collection, key).await {
+            warn!(
+                "Failed to delete damaged {}/{}: {}",
+                collection.name(),
+                key,
+                e
+            );
+        }
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Storage returned different data for {}/{} after writing it ({} bytes instead of {})",
+                collection.name(),
+                key,
+                stored_bytes,
+                written_bytes
+            ),
+        )
+    }
+}
+
+#[async_trait]
+impl Storage for VerifiedStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.inner.write(collection, key, data).await?;
+        self.verify(collection, key, data).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        self.inner.read(collection, key, buffer).await
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.inner.get_collection_items(collection).await
+    }
+
+    async fn get_collection_items_with_prefix(
+        &self,
+        collection: Collection,
+        prefix: &str,
+    ) -> StorageItems {
+        self.inner
+            .get_collection_items_with_prefix(collection, prefix)
+            .await
+    }
+
+    async fn write_stream(
+        &self,
+        collection: Collection,
+        key: &str,
+        reader: &mut (dyn AsyncRead + Send + Unpin),
+    ) -> StorageWrite {
+        let mut written = HashingReader::new(reader);
+        self.inner
+            .write_stream(collection, key, &mut written)
+            .await?;
+        let mut stored = HashingReader::new(self.inner.read_stream(collection, key).await?);
+        io::copy(&mut stored, &mut io::sink()).await?;
+        let (stored_bytes, written_bytes) = (stored.bytes(), written.bytes());
+        if stored_bytes == written_bytes && stored.finish() == written.finish() {
+            return Ok(());
+        }
+        Err(self
+            .discard(collection, key, stored_bytes, written_bytes)
+            .await)
+    }
+
+    async fn read_stream(&self, collection: Collection, key: &str) -> io::Result<StorageReader> {
+        self.inner.read_stream(collection, key).await
+    }
+
+    async fn replace(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        self.inner.replace(collection, key, data).await?;
+        self.verify(collection, key, data).await
+    }
+
+    async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+        self.inner.delete(collection, key).await
+    }
+
+    async fn has_many(&self, collection: Collection, keys: &[String]) -> io::Result<Vec<bool>> {
+        self.inner.has_many(collection, keys).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn clean_temporary(&self, older_than: SystemTime) -> io::Result<u64> {
+        self.inner.clean_temporary(older_than).await
+    }
+
+    async fn init(&self, repo_id: &str) -> StorageWrite {
+        self.inner.init(repo_id).await
+    }
+
+    fn local_path(&self) -> Option<&Path> {
+        self.inner.local_path()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    struct VerifiedStorageTestState {
+        storage: VerifiedStorage,
+    }
+
+    impl VerifiedStorageTestState {
+        async fn new() -> Self {
+            Self {
+                storage: VerifiedStorage::new(Box::new(MemoryStorage::new())),
+            }
+        }
+    }
+
+    storage_tests!(VerifiedStorageTestState);
+
+    /// Storage that drops the last byte of everything written to it.
+    struct TruncatingStorage(MemoryStorage);
+
+    #[async_trait]
+    impl Storage for TruncatingStorage {
+        async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+            self.0
+                .write(collection, key, &data[..data.len().saturating_sub(1)])
+                .await
+        }
+
+        async fn read(
+            &self,
+            collection: Collection,
+            key: &str,
+            buffer: &mut Vec<u8>,
+        ) -> StorageRead {
+            self.0.read(collection, key, buffer).await
+        }
+
+        async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+            self.0.get_collection_items(collection).await
+        }
+
+        async fn delete(&self, collection: Collection, key: &str) -> StorageWrite {
+            self.0.delete(collection, key).await
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_write_fails_and_is_deleted() {
+        let storage = VerifiedStorage::new(Box::new(TruncatingStorage(MemoryStorage::new())));
+
+        let error = storage
+            .write(Collection::Blob, "key", b"Hello World!")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = storage
+            .write_stream(Collection::Blob, "streamed", &mut &b"Hello World!"[..])
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            error.to_string().contains("11 bytes instead of 12"),
+            "{}",
+            error
+        );
+
+        let items = storage
+            .get_collection_items(Collection::Blob)
+            .await
+            .unwrap();
+        assert!(items.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+struct ChunkCacheState {
+    /// Data and last use of each cached chunk.
+    chunks: HashMap<String, (Arc<Vec<u8>>, u64)>,
+    /// Chunk hashes by last use, oldest first.
+    by_use: BTreeMap<u64, String>,
+    size: u64,
+    clock: u64,
+}
+
+/// Chunks by hash, evicting the least recently used ones once their total
+/// size goes over the capacity.
+pub struct ChunkCache {
+    capacity: u64,
+    state: Mutex<ChunkCacheState>,
+}
+
+impl ChunkCache {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(ChunkCacheState {
+                chunks: HashMap::new(),
+                by_use: BTreeMap::new(),
+                size: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, hash: &str) -> Option<Arc<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let (data, last_use) = state.chunks.get_mut(hash)?;
+        let data = data.clone();
+        let previous_use = std::mem::replace(last_use, clock);
+        state.by_use.remove(&previous_use);
+        state.by_use.insert(clock, hash.to_string());
+        Some(data)
+    }
+
+    /// Cache a chunk unless it is larger than the whole cache.
+    pub fn insert(&self, hash: &str, data: &[u8]) {
+        let length = data.len() as u64;
+        if length > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.chunks.contains_key(hash) {
+            return;
+        }
+        while state.size + length > self.capacity {
+            let Some((_, oldest)) = state.by_use.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = state.chunks.remove(&oldest) {
+                state.size -= evicted.len() as u64;
+            }
+        }
+
+        state.clock += 1;
+        let clock = state.clock;
+        state
+            .chunks
+            .insert(hash.to_string(), (Arc::new(data.to_vec()), clock));
+        state.by_use.insert(clock, hash.to_string());
+        state.size += length;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chunk_cache_evicts_least_recently_used() {
+        let cache = ChunkCache::new(10);
+        cache.insert("a", b"aaaa");
+        cache.insert("b", b"bbbb");
+        assert_eq!(cache.get("a").unwrap().as_slice(), b"aaaa");
+
+        // "b" is the least recently used.
+        cache.insert("c", b"cccc");
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("c").is_some());
+
+        // Larger than the whole cache.
+        cache.insert("d", &[0; 11]);
+        assert!(cache.get("d").is_none());
+        assert!(cache.get("a").is_some());
+    }
+}
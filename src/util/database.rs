@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use tracing::info;
+
+use crate::{
+    cmd::common::CommandResult,
+    data::config::{DatabaseConfig, DatabaseKind},
+    util::process::{run_command, run_command_to_file},
+};
+
+/// Directory in the state directory that database dumps are written to
+/// before they are backed up.
+pub const DATABASE_DUMP_DIR: &str = "database-dump";
+
+/// How to dump one kind of database.
+struct Recipe {
+    /// Prints the version of the dump tool.
+    version: Vec<String>,
+    dump: Vec<String>,
+    /// Whether `dump` writes to stdout rather than to `path` itself.
+    to_stdout: bool,
+    path: PathBuf,
+}
+
+fn recipe(config: &DatabaseConfig, dir: &Path) -> Recipe {
+    let file_name = |extension: &str| {
+        let name = Path::new(&config.database)
+            .file_name()
+            .map_or("database".into(), |name| name.to_string_lossy());
+        dir.join(format!("{}{}", name, extension))
+    };
+    let with_args = |tool: &[&str]| -> Vec<String> {
+        tool.iter()
+            .map(|arg| arg.to_string())
+            .chain(config.args.iter().cloned())
+            .chain([config.database.clone()])
+            .collect()
+    };
+    match config.kind {
+        DatabaseKind::Postgres => Recipe {
+            version: vec!["pg_dump".to_string(), "--version".to_string()],
+            dump: with_args(&["pg_dump", "--format=custom"]),
+            to_stdout: true,
+            path: file_name(".dump"),
+        },
+        DatabaseKind::Mysql => Recipe {
+            version: vec!["mysqldump".to_string(), "--version".to_string()],
+            // A consistent view of InnoDB tables without locking them.
+            dump: with_args(&[
+                "mysqldump",
+                "--single-transaction",
+                "--routines",
+                "--triggers",
+            ]),
+            to_stdout: true,
+            path: file_name(".sql"),
+        },
+        DatabaseKind::Sqlite => {
+            // The online backup API copies a consistent state of the database
+            // even while it is being written to.
+            let path = file_name("");
+            Recipe {
+                version: vec!["sqlite3".to_string(), "--version".to_string()],
+                dump: vec![
+                    "sqlite3".to_string(),
+                    config.database.clone(),
+                    format!(".backup '{}'", path.to_string_lossy().replace('\'', "''")),
+                ],
+                to_stdout: false,
+                path,
+            }
+        }
+    }
+}
+
+/// Dump the database into `dir` with its own tools. Returns the snapshot
+/// metadata to record: the database and the version of the dump tool.
+pub async fn dump_database(
+    config: &DatabaseConfig,
+    dir: &Path,
+) -> CommandResult<Vec<(String, String)>> {
+    let recipe = recipe(config, dir);
+    let version = run_command(recipe.version).await?;
+    let version = version.lines().next().unwrap_or("").trim().to_string();
+    info!("Dumping {} with {}", config.database, version);
+    if recipe.to_stdout {
+        run_command_to_file(recipe.dump, &recipe.path).await?;
+    } else {
+        run_command(recipe.dump).await?;
+    }
+    Ok(vec![
+        ("database".to_string(), config.database.clone()),
+        ("dump_tool".to_string(), version),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(kind: DatabaseKind, database: &str) -> DatabaseConfig {
+        DatabaseConfig {
+            kind,
+            database: database.to_string(),
+            args: vec!["--host=db".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_recipes() {
+        let dir = Path::new("/state/dump");
+
+        let postgres = recipe(&config(DatabaseKind::Postgres, "shop"), dir);
+        assert_eq!(
+            postgres.dump.join(" "),
+            "pg_dump --format=custom --host=db shop"
+        );
+        assert!(postgres.to_stdout);
+        assert_eq!(postgres.path, Path::new("/state/dump/shop.dump"));
+
+        let mysql = recipe(&config(DatabaseKind::Mysql, "shop"), dir);
+        assert_eq!(
+            mysql.dump.join(" "),
+            "mysqldump --single-transaction --routines --triggers --host=db shop"
+        );
+        assert_eq!(mysql.path, Path::new("/state/dump/shop.sql"));
+
+        let sqlite = recipe(&config(DatabaseKind::Sqlite, "/var/lib/app/app.db"), dir);
+        assert_eq!(
+            sqlite.dump,
+            [
+                "sqlite3",
+                "/var/lib/app/app.db",
+                ".backup '/state/dump/app.db'"
+            ]
+        );
+        assert!(!sqlite.to_stdout);
+        assert_eq!(sqlite.path, Path::new("/state/dump/app.db"));
+    }
+}
@@ -1,4 +1,4 @@
-use std::ffi::OsString;
+use std::{ffi::OsString, io, os::fd::AsRawFd};
 
 use crate::cmd::common::{CommandError, CommandErrorKind, CommandResult};
 
@@ -11,3 +11,21 @@ pub fn sanitize_os_string(os_string: OsString) -> CommandResult<String> {
         )),
     }
 }
+
+/// Make `target` share the data of `source`, on file systems with reflinks
+/// such as Btrfs and XFS. Fails if the file system doesn't support them.
+#[cfg(target_os = "linux")]
+pub fn reflink(source: &impl AsRawFd, target: &impl AsRawFd) -> io::Result<()> {
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reflink(_source: &impl AsRawFd, _target: &impl AsRawFd) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Reflinks are not supported on this platform",
+    ))
+}
@@ -0,0 +1,262 @@
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+
+use crate::{
+    cmd::common::{CommandError, CommandErrorKind, CommandResult},
+    data::config::{FsSnapshotConfig, FsSnapshotKind},
+    util::process::run_command,
+};
+
+const DEFAULT_NAME: &str = "freebck";
+
+/// Command that creates part of a snapshot and the one that undoes it.
+struct Step {
+    run: Vec<String>,
+    undo: Option<Vec<String>>,
+}
+
+fn command(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+fn missing(config: &FsSnapshotConfig, field: &str) -> CommandError {
+    CommandError::new(
+        CommandErrorKind::User,
+        format!("{:?} snapshots need fs_snapshot.{} set", config.kind, field),
+    )
+}
+
+/// Commands that make the snapshot available, in order.
+fn steps(config: &FsSnapshotConfig) -> CommandResult<Vec<Step>> {
+    let name = config.name.as_deref().unwrap_or(DEFAULT_NAME);
+    Ok(match config.kind {
+        FsSnapshotKind::Btrfs => {
+            let mount = config
+                .mount
+                .as_deref()
+                .ok_or_else(|| missing(config, "mount"))?;
+            vec![Step {
+                run: command(&[
+                    "btrfs",
+                    "subvolume",
+                    "snapshot",
+                    "-r",
+                    &config.volume,
+                    mount,
+                ]),
+                undo: Some(command(&["btrfs", "subvolume", "delete", mount])),
+            }]
+        }
+        FsSnapshotKind::Lvm => {
+            let mount = config
+                .mount
+                .as_deref()
+                .ok_or_else(|| missing(config, "mount"))?;
+            let size = config
+                .size
+                .as_deref()
+                .ok_or_else(|| missing(config, "size"))?;
+            let Some((group, _)) = config.volume.split_once('/') else {
+                return Err(CommandError::new(
+                    CommandErrorKind::User,
+                    format!(
+                        "LVM volume should be given as group/volume: {}",
+                        config.volume
+                    ),
+                ));
+            };
+            let snapshot = format!("{}/{}", group, name);
+            vec![
+                Step {
+                    run: command(&[
+                        "lvcreate",
+                        "--snapshot",
+                        "--size",
+                        size,
+                        "--name",
+                        name,
+                        &config.volume,
+                    ]),
+                    undo: Some(command(&["lvremove", "--force", &snapshot])),
+                },
+                Step {
+                    run: command(&["mkdir", "-p", mount]),
+                    undo: None,
+                },
+                Step {
+                    run: command(&[
+                        "mount",
+                        "-o",
+                        config.mount_options.as_deref().unwrap_or("ro"),
+                        &format!("/dev/{}", snapshot),
+                        mount,
+                    ]),
+                    undo: Some(command(&["umount", mount])),
+                },
+            ]
+        }
+        FsSnapshotKind::Zfs => {
+            let snapshot = format!("{}@{}", config.volume, name);
+            vec![Step {
+                run: command(&["zfs", "snapshot", &snapshot]),
+                undo: Some(command(&["zfs", "destroy", &snapshot])),
+            }]
+        }
+    })
+}
+
+/// Point-in-time snapshot of the file system that a backup reads from, so
+/// that files changed during the backup aren't read half-written. Has to be
+/// removed with `remove` once the backup is done.
+pub struct FsSnapshot {
+    path: PathBuf,
+    undo: Vec<Vec<String>>,
+}
+
+impl FsSnapshot {
+    pub async fn create(config: &FsSnapshotConfig) -> CommandResult<Self> {
+        let mut snapshot = FsSnapshot {
+            path: PathBuf::new(),
+            undo: Vec::new(),
+        };
+        for step in steps(config)? {
+            if let Err(e) = run_command(step.run).await {
+                // Failures to clean up are logged, the cause matters more.
+                let _ = snapshot.remove().await;
+                return Err(e);
+            }
+            snapshot.undo.extend(step.undo);
+        }
+
+        let root = match config.kind {
+            FsSnapshotKind::Btrfs | FsSnapshotKind::Lvm => {
+                PathBuf::from(config.mount.as_ref().unwrap())
+            }
+            FsSnapshotKind::Zfs => {
+                let mountpoint = run_command(command(&[
+                    "zfs",
+                    "get",
+                    "-H",
+                    "-o",
+                    "value",
+                    "mountpoint",
+                    &config.volume,
+                ]))
+                .await;
+                match mountpoint {
+                    Ok(mountpoint) => Path::new(mountpoint.trim())
+                        .join(".zfs/snapshot")
+                        .join(config.name.as_deref().unwrap_or(DEFAULT_NAME)),
+                    Err(e) => {
+                        let _ = snapshot.remove().await;
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        snapshot.path = match config.subdir {
+            Some(ref subdir) => root.join(subdir),
+            None => root,
+        };
+        info!(
+            "Backing up from file system snapshot {}",
+            snapshot.path.display()
+        );
+        Ok(snapshot)
+    }
+
+    /// Directory to back up, which corresponds to the backup target.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Unmount and delete the snapshot. Keeps going if a step fails, so that
+    /// as much as possible is cleaned up, and returns the first failure.
+    pub async fn remove(mut self) -> CommandResult {
+        let mut result = Ok(());
+        while let Some(command) = self.undo.pop() {
+            if let Err(e) = run_command(command).await {
+                warn!("Failed to remove file system snapshot: {}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(kind: FsSnapshotKind, volume: &str) -> FsSnapshotConfig {
+        FsSnapshotConfig {
+            kind,
+            volume: volume.to_string(),
+            mount: Some("/mnt/snap".to_string()),
+            size: Some("5G".to_string()),
+            mount_options: None,
+            name: None,
+            subdir: None,
+        }
+    }
+
+    fn runs(config: &FsSnapshotConfig) -> Vec<String> {
+        steps(config)
+            .unwrap()
+            .into_iter()
+            .map(|step| step.run.join(" "))
+            .collect()
+    }
+
+    fn undos(config: &FsSnapshotConfig) -> Vec<String> {
+        steps(config)
+            .unwrap()
+            .into_iter()
+            .filter_map(|step| step.undo.map(|undo| undo.join(" ")))
+            .collect()
+    }
+
+    #[test]
+    fn test_lvm_steps() {
+        let config = config(FsSnapshotKind::Lvm, "vg0/home");
+        assert_eq!(
+            runs(&config),
+            [
+                "lvcreate --snapshot --size 5G --name freebck vg0/home",
+                "mkdir -p /mnt/snap",
+                "mount -o ro /dev/vg0/freebck /mnt/snap",
+            ]
+        );
+        assert_eq!(
+            undos(&config),
+            ["lvremove --force vg0/freebck", "umount /mnt/snap"]
+        );
+    }
+
+    #[test]
+    fn test_btrfs_and_zfs_steps() {
+        let btrfs = config(FsSnapshotKind::Btrfs, "/home");
+        assert_eq!(
+            runs(&btrfs),
+            ["btrfs subvolume snapshot -r /home /mnt/snap"]
+        );
+        assert_eq!(undos(&btrfs), ["btrfs subvolume delete /mnt/snap"]);
+
+        let mut zfs = config(FsSnapshotKind::Zfs, "tank/home");
+        zfs.name = Some("nightly".to_string());
+        assert_eq!(runs(&zfs), ["zfs snapshot tank/home@nightly"]);
+        assert_eq!(undos(&zfs), ["zfs destroy tank/home@nightly"]);
+    }
+
+    #[test]
+    fn test_missing_settings() {
+        let mut lvm = config(FsSnapshotKind::Lvm, "home");
+        assert!(steps(&lvm).is_err());
+        lvm.volume = "vg0/home".to_string();
+        lvm.size = None;
+        assert!(steps(&lvm).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+/// Shell-style glob over '/' separated relative paths. `*` and `?` match
+/// within a single path component, `[...]` matches a character class and
+/// `**` matches any number of components. A pattern without a '/' matches
+/// a name at any depth.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    components: Vec<GlobComponent>,
+}
+
+#[derive(Debug, Clone)]
+enum GlobComponent {
+    AnyComponents,
+    Pattern(Vec<char>),
+}
+
+fn match_component(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| match_component(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && match_component(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let Some(end) = pattern.iter().skip(1).position(|c| *c == ']') else {
+                return name.first() == Some(&'[') && match_component(&pattern[1..], &name[1..]);
+            };
+            let class = &pattern[1..end + 1];
+            let Some(c) = name.first() else {
+                return false;
+            };
+
+            let (negated, class) = match class.first() {
+                Some('!') | Some('^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    matched |= class[i] <= *c && *c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == *c;
+                    i += 1;
+                }
+            }
+
+            matched != negated && match_component(&pattern[end + 2..], &name[1..])
+        }
+        Some(p) => name.first() == Some(p) && match_component(&pattern[1..], &name[1..]),
+    }
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim_matches('/');
+        let mut components = Vec::new();
+        if !pattern.contains('/') {
+            components.push(GlobComponent::AnyComponents);
+        }
+        for component in pattern.split('/').filter(|c| !c.is_empty()) {
+            components.push(match component {
+                "**" => GlobComponent::AnyComponents,
+                _ => GlobComponent::Pattern(component.chars().collect()),
+            });
+        }
+
+        Self { components }
+    }
+
+    fn match_components(components: &[GlobComponent], path: &[Vec<char>]) -> bool {
+        match components.first() {
+            None => path.is_empty(),
+            Some(GlobComponent::AnyComponents) => {
+                (0..=path.len()).any(|i| Self::match_components(&components[1..], &path[i..]))
+            }
+            Some(GlobComponent::Pattern(pattern)) => {
+                !path.is_empty()
+                    && match_component(pattern, &path[0])
+                    && Self::match_components(&components[1..], &path[1..])
+            }
+        }
+    }
+
+    fn match_prefix(components: &[GlobComponent], path: &[Vec<char>]) -> bool {
+        match components.first() {
+            None => false,
+            Some(GlobComponent::AnyComponents) => true,
+            Some(GlobComponent::Pattern(pattern)) => match path.first() {
+                None => true,
+                Some(name) => {
+                    match_component(pattern, name)
+                        && Self::match_prefix(&components[1..], &path[1..])
+                }
+            },
+        }
+    }
+
+    fn split(path: &str) -> Vec<Vec<char>> {
+        path.split('/')
+            .filter(|c| !c.is_empty())
+            .map(|c| c.chars().collect())
+            .collect()
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        Self::match_components(&self.components, &Self::split(path))
+    }
+
+    /// Whether some path below `path` could still match the pattern.
+    pub fn could_match_below(&self, path: &str) -> bool {
+        Self::match_prefix(&self.components, &Self::split(path))
+    }
+}
+
+/// Set of include patterns. An empty filter includes everything.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    include: Vec<Glob>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String]) -> Self {
+        Self {
+            include: include.iter().map(|pattern| Glob::new(pattern)).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+    }
+
+    /// Whether the path is included, along with everything below it.
+    pub fn includes(&self, path: &str) -> bool {
+        self.is_empty() || self.include.iter().any(|glob| glob.matches(path))
+    }
+
+    /// Whether a directory needs to be walked to find included paths.
+    pub fn should_descend(&self, path: &str) -> bool {
+        self.includes(path) || self.include.iter().any(|glob| glob.could_match_below(path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(Glob::new("*.jpg").matches("photos/2023/cat.jpg"));
+        assert!(Glob::new("*.jpg").matches("cat.jpg"));
+        assert!(!Glob::new("*.jpg").matches("cat.jpeg"));
+        assert!(Glob::new("Documents").matches("Documents"));
+        assert!(Glob::new("/Documents/").matches("Documents"));
+        assert!(Glob::new("photos/*/cat.jpg").matches("photos/2023/cat.jpg"));
+        assert!(!Glob::new("photos/*.jpg").matches("photos/2023/cat.jpg"));
+        assert!(Glob::new("photos/**/*.jpg").matches("photos/2023/01/cat.jpg"));
+        assert!(Glob::new("photos/**/*.jpg").matches("photos/cat.jpg"));
+        assert!(Glob::new("file?.[ch]").matches("src/file1.c"));
+        assert!(!Glob::new("file?.[!ch]").matches("src/file1.c"));
+        assert!(Glob::new("[a-c]at").matches("bat"));
+    }
+
+    #[test]
+    fn test_path_filter() {
+        let filter = PathFilter::new(&["home/*/Documents".to_string()]);
+        assert!(filter.includes("home/alice/Documents"));
+        assert!(!filter.includes("home/alice"));
+        assert!(filter.should_descend("home"));
+        assert!(filter.should_descend("home/alice"));
+        assert!(!filter.should_descend("var"));
+        assert!(!filter.should_descend("home/alice/Music"));
+
+        assert!(PathFilter::default().includes("anything"));
+    }
+}
@@ -1,18 +1,226 @@
-use std::{io, pin::Pin};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
 
 use sha2::{Digest, Sha256};
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, ReadBuf},
+    task::spawn_blocking,
+};
 
-pub async fn read_hash(mut file: Pin<&mut (dyn AsyncRead + Send)>) -> io::Result<String> {
+/// Run CPU-heavy work such as hashing or encoding on the blocking thread
+/// pool, so that it doesn't hold up I/O tasks on the runtime threads.
+pub async fn run_blocking<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    spawn_blocking(f).await.map_err(io::Error::other)
+}
+
+/// SHA-256 of `data` as hex, calculated off the runtime threads. The buffer
+/// is handed back so it can be reused.
+pub async fn sha256_hex(data: Vec<u8>) -> io::Result<(Vec<u8>, String)> {
+    run_blocking(move || {
+        let hash = format!("{:x}", Sha256::digest(&data));
+        (data, hash)
+    })
+    .await
+}
+
+/// Algorithm prefix of blob keys. Repositories created before keys named
+/// their algorithm keep using bare hex keys, which are SHA-256 too.
+pub const SHA256_KEY_PREFIX: &str = "sha256-";
+
+/// Algorithm and hash of a blob key in either form.
+pub fn split_blob_key(key: &str) -> (&str, &str) {
+    match key.split_once('-') {
+        Some((algorithm, hash)) => (algorithm, hash),
+        None => ("sha256", key),
+    }
+}
+
+/// Whether `data` hashes to the blob key `key`. Keys naming an algorithm
+/// this version doesn't know never match.
+pub fn blob_key_matches(key: &str, data: &[u8]) -> bool {
+    match split_blob_key(key) {
+        ("sha256", hash) => format!("{:x}", Sha256::digest(data)) == hash,
+        _ => false,
+    }
+}
+
+/// HMAC-SHA256 of `data`, as used for request signing.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// SHA-256 of everything `file` returns as hex, read `buffer_size` bytes at
+/// a time.
+pub async fn read_hash(
+    mut file: Pin<&mut (dyn AsyncRead + Send)>,
+    buffer_size: usize,
+) -> io::Result<String> {
     let mut hasher = Sha256::new();
-    let mut buffer = vec![0; 1024 * 1024];
+    let mut buffer = vec![0; buffer_size];
     loop {
         let bytes_read = file.read(buffer.as_mut()).await?;
         if bytes_read == 0 {
             break;
         }
-        hasher.update(&buffer[..bytes_read]);
+        (hasher, buffer) = run_blocking(move || {
+            hasher.update(&buffer[..bytes_read]);
+            (hasher, buffer)
+        })
+        .await?;
     }
 
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+/// SHA-256 of everything `file` returns as hex, along with the hash and
+/// size of each `chunk_size` piece of it, read `buffer_size` bytes at a time.
+pub async fn read_chunk_hashes(
+    mut file: Pin<&mut (dyn AsyncRead + Send)>,
+    buffer_size: usize,
+    chunk_size: u64,
+) -> io::Result<(String, Vec<(String, u64)>)> {
+    let mut hasher = Sha256::new();
+    let mut chunk_hasher = Sha256::new();
+    let mut chunk_bytes = 0;
+    let mut chunks = Vec::new();
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let bytes_read = file.read(buffer.as_mut()).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        let finished;
+        (hasher, chunk_hasher, chunk_bytes, finished, buffer) = run_blocking(move || {
+            let mut finished = Vec::new();
+            let mut data = &buffer[..bytes_read];
+            hasher.update(data);
+            while !data.is_empty() {
+                let piece = data.len().min((chunk_size - chunk_bytes) as usize);
+                chunk_hasher.update(&data[..piece]);
+                chunk_bytes += piece as u64;
+                data = &data[piece..];
+                if chunk_bytes == chunk_size {
+                    finished.push((format!("{:x}", chunk_hasher.finalize_reset()), chunk_bytes));
+                    chunk_bytes = 0;
+                }
+            }
+            (hasher, chunk_hasher, chunk_bytes, finished, buffer)
+        })
+        .await?;
+        chunks.extend(finished);
+    }
+    if chunk_bytes > 0 {
+        chunks.push((format!("{:x}", chunk_hasher.finalize()), chunk_bytes));
+    }
+
+    Ok((format!("{:x}", hasher.finalize()), chunks))
+}
+
+/// Reader that hashes and counts the bytes read through it, for data that
+/// is streamed instead of held in memory.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    bytes: u64,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Number of bytes read so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// SHA-256 of the bytes read as hex.
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[filled..];
+        self.hasher.update(read);
+        self.bytes += read.len() as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 2 and 6.
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            mac.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let mac = hmac_sha256(
+            &[0xaa; 131],
+            b"Test Using Larger Than Block-Size Key - Hash Key First",
+        );
+        assert_eq!(
+            mac.iter().map(|b| format!("{:02x}", b)).collect::<String>(),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+    #[test]
+    fn test_blob_key_matches() {
+        let hash = format!("{:x}", Sha256::digest(b"Hello"));
+        assert!(blob_key_matches(&hash, b"Hello"));
+        assert!(blob_key_matches(&format!("sha256-{}", hash), b"Hello"));
+        assert!(!blob_key_matches(&format!("sha256-{}", hash), b"Hello!"));
+        assert!(!blob_key_matches(&format!("blake3-{}", hash), b"Hello"));
+    }
+
+    #[tokio::test]
+    async fn test_read_chunk_hashes() {
+        let data: Vec<u8> = (0..25u8).collect();
+        let mut reader = data.as_slice();
+        let reader: Pin<&mut (dyn AsyncRead + Send + Unpin)> = Pin::new(&mut reader);
+        let (hash, chunks) = read_chunk_hashes(reader, 4, 10).await.unwrap();
+        assert_eq!(hash, format!("{:x}", Sha256::digest(&data)));
+        let expected: Vec<_> = data
+            .chunks(10)
+            .map(|chunk| (format!("{:x}", Sha256::digest(chunk)), chunk.len() as u64))
+            .collect();
+        assert_eq!(chunks, expected);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::{cmd::common::ProgramContext, data::config::HooksConfig};
+
+/// Repository event that external commands can be notified of.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum HookEvent<'a> {
+    SnapshotCreated {
+        snapshot: &'a str,
+        root_hash: &'a str,
+        started: i64,
+        finished: i64,
+    },
+    CheckFailed {
+        damaged_blobs: usize,
+    },
+    SnapshotForgotten {
+        snapshot: &'a str,
+        started: i64,
+    },
+    PruneCompleted {
+        deleted_blobs: usize,
+    },
+}
+
+impl HookEvent<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::SnapshotCreated { .. } => "snapshot-created",
+            HookEvent::CheckFailed { .. } => "check-failed",
+            HookEvent::SnapshotForgotten { .. } => "snapshot-forgotten",
+            HookEvent::PruneCompleted { .. } => "prune-completed",
+        }
+    }
+
+    fn command<'a>(&self, config: &'a HooksConfig) -> Option<&'a String> {
+        match self {
+            HookEvent::SnapshotCreated { .. } => config.snapshot_created.as_ref(),
+            HookEvent::CheckFailed { .. } => config.check_failed.as_ref(),
+            HookEvent::SnapshotForgotten { .. } => config.snapshot_forgotten.as_ref(),
+            HookEvent::PruneCompleted { .. } => config.prune_completed.as_ref(),
+        }
+    }
+
+    /// JSON object describing the event, written to the hook's stdin.
+    pub fn payload(&self, archive_name: &str) -> String {
+        let payload = Payload {
+            event: self.name(),
+            archive: archive_name,
+            fields: self,
+        };
+        serde_json::to_string(&payload).expect("hook payloads are JSON objects")
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    archive: &'a str,
+    #[serde(flatten)]
+    fields: &'a HookEvent<'a>,
+}
+
+/// Run the command configured for the event, if any, with the event payload
+/// on stdin. Hook failures are logged but don't fail the operation.
+pub async fn fire_hook(context: &ProgramContext, event: HookEvent<'_>) {
+    let Some(command) = event.command(&context.hooks) else {
+        return;
+    };
+    let command = command.clone();
+    let payload = event.payload(&context.archive_name);
+    let name = event.name();
+    debug!("Running {} hook: {}", name, command);
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .env("FREEBCK_EVENT", name)
+            .stdin(Stdio::piped())
+            .spawn()?;
+        // The hook may exit without reading its input.
+        let _ = child.stdin.take().unwrap().write_all(payload.as_bytes());
+        child.wait()
+    })
+    .await;
+
+    match result {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => warn!("The {} hook failed with {}", name, status),
+        Ok(Err(e)) => warn!("Failed to run the {} hook: {}", name, e),
+        Err(e) => warn!("Failed to run the {} hook: {}", name, e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload() {
+        let event = HookEvent::SnapshotCreated {
+            snapshot: "home/3",
+            root_hash: "abc",
+            started: 10,
+            finished: 20,
+        };
+        assert_eq!(
+            event.payload("home \"laptop\"\n"),
+            r#"{"event":"snapshot-created","archive":"home \"laptop\"\n","snapshot":"home/3","root_hash":"abc","started":10,"finished":20}"#
+        );
+    }
+
+    #[test]
+    fn test_forget_and_prune_payloads() {
+        let event = HookEvent::SnapshotForgotten {
+            snapshot: "home/1",
+            started: 10,
+        };
+        assert_eq!(
+            event.payload("home"),
+            r#"{"event":"snapshot-forgotten","archive":"home","snapshot":"home/1","started":10}"#
+        );
+
+        let event = HookEvent::PruneCompleted { deleted_blobs: 3 };
+        assert_eq!(
+            event.payload("home"),
+            r#"{"event":"prune-completed","archive":"home","deleted_blobs":3}"#
+        );
+    }
+}
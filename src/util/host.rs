@@ -0,0 +1,25 @@
+use std::ffi::CStr;
+
+use crate::cmd::common::{CommandError, CommandErrorKind, CommandResult};
+
+pub fn hostname() -> CommandResult<String> {
+    let mut buffer = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr(), buffer.len()) } != 0 {
+        return Err(CommandError::with_source(
+            CommandErrorKind::System,
+            "Failed to get hostname".to_string(),
+            Box::new(std::io::Error::last_os_error()),
+        ));
+    }
+
+    // The name may be truncated without a terminating nul byte.
+    buffer[buffer.len() - 1] = 0;
+    let hostname = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+    hostname.to_str().map(|s| s.to_string()).map_err(|e| {
+        CommandError::with_source(
+            CommandErrorKind::System,
+            "Invalid UTF-8 in hostname".to_string(),
+            Box::new(e),
+        )
+    })
+}
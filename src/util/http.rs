@@ -0,0 +1,329 @@
+use std::{convert::Infallible, fs::File, future::Future, io, path::Path, sync::Arc};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{
+    body::Incoming, header::CONTENT_LENGTH, server::conn::http1, service::service_fn, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+use tracing::debug;
+
+// Most headers a request to a server may have. Clients send a handful, and
+// hyper refuses requests with more with 431.
+const MAX_HEADERS: usize = 32;
+
+/// Client for the remote storages. TLS is done by rustls with the Mozilla
+/// root certificates, so https:// endpoints work without a proxy.
+/// A PEM file of CA certificates can be given to also trust servers with
+/// certificates of a private CA, or self-signed ones.
+pub fn client(ca_cert: Option<&Path>) -> io::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().use_rustls_tls();
+    if let Some(ca_cert) = ca_cert {
+        let pem = std::fs::read(ca_cert)?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid CA certificate {}: {}", ca_cert.display(), e),
+            )
+        })?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    builder.build().map_err(request_error)
+}
+
+/// Check that `url` is an http:// or https:// URL and strip trailing slashes
+/// from its path.
+pub fn parse_url(url: &str) -> io::Result<reqwest::Url> {
+    let invalid = |message: &str| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", message, url))
+    };
+    let mut parsed = reqwest::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid("Expected an http:// or https:// URL"));
+    }
+    if parsed.host_str().is_none() {
+        return Err(invalid("No host in URL"));
+    }
+    let path = parsed.path().trim_end_matches('/').to_string();
+    parsed.set_path(&path);
+    Ok(parsed)
+}
+
+/// Host and port as sent in the Host header, without the default port.
+pub fn authority(url: &reqwest::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Send a request and read the whole response.
+pub async fn send(request: reqwest::RequestBuilder) -> io::Result<Response> {
+    let response = request.send().await.map_err(request_error)?;
+    let status = response.status().as_u16();
+    let headers = response
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect();
+    let body = response.bytes().await.map_err(request_error)?.to_vec();
+    Ok(Response {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn request_error(error: reqwest::Error) -> io::Error {
+    let kind = if error.is_timeout() {
+        io::ErrorKind::TimedOut
+    } else {
+        io::ErrorKind::Other
+    };
+    // The error itself only names the URL, the reason is in its sources.
+    let mut message = error.to_string();
+    let mut source = std::error::Error::source(&error);
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    io::Error::new(kind, message)
+}
+
+/// Response read in full by `send`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// An error for an unexpected status, with the start of the body, which
+    /// usually explains it.
+    pub fn error(&self, operation: &str) -> io::Error {
+        let kind = match self.status {
+            404 => io::ErrorKind::NotFound,
+            401 | 403 => io::ErrorKind::PermissionDenied,
+            409 | 412 => io::ErrorKind::AlreadyExists,
+            400 => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        let body = String::from_utf8_lossy(&self.body);
+        let body: String = body.chars().take(200).collect();
+        io::Error::new(
+            kind,
+            format!(
+                "{} failed with HTTP {}: {}",
+                operation,
+                self.status,
+                body.trim()
+            ),
+        )
+    }
+}
+
+pub type ServerResponse = hyper::Response<Full<Bytes>>;
+
+pub fn response(status: u16, body: impl Into<Bytes>) -> ServerResponse {
+    let mut response = hyper::Response::new(Full::new(body.into()));
+    *response.status_mut() =
+        StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    response
+}
+
+/// Read the body of a request, or the response refusing it. Bodies longer
+/// than `limit` are refused with 413, and only read up to the limit.
+pub async fn read_body(
+    request: hyper::Request<Incoming>,
+    limit: u64,
+) -> Result<Bytes, ServerResponse> {
+    let too_large = || response(413, format!("Body is larger than {} bytes", limit));
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    match Limited::new(request.into_body(), limit).collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) if e.is::<LengthLimitError>() => Err(too_large()),
+        Err(e) => Err(response(400, e.to_string())),
+    }
+}
+
+/// TLS settings for a server from PEM files with the certificate chain and
+/// its private key.
+pub fn tls_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let invalid = |path: &Path, message: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: {}", path.display(), message),
+        )
+    };
+    let certificates = rustls_pemfile::certs(&mut io::BufReader::new(File::open(cert)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    if certificates.is_empty() {
+        return Err(invalid(cert, "No certificates".to_string()));
+    }
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(File::open(key)?))?
+        .ok_or_else(|| invalid(key, "No private key".to_string()))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(certificates, key)
+        })
+        .map_err(|e| invalid(cert, e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Answer HTTP/1.1 requests on connections to `listener` with `handler`
+/// until the listener fails. With `tls`, connections must start with a TLS
+/// handshake.
+pub async fn serve<H, F>(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    handler: H,
+) -> io::Result<()>
+where
+    H: Fn(hyper::Request<Incoming>) -> F + Clone + Send + 'static,
+    F: Future<Output = ServerResponse> + Send + 'static,
+{
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let tls = tls.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, handler).await,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", address, e);
+                        return;
+                    }
+                },
+                None => serve_connection(stream, handler).await,
+            };
+            if let Err(e) = result {
+                debug!("Connection from {} failed: {}", address, e);
+            }
+        });
+    }
+}
+
+async fn serve_connection<S, H, F>(stream: S, handler: H) -> hyper::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: Fn(hyper::Request<Incoming>) -> F + Send + 'static,
+    F: Future<Output = ServerResponse> + Send + 'static,
+{
+    let service = service_fn(move |request| {
+        let response = handler(request);
+        async move { Ok::<_, Infallible>(response.await) }
+    });
+    http1::Builder::new()
+        .max_headers(MAX_HEADERS)
+        .serve_connection(TokioIo::new(stream), service)
+        .await
+}
+
+/// Percent-encode everything except unreserved characters, and '/' unless
+/// `encode_slash` is set.
+pub fn percent_encode(text: &str, encode_slash: bool) -> String {
+    let mut output = String::new();
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                output.push(byte as char)
+            }
+            b'/' if !encode_slash => output.push('/'),
+            _ => output.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    output
+}
+
+pub fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut output = Vec::new();
+    let mut index = 0;
+    while index < bytes.len() {
+        let decoded = match bytes[index] {
+            b'%' if index + 2 < bytes.len() => std::str::from_utf8(&bytes[index + 1..index + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match decoded {
+            Some(byte) => {
+                output.push(byte);
+                index += 3;
+            }
+            None => {
+                output.push(bytes[index]);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&output).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url = parse_url("http://localhost:9000/base/").unwrap();
+        assert_eq!(url.path(), "/base");
+        assert_eq!(authority(&url), "localhost:9000");
+        assert_eq!(
+            authority(&parse_url("https://s3.amazonaws.com:443").unwrap()),
+            "s3.amazonaws.com"
+        );
+        assert!(parse_url("ftp://minio").is_err());
+        assert!(parse_url("minio:9000").is_err());
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("a b/c~", false), "a%20b/c~");
+        assert_eq!(percent_encode("a b/c~", true), "a%20b%2Fc~");
+        assert_eq!(percent_decode("a%20b%2Fc%"), "a b/c%");
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use prost::Message;
+use rand::distributions::{Alphanumeric, DistString};
+use tokio::{io, sync::Mutex, task::JoinHandle};
+use tracing::{debug, warn};
+
+use crate::{
+    cmd::common::{
+        CommandError, CommandErrorKind, CommandResult, IntoIoCommandError, IntoIoCommandResult,
+        ProgramContext,
+    },
+    data::backup::Lock,
+    storage::{Collection, Storage},
+    util::time::{as_unix_timestamp, format_time},
+};
+
+/// Locks are refreshed this often while they are held.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Locks that haven't been refreshed for this long are left behind by
+/// processes that didn't finish, and are ignored.
+const STALE_AFTER: Duration = Duration::from_secs(30 * 60);
+
+/// Lock of the repository, held for the duration of an operation.
+/// Operations that only add data or read it take shared locks, which can be
+/// held at the same time. Operations that delete data take an exclusive
+/// lock, so that e.g. prune can't delete blobs that a running backup relies
+/// on.
+pub struct RepositoryLock {
+    storage: Arc<dyn Storage>,
+    /// Storage key of the lock, which changes when it is refreshed.
+    key: Arc<Mutex<String>>,
+    refresh: JoinHandle<()>,
+}
+
+fn new_lock_key() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+}
+
+async fn write_lock(
+    storage: &dyn Storage,
+    key: &str,
+    exclusive: bool,
+    client_id: &str,
+) -> io::Result<()> {
+    let lock = Lock {
+        exclusive,
+        refreshed: as_unix_timestamp(SystemTime::now()),
+        client_id: client_id.to_owned(),
+    };
+    storage
+        .write(Collection::Lock, key, &lock.encode_to_vec())
+        .await
+}
+
+/// The other locks that aren't stale, leaving out those released meanwhile.
+async fn other_locks(storage: &dyn Storage, own_key: &str) -> CommandResult<Vec<Lock>> {
+    let stale_before = as_unix_timestamp(SystemTime::now() - STALE_AFTER);
+    let mut locks = Vec::new();
+    let mut buffer = Vec::new();
+    for key in storage
+        .get_collection_items(Collection::Lock)
+        .await
+        .into_io_command_result("Failed to list locks")?
+    {
+        if key == own_key {
+            continue;
+        }
+        match storage.read(Collection::Lock, &key, &mut buffer).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into_io_command_error("Failed to read lock")),
+        }
+        let lock = Lock::decode(buffer.as_slice()).map_err(|e| {
+            CommandError::with_source(
+                CommandErrorKind::Corrupt,
+                format!("Failed to decode lock {}", key),
+                Box::new(e),
+            )
+        })?;
+        if lock.refreshed < stale_before {
+            debug!("Ignoring stale lock {}", key);
+            continue;
+        }
+        locks.push(lock);
+    }
+    Ok(locks)
+}
+
+/// Lock the repository of `context`, failing if another operation holds a
+/// lock that conflicts. The lock is written before the other locks are
+/// checked, so that of two operations starting at the same time, at least
+/// one sees the other.
+pub async fn lock_repository(
+    context: &ProgramContext,
+    exclusive: bool,
+) -> CommandResult<RepositoryLock> {
+    let storage = context.storage.clone();
+    let client_id = context.client_id.clone();
+    let key = new_lock_key();
+    write_lock(&*storage, &key, exclusive, &client_id)
+        .await
+        .into_io_command_result("Failed to write lock")?;
+    let result = other_locks(&*storage, &key).await;
+    let conflict = match result {
+        Ok(ref locks) => locks.iter().find(|lock| exclusive || lock.exclusive),
+        Err(_) => None,
+    };
+    if result.is_err() || conflict.is_some() {
+        if let Err(e) = storage.delete(Collection::Lock, &key).await {
+            warn!("Failed to remove lock {}: {}", key, e);
+        }
+    }
+    if let Some(conflict) = conflict {
+        return Err(CommandError::new(
+            CommandErrorKind::Locked,
+            format!(
+                "Repository is locked by another operation, {} lock of client {} refreshed {}",
+                if conflict.exclusive {
+                    "an exclusive"
+                } else {
+                    "a shared"
+                },
+                conflict.client_id,
+                format_time(conflict.refreshed)
+            ),
+        ));
+    }
+    result?;
+    debug!("Locked repository with lock {}", key);
+
+    let key = Arc::new(Mutex::new(key));
+    let refresh = tokio::spawn({
+        let storage = storage.clone();
+        let key = key.clone();
+        async move {
+            loop {
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+                // A new lock is written before the old one is removed, as
+                // not every storage can replace items.
+                let mut key = key.lock().await;
+                let new_key = new_lock_key();
+                if let Err(e) = write_lock(&*storage, &new_key, exclusive, &client_id).await {
+                    warn!("Failed to refresh lock: {}", e);
+                    continue;
+                }
+                if let Err(e) = storage.delete(Collection::Lock, &key).await {
+                    warn!("Failed to remove lock {}: {}", key, e);
+                }
+                *key = new_key;
+            }
+        }
+    });
+    Ok(RepositoryLock {
+        storage,
+        key,
+        refresh,
+    })
+}
+
+/// Run `operation` while holding a lock of the repository, and release the
+/// lock whether it succeeds or not.
+pub async fn with_lock<T>(
+    context: &ProgramContext,
+    exclusive: bool,
+    operation: impl Future<Output = CommandResult<T>>,
+) -> CommandResult<T> {
+    let lock = lock_repository(context, exclusive).await?;
+    let result = operation.await;
+    lock.release().await;
+    result
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        self.refresh.abort();
+    }
+}
+
+impl RepositoryLock {
+    /// Remove the lock. A lock that isn't released, e.g. because the
+    /// process was killed, is ignored once it is stale.
+    pub async fn release(self) {
+        // Refreshes hold the key while they run, so none is cut short.
+        let key = self.key.lock().await;
+        self.refresh.abort();
+        match self.storage.delete(Collection::Lock, &key).await {
+            Ok(()) => debug!("Released lock {}", key),
+            Err(e) => warn!("Failed to remove lock {}: {}", key, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn test_context() -> ProgramContext {
+        ProgramContext {
+            archive_name: "test".to_owned(),
+            client_id: "test_client".to_owned(),
+            storage: Arc::new(MemoryStorage::new()),
+            backup_target: Default::default(),
+            state_dir: Default::default(),
+            hooks: Default::default(),
+            fs_snapshot: None,
+            database: None,
+            compression: Default::default(),
+            tuning: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_locks_exclude_exclusive_ones() {
+        let context = test_context();
+        let first = lock_repository(&context, false).await.unwrap();
+        let second = lock_repository(&context, false).await.unwrap();
+        let error = lock_repository(&context, true).await.err().unwrap();
+        assert_eq!(error.kind(), CommandErrorKind::Locked);
+        assert!(error.to_string().contains("test_client"), "{}", error);
+        // The failed attempt leaves no lock behind.
+        let locks = context
+            .storage
+            .get_collection_items(Collection::Lock)
+            .await
+            .unwrap();
+        assert_eq!(locks.len(), 2);
+
+        first.release().await;
+        second.release().await;
+        let exclusive = lock_repository(&context, true).await.unwrap();
+        let error = lock_repository(&context, false).await.err().unwrap();
+        assert_eq!(error.kind(), CommandErrorKind::Locked);
+        exclusive.release().await;
+        lock_repository(&context, false)
+            .await
+            .unwrap()
+            .release()
+            .await;
+        assert!(context
+            .storage
+            .get_collection_items(Collection::Lock)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn stale_locks_are_ignored() {
+        let context = test_context();
+        let stale = Lock {
+            exclusive: true,
+            refreshed: as_unix_timestamp(SystemTime::now() - STALE_AFTER * 2),
+            client_id: "other_client".to_owned(),
+        };
+        context
+            .storage
+            .write(Collection::Lock, "stale_lock", &stale.encode_to_vec())
+            .await
+            .unwrap();
+        lock_repository(&context, true)
+            .await
+            .unwrap()
+            .release()
+            .await;
+    }
+}
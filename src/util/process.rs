@@ -0,0 +1,56 @@
+use std::{
+    fs::File,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+use tracing::debug;
+
+use crate::{
+    cmd::common::{CommandError, CommandErrorKind, CommandResult, IntoIoCommandResult},
+    util::hash::run_blocking,
+};
+
+/// Run `command`, the program followed by its arguments, and return its
+/// stdout. Fails if it exits unsuccessfully, with its stderr in the message.
+pub async fn run_command(command: Vec<String>) -> CommandResult<String> {
+    let output = run(command, Stdio::piped()).await?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Run `command` like `run_command` with its stdout written to `path`.
+pub async fn run_command_to_file(command: Vec<String>, path: &Path) -> CommandResult {
+    let file = File::create(path)
+        .into_io_command_result(&format!("Failed to create {}", path.display()))?;
+    run(command, file.into()).await?;
+    Ok(())
+}
+
+async fn run(command: Vec<String>, stdout: Stdio) -> CommandResult<Output> {
+    let line = command.join(" ");
+    debug!("Running {}", line);
+    let output = run_blocking(move || {
+        Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stdout(stdout)
+            .stderr(Stdio::piped())
+            .spawn()?
+            .wait_with_output()
+    })
+    .await
+    .and_then(|result| result)
+    .into_io_command_result(&format!("Failed to run {}", line))?;
+    if !output.status.success() {
+        return Err(CommandError::new(
+            CommandErrorKind::System,
+            format!(
+                "{} failed with {}: {}",
+                line,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(output)
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+struct RateLimiterState {
+    available: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket limiting throughput to a number of bytes per second, with
+/// bursts of up to one second worth of bytes.
+pub struct RateLimiter {
+    bytes_per_second: f64,
+    state: Mutex<RateLimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        let bytes_per_second = bytes_per_second.max(1) as f64;
+        Self {
+            bytes_per_second,
+            state: Mutex::new(RateLimiterState {
+                available: bytes_per_second,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` may be transferred. Waiters are served in order.
+    pub async fn acquire(&self, bytes: u64) {
+        let mut state = self.state.lock().await;
+
+        let now = Instant::now();
+        let refill = now.duration_since(state.last_refill).as_secs_f64() * self.bytes_per_second;
+        state.available = (state.available + refill).min(self.bytes_per_second);
+        state.last_refill = now;
+
+        state.available -= bytes as f64;
+        if state.available < 0.0 {
+            let wait = Duration::from_secs_f64(-state.available / self.bytes_per_second);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limiter_waits_after_burst() {
+        let limiter = RateLimiter::new(10_000);
+
+        let started = Instant::now();
+        limiter.acquire(10_000).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        limiter.acquire(2_000).await;
+        assert!(started.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    fmt::Arguments,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, enabled, Level};
+
+// Paths logged in each interval before the rest are only counted.
+const PATHS_PER_INTERVAL: u64 = 20;
+const INTERVAL: Duration = Duration::from_secs(5);
+
+struct SampledLogState {
+    interval_started: Instant,
+    logged: u64,
+    not_logged: u64,
+    total: u64,
+}
+
+/// Debug logging of the paths a command goes through, which on large trees
+/// would be too many lines to read. The first paths of each interval are
+/// logged and the rest only counted, with a progress line for them when the
+/// interval ends. At trace level every path is logged.
+pub struct SampledLog {
+    /// What is counted, e.g. "files".
+    noun: &'static str,
+    state: Mutex<SampledLogState>,
+}
+
+impl SampledLog {
+    pub fn new(noun: &'static str) -> Self {
+        Self {
+            noun,
+            state: Mutex::new(SampledLogState {
+                interval_started: Instant::now(),
+                logged: 0,
+                not_logged: 0,
+                total: 0,
+            }),
+        }
+    }
+
+    /// Log a line about one path at debug level, unless enough were logged
+    /// in this interval already.
+    pub fn log(&self, message: Arguments) {
+        if !enabled!(Level::DEBUG) {
+            return;
+        }
+        if enabled!(Level::TRACE) {
+            debug!("{}", message);
+            return;
+        }
+
+        let (log, progress) = self.count(Instant::now());
+        if let Some(progress) = progress {
+            debug!("{}", progress);
+        }
+        if log {
+            debug!("{}", message);
+        }
+    }
+
+    /// Count a path seen at `now`. Returns whether to log it, and the
+    /// progress line of the interval it ended if it did.
+    fn count(&self, now: Instant) -> (bool, Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        let mut progress = None;
+        if now.duration_since(state.interval_started) >= INTERVAL {
+            progress = self.progress(&state);
+            state.interval_started = now;
+            state.logged = 0;
+            state.not_logged = 0;
+        }
+        state.total += 1;
+        if state.logged < PATHS_PER_INTERVAL {
+            state.logged += 1;
+            (true, progress)
+        } else {
+            state.not_logged += 1;
+            (false, progress)
+        }
+    }
+
+    /// Log the paths of the last interval that weren't logged.
+    pub fn finish(&self) {
+        if let Some(progress) = self.progress(&self.state.lock().unwrap()) {
+            debug!("{}", progress);
+        }
+    }
+
+    fn progress(&self, state: &SampledLogState) -> Option<String> {
+        (state.not_logged > 0).then(|| {
+            format!(
+                "{} {} so far, {} more since the last one logged",
+                state.total, self.noun, state.not_logged
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sampled_log_counts_after_limit() {
+        let log = SampledLog::new("files");
+        let started = log.state.lock().unwrap().interval_started;
+        for i in 0..25 {
+            assert_eq!(log.count(started), (i < PATHS_PER_INTERVAL, None));
+        }
+        assert_eq!(
+            log.count(started + INTERVAL),
+            (
+                true,
+                Some("25 files so far, 5 more since the last one logged".to_string())
+            )
+        );
+        assert_eq!(log.progress(&log.state.lock().unwrap()), None);
+    }
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// Units sizes are displayed in, as a SizeUnits.
+static DISPLAY_UNITS: AtomicU8 = AtomicU8::new(SizeUnits::Binary as u8);
+
+/// Units for displaying sizes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SizeUnits {
+    /// Powers of 1024, e.g. "1.5 GiB".
+    #[default]
+    Binary,
+    /// Powers of 1000, e.g. "1.6 GB".
+    Si,
+    /// Exact byte counts without a unit, for scripts.
+    Bytes,
+}
+
+/// Display sizes in `units` from now on.
+pub fn set_display_units(units: SizeUnits) {
+    DISPLAY_UNITS.store(units as u8, Ordering::Relaxed);
+}
+
+/// Units sizes are displayed in.
+pub fn display_units() -> SizeUnits {
+    match DISPLAY_UNITS.load(Ordering::Relaxed) {
+        1 => SizeUnits::Si,
+        2 => SizeUnits::Bytes,
+        _ => SizeUnits::Binary,
+    }
+}
+
+/// Parse a byte size like "512", "64K" or "1.5G". Suffixes are binary
+/// multiples and may be followed by "iB" or "B".
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let number_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(number_end);
+
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size: {}", value))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("Invalid size suffix: {}", suffix)),
+    };
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Format a byte size in the display units, e.g. "1.5 GiB".
+pub fn format_size(bytes: u64) -> String {
+    format_size_in(bytes, display_units())
+}
+
+/// Format a byte size in `units`. The decimal point is always '.', whatever
+/// the locale.
+pub fn format_size_in(bytes: u64, units: SizeUnits) -> String {
+    let (base, names) = match units {
+        SizeUnits::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB"]),
+        SizeUnits::Si => (1000.0, ["B", "kB", "MB", "GB", "TB", "PB"]),
+        SizeUnits::Bytes => return bytes.to_string(),
+    };
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= base && unit < names.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, names[unit]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("10MiB"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_size("1.5g"), Ok(1536 * 1024 * 1024));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+        assert_eq!(format_size_in(1536, SizeUnits::Si), "1.5 kB");
+        assert_eq!(format_size_in(999, SizeUnits::Si), "999 B");
+        assert_eq!(format_size_in(1_600_000_000, SizeUnits::Si), "1.6 GB");
+        assert_eq!(format_size_in(3 << 30, SizeUnits::Bytes), "3221225472");
+    }
+}
@@ -0,0 +1,277 @@
+use std::{collections::HashMap, io};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TarEntryKind {
+    File,
+    Directory,
+    /// Links, devices and other special entries.
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TarEntry {
+    pub path: String,
+    pub kind: TarEntryKind,
+    pub size: u64,
+    pub modified: i64,
+    /// SHA-256 of the contents, empty for entries other than files.
+    pub content_hash: String,
+}
+
+/// Minimal streaming reader for ustar archives, including GNU long names and
+/// pax path, size and mtime overrides.
+pub struct TarReader<R> {
+    reader: R,
+}
+
+fn parse_number(field: &[u8]) -> io::Result<u64> {
+    // GNU base-256 encoding for values that don't fit in octal.
+    if let Some(first) = field.first() {
+        if first & 0x80 != 0 {
+            let mut value: u64 = (first & 0x7f) as u64;
+            for byte in &field[1..] {
+                value = (value << 8) | *byte as u64;
+            }
+            return Ok(value);
+        }
+    }
+
+    let text = std::str::from_utf8(field)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn parse_string(field: &[u8]) -> io::Result<String> {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8(field[..end].to_vec())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn parse_pax_records(data: &[u8]) -> io::Result<HashMap<String, String>> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|b| *b == b' ')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid pax record"))?;
+        let length: usize = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+            .filter(|l| *l > space && *l <= rest.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid pax record"))?;
+
+        let record = String::from_utf8(rest[space + 1..length].to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[length..];
+    }
+    Ok(records)
+}
+
+impl<R: AsyncRead + Unpin> TarReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader }
+    }
+
+    async fn read_block(&mut self, block: &mut [u8; BLOCK_SIZE]) -> io::Result<()> {
+        self.reader.read_exact(block).await.map(|_| ())
+    }
+
+    /// Read the contents of an entry, hashing them if `hasher` is set and
+    /// collecting them if `collect` is set, and skip the padding after it.
+    async fn read_content(
+        &mut self,
+        size: u64,
+        mut hasher: Option<&mut Sha256>,
+        mut collect: Option<&mut Vec<u8>>,
+    ) -> io::Result<()> {
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut remaining = size;
+        while remaining > 0 {
+            self.read_block(&mut block).await?;
+            let used = remaining.min(BLOCK_SIZE as u64) as usize;
+            if let Some(ref mut hasher) = hasher {
+                hasher.update(&block[..used]);
+            }
+            if let Some(ref mut collect) = collect {
+                collect.extend_from_slice(&block[..used]);
+            }
+            remaining -= used as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn next_entry(&mut self) -> io::Result<Option<TarEntry>> {
+        let mut long_name: Option<String> = None;
+        let mut pax_records: HashMap<String, String> = HashMap::new();
+        let mut header = [0u8; BLOCK_SIZE];
+
+        loop {
+            match self.read_block(&mut header).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            if header.iter().all(|b| *b == 0) {
+                return Ok(None);
+            }
+
+            let checksum = parse_number(&header[148..156])?;
+            let actual_checksum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b } as u64)
+                .sum();
+            if checksum != actual_checksum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid tar header checksum",
+                ));
+            }
+
+            let mut size = parse_number(&header[124..136])?;
+            let type_flag = header[156];
+            match type_flag {
+                b'L' => {
+                    let mut name = Vec::new();
+                    self.read_content(size, None, Some(&mut name)).await?;
+                    long_name = Some(parse_string(&name)?);
+                    continue;
+                }
+                b'x' => {
+                    let mut data = Vec::new();
+                    self.read_content(size, None, Some(&mut data)).await?;
+                    pax_records = parse_pax_records(&data)?;
+                    continue;
+                }
+                b'g' => {
+                    self.read_content(size, None, None).await?;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let mut path = match long_name.take() {
+                Some(long_name) => long_name,
+                None => {
+                    let name = parse_string(&header[0..100])?;
+                    let prefix = if &header[257..262] == b"ustar" {
+                        parse_string(&header[345..500])?
+                    } else {
+                        String::new()
+                    };
+                    if prefix.is_empty() {
+                        name
+                    } else {
+                        format!("{}/{}", prefix, name)
+                    }
+                }
+            };
+            let mut modified = parse_number(&header[136..148])? as i64;
+            if let Some(pax_path) = pax_records.remove("path") {
+                path = pax_path;
+            }
+            if let Some(pax_size) = pax_records.get("size").and_then(|s| s.parse().ok()) {
+                size = pax_size;
+            }
+            if let Some(pax_mtime) = pax_records
+                .get("mtime")
+                .and_then(|s| s.split('.').next())
+                .and_then(|s| s.parse().ok())
+            {
+                modified = pax_mtime;
+            }
+
+            let kind = match type_flag {
+                b'0' | b'\0' | b'7' => TarEntryKind::File,
+                b'5' => TarEntryKind::Directory,
+                _ => TarEntryKind::Other,
+            };
+
+            let mut content_hash = String::new();
+            if kind == TarEntryKind::File {
+                let mut hasher = Sha256::new();
+                self.read_content(size, Some(&mut hasher), None).await?;
+                content_hash = format!("{:x}", hasher.finalize());
+            } else if type_flag != b'1' && type_flag != b'2' {
+                // Links store their target in the header, not as content.
+                self.read_content(size, None, None).await?;
+            }
+
+            return Ok(Some(TarEntry {
+                path,
+                kind,
+                size,
+                modified,
+                content_hash,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(name: &str, size: usize, type_flag: u8) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[136..147].copy_from_slice(format!("{:011o}", 1700000000).as_bytes());
+        header[156] = type_flag;
+        header[257..263].copy_from_slice(b"ustar\0");
+
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        header
+    }
+
+    fn content(data: &[u8]) -> Vec<u8> {
+        let mut block = data.to_vec();
+        block.resize(data.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        block
+    }
+
+    #[tokio::test]
+    async fn test_read_entries() {
+        let long_name = "dir/".to_string() + &"x".repeat(150);
+        let mut archive = Vec::new();
+        archive.extend(header("dir/", 0, b'5'));
+        archive.extend(header("dir/hello.txt", 5, b'0'));
+        archive.extend(content(b"Hello"));
+        archive.extend(header("././@LongLink", long_name.len() + 1, b'L'));
+        archive.extend(content(format!("{}\0", long_name).as_bytes()));
+        archive.extend(header("truncated", 0, b'0'));
+        archive.extend(vec![0u8; BLOCK_SIZE * 2]);
+
+        let mut reader = TarReader::new(archive.as_slice());
+        let dir = reader.next_entry().await.unwrap().unwrap();
+        assert_eq!(dir.path, "dir/");
+        assert_eq!(dir.kind, TarEntryKind::Directory);
+
+        let file = reader.next_entry().await.unwrap().unwrap();
+        assert_eq!(file.path, "dir/hello.txt");
+        assert_eq!(file.kind, TarEntryKind::File);
+        assert_eq!(file.size, 5);
+        assert_eq!(file.modified, 1700000000);
+        assert_eq!(file.content_hash, format!("{:x}", Sha256::digest(b"Hello")));
+
+        let long = reader.next_entry().await.unwrap().unwrap();
+        assert_eq!(long.path, long_name);
+
+        assert_eq!(reader.next_entry().await.unwrap(), None);
+    }
+}
@@ -1,7 +1,13 @@
-use std::time::{Duration, SystemTime};
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime},
+};
 
 use crate::cmd::common::{CommandError, CommandErrorKind, CommandResult};
 
+// Whether times are displayed in UTC instead of the local time zone.
+static DISPLAY_UTC: AtomicBool = AtomicBool::new(false);
+
 pub fn as_unix_timestamp(time: SystemTime) -> i64 {
     match time.duration_since(SystemTime::UNIX_EPOCH) {
         Ok(duration) => duration.as_secs() as i64,
@@ -9,14 +15,34 @@ pub fn as_unix_timestamp(time: SystemTime) -> i64 {
     }
 }
 
+/// Seconds and nanoseconds since the Unix epoch. Times before the epoch round
+/// the seconds down, so that the nanoseconds are never negative.
+pub fn as_unix_timestamp_nanos(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+        Err(error) => {
+            let duration = error.duration();
+            match duration.subsec_nanos() {
+                0 => (-(duration.as_secs() as i64), 0),
+                nanos => (-(duration.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
 pub fn system_time_from_unix_timestamp(time: i64) -> CommandResult<SystemTime> {
+    system_time_from_unix_timestamp_nanos(time, 0)
+}
+
+pub fn system_time_from_unix_timestamp_nanos(time: i64, nanos: u32) -> CommandResult<SystemTime> {
     // Addition or subtraction may overflow SystemTime range and panic.
     std::panic::catch_unwind(move || {
-        if time < 0 {
+        let seconds = if time < 0 {
             SystemTime::UNIX_EPOCH - Duration::from_secs((-time) as u64)
         } else {
             SystemTime::UNIX_EPOCH + Duration::from_secs(time as u64)
-        }
+        };
+        seconds + Duration::from_nanos(nanos as u64)
     })
     .map_err(|_| {
         CommandError::new(
@@ -25,3 +51,206 @@ pub fn system_time_from_unix_timestamp(time: i64) -> CommandResult<SystemTime> {
         )
     })
 }
+
+/// Whether `time` is the recorded modified time. Entries written before
+/// sub-second times were recorded have no nanoseconds, so only their seconds
+/// are compared.
+pub fn modified_matches(seconds: i64, nanos: u32, time: SystemTime) -> bool {
+    if nanos == 0 {
+        return as_unix_timestamp(time) == seconds;
+    }
+    as_unix_timestamp_nanos(time) == (seconds, nanos)
+}
+
+/// Display times in UTC instead of the local time zone from now on.
+pub fn set_display_utc(utc: bool) {
+    DISPLAY_UTC.store(utc, Ordering::Relaxed);
+}
+
+fn broken_down_time(time: i64, utc: bool) -> Option<libc::tm> {
+    let time = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        if utc {
+            libc::gmtime_r(&time, &mut tm)
+        } else {
+            libc::localtime_r(&time, &mut tm)
+        }
+    };
+    (!result.is_null()).then_some(tm)
+}
+
+/// Whether times are displayed in UTC instead of the local time zone.
+pub fn display_utc() -> bool {
+    DISPLAY_UTC.load(Ordering::Relaxed)
+}
+
+/// Day, week and month that a Unix timestamp falls in, in UTC or the local
+/// time zone. Times in the same period get the same numbers. Weeks start on
+/// Monday.
+pub fn calendar_periods(time: i64, utc: bool) -> (i64, i64, i64) {
+    let tm = broken_down_time(time, utc).unwrap_or(unsafe { std::mem::zeroed() });
+    let day = (time + tm.tm_gmtoff).div_euclid(86_400);
+    // 1970-01-01 was a Thursday.
+    let week = (day + 3).div_euclid(7);
+    let month = (tm.tm_year as i64 + 1900) * 12 + tm.tm_mon as i64;
+    (day, week, month)
+}
+
+/// Unix timestamp as "2024-05-01 03:12", in UTC or the local time zone.
+pub fn format_date(time: i64, utc: bool) -> String {
+    let Some(tm) = broken_down_time(time, utc) else {
+        return format!("@{}", time);
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        tm.tm_year as i64 + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min
+    )
+}
+
+/// Parse a time given as "2024-05-01", "2024-05-01 12:00" or
+/// "2024-05-01 12:00:30" into a Unix timestamp, in UTC or the local time
+/// zone.
+pub fn parse_date(text: &str, utc: bool) -> Result<i64, String> {
+    let invalid = || {
+        format!(
+            "Invalid time {:?}, expected e.g. \"2024-05-01 12:00\"",
+            text
+        )
+    };
+    let (date, time) = match text.trim().split_once([' ', 'T']) {
+        Some((date, time)) => (date, time),
+        None => (text.trim(), "00:00"),
+    };
+    let date: Vec<&str> = date.split('-').collect();
+    let time: Vec<&str> = time.split(':').collect();
+    if date.len() != 3 || !(2..=3).contains(&time.len()) {
+        return Err(invalid());
+    }
+    let field = |value: &str, range: std::ops::RangeInclusive<i32>| {
+        value
+            .parse::<i32>()
+            .ok()
+            .filter(|value| range.contains(value))
+            .ok_or_else(invalid)
+    };
+
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = field(date[0], 1..=9999)? - 1900;
+    tm.tm_mon = field(date[1], 1..=12)? - 1;
+    tm.tm_mday = field(date[2], 1..=31)?;
+    tm.tm_hour = field(time[0], 0..=23)?;
+    tm.tm_min = field(time[1], 0..=59)?;
+    tm.tm_sec = match time.get(2) {
+        Some(seconds) => field(seconds, 0..=60)?,
+        None => 0,
+    };
+    // Let mktime work out whether daylight saving time is in effect.
+    tm.tm_isdst = -1;
+    let time = unsafe {
+        if utc {
+            libc::timegm(&mut tm)
+        } else {
+            libc::mktime(&mut tm)
+        }
+    };
+    Ok(time as i64)
+}
+
+/// Parse a time given by the user, in the display time zone.
+pub fn parse_time(text: &str) -> Result<i64, String> {
+    parse_date(text, DISPLAY_UTC.load(Ordering::Relaxed))
+}
+
+/// Unix timestamp in UTC as "20240501T031200Z", the basic ISO 8601 format
+/// used in request signatures.
+pub fn format_iso8601_basic(time: i64) -> String {
+    let tm = broken_down_time(time, true).unwrap_or(unsafe { std::mem::zeroed() });
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        tm.tm_year as i64 + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// How long before or after `now` the time is, e.g. "3 days ago".
+pub fn format_relative(time: i64, now: i64) -> String {
+    let difference = now - time;
+    let seconds = difference.unsigned_abs();
+    if seconds < 60 {
+        return "just now".to_string();
+    }
+    let (count, unit) = match seconds {
+        0..=3_599 => (seconds / 60, "minute"),
+        3_600..=172_799 => (seconds / 3_600, "hour"),
+        _ => (seconds / 86_400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    if difference < 0 {
+        format!("in {} {}{}", count, unit, plural)
+    } else {
+        format!("{} {}{} ago", count, unit, plural)
+    }
+}
+
+/// Unix timestamp for display, e.g. "2024-05-01 03:12, 3 days ago".
+pub fn format_time(time: i64) -> String {
+    format!(
+        "{}, {}",
+        format_date(time, DISPLAY_UTC.load(Ordering::Relaxed)),
+        format_relative(time, as_unix_timestamp(SystemTime::now()))
+    )
+}
+
+/// Unix timestamp for display in tables, without the relative time.
+pub fn format_short_time(time: i64) -> String {
+    format_date(time, DISPLAY_UTC.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unix_timestamp_nanos() {
+        for (seconds, nanos) in [(1_700_000_000, 123_456_789), (0, 0), (-2, 250_000_000)] {
+            let time = system_time_from_unix_timestamp_nanos(seconds, nanos).unwrap();
+            assert_eq!(as_unix_timestamp_nanos(time), (seconds, nanos));
+        }
+
+        let time = system_time_from_unix_timestamp_nanos(1_700_000_000, 500).unwrap();
+        assert!(modified_matches(1_700_000_000, 500, time));
+        assert!(!modified_matches(1_700_000_000, 501, time));
+        // Recorded without nanoseconds.
+        assert!(modified_matches(1_700_000_000, 0, time));
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_date(1_714_533_120, true), "2024-05-01 03:12");
+        assert_eq!(format_date(-60, true), "1969-12-31 23:59");
+        assert_eq!(format_iso8601_basic(1_714_533_125), "20240501T031205Z");
+
+        assert_eq!(parse_date("2024-05-01 03:12", true), Ok(1_714_533_120));
+        assert_eq!(parse_date("2024-05-01 03:12:30", true), Ok(1_714_533_150));
+        assert_eq!(parse_date("2024-05-01", true), Ok(1_714_521_600));
+        for text in ["2024-05", "2024-13-01", "yesterday", "2024-05-01 25:00"] {
+            assert!(parse_date(text, true).is_err(), "{}", text);
+        }
+
+        let now = 1_714_533_120;
+        assert_eq!(format_relative(now - 30, now), "just now");
+        assert_eq!(format_relative(now - 60, now), "1 minute ago");
+        assert_eq!(format_relative(now - 5 * 3_600, now), "5 hours ago");
+        assert_eq!(format_relative(now - 3 * 86_400 - 100, now), "3 days ago");
+        assert_eq!(format_relative(now + 2 * 60, now), "in 2 minutes");
+    }
+}
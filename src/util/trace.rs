@@ -0,0 +1,164 @@
+use std::{
+    env, fmt,
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    filter::filter_fn,
+    fmt::{
+        format::{FmtSpan, Writer},
+        FmtContext, FormatEvent, FormatFields, MakeWriter,
+    },
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+static OUTPUT: OnceLock<Mutex<BufWriter<File>>> = OnceLock::new();
+
+/// Formats events as their message and fields alone, which is what users see
+/// without --verbose.
+struct MessageOnly;
+
+impl<S, N> FormatEvent<S, N> for MessageOnly
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        context: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        context
+            .field_format()
+            .format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// Writes to the trace file, holding its lock for a whole line so that lines
+/// of different threads don't interleave.
+struct TraceOutput(&'static Mutex<BufWriter<File>>);
+
+struct TraceOutputGuard<'a>(MutexGuard<'a, BufWriter<File>>);
+
+impl<'a> MakeWriter<'a> for TraceOutput {
+    type Writer = TraceOutputGuard<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        TraceOutputGuard(self.0.lock().unwrap())
+    }
+}
+
+impl Write for TraceOutputGuard<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+fn use_ansi() -> bool {
+    match env::var("FREEBCK_LOG_STYLE").as_deref() {
+        Ok("always") => true,
+        Ok("never") => false,
+        _ => io::stderr().is_terminal(),
+    }
+}
+
+fn trace_layer<S>(output: &'static Mutex<BufWriter<File>>) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(TraceOutput(output))
+        .with_filter(filter_fn(|metadata| {
+            metadata.is_span() && metadata.target().starts_with("freebck")
+        }))
+}
+
+/// Log to stderr at the level set by FREEBCK_LOG_LEVEL, or debug with
+/// `verbose` and info otherwise. With `trace_path`, every span of freebck is
+/// also written there as a JSON object when it closes, with its fields and
+/// busy and idle times. Only the first call has an effect.
+pub fn init_tracing(verbose: bool, trace_path: Option<&Path>) -> io::Result<()> {
+    let filter = EnvFilter::try_from_env("FREEBCK_LOG_LEVEL")
+        .unwrap_or_else(|_| EnvFilter::new(if verbose { "debug" } else { "info" }));
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_writer(io::stderr)
+        .with_ansi(use_ansi());
+    // Verbose output is for debugging, so it shows the level and the spans
+    // each line was logged in.
+    let stderr = match verbose {
+        true => stderr.without_time().with_target(false).boxed(),
+        false => stderr.event_format(MessageOnly).boxed(),
+    };
+
+    let trace = match trace_path {
+        Some(path) => {
+            let file = File::create(path)?;
+            let output = OUTPUT.get_or_init(|| Mutex::new(BufWriter::new(file)));
+            Some(trace_layer(output))
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(stderr.with_filter(filter))
+        .with(trace)
+        .try_init()
+        .map_err(io::Error::other)
+}
+
+/// Write out the spans buffered so far.
+pub fn finish_trace() -> io::Result<()> {
+    match OUTPUT.get() {
+        Some(output) => output.lock().unwrap().flush(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tracing::{debug_span, field, info};
+
+    use super::*;
+
+    #[test]
+    fn test_spans_are_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.json");
+        // The layer keeps the output for good, so it can't be borrowed
+        // mutably to flush it.
+        let output: &'static Mutex<_> = Box::leak(Box::new(Mutex::new(BufWriter::new(
+            File::create(&path).unwrap(),
+        ))));
+        let subscriber = tracing_subscriber::registry().with(trace_layer(output));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = debug_span!("test_span", path = "a \"quoted\" name", size = field::Empty);
+            span.in_scope(|| info!("Not a span"));
+            span.record("size", 5);
+        });
+        output.lock().unwrap().flush().unwrap();
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 1, "{}", trace);
+        let span: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(span["span"]["name"].as_str(), Some("test_span"));
+        assert_eq!(span["span"]["path"].as_str(), Some("a \"quoted\" name"));
+        assert_eq!(span["span"]["size"].as_u64(), Some(5));
+        assert!(span["fields"]["time.busy"].is_string());
+    }
+}
@@ -1,16 +1,64 @@
-use log::debug;
-use std::{error::Error, path::PathBuf};
+use async_trait::async_trait;
+use prost::Message;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    error::Error,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
 use test_log::{self, test};
 use tokio::fs;
+use tracing::debug;
 use walkdir::WalkDir;
 
 use freebck::{
     cmd::{
-        backup::{backup, BackupArgs},
-        common::ProgramContext,
-        restore::{restore, RestoreArgs},
+        backup::{
+            backup, backup_all, backup_from, estimate_backup,
+            source::{BackupSource, EntryType, SourceEntry, SourceMetadata, SourceReader},
+            BackupArgs, SpecialType,
+        },
+        check::{check, CheckArgs},
+        common::{
+            get_dir_entry, get_latest_snapshot, get_snapshot, get_stats_history, CommandError,
+            CommandErrorKind, ProgramContext, RuntimeTuning,
+        },
+        doctor::{diagnose, Severity},
+        find::find_hash,
+        forget::{forget, ForgetArgs},
+        ls::{resolve_path, PathEntry},
+        prune::{explain_forget, find_unused_blobs, prune, PruneArgs},
+        repair::{repair, RepairArgs},
+        repo::{clone, export, import, init_storage, CloneArgs, ExportArgs, ImportArgs, InitArgs},
+        restore::{
+            restore, restore_to,
+            target::{CreateMode, RestoreFile, RestoreTarget},
+            Overwrite, RestoreArgs,
+        },
+        scan::{scan_source, ScanArgs, ScanTotals},
+        snapshots::{list_snapshots, summarize_snapshots, SnapshotsArgs},
+        stats::repository_stats,
+        verify::{verify, VerifyArgs},
+    },
+    data::{
+        backup::{sub_dir_entry::Content, DirEntry, FileEntry, Snapshot},
+        config::{DatabaseConfig, DatabaseKind, HooksConfig},
     },
-    storage::file::FileStorage,
+    storage::{
+        compressed::CompressedStorage,
+        encrypted::{read_keys, EncryptedStorage, KdfParams},
+        file::{init_repository, FileStorage},
+        memory::MemoryStorage,
+        pack::PackStorage,
+        Collection, Storage, StorageItems, StorageRead, StorageWrite,
+    },
+    util::{database::DATABASE_DUMP_DIR, lock::lock_repository, time::parse_time},
 };
 
 #[test(tokio::test)]
@@ -22,14 +70,21 @@ async fn test_backup_and_restore() -> Result<(), Box<dyn Error>> {
     let backup_dir = tempfile::tempdir()?;
     debug!("Test backup output: {:}", backup_dir.path().display());
 
-    let storage = Box::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
     let mut context = ProgramContext {
         archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
         storage,
         backup_target: content_path.clone(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
     };
 
-    backup(&context, &BackupArgs {}).await?;
+    backup(&context, &BackupArgs::default()).await?;
 
     let restore_dir = tempfile::tempdir()?;
     context.backup_target = restore_dir.path().into();
@@ -37,9 +92,10 @@ async fn test_backup_and_restore() -> Result<(), Box<dyn Error>> {
     restore(
         &context,
         &RestoreArgs {
-            snapshot: "1".to_owned(),
-            keep_going: false,
-            no_override_files: true,
+            snapshot: Some("1".to_owned()),
+            archive: None,
+            overwrite: Some(Overwrite::Never),
+            ..Default::default()
         },
     )
     .await?;
@@ -81,3 +137,4153 @@ async fn test_backup_and_restore() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[test(tokio::test)]
+async fn test_restore_from_other_archive() -> Result<(), Box<dyn Error>> {
+    let content_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("test_data/test_backup_content")
+        .canonicalize()?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "old_laptop".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_path.clone(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+
+    context.archive_name = "new_laptop".to_owned();
+    for (snapshot, archive) in [("1", Some("old_laptop")), ("old_laptop/1", None)] {
+        let restore_dir = tempfile::tempdir()?;
+        context.backup_target = restore_dir.path().into();
+
+        restore(
+            &context,
+            &RestoreArgs {
+                snapshot: Some(snapshot.to_owned()),
+                archive: archive.map(|a| a.to_owned()),
+                overwrite: Some(Overwrite::Never),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        assert!(restore_dir.path().join("dir_a/hello.txt").is_file());
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_verify_compare_source() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    let hello_path = content_dir.path().join("dir_a/hello.txt");
+    // Modified times are set explicitly, so that a change is seen however
+    // quickly it follows the backup.
+    let backed_up = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    let set_hello = |content: &str, modified: SystemTime| -> std::io::Result<()> {
+        std::fs::write(&hello_path, content)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&hello_path)?
+            .set_modified(modified)
+    };
+    set_hello("Hello", backed_up)?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+
+    for bitwise in [false, true] {
+        let args = VerifyArgs {
+            snapshot: None,
+            compare_source: true,
+            bitwise,
+        };
+        verify(&context, &args).await?;
+
+        set_hello("World", backed_up + Duration::from_secs(60))?;
+        assert!(verify(&context, &args).await.is_err());
+        set_hello("Hello", backed_up)?;
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_fixed_block_backup_and_restore() -> Result<(), Box<dyn Error>> {
+    let mut content = vec![0u8; 64];
+    content[10] = 1;
+    content[40..50].copy_from_slice(b"0123456789");
+
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("disk.img"), &content).await?;
+    fs::write(content_dir.path().join("zeros.img"), vec![0u8; 30]).await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(
+        &context,
+        &BackupArgs {
+            fixed_block: true,
+            block_size: Some(8),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    assert_eq!(
+        fs::read(restore_dir.path().join("disk.img")).await?,
+        content
+    );
+    assert_eq!(
+        fs::read(restore_dir.path().join("zeros.img")).await?,
+        vec![0u8; 30]
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_fixed_block_zero_blocks() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("zeros.img"), vec![0u8; 30]).await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(
+        &context,
+        &BackupArgs {
+            fixed_block: true,
+            block_size: Some(8),
+            store_zero_blocks: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Zero blocks are stored when asked to.
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/1").await?.root_hash).await?;
+    assert_eq!(root.file[0].chunk_hash.len(), 4);
+    assert!(root.file[0].chunk_hash.iter().all(|hash| !hash.is_empty()));
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read(restore_dir.path().join("zeros.img")).await?,
+        vec![0u8; 30]
+    );
+
+    // An entry whose chunks go past its size is corrupt.
+    let root = DirEntry {
+        file: vec![FileEntry {
+            name: "short.img".to_owned(),
+            size: 4,
+            block_size: 4,
+            chunk_hash: vec![String::new(), String::new()],
+            chunk_size: vec![8],
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+    .encode_to_vec();
+    let root_hash = format!("{:x}", Sha256::digest(&root));
+    context
+        .storage
+        .write(Collection::Blob, &root_hash, &root)
+        .await?;
+    let snapshot = Snapshot {
+        root_hash,
+        ..Default::default()
+    };
+    context
+        .storage
+        .write(Collection::Snapshot, "test/2", &snapshot.encode_to_vec())
+        .await?;
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    let error = restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("2".to_owned()),
+            keep_going: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap_err();
+    let failures = error.failures().unwrap();
+    assert_eq!(failures.grouped()[0].0, CommandErrorKind::Corrupt);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_with_undo_dir() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello again").await?;
+
+    let undo_dir = tempfile::tempdir()?;
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            undo_dir: Some(undo_dir.path().into()),
+            into_nonempty: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    assert_eq!(
+        fs::read_to_string(content_dir.path().join("dir_a/hello.txt")).await?,
+        "Hello"
+    );
+    assert_eq!(
+        fs::read_to_string(undo_dir.path().join("dir_a/hello.txt")).await?,
+        "Hello again"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_salvage() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file.bin"), "AAAABBBBCCCC").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(
+        &context,
+        &BackupArgs {
+            fixed_block: true,
+            block_size: Some(4),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Corrupt the middle chunk.
+    for entry in WalkDir::new(backup_dir.path().join("blob")) {
+        let entry = entry?;
+        if entry.file_type().is_file() && fs::read(entry.path()).await? == b"BBBB" {
+            fs::write(entry.path(), "XXXX").await?;
+        }
+    }
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            salvage: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    assert_eq!(
+        fs::read(restore_dir.path().join("file.bin")).await?,
+        b"AAAA\0\0\0\0CCCC"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_include() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir_all(content_dir.path().join("photos/2023")).await?;
+    fs::create_dir(content_dir.path().join("music")).await?;
+    fs::write(content_dir.path().join("photos/2023/cat.jpg"), "Meow").await?;
+    fs::write(content_dir.path().join("photos/2023/notes.txt"), "Notes").await?;
+    fs::write(content_dir.path().join("music/song.mp3"), "La la").await?;
+    fs::write(content_dir.path().join("README"), "Read me").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(
+        &context,
+        &BackupArgs {
+            include: vec!["*.jpg".to_owned(), "README".to_owned()],
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    assert!(restore_dir.path().join("photos/2023/cat.jpg").is_file());
+    assert!(restore_dir.path().join("README").is_file());
+    assert!(!restore_dir.path().join("photos/2023/notes.txt").exists());
+    assert!(!restore_dir.path().join("music").exists());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_scan_estimates_changes() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello").await?;
+    fs::write(content_dir.path().join("README"), "Read me").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    let totals = scan_source(&context, &ScanArgs::default(), None).await?;
+    assert_eq!(
+        totals,
+        ScanTotals {
+            files: 2,
+            dirs: 1,
+            bytes: 12,
+            changed_files: 2,
+            changed_bytes: 12,
+        }
+    );
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("dir_a/new.txt"), "New").await?;
+
+    let snapshot = get_snapshot(&context, "test/1").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    let totals = scan_source(&context, &ScanArgs::default(), Some(&root)).await?;
+    assert_eq!(totals.files, 3);
+    assert_eq!(totals.changed_files, 1);
+    assert_eq!(totals.changed_bytes, 3);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_scan_skips_as_backup() -> Result<(), Box<dyn Error>> {
+    // The state and the repository are in the backup target, as with the
+    // default layout, next to a nested repository and a symlink.
+    let content_dir = tempfile::tempdir()?;
+    let state_dir = content_dir.path().join(".freebck");
+    fs::write(content_dir.path().join("a"), "Alpha").await?;
+    init_repository(&content_dir.path().join("other_repo"), "other").await?;
+    std::os::unix::fs::symlink("a", content_dir.path().join("link"))?;
+    let storage = Arc::new(FileStorage::new(state_dir.join("repo")).await?);
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir,
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    let args = BackupArgs {
+        exclude_type: vec![SpecialType::Symlink],
+        ..Default::default()
+    };
+    backup(&context, &args).await?;
+
+    let snapshot = get_snapshot(&context, "test/1").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    let args = ScanArgs {
+        exclude_type: vec![SpecialType::Symlink],
+        ..Default::default()
+    };
+    let totals = scan_source(&context, &args, Some(&root)).await?;
+    assert_eq!(
+        totals,
+        ScanTotals {
+            files: 1,
+            dirs: 0,
+            bytes: 5,
+            changed_files: 0,
+            changed_bytes: 0,
+        }
+    );
+
+    let args = ScanArgs {
+        include_repos: true,
+        ..Default::default()
+    };
+    let totals = scan_source(&context, &args, Some(&root)).await?;
+    assert_eq!(totals.files, 2);
+    assert_eq!(totals.dirs, 1);
+    assert_eq!(totals.changed_files, 1);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_with_memory_limit() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let content: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(content_dir.path().join("large.bin"), &content).await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("copy.bin"), &content).await?;
+    backup(
+        &context,
+        &BackupArgs {
+            max_memory: Some(4 * 1024 * 1024),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // The limit doesn't change the chunks, so the copy deduplicates against
+    // the file backed up without one.
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/2").await?.root_hash).await?;
+    assert_eq!(root.file[0].name, "copy.bin");
+    assert_eq!(root.file[0].chunk_hash, root.file[1].chunk_hash);
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("2".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    assert_eq!(
+        fs::read(restore_dir.path().join("copy.bin")).await?,
+        content
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_large_dirs_by_hash() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir_all(content_dir.path().join("a/b")).await?;
+    fs::write(content_dir.path().join("a/b/file"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: RuntimeTuning {
+            inline_dir_size: 1,
+            ..Default::default()
+        },
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Directories larger than the limit are stored on their own.
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/1").await?.root_hash).await?;
+    let Some(Content::Hash(ref hash)) = root.sub_dir[0].content else {
+        panic!("Directory is stored inline: {:?}", root.sub_dir[0]);
+    };
+    let sub_dir = get_dir_entry(&context, hash).await?;
+    assert!(matches!(sub_dir.sub_dir[0].content, Some(Content::Hash(_))));
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("a/b/file")).await?,
+        "Hello"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_streams_large_chunks() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let content: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    fs::write(content_dir.path().join("large.bin"), &content).await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: RuntimeTuning {
+            chunk_size: 64 * 1024,
+            ..Default::default()
+        },
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Streamed chunks are the same as buffered ones, so the copy
+    // deduplicates against the file backed up first.
+    let other: Vec<u8> = content.iter().rev().copied().collect();
+    fs::write(content_dir.path().join("copy.bin"), &content).await?;
+    fs::write(content_dir.path().join("other.bin"), &other).await?;
+    context.tuning.stream_chunk_size = 1024;
+    backup(&context, &BackupArgs::default()).await?;
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/2").await?.root_hash).await?;
+    assert_eq!(root.file[0].name, "copy.bin");
+    assert_eq!(root.file[0].chunk_hash.len(), 4);
+    assert_eq!(root.file[0].chunk_hash, root.file[1].chunk_hash);
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("2".to_owned()),
+            verify: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read(restore_dir.path().join("copy.bin")).await?,
+        content
+    );
+    assert_eq!(fs::read(restore_dir.path().join("other.bin")).await?, other);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_retention_class() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("README"), "Read me").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(
+        &context,
+        &BackupArgs {
+            retention_class: Some("monthly".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    assert_eq!(
+        get_snapshot(&context, "test/1").await?.retention_class,
+        "monthly"
+    );
+    assert_eq!(get_snapshot(&context, "test/2").await?.retention_class, "");
+
+    // Newer snapshots of other classes don't push the monthly one out.
+    backup(&context, &BackupArgs::default()).await?;
+    let args = ForgetArgs {
+        keep_last: Some(1),
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    let names: Vec<String> = list_snapshots(&context, &SnapshotsArgs::default())
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, ["test/1", "test/3"]);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_check_auto_rotates_blobs() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    for name in ["a", "b", "c"] {
+        fs::write(content_dir.path().join(name), name).await?;
+    }
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Each run with a budget of one byte checks a single blob, starting with
+    // the ones never verified.
+    let args = CheckArgs {
+        auto: true,
+        max_bytes: Some(1),
+        ..Default::default()
+    };
+    check(&context, &args).await?;
+    let verified = fs::read_to_string(context.state_dir.join("verified_blobs")).await?;
+    assert_eq!(verified.lines().count(), 1);
+
+    check(&context, &args).await?;
+    let verified = fs::read_to_string(context.state_dir.join("verified_blobs")).await?;
+    assert_eq!(verified.lines().count(), 2);
+
+    // Corrupt one of the blobs.
+    for entry in WalkDir::new(backup_dir.path().join("blob")) {
+        let entry = entry?;
+        if entry.file_type().is_file() && fs::read(entry.path()).await? == b"a" {
+            fs::write(entry.path(), "x").await?;
+        }
+    }
+    let result = check(
+        &context,
+        &CheckArgs {
+            auto: true,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_check_snapshots() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    for name in ["a", "b", "c", "d"] {
+        fs::write(content_dir.path().join(name), name).await?;
+    }
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/1").await?.root_hash).await?;
+    let chunk = |name: &str| {
+        let file = root.file.iter().find(|file| file.name == name).unwrap();
+        file.chunk_hash[0].clone()
+    };
+
+    check(&context, &CheckArgs::default()).await?;
+    let verified_path = context.state_dir.join("verified_blobs");
+    assert!(!verified_path.exists());
+
+    // Half of the root entry and the four chunks, rounded up.
+    let args = CheckArgs {
+        read_data_subset: Some(0.5),
+        ..Default::default()
+    };
+    check(&context, &args).await?;
+    let verified = fs::read_to_string(&verified_path).await?;
+    assert_eq!(verified.lines().count(), 3);
+
+    // Damaged data is only found by reading it, and stays a problem until
+    // it is read again.
+    context
+        .storage
+        .replace(Collection::Blob, &chunk("a"), b"x")
+        .await?;
+    check(&context, &CheckArgs::default()).await?;
+    let read_data = CheckArgs {
+        read_data: true,
+        ..Default::default()
+    };
+    let error = check(&context, &read_data).await.unwrap_err();
+    assert_eq!(error.kind(), CommandErrorKind::Corrupt);
+    assert!(check(&context, &CheckArgs::default()).await.is_err());
+    context
+        .storage
+        .replace(Collection::Blob, &chunk("a"), b"a")
+        .await?;
+    check(&context, &read_data).await?;
+
+    // Missing blobs are found without reading any.
+    context
+        .storage
+        .delete(Collection::Blob, &chunk("b"))
+        .await?;
+    assert!(check(&context, &CheckArgs::default()).await.is_err());
+
+    Ok(())
+}
+#[test(tokio::test)]
+async fn test_repair_from_mirror() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    for name in ["a", "b", "c"] {
+        fs::write(content_dir.path().join(name), name).await?;
+    }
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let mirror = MemoryStorage::new();
+    for collection in [Collection::Snapshot, Collection::Blob] {
+        for key in context.storage.get_collection_items(collection).await? {
+            let mut buffer = Vec::new();
+            context.storage.read(collection, &key, &mut buffer).await?;
+            mirror.write(collection, &key, &buffer).await?;
+        }
+    }
+
+    // Corrupt one blob and lose another.
+    for entry in WalkDir::new(backup_dir.path().join("blob")) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        match fs::read(entry.path()).await?.as_slice() {
+            b"a" => fs::write(entry.path(), "x").await?,
+            b"b" => fs::remove_file(entry.path()).await?,
+            _ => {}
+        }
+    }
+    let check_args = CheckArgs {
+        auto: true,
+        ..Default::default()
+    };
+    assert!(check(&context, &check_args).await.is_err());
+
+    let args = RepairArgs {
+        from_mirror: true,
+        ..Default::default()
+    };
+    let result = repair(&context, &args, None).await;
+    assert_eq!(result.unwrap_err().kind(), CommandErrorKind::User);
+    repair(&context, &args, Some(&mirror)).await?;
+    check(&context, &check_args).await?;
+    assert!(!context.state_dir.join("damaged_blobs").exists());
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    for name in ["a", "b", "c"] {
+        assert_eq!(
+            fs::read_to_string(restore_dir.path().join(name)).await?,
+            name
+        );
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_repair_remove_damaged() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("sub")).await?;
+    fs::write(content_dir.path().join("sub/a"), "a").await?;
+    fs::write(content_dir.path().join("sub/b"), "b").await?;
+    fs::write(content_dir.path().join("c"), "c").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    let hash = |content: &str| format!("sha256-{:x}", Sha256::digest(content));
+
+    // Corrupt one blob and lose another.
+    context
+        .storage
+        .replace(Collection::Blob, &hash("a"), b"x")
+        .await?;
+    context.storage.delete(Collection::Blob, &hash("c")).await?;
+    let check_args = CheckArgs {
+        read_data: true,
+        ..Default::default()
+    };
+    assert!(check(&context, &check_args).await.is_err());
+
+    let root_hash = get_snapshot(&context, "test/1").await?.root_hash;
+    let args = RepairArgs {
+        remove_damaged: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    repair(&context, &args, None).await?;
+    assert_eq!(get_snapshot(&context, "test/1").await?.root_hash, root_hash);
+
+    let args = RepairArgs {
+        dry_run: false,
+        ..args
+    };
+    // New directories aren't used by a snapshot until the repair is done,
+    // so it can't run during a prune.
+    let lock = lock_repository(&context, true).await?;
+    let error = repair(&context, &args, None).await.unwrap_err();
+    assert_eq!(error.kind(), CommandErrorKind::Locked);
+    lock.release().await;
+    repair(&context, &args, None).await?;
+    let snapshot = get_snapshot(&context, "test/1").await?;
+    assert_eq!(snapshot.removed_path, ["c", "sub/a"]);
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    assert_eq!(root.size, 1);
+    assert!(root.metadata_hash.is_empty());
+    check(&context, &check_args).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("sub/b")).await?,
+        "b"
+    );
+    assert!(!restore_dir.path().join("sub/a").exists());
+    assert!(!restore_dir.path().join("c").exists());
+
+    Ok(())
+}
+#[test(tokio::test)]
+async fn test_restore_flatten() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir_all(content_dir.path().join("a/b")).await?;
+    fs::write(content_dir.path().join("report.docx"), "1").await?;
+    fs::write(content_dir.path().join("a/report.docx"), "2").await?;
+    fs::write(content_dir.path().join("a/b/notes.docx"), "3").await?;
+    fs::write(content_dir.path().join("a/b/notes.txt"), "4").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    let mut args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        flatten: true,
+        include: vec!["*.docx".to_owned()],
+        ..Default::default()
+    };
+    assert!(restore(&context, &args).await.is_err());
+
+    args.rename_collisions = true;
+    restore(&context, &args).await?;
+
+    let mut names = Vec::new();
+    let mut entries = fs::read_dir(restore_dir.path()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        names.push(entry.file_name().into_string().unwrap());
+    }
+    names.sort();
+    assert_eq!(names, ["notes.docx", "report (1).docx", "report.docx"]);
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("report.docx")).await?,
+        "2"
+    );
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("report (1).docx")).await?,
+        "1"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_paths() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir_all(content_dir.path().join("a/b")).await?;
+    fs::create_dir_all(content_dir.path().join("c")).await?;
+    fs::write(content_dir.path().join("a/b/one.txt"), "1").await?;
+    fs::write(content_dir.path().join("a/b/two.txt"), "2").await?;
+    fs::write(content_dir.path().join("a/three.txt"), "3").await?;
+    fs::write(content_dir.path().join("c/four.txt"), "4").await?;
+    fs::write(content_dir.path().join("five.txt"), "5").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    let mut args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        path: vec!["a/b/one.txt".into(), "a/missing".into()],
+        ..Default::default()
+    };
+    let error = restore(&context, &args).await.unwrap_err();
+    assert_eq!(error.kind(), CommandErrorKind::NotFound);
+
+    args.path = vec!["a/b/one.txt".into(), "./c".into(), "/a/b".into()];
+    restore(&context, &args).await?;
+    for (path, content) in [
+        ("a/b/one.txt", "1"),
+        ("a/b/two.txt", "2"),
+        ("c/four.txt", "4"),
+    ] {
+        assert_eq!(
+            fs::read_to_string(restore_dir.path().join(path)).await?,
+            content
+        );
+    }
+    assert!(!restore_dir.path().join("a/three.txt").exists());
+    assert!(!restore_dir.path().join("five.txt").exists());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_prune_empty_dirs() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir_all(content_dir.path().join("cache/a/b")).await?;
+    fs::create_dir_all(content_dir.path().join("empty")).await?;
+    fs::create_dir_all(content_dir.path().join("docs/empty")).await?;
+    fs::write(content_dir.path().join("docs/file"), "File").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    let sub_dirs = |dir_entry: &DirEntry| -> Vec<String> {
+        dir_entry.sub_dir.iter().map(|d| d.name.clone()).collect()
+    };
+
+    // Empty directories are kept unless asked otherwise.
+    backup(&context, &BackupArgs::default()).await?;
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/1").await?.root_hash).await?;
+    assert_eq!(sub_dirs(&root), ["cache", "docs", "empty"]);
+    let args = BackupArgs {
+        prune_empty_dirs: true,
+        ..Default::default()
+    };
+    backup(&context, &args).await?;
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/2").await?.root_hash).await?;
+    assert_eq!(sub_dirs(&root), ["docs"]);
+    let Some(Content::Inline(ref docs)) = root.sub_dir[0].content else {
+        panic!("docs not inline");
+    };
+    assert!(docs.sub_dir.is_empty());
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            prune_empty_dirs: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("docs/file")).await?,
+        "File"
+    );
+    for path in ["cache", "empty", "docs/empty"] {
+        assert!(!restore_dir.path().join(path).exists(), "{}", path);
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_verify() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a"), "Alpha").await?;
+    fs::write(content_dir.path().join("b"), "Bravo").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // A file that looks up to date is only trusted without --verify.
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    let mut args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        ..Default::default()
+    };
+    restore(&context, &args).await?;
+    let path = restore_dir.path().join("a");
+    let modified = std::fs::metadata(&path)?.modified()?;
+    fs::write(&path, "Amiss").await?;
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+    args.into_nonempty = true;
+    restore(&context, &args).await?;
+    assert_eq!(fs::read_to_string(&path).await?, "Amiss");
+    let undo_dir = tempfile::tempdir()?;
+    args.undo_dir = Some(undo_dir.path().into());
+    args.verify = true;
+    restore(&context, &args).await?;
+    assert_eq!(fs::read_to_string(&path).await?, "Alpha");
+    assert_eq!(
+        fs::read_to_string(undo_dir.path().join("a")).await?,
+        "Amiss"
+    );
+
+    // Damage of the same length goes unnoticed without --verify.
+    let hash = format!("sha256-{:x}", Sha256::digest("Bravo"));
+    context
+        .storage
+        .replace(Collection::Blob, &hash, b"Brave")
+        .await?;
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    assert!(restore(&context, &args).await.is_err());
+    args.verify = false;
+    restore(&context, &args).await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("b")).await?,
+        "Brave"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_overwrite() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a"), "Alpha").await?;
+    fs::write(content_dir.path().join("b"), "Bravo").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    let set_file = |name: &str, content: &str, modified: SystemTime| -> std::io::Result<()> {
+        let path = restore_dir.path().join(name);
+        std::fs::write(&path, content)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)
+    };
+    let read_file = |name: &str| std::fs::read_to_string(restore_dir.path().join(name));
+    set_file("a", "Newer", SystemTime::now() + Duration::from_secs(3600))?;
+    set_file(
+        "b",
+        "Older",
+        SystemTime::UNIX_EPOCH + Duration::from_secs(3600),
+    )?;
+
+    // Existing files are only replaced when asked to.
+    let mut args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        into_nonempty: true,
+        ..Default::default()
+    };
+    assert!(restore(&context, &args).await.is_err());
+    assert_eq!(read_file("b")?, "Older");
+
+    args.overwrite = Some(Overwrite::IfNewer);
+    restore(&context, &args).await?;
+    assert_eq!(read_file("a")?, "Newer");
+    assert_eq!(read_file("b")?, "Bravo");
+
+    // Up to date files are skipped unless always replaced.
+    let modified = std::fs::metadata(restore_dir.path().join("b"))?.modified()?;
+    set_file("b", "Brave", modified)?;
+    args.overwrite = Some(Overwrite::IfDifferent);
+    restore(&context, &args).await?;
+    assert_eq!(read_file("a")?, "Alpha");
+    assert_eq!(read_file("b")?, "Brave");
+    args.overwrite = Some(Overwrite::Always);
+    restore(&context, &args).await?;
+    assert_eq!(read_file("b")?, "Bravo");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_delete() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir")).await?;
+    fs::write(content_dir.path().join("dir/a"), "Alpha").await?;
+    fs::write(content_dir.path().join("b"), "Bravo").await?;
+    fs::create_dir(content_dir.path().join("c")).await?;
+    fs::write(content_dir.path().join("c/d"), "Delta").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    fs::create_dir_all(restore_dir.path().join("dir")).await?;
+    fs::create_dir_all(restore_dir.path().join("old/sub")).await?;
+    fs::write(restore_dir.path().join("dir/extra"), "Extra").await?;
+    fs::write(restore_dir.path().join("old/sub/file"), "Old").await?;
+    // A directory where the snapshot has a file, and a file where it has a
+    // directory.
+    fs::create_dir(restore_dir.path().join("b")).await?;
+    fs::write(restore_dir.path().join("c"), "File").await?;
+    let paths = || -> Vec<String> {
+        let mut paths: Vec<String> = WalkDir::new(restore_dir.path())
+            .min_depth(1)
+            .into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let path = entry.path().strip_prefix(restore_dir.path()).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+        paths.sort();
+        paths
+    };
+    let existing = paths();
+
+    // A dry run changes nothing.
+    let mut args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        delete: true,
+        dry_run: true,
+        ..Default::default()
+    };
+    restore(&context, &args).await?;
+    assert_eq!(paths(), existing);
+
+    args.dry_run = false;
+    args.undo_dir = Some(restore_dir.path().join(".undo"));
+    restore(&context, &args).await?;
+    assert_eq!(
+        paths(),
+        [
+            ".undo",
+            ".undo/b",
+            ".undo/c",
+            ".undo/dir",
+            ".undo/dir/extra",
+            ".undo/old",
+            ".undo/old/sub",
+            ".undo/old/sub/file",
+            "b",
+            "c",
+            "c/d",
+            "dir",
+            "dir/a",
+        ]
+    );
+    args.undo_dir = None;
+    restore(&context, &args).await?;
+    assert_eq!(paths(), ["b", "c", "c/d", "dir", "dir/a"]);
+
+    // An undo directory on another file system gets a copy of the tree.
+    if Path::new("/dev/shm").is_dir() {
+        fs::create_dir_all(restore_dir.path().join("old/sub")).await?;
+        fs::write(restore_dir.path().join("old/sub/file"), "Old").await?;
+        std::os::unix::fs::symlink("sub/file", restore_dir.path().join("old/link"))?;
+        let undo_dir = tempfile::tempdir_in("/dev/shm")?;
+        args.undo_dir = Some(undo_dir.path().into());
+        restore(&context, &args).await?;
+        assert_eq!(paths(), ["b", "c", "c/d", "dir", "dir/a"]);
+        assert_eq!(
+            fs::read_to_string(undo_dir.path().join("old/sub/file")).await?,
+            "Old"
+        );
+        assert_eq!(
+            fs::read_link(undo_dir.path().join("old/link")).await?,
+            Path::new("sub/file")
+        );
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_default_layout() -> Result<(), Box<dyn Error>> {
+    // The config and state are in .freebck in the backup target, with the
+    // repository inside it.
+    let content_dir = tempfile::tempdir()?;
+    let state_dir = content_dir.path().join(".freebck");
+    let repo_dir = state_dir.join("repo");
+    fs::write(content_dir.path().join("a"), "Alpha").await?;
+    let storage = Arc::new(FileStorage::new(repo_dir.clone()).await?);
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.clone(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Only freebck's own directories are left, which doesn't make the
+    // target count as not empty.
+    fs::remove_file(content_dir.path().join("a")).await?;
+    let mut args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        ..Default::default()
+    };
+    restore(&context, &args).await?;
+    assert_eq!(fs::read(content_dir.path().join("a")).await?, b"Alpha");
+
+    // Nor does --delete delete them.
+    fs::write(content_dir.path().join("extra"), "Extra").await?;
+    args.delete = true;
+    restore(&context, &args).await?;
+    assert!(!content_dir.path().join("extra").exists());
+    assert!(repo_dir.join("snapshot").exists());
+    assert!(state_dir.exists());
+    get_snapshot(&context, "test/1").await?;
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_delete_symlinks() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir")).await?;
+    fs::write(content_dir.path().join("dir/a"), "Alpha").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Links in the target to a directory outside of it, one with the name of
+    // a directory in the snapshot and one with a name it doesn't have.
+    let outside_dir = tempfile::tempdir()?;
+    fs::write(outside_dir.path().join("precious"), "Keep").await?;
+    let restore_dir = tempfile::tempdir()?;
+    std::os::unix::fs::symlink(outside_dir.path(), restore_dir.path().join("dir"))?;
+    std::os::unix::fs::symlink(outside_dir.path(), restore_dir.path().join("link"))?;
+    context.backup_target = restore_dir.path().into();
+
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            delete: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let mut outside = std::fs::read_dir(outside_dir.path())?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    outside.sort();
+    assert_eq!(outside, ["precious"]);
+    let dir_metadata = std::fs::symlink_metadata(restore_dir.path().join("dir"))?;
+    assert!(dir_metadata.is_dir());
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("dir/a")).await?,
+        "Alpha"
+    );
+    assert!(!restore_dir.path().join("link").exists());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_resume_session() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file.bin"), "AAAABBBBCCCC").await?;
+    fs::write(content_dir.path().join("other.txt"), "Other").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(
+        &context,
+        &BackupArgs {
+            fixed_block: true,
+            block_size: Some(4),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Leave the state of a restore interrupted after the first block.
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    fs::write(restore_dir.path().join("file.bin"), "AAAAXXXXXXXXXXXX").await?;
+    fs::write(restore_dir.path().join("other.txt"), "Stale").await?;
+    let session_path = context.state_dir.join("restore_sessions/interrupted");
+    fs::create_dir_all(session_path.parent().unwrap()).await?;
+    fs::write(
+        &session_path,
+        format!(
+            "snapshot test/1\ntarget {}\ndone other.txt\npartial 1 4 file.bin\npartial 2",
+            restore_dir.path().display()
+        ),
+    )
+    .await?;
+
+    restore(
+        &context,
+        &RestoreArgs {
+            resume: Some("interrupted".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    assert_eq!(
+        fs::read(restore_dir.path().join("file.bin")).await?,
+        b"AAAABBBBCCCC"
+    );
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("other.txt")).await?,
+        "Stale"
+    );
+    assert!(!session_path.exists());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_rejects_wrong_chunk_length() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file.bin"), "AAAABBBBCCCC").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(
+        &context,
+        &BackupArgs {
+            fixed_block: true,
+            block_size: Some(4),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Truncate the middle chunk.
+    for entry in WalkDir::new(backup_dir.path().join("blob")) {
+        let entry = entry?;
+        if entry.file_type().is_file() && fs::read(entry.path()).await? == b"BBBB" {
+            fs::write(entry.path(), "BB").await?;
+        }
+    }
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    let result = restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert!(result.is_err());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_keep_going_stops_at_failed_chunk() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file.bin"), "AAAABBBBCCCC").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(
+        &context,
+        &BackupArgs {
+            fixed_block: true,
+            block_size: Some(4),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    // Truncate the middle chunk.
+    for entry in WalkDir::new(backup_dir.path().join("blob")) {
+        let entry = entry?;
+        if entry.file_type().is_file() && fs::read(entry.path()).await? == b"BBBB" {
+            fs::write(entry.path(), "BB").await?;
+        }
+    }
+
+    // Whether the chunk is read whole or streamed, the last chunk isn't
+    // written at the wrong offset, and the rest of the file stays empty.
+    for (stream_chunk_size, expected) in [(1024, "AAAA\0\0\0\0\0\0\0\0"), (1, "AAAABB\0\0\0\0\0\0")]
+    {
+        context.tuning.stream_chunk_size = stream_chunk_size;
+        let restore_dir = tempfile::tempdir()?;
+        context.backup_target = restore_dir.path().into();
+        let error = restore(
+            &context,
+            &RestoreArgs {
+                snapshot: Some("1".to_owned()),
+                keep_going: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.kind(), CommandErrorKind::Partial);
+        assert_eq!(
+            fs::read_to_string(restore_dir.path().join("file.bin")).await?,
+            expected
+        );
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_repo_init_storage_refuses_data() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file"), "Data").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Another repository already uses the storage.
+    let error = init_storage(&context, &InitArgs::default())
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), CommandErrorKind::User);
+
+    let empty_context = ProgramContext {
+        storage: Arc::new(MemoryStorage::new()),
+        ..context.clone()
+    };
+    init_storage(&empty_context, &InitArgs::default()).await?;
+    assert!(init_storage(&empty_context, &InitArgs::default())
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_repo_export_import() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("old"), "Old file").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("old")).await?;
+    fs::write(content_dir.path().join("new"), "New file").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let export_dir = tempfile::tempdir()?;
+    let export_path = export_dir.path().join("export");
+    export(
+        &context,
+        &ExportArgs {
+            file: export_path.clone(),
+            snapshot: vec!["2".to_owned()],
+        },
+    )
+    .await?;
+
+    let import_dir = tempfile::tempdir()?;
+    let restore_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(import_dir.path().into()).await.unwrap());
+    let imported_context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: restore_dir.path().into(),
+        state_dir: import_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    import(
+        &imported_context,
+        &ImportArgs {
+            file: export_path.clone(),
+        },
+    )
+    .await?;
+    // Importing again is a no-op.
+    import(&imported_context, &ImportArgs { file: export_path }).await?;
+
+    assert!(get_snapshot(&imported_context, "test/1").await.is_err());
+    restore(
+        &imported_context,
+        &RestoreArgs {
+            snapshot: Some("2".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("new")).await?,
+        "New file"
+    );
+    assert!(!restore_dir.path().join("old").exists());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_repo_clone_depth() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("old"), "Old file").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("old")).await?;
+    fs::write(content_dir.path().join("new"), "New file").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let clone_dir = tempfile::tempdir()?;
+    clone(
+        &context,
+        &CloneArgs {
+            destination: clone_dir.path().into(),
+            depth: Some(1),
+        },
+    )
+    .await?;
+
+    let storage = Arc::new(FileStorage::new(clone_dir.path().into()).await.unwrap());
+    let restore_dir = tempfile::tempdir()?;
+    let cloned_context = ProgramContext {
+        storage,
+        backup_target: restore_dir.path().into(),
+        ..context
+    };
+    assert!(get_snapshot(&cloned_context, "test/1").await.is_err());
+    restore(
+        &cloned_context,
+        &RestoreArgs {
+            snapshot: Some("2".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("new")).await?,
+        "New file"
+    );
+
+    let mut blobs = Vec::new();
+    let old_hash = format!("sha256-{:x}", Sha256::digest(b"Old file"));
+    assert!(cloned_context
+        .storage
+        .read(Collection::Blob, &old_hash, &mut blobs)
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_missing_snapshot_is_not_found() -> Result<(), Box<dyn Error>> {
+    let backup_dir = tempfile::tempdir()?;
+    let restore_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: restore_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    let error = restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap_err();
+    assert!(error.is_not_found());
+    assert_eq!(error.kind(), CommandErrorKind::NotFound);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_keep_going_collects_failures() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a"), "A").await?;
+    fs::write(content_dir.path().join("b"), "B").await?;
+    fs::write(content_dir.path().join("c"), "C").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    fs::write(restore_dir.path().join("a"), "Changed").await?;
+    fs::write(restore_dir.path().join("c"), "Changed").await?;
+    context.backup_target = restore_dir.path().into();
+
+    let error = restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            keep_going: true,
+            overwrite: Some(Overwrite::Never),
+            into_nonempty: true,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap_err();
+
+    assert_eq!(error.kind(), CommandErrorKind::Partial);
+    let failures = error.failures().unwrap();
+    let mut paths: Vec<_> = failures
+        .errors()
+        .iter()
+        .map(|failure| failure.path.clone())
+        .collect();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [restore_dir.path().join("a"), restore_dir.path().join("c")]
+    );
+    let groups = failures.grouped();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].0, CommandErrorKind::FileSystemConflict);
+    assert_eq!(fs::read_to_string(restore_dir.path().join("b")).await?, "B");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_doctor() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("README"), "Read me").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::create_dir_all(&context.state_dir).await?;
+    fs::write(context.state_dir.join("verified_blobs.tmp"), "").await?;
+
+    let findings = diagnose(&context).await;
+    assert!(findings.iter().all(|f| f.severity != Severity::Problem));
+    assert!(findings
+        .iter()
+        .any(|f| f.severity == Severity::Warning && f.message.contains("verified_blobs.tmp")));
+
+    context.backup_target = content_dir.path().join("missing");
+    let findings = diagnose(&context).await;
+    assert!(findings
+        .iter()
+        .any(|f| f.severity == Severity::Problem && f.message.contains("does not exist")));
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_empty_source_and_nonempty_target_checks() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().join("missing"),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    assert!(backup(&context, &BackupArgs::default())
+        .await
+        .unwrap_err()
+        .is_user());
+    context.backup_target = content_dir.path().into();
+    assert!(backup(&context, &BackupArgs::default())
+        .await
+        .unwrap_err()
+        .is_user());
+    backup(
+        &context,
+        &BackupArgs {
+            allow_empty_source: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    fs::write(content_dir.path().join("README"), "Read me").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    fs::write(restore_dir.path().join("existing"), "Existing").await?;
+    context.backup_target = restore_dir.path().into();
+    let args = RestoreArgs {
+        snapshot: Some("2".to_owned()),
+        ..Default::default()
+    };
+    assert!(restore(&context, &args).await.unwrap_err().is_user());
+    assert!(!restore_dir.path().join("README").exists());
+
+    restore(
+        &context,
+        &RestoreArgs {
+            into_nonempty: true,
+            ..args
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("README")).await?,
+        "Read me"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_prune_explain() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir")).await?;
+    fs::write(content_dir.path().join("dir/shared"), "Shared").await?;
+    fs::write(content_dir.path().join("old"), "Old file").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("old")).await?;
+    fs::write(content_dir.path().join("new"), "New").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let explanation = explain_forget(&context, "test/1").await?;
+    // The root entry and the old file are unique, the shared file is not.
+    assert_eq!(explanation.blobs, 3);
+    assert_eq!(explanation.unique_blobs.len(), 2);
+    assert_eq!(explanation.unique_bytes, 8);
+    assert_eq!(
+        explanation.files.into_iter().collect::<Vec<_>>(),
+        [("old".to_owned(), 8)]
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_forget_keep_last() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file"), "Content").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    for _ in 0..3 {
+        backup(&context, &BackupArgs::default()).await?;
+    }
+
+    // Without a policy nothing may be forgotten.
+    let error = forget(&context, &ForgetArgs::default(), None)
+        .await
+        .unwrap_err();
+    assert_eq!(error.kind(), CommandErrorKind::User);
+
+    let args = ForgetArgs {
+        keep_last: Some(2),
+        dry_run: true,
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    let snapshots = list_snapshots(&context, &SnapshotsArgs::default()).await?;
+    assert_eq!(snapshots.len(), 3);
+
+    let args = ForgetArgs {
+        dry_run: false,
+        ..args
+    };
+    forget(&context, &args, None).await?;
+    let names: Vec<String> = list_snapshots(&context, &SnapshotsArgs::default())
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, ["test/2", "test/3"]);
+    let stats: Vec<String> = get_stats_history(&context, None)
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(stats, ["test/2", "test/3"]);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_forget_expired() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file"), "Content").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    let expiring = BackupArgs {
+        expire_after: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+    backup(&context, &expiring).await?;
+    backup(&context, &expiring).await?;
+    backup(&context, &BackupArgs::default()).await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Backdate the expiry of the first one.
+    let mut snapshot = get_snapshot(&context, "test/1").await?;
+    assert!(snapshot.expires > snapshot.started);
+    snapshot.expires = 1_700_000_000;
+    context
+        .storage
+        .replace(Collection::Snapshot, "test/1", &snapshot.encode_to_vec())
+        .await?;
+
+    let names = || async {
+        list_snapshots(&context, &SnapshotsArgs::default())
+            .await
+            .map(|snapshots| {
+                snapshots
+                    .into_iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>()
+            })
+    };
+    let args = ForgetArgs {
+        expired: true,
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    assert_eq!(names().await?, ["test/2", "test/3", "test/4"]);
+
+    // The policy applies to the snapshots that haven't expired.
+    let mut snapshot = get_snapshot(&context, "test/4").await?;
+    snapshot.expires = 1_700_000_000;
+    context
+        .storage
+        .replace(Collection::Snapshot, "test/4", &snapshot.encode_to_vec())
+        .await?;
+    let args = ForgetArgs {
+        expired: true,
+        keep_last: Some(1),
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    assert_eq!(names().await?, ["test/3"]);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_prune_deletes_unused_blobs() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("shared"), "Shared").await?;
+    fs::write(content_dir.path().join("old"), "Old file").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("old")).await?;
+    backup(&context, &BackupArgs::default()).await?;
+    assert!(find_unused_blobs(&context).await?.is_empty());
+
+    let unique = explain_forget(&context, "test/1").await?.unique_blobs;
+    let args = ForgetArgs {
+        keep_last: Some(1),
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    assert_eq!(find_unused_blobs(&context).await?, unique);
+
+    let blobs = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await?;
+    let args = PruneArgs {
+        dry_run: true,
+        ..Default::default()
+    };
+    prune(&context, &args).await?;
+    assert_eq!(
+        context
+            .storage
+            .get_collection_items(Collection::Blob)
+            .await?
+            .len(),
+        blobs.len()
+    );
+
+    prune(&context, &PruneArgs::default()).await?;
+    let remaining = context
+        .storage
+        .get_collection_items(Collection::Blob)
+        .await?;
+    assert_eq!(remaining.len(), blobs.len() - unique.len());
+    assert!(find_unused_blobs(&context).await?.is_empty());
+
+    // The kept snapshot still restores.
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("2".to_owned()),
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+    assert_eq!(target.files.lock().unwrap()[Path::new("shared")], b"Shared");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_forget_and_prune_hooks() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("shared"), "Shared").await?;
+    fs::write(content_dir.path().join("old"), "Old file").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let events = state_dir.path().join("events");
+    // Each payload is one line of JSON.
+    let hook = format!("cat >> {}; echo >> {}", events.display(), events.display());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: HooksConfig {
+            snapshot_forgotten: Some(hook.clone()),
+            prune_completed: Some(hook),
+            ..Default::default()
+        },
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("old")).await?;
+    backup(&context, &BackupArgs::default()).await?;
+    let started = get_snapshot(&context, "test/1").await?.started;
+
+    let args = ForgetArgs {
+        keep_last: Some(1),
+        dry_run: true,
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    assert!(!events.exists());
+
+    let args = ForgetArgs {
+        keep_last: Some(1),
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    prune(&context, &PruneArgs::default()).await?;
+
+    let events = fs::read_to_string(&events).await?;
+    let events: Vec<serde_json::Value> = events
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["event"], "snapshot-forgotten");
+    assert_eq!(events[0]["snapshot"], "test/1");
+    assert_eq!(events[0]["started"], started);
+    assert_eq!(events[1]["event"], "prune-completed");
+    // The old file and the root directory.
+    assert_eq!(events[1]["deleted_blobs"], 2);
+
+    Ok(())
+}
+#[test(tokio::test)]
+async fn test_sub_second_modified_time() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let path = content_dir.path().join("build.out");
+    let second = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+    fs::write(&path, "First").await?;
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(second + Duration::from_millis(100))?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Rewritten within the same second, with the same size.
+    fs::write(&path, "Again").await?;
+    let modified = second + Duration::from_millis(600);
+    std::fs::File::options()
+        .write(true)
+        .open(&path)?
+        .set_modified(modified)?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let snapshot = get_snapshot(&context, "test/2").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    assert_eq!(root.file[0].modified, 1_700_000_000);
+    assert_eq!(root.file[0].modified_nanos, 600_000_000);
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("2".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    let restored = restore_dir.path().join("build.out");
+    assert_eq!(fs::read_to_string(&restored).await?, "Again");
+    assert_eq!(fs::metadata(&restored).await?.modified()?, modified);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_before() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("README"), "First").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("README"), "Second").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Copy the snapshots to another archive with known start times.
+    for (number, started) in [(1, "2024-05-01 10:00"), (2, "2024-05-02")] {
+        let mut snapshot = get_snapshot(&context, &format!("test/{}", number)).await?;
+        snapshot.started = parse_time(started)?;
+        context
+            .storage
+            .write(
+                Collection::Snapshot,
+                &format!("dated/{}", number),
+                &snapshot.encode_to_vec(),
+            )
+            .await?;
+    }
+    context.archive_name = "dated".to_owned();
+
+    for (before, expected) in [("2024-05-01 12:00", "First"), ("2024-05-03", "Second")] {
+        let restore_dir = tempfile::tempdir()?;
+        context.backup_target = restore_dir.path().into();
+        restore(
+            &context,
+            &RestoreArgs {
+                before: Some(before.to_owned()),
+                ..Default::default()
+            },
+        )
+        .await?;
+        assert_eq!(
+            fs::read_to_string(restore_dir.path().join("README")).await?,
+            expected
+        );
+    }
+
+    let args = RestoreArgs {
+        before: Some("2024-05-01".to_owned()),
+        ..Default::default()
+    };
+    assert!(restore(&context, &args).await.unwrap_err().is_not_found());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_skips_repositories() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("docs")).await?;
+    fs::write(content_dir.path().join("docs/notes.txt"), "Notes").await?;
+    fs::create_dir(content_dir.path().join(".freebck")).await?;
+    fs::write(content_dir.path().join(".freebck/client_id"), "client").await?;
+    init_repository(&content_dir.path().join("old_repo"), "old").await?;
+
+    // The repository and the config directory are in the backup target.
+    let storage = Arc::new(FileStorage::new(content_dir.path().join("repo")).await?);
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: content_dir.path().join(".freebck"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    for (include_repos, expected) in [(false, vec!["docs"]), (true, vec!["docs", "old_repo"])] {
+        backup(
+            &context,
+            &BackupArgs {
+                include_repos,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let number = if include_repos { "test/2" } else { "test/1" };
+        let snapshot = get_snapshot(&context, number).await?;
+        let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+        let mut names: Vec<_> = root.sub_dir.iter().map(|d| d.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, expected);
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_identical_files() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let content = "Template ".repeat(10_000);
+    for dir in ["a", "b", "c"] {
+        fs::create_dir(content_dir.path().join(dir)).await?;
+        fs::write(content_dir.path().join(dir).join("template.txt"), &content).await?;
+    }
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Files are cloned where the file system supports it and written
+    // otherwise, and either way must end up with the same content.
+    for no_reflink in [false, true] {
+        let restore_dir = tempfile::tempdir()?;
+        context.backup_target = restore_dir.path().into();
+        restore(
+            &context,
+            &RestoreArgs {
+                snapshot: Some("1".to_owned()),
+                no_reflink,
+                ..Default::default()
+            },
+        )
+        .await?;
+        for dir in ["a", "b", "c"] {
+            let restored = restore_dir.path().join(dir).join("template.txt");
+            assert_eq!(fs::read_to_string(&restored).await?, content);
+        }
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_skips_special_files() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("notes.txt"), "Notes").await?;
+    fs::symlink("notes.txt", content_dir.path().join("link")).await?;
+    std::os::unix::net::UnixListener::bind(content_dir.path().join("socket"))?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    for exclude_type in [vec![], vec![SpecialType::Symlink, SpecialType::Socket]] {
+        backup(
+            &context,
+            &BackupArgs {
+                exclude_type,
+                ..Default::default()
+            },
+        )
+        .await?;
+    }
+    for number in ["test/1", "test/2"] {
+        let snapshot = get_snapshot(&context, number).await?;
+        let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+        let names: Vec<_> = root.file.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["notes.txt"]);
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_and_restore_in_memory() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("dir_a/hello.txt")).await?,
+        "Hello"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_records_stats() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("hello.txt"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    // Nothing changed, so nothing new is uploaded.
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("world.txt"), "World!").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let history = get_stats_history(&context, None).await?;
+    let names: Vec<_> = history.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["test/1", "test/2", "test/3"]);
+    let stats: Vec<_> = history.iter().map(|(_, stats)| stats).collect();
+    assert_eq!(stats[0].size, 5);
+    assert!(stats[0].new_bytes > 5);
+    assert_eq!(stats[1].new_bytes, 0);
+    assert_eq!(stats[2].size, 11);
+    assert!(stats[2].new_bytes > 6);
+    assert_eq!(
+        stats[2].stored_bytes,
+        stats[0].new_bytes + stats[2].new_bytes
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_all_archives() -> Result<(), Box<dyn Error>> {
+    let documents_dir = tempfile::tempdir()?;
+    fs::write(documents_dir.path().join("notes.txt"), "Notes").await?;
+    let photos_dir = tempfile::tempdir()?;
+    fs::write(photos_dir.path().join("cat.jpg"), "Meow").await?;
+
+    let storage = Arc::new(MemoryStorage::new());
+    let state_dir = tempfile::tempdir()?;
+    let contexts: Vec<_> = [
+        ("documents", &documents_dir),
+        ("photos", &photos_dir),
+        ("missing", &photos_dir),
+    ]
+    .into_iter()
+    .map(|(name, dir)| ProgramContext {
+        archive_name: name.to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: storage.clone(),
+        backup_target: if name == "missing" {
+            dir.path().join("missing")
+        } else {
+            dir.path().into()
+        },
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    })
+    .collect();
+
+    // The missing backup target fails, but the others are still backed up.
+    let args = BackupArgs {
+        all: true,
+        ..Default::default()
+    };
+    let result = backup_all(&contexts, &args, 2).await;
+    assert_eq!(result.unwrap_err().kind(), CommandErrorKind::Partial);
+    let mut snapshots = storage.get_collection_items(Collection::Snapshot).await?;
+    snapshots.sort();
+    assert_eq!(snapshots, ["documents/1", "photos/1"]);
+
+    backup_all(&contexts[..1], &args, 2).await?;
+    assert!(storage
+        .get_collection_items(Collection::Snapshot)
+        .await?
+        .contains(&"documents/2".to_owned()));
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_find_hash() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("leaked.txt"), "Secret").await?;
+    fs::write(content_dir.path().join("other.txt"), "Other").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("dir_a/copy.txt"), "Secret").await?;
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("leaked.txt")).await?;
+    fs::remove_file(content_dir.path().join("dir_a/copy.txt")).await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let hash = format!("{:X}", Sha256::digest(b"Secret"));
+    let matches = find_hash(&context, &hash, None).await?;
+    let found: Vec<_> = matches
+        .iter()
+        .map(|found| (found.snapshot_name.as_str(), found.paths.clone()))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            ("test/1", vec!["leaked.txt".to_owned()]),
+            (
+                "test/2",
+                vec!["dir_a/copy.txt".to_owned(), "leaked.txt".to_owned()]
+            ),
+        ]
+    );
+
+    assert!(find_hash(&context, &hash, Some("other")).await?.is_empty());
+    let result = find_hash(&context, "not a hash", None).await;
+    assert_eq!(result.unwrap_err().kind(), CommandErrorKind::User);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_report() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello").await?;
+    fs::write(content_dir.path().join("lost.txt"), "Lost").await?;
+
+    let backup_dir = tempfile::tempdir()?;
+    let storage = Arc::new(FileStorage::new(backup_dir.path().into()).await.unwrap());
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage,
+        backup_target: content_dir.path().into(),
+        state_dir: backup_dir.path().join("state"),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    for entry in WalkDir::new(backup_dir.path().join("blob")) {
+        let entry = entry?;
+        if entry.file_type().is_file() && fs::read(entry.path()).await? == b"Lost" {
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    let restore_dir = tempfile::tempdir()?;
+    let report_path = restore_dir.path().join("report.json");
+    context.backup_target = restore_dir.path().join("restored");
+    let args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        keep_going: true,
+        into_nonempty: true,
+        report: Some(report_path.clone()),
+        ..Default::default()
+    };
+    let result = restore(&context, &args).await;
+    assert_eq!(result.unwrap_err().kind(), CommandErrorKind::Partial);
+
+    let statuses = |report: serde_json::Value| -> Vec<(String, String)> {
+        assert_eq!(report["snapshot"].as_str(), Some("test/1"));
+        report["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| {
+                (
+                    file["path"].as_str().unwrap().to_owned(),
+                    file["status"].as_str().unwrap().to_owned(),
+                )
+            })
+            .collect()
+    };
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path).await?)?;
+    assert_eq!(
+        report["files"][0]["hash"].as_str(),
+        Some(format!("{:x}", Sha256::digest(b"Hello")).as_str())
+    );
+    assert!(report["files"][1]["reason"]
+        .as_str()
+        .unwrap()
+        .contains("Failed to read chunk"));
+    assert_eq!(
+        statuses(report),
+        [
+            ("dir_a/hello.txt".to_owned(), "restored".to_owned()),
+            ("lost.txt".to_owned(), "failed".to_owned()),
+        ]
+    );
+
+    // Files already restored are skipped by a rerun.
+    fs::remove_file(restore_dir.path().join("restored/lost.txt")).await?;
+    assert!(restore(&context, &args).await.is_err());
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(&report_path).await?)?;
+    assert_eq!(
+        statuses(report),
+        [
+            ("dir_a/hello.txt".to_owned(), "skipped".to_owned()),
+            ("lost.txt".to_owned(), "failed".to_owned()),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_blob_keys_keep_repository_form() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("hello.txt"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    assert!(get_snapshot(&context, "test/1")
+        .await?
+        .root_hash
+        .starts_with("sha256-"));
+
+    // A repository from before keys had a prefix keeps using bare keys.
+    context.storage = Arc::new(MemoryStorage::new());
+    let root = DirEntry::default().encode_to_vec();
+    let root_hash = format!("{:x}", Sha256::digest(&root));
+    context
+        .storage
+        .write(Collection::Blob, &root_hash, &root)
+        .await?;
+    let snapshot = Snapshot {
+        root_hash,
+        ..Default::default()
+    };
+    context
+        .storage
+        .write(Collection::Snapshot, "test/1", &snapshot.encode_to_vec())
+        .await?;
+    backup(&context, &BackupArgs::default()).await?;
+    let root_hash = get_snapshot(&context, "test/2").await?.root_hash;
+    assert!(!root_hash.contains('-'));
+    let root = get_dir_entry(&context, &root_hash).await?;
+    assert!(!root.file[0].chunk_hash[0].contains('-'));
+    check(
+        &context,
+        &CheckArgs {
+            auto: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_estimate() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a.txt"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    let estimate_args = BackupArgs {
+        estimate: true,
+        ..Default::default()
+    };
+    let estimate = estimate_backup(&context, &estimate_args).await?;
+    assert_eq!(estimate.blobs, 2);
+    assert_eq!(estimate.new_blobs, 2);
+    assert_eq!(estimate.new_bytes, estimate.bytes);
+    backup(&context, &estimate_args).await?;
+    assert!(context
+        .storage
+        .get_collection_items(Collection::Snapshot)
+        .await?
+        .is_empty());
+
+    backup(&context, &BackupArgs::default()).await?;
+    let estimate = estimate_backup(&context, &estimate_args).await?;
+    assert_eq!(estimate.new_blobs, 0);
+    assert_eq!(estimate.new_bytes, 0);
+
+    // A copy of existing contents only needs a new directory entry.
+    fs::write(content_dir.path().join("b.txt"), "Hello").await?;
+    let estimate = estimate_backup(&context, &estimate_args).await?;
+    assert_eq!(estimate.blobs, 2);
+    assert_eq!(estimate.new_blobs, 1);
+    assert_eq!(estimate.new_bytes, estimate.bytes - 5);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_and_restore_with_packs() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello").await?;
+    fs::write(content_dir.path().join("world.txt"), "World").await?;
+
+    let inner = Arc::new(MemoryStorage::new());
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(PackStorage::new(Box::new(inner.clone()), 1 << 20)),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    assert!(inner
+        .get_collection_items(Collection::Blob)
+        .await?
+        .is_empty());
+    assert_eq!(inner.get_collection_items(Collection::Pack).await?.len(), 1);
+
+    let restore_dir = tempfile::tempdir()?;
+    context.storage = Arc::new(PackStorage::new(Box::new(inner), 1 << 20));
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            into_nonempty: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("dir_a/hello.txt")).await?,
+        "Hello"
+    );
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("world.txt")).await?,
+        "World"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_prune_repacks() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("shared"), "Shared").await?;
+    fs::write(content_dir.path().join("old"), "Old file").await?;
+
+    let inner = Arc::new(MemoryStorage::new());
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(PackStorage::new(Box::new(inner.clone()), 1 << 20)),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::remove_file(content_dir.path().join("old")).await?;
+    backup(&context, &BackupArgs::default()).await?;
+    let args = ForgetArgs {
+        keep_last: Some(1),
+        ..Default::default()
+    };
+    forget(&context, &args, None).await?;
+    let unused = find_unused_blobs(&context).await?;
+    assert_eq!(unused.len(), 2);
+    let packs = inner.get_collection_items(Collection::Pack).await?;
+    assert_eq!(packs.len(), 2);
+
+    prune(&context, &PruneArgs::default()).await?;
+    assert!(find_unused_blobs(&context).await?.is_empty());
+    // The pack of the first backup is written again without the unused
+    // blobs, the one of the second backup stays.
+    let repacked = inner.get_collection_items(Collection::Pack).await?;
+    assert_eq!(repacked.len(), 2);
+    assert_eq!(
+        repacked.iter().filter(|pack| packs.contains(pack)).count(),
+        1
+    );
+
+    // A new instance reads the indexes from the storage again.
+    context.storage = Arc::new(PackStorage::new(Box::new(inner), 1 << 20));
+    assert!(find_unused_blobs(&context).await?.is_empty());
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("2".to_owned()),
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+    assert_eq!(target.files.lock().unwrap()[Path::new("shared")], b"Shared");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_compressed_backup_and_restore() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let text = "Hello World! ".repeat(10000);
+    fs::write(content_dir.path().join("text"), &text).await?;
+    fs::write(content_dir.path().join("small"), "Small file").await?;
+    // Stored as it is, as its extension is on the skip list.
+    let photo = "Not really a photo. ".repeat(100);
+    fs::write(content_dir.path().join("photo.JPG"), &photo).await?;
+
+    let inner = Arc::new(MemoryStorage::new());
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(CompressedStorage::new(
+            Box::new(PackStorage::new(Box::new(inner.clone()), 1 << 20)),
+            3,
+        )),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let mut stored = 0;
+    let mut buffer = Vec::new();
+    for pack in inner.get_collection_items(Collection::Pack).await? {
+        inner.read(Collection::Pack, &pack, &mut buffer).await?;
+        stored += buffer.len();
+    }
+    assert!(
+        stored < text.len() / 10 + photo.len(),
+        "{} bytes stored",
+        stored
+    );
+    let packs = PackStorage::new(Box::new(inner.clone()), 1 << 20);
+    let photo_key = format!("sha256-{:x}", Sha256::digest(&photo));
+    packs
+        .read(Collection::Blob, &photo_key, &mut buffer)
+        .await?;
+    assert_eq!(buffer[0], 0);
+    assert_eq!(&buffer[1..], photo.as_bytes());
+
+    let args = CheckArgs {
+        read_data: true,
+        ..Default::default()
+    };
+    check(&context, &args).await?;
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+    let files = target.files.lock().unwrap();
+    assert_eq!(files[Path::new("text")], text.as_bytes());
+    assert_eq!(files[Path::new("small")], b"Small file");
+    assert_eq!(files[Path::new("photo.JPG")], photo.as_bytes());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_encrypted_backup_and_restore() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("secret"), "Very secret content").await?;
+
+    let inner = Arc::new(MemoryStorage::new());
+    let kdf = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+    };
+    let encrypted =
+        EncryptedStorage::open(Box::new(inner.clone()), &[], "passphrase", kdf, "client").await?;
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(CompressedStorage::new(Box::new(encrypted), 3)),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    init_storage(&context, &InitArgs::default()).await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let mut buffer = Vec::new();
+    for collection in [Collection::Snapshot, Collection::Blob, Collection::Config] {
+        for key in inner.get_collection_items(collection).await? {
+            inner.read(collection, &key, &mut buffer).await?;
+            assert!(!buffer.windows(6).any(|window| window == b"secret"));
+        }
+    }
+
+    // A new instance opens the repository with the stored key.
+    let keys = read_keys(&*inner).await?;
+    assert_eq!(keys.len(), 1);
+    let encrypted =
+        EncryptedStorage::open(Box::new(inner.clone()), &keys, "passphrase", kdf, "client").await?;
+    context.storage = Arc::new(CompressedStorage::new(Box::new(encrypted), 3));
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+    assert_eq!(
+        target.files.lock().unwrap()[Path::new("secret")],
+        b"Very secret content"
+    );
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_prune_waits_for_backups() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file"), "Content").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    // A running backup holds a shared lock, which other backups can share
+    // but prune can't.
+    let lock = lock_repository(&context, false).await?;
+    backup(&context, &BackupArgs::default()).await?;
+    let error = prune(&context, &PruneArgs::default()).await.err().unwrap();
+    assert_eq!(error.kind(), CommandErrorKind::Locked);
+    let args = PruneArgs {
+        dry_run: true,
+        ..Default::default()
+    };
+    prune(&context, &args).await?;
+
+    lock.release().await;
+    prune(&context, &PruneArgs::default()).await?;
+    assert!(context
+        .storage
+        .get_collection_items(Collection::Lock)
+        .await?
+        .is_empty());
+
+    Ok(())
+}
+
+/// Memory storage that counts the writes of blobs.
+#[derive(Default)]
+struct CountingStorage {
+    inner: MemoryStorage,
+    blob_writes: AtomicU64,
+    max_object_size: Option<u64>,
+}
+
+#[async_trait]
+impl Storage for CountingStorage {
+    async fn write(&self, collection: Collection, key: &str, data: &[u8]) -> StorageWrite {
+        if collection == Collection::Blob {
+            self.blob_writes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.inner.write(collection, key, data).await
+    }
+
+    async fn read(&self, collection: Collection, key: &str, buffer: &mut Vec<u8>) -> StorageRead {
+        self.inner.read(collection, key, buffer).await
+    }
+
+    async fn get_collection_items(&self, collection: Collection) -> StorageItems {
+        self.inner.get_collection_items(collection).await
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.max_object_size
+    }
+}
+
+#[test(tokio::test)]
+async fn test_backup_skips_known_blobs() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a.txt"), "Hello").await?;
+    fs::write(content_dir.path().join("b.txt"), "Hello").await?;
+
+    let storage = Arc::new(CountingStorage::default());
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "a".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: storage.clone(),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    // The contents of both files and the root directory entry.
+    assert_eq!(storage.blob_writes.load(Ordering::Relaxed), 2);
+
+    // Another archive with the same contents has nothing to upload.
+    context.archive_name = "b".to_owned();
+    backup(&context, &BackupArgs::default()).await?;
+    assert_eq!(storage.blob_writes.load(Ordering::Relaxed), 2);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_snapshot_meta() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a.txt"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    let meta = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    for commit in ["aaa", "bbb"] {
+        let args = BackupArgs {
+            meta: meta(&[("commit", commit), ("app", "1.0")]),
+            ..Default::default()
+        };
+        backup(&context, &args).await?;
+    }
+
+    let snapshot = get_snapshot(&context, "test/2").await?;
+    assert_eq!(snapshot.meta["commit"], "bbb");
+    let names = |snapshots: Vec<(String, Snapshot)>| -> Vec<String> {
+        snapshots.into_iter().map(|(name, _)| name).collect()
+    };
+    let args = SnapshotsArgs {
+        meta: meta(&[("commit", "aaa")]),
+        ..Default::default()
+    };
+    assert_eq!(names(list_snapshots(&context, &args).await?), ["test/1"]);
+    let args = SnapshotsArgs {
+        meta: meta(&[("app", "1.0")]),
+        ..Default::default()
+    };
+    assert_eq!(
+        names(list_snapshots(&context, &args).await?),
+        ["test/1", "test/2"]
+    );
+    let args = SnapshotsArgs {
+        meta: meta(&[("app", "1.0"), ("commit", "ccc")]),
+        ..Default::default()
+    };
+    assert!(list_snapshots(&context, &args).await?.is_empty());
+
+    Ok(())
+}
+
+/// Restore target that keeps the restored tree in memory, by path.
+#[derive(Default)]
+struct MemoryRestoreTarget {
+    dirs: Mutex<Vec<PathBuf>>,
+    /// Files in the order they were started.
+    created: Mutex<Vec<PathBuf>>,
+    files: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+struct MemoryRestoreFile {
+    path: PathBuf,
+    data: Vec<u8>,
+    files: Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>,
+}
+
+#[async_trait]
+impl RestoreTarget for MemoryRestoreTarget {
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.dirs.lock().unwrap().push(path.to_path_buf());
+        Ok(())
+    }
+
+    async fn create_file(
+        &self,
+        path: &Path,
+        _file: &FileEntry,
+        _mode: CreateMode,
+    ) -> io::Result<Box<dyn RestoreFile>> {
+        self.created.lock().unwrap().push(path.to_path_buf());
+        Ok(Box::new(MemoryRestoreFile {
+            path: path.to_path_buf(),
+            data: Vec::new(),
+            files: self.files.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl RestoreFile for MemoryRestoreFile {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>, _intact: bool) -> io::Result<()> {
+        self.files.lock().unwrap().insert(self.path, self.data);
+        Ok(())
+    }
+}
+
+#[test(tokio::test)]
+async fn test_restore_to_custom_target() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir(content_dir.path().join("dir_a")).await?;
+    fs::write(content_dir.path().join("dir_a/hello.txt"), "Hello").await?;
+    fs::write(content_dir.path().join("world.txt"), "World").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+    assert!(target
+        .dirs
+        .lock()
+        .unwrap()
+        .contains(&PathBuf::from("dir_a")));
+    {
+        let files = target.files.lock().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[Path::new("dir_a/hello.txt")], b"Hello");
+        assert_eq!(files[Path::new("world.txt")], b"World");
+    }
+
+    // Sessions record progress against files on disk.
+    let error = restore_to(
+        &context,
+        &RestoreArgs {
+            session: Some("session".to_owned()),
+            ..args
+        },
+        &target,
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(error.kind(), CommandErrorKind::User);
+
+    Ok(())
+}
+
+/// Backup source of files held in memory, by path.
+struct MemoryBackupSource {
+    files: HashMap<PathBuf, Vec<u8>>,
+    modified: SystemTime,
+    /// Directories listed so far.
+    listed: Mutex<Vec<PathBuf>>,
+}
+
+#[async_trait]
+impl BackupSource for MemoryBackupSource {
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<SourceEntry>> {
+        self.listed.lock().unwrap().push(path.to_path_buf());
+        let mut entries: Vec<SourceEntry> = Vec::new();
+        for file_path in self.files.keys() {
+            let Ok(relative_path) = file_path.strip_prefix(path) else {
+                continue;
+            };
+            let mut components = relative_path.components();
+            let name = components.next().unwrap().as_os_str().to_owned();
+            let entry_type = match components.next() {
+                Some(_) => EntryType::Dir,
+                None => EntryType::File,
+            };
+            if !entries.iter().any(|entry| entry.name == name) {
+                entries.push(SourceEntry { name, entry_type });
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn metadata(&self, path: &Path) -> io::Result<SourceMetadata> {
+        let (entry_type, size) = match self.files.get(path) {
+            Some(data) => (EntryType::File, data.len() as u64),
+            None => (EntryType::Dir, 0),
+        };
+        Ok(SourceMetadata {
+            entry_type,
+            size,
+            modified: self.modified,
+        })
+    }
+
+    async fn open(&self, path: &Path) -> io::Result<SourceReader> {
+        let data = self.files.get(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(Box::new(io::Cursor::new(data.clone())))
+    }
+}
+
+#[test(tokio::test)]
+async fn test_concurrent_restores_share_context() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file.txt"), "First").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("file.txt"), "Second").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    // Each session gets a clone of the context, all sharing one storage.
+    let sessions: Vec<_> = ["1", "2", "1", "2"]
+        .into_iter()
+        .map(|snapshot| {
+            let context = context.clone();
+            tokio::spawn(async move {
+                let target = MemoryRestoreTarget::default();
+                let args = RestoreArgs {
+                    snapshot: Some(snapshot.to_owned()),
+                    ..Default::default()
+                };
+                restore_to(&context, &args, &target).await?;
+                let files = target.files.lock().unwrap();
+                Ok::<_, CommandError>(files[Path::new("file.txt")].clone())
+            })
+        })
+        .collect();
+    let mut restored = Vec::new();
+    for session in sessions {
+        restored.push(session.await??);
+    }
+    assert_eq!(restored, [&b"First"[..], b"Second", b"First", b"Second"]);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_from_custom_source() -> Result<(), Box<dyn Error>> {
+    let source = MemoryBackupSource {
+        files: HashMap::from([
+            (PathBuf::from("dir_a/hello.txt"), b"Hello".to_vec()),
+            (PathBuf::from("world.txt"), b"World".to_vec()),
+        ]),
+        modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        listed: Default::default(),
+    };
+
+    let state_dir = tempfile::tempdir()?;
+    let restore_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: restore_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup_from(&context, &BackupArgs::default(), &source).await?;
+
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("dir_a/hello.txt")).await?,
+        "Hello"
+    );
+    let world = restore_dir.path().join("world.txt");
+    assert_eq!(fs::read_to_string(&world).await?, "World");
+    assert_eq!(fs::metadata(&world).await?.modified()?, source.modified);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_reuses_unchanged_dirs() -> Result<(), Box<dyn Error>> {
+    let mut source = MemoryBackupSource {
+        files: HashMap::from([
+            (PathBuf::from("a/1.txt"), b"One".to_vec()),
+            (PathBuf::from("b/c/2.txt"), b"Two".to_vec()),
+            (PathBuf::from("README"), b"Read me".to_vec()),
+        ]),
+        modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        listed: Default::default(),
+    };
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: state_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    let listings = |source: &MemoryBackupSource, path: &str| {
+        let listed = source.listed.lock().unwrap();
+        listed
+            .iter()
+            .filter(|listed| *listed == Path::new(path))
+            .count()
+    };
+
+    backup_from(&context, &BackupArgs::default(), &source).await?;
+    source.listed.lock().unwrap().clear();
+
+    // Each directory is listed once, and only the metadata is read when
+    // nothing changed.
+    backup_from(&context, &BackupArgs::default(), &source).await?;
+    for path in ["", "a", "b", "b/c"] {
+        assert_eq!(listings(&source, path), 1, "{}", path);
+    }
+    assert_eq!(
+        get_snapshot(&context, "test/1").await?.root_hash,
+        get_snapshot(&context, "test/2").await?.root_hash
+    );
+
+    // Directories with changes below them are backed up again, the rest is
+    // reused, still listing each directory once.
+    source
+        .files
+        .insert(PathBuf::from("b/c/2.txt"), b"Two, edited".to_vec());
+    source.listed.lock().unwrap().clear();
+    backup_from(&context, &BackupArgs::default(), &source).await?;
+    for path in ["", "a", "b", "b/c"] {
+        assert_eq!(listings(&source, path), 1, "{}", path);
+    }
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/3").await?.root_hash).await?;
+    let previous_root =
+        get_dir_entry(&context, &get_snapshot(&context, "test/2").await?.root_hash).await?;
+    assert_eq!(root.sub_dir[0], previous_root.sub_dir[0]);
+    assert_ne!(root.sub_dir[1], previous_root.sub_dir[1]);
+    assert_ne!(root.metadata_hash, previous_root.metadata_hash);
+
+    // Files unchanged since the first snapshot keep their chunks.
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("3".to_owned()),
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+    let files = target.files.lock().unwrap();
+    assert_eq!(files[Path::new("a/1.txt")], b"One");
+    assert_eq!(files[Path::new("b/c/2.txt")], b"Two, edited");
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_summarize_snapshots() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a.txt"), "Hello").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "b".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    context.archive_name = "a".to_owned();
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("b.txt"), "World!").await?;
+    backup(&context, &BackupArgs::default()).await?;
+
+    let summaries = summarize_snapshots(&context, &SnapshotsArgs::default()).await?;
+    let names: Vec<String> = summaries.iter().map(|summary| summary.name()).collect();
+    assert_eq!(names, ["a/1", "a/2", "b/1"]);
+    let sizes: Vec<Option<u64>> = summaries.iter().map(|summary| summary.size).collect();
+    assert_eq!(sizes, [Some(5), Some(11), Some(5)]);
+    assert!(summaries[1].snapshot.finished >= summaries[1].snapshot.started);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_snapshot_sequence_orders_archive() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file"), "Old").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    fs::write(content_dir.path().join("file"), "New").await?;
+    backup(&context, &BackupArgs::default()).await?;
+    assert_eq!(get_snapshot(&context, "test/1").await?.sequence, 1);
+    assert_eq!(get_snapshot(&context, "test/2").await?.sequence, 2);
+
+    // Renaming the older snapshot to a higher key doesn't make it the newest.
+    let mut buffer = Vec::new();
+    context
+        .storage
+        .read(Collection::Snapshot, "test/1", &mut buffer)
+        .await?;
+    context
+        .storage
+        .write(Collection::Snapshot, "test/5", &buffer)
+        .await?;
+    context
+        .storage
+        .delete(Collection::Snapshot, "test/1")
+        .await?;
+
+    let (latest, _) = get_latest_snapshot(&context, None).await?.unwrap();
+    assert_eq!(latest, "test/2");
+    let names: Vec<String> = list_snapshots(&context, &SnapshotsArgs::default())
+        .await?
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert_eq!(names, ["test/5", "test/2"]);
+
+    // New snapshots take the next free key and follow the newest one.
+    backup(&context, &BackupArgs::default()).await?;
+    let (latest, snapshot) = get_latest_snapshot(&context, None).await?.unwrap();
+    assert_eq!(latest, "test/6");
+    assert_eq!(snapshot.sequence, 3);
+
+    // A damaged snapshot is skipped rather than failing every backup.
+    context
+        .storage
+        .write(Collection::Snapshot, "test/3", b"\xff\xff")
+        .await?;
+    backup(&context, &BackupArgs::default()).await?;
+    let (latest, snapshot) = get_latest_snapshot(&context, None).await?.unwrap();
+    assert_eq!(latest, "test/7");
+    assert_eq!(snapshot.sequence, 4);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_ls_resolve_path() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::create_dir_all(content_dir.path().join("dir_a/dir_b")).await?;
+    fs::write(content_dir.path().join("dir_a/dir_b/hello.txt"), "Hello").await?;
+    fs::write(content_dir.path().join("world.txt"), "World").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+    let snapshot = get_snapshot(&context, "test/1").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+
+    match resolve_path(&context, root.clone(), Path::new("dir_a/dir_b")).await? {
+        PathEntry::Dir(dir_entry) => {
+            assert_eq!(dir_entry.file.len(), 1);
+            assert_eq!(dir_entry.file[0].name, "hello.txt");
+            assert_eq!(dir_entry.size, 5);
+        }
+        PathEntry::File(_) => panic!("Expected a directory"),
+    }
+    match resolve_path(&context, root.clone(), Path::new("/world.txt")).await? {
+        PathEntry::File(file) => assert_eq!(file.size, 5),
+        PathEntry::Dir(_) => panic!("Expected a file"),
+    }
+    for (path, kind) in [
+        ("dir_a/missing", CommandErrorKind::NotFound),
+        ("world.txt/x", CommandErrorKind::NotFound),
+        ("dir_a/../world.txt", CommandErrorKind::User),
+    ] {
+        let error = resolve_path(&context, root.clone(), Path::new(path))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), kind, "{}", path);
+    }
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_sqlite_database() -> Result<(), Box<dyn Error>> {
+    let sqlite = std::process::Command::new("sqlite3")
+        .arg("--version")
+        .output();
+    if !sqlite.is_ok_and(|output| output.status.success()) {
+        debug!("sqlite3 not found, skipping");
+        return Ok(());
+    }
+    let content_dir = tempfile::tempdir()?;
+    let database = content_dir.path().join("app.db");
+    let status = std::process::Command::new("sqlite3")
+        .arg(&database)
+        .arg("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+        .status()?;
+    assert!(status.success());
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: Some(DatabaseConfig {
+            kind: DatabaseKind::Sqlite,
+            database: database.to_string_lossy().into_owned(),
+            args: Vec::new(),
+        }),
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let snapshot = get_snapshot(&context, "test/1").await?;
+    assert_eq!(snapshot.meta["database"], database.to_string_lossy());
+    assert!(snapshot.meta["dump_tool"].starts_with('3'));
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    assert_eq!(root.file.len(), 1);
+    assert_eq!(root.file[0].name, "app.db");
+    // The dump is only kept until it is backed up.
+    assert!(!state_dir.path().join(DATABASE_DUMP_DIR).exists());
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_restore_priority_paths_first() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    for dir in ["etc/app", "data", "srv"] {
+        fs::create_dir_all(content_dir.path().join(dir)).await?;
+    }
+    for file in [
+        "etc/passwd",
+        "etc/app/app.toml",
+        "data/big.bin",
+        "srv/site.conf",
+        "readme.txt",
+    ] {
+        fs::write(content_dir.path().join(file), file).await?;
+    }
+
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let target = MemoryRestoreTarget::default();
+    let args = RestoreArgs {
+        snapshot: Some("1".to_owned()),
+        priority_path: vec!["etc".to_owned(), "*.conf".to_owned()],
+        ..Default::default()
+    };
+    restore_to(&context, &args, &target).await?;
+
+    let created = target.created.lock().unwrap();
+    let mut first: Vec<_> = created[..3]
+        .iter()
+        .map(|path| path.to_str().unwrap())
+        .collect();
+    first.sort();
+    assert_eq!(first, ["etc/app/app.toml", "etc/passwd", "srv/site.conf"]);
+    assert_eq!(created.len(), 5);
+    assert_eq!(target.files.lock().unwrap().len(), 5);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_backup_respects_max_object_size() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+    fs::write(content_dir.path().join("big.bin"), &data).await?;
+
+    let storage = Arc::new(CountingStorage {
+        max_object_size: Some(1000),
+        ..Default::default()
+    });
+    let state_dir = tempfile::tempdir()?;
+    let context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: storage.clone(),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+
+    // Files are split into chunks the storage accepts.
+    backup(&context, &BackupArgs::default()).await?;
+    let snapshot = get_snapshot(&context, "test/1").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    assert_eq!(root.file[0].chunk_size, [1000, 1000, 500]);
+
+    // Blocks too large for the storage leave the file out.
+    let args = BackupArgs {
+        fixed_block: true,
+        allow_empty_source: true,
+        ..Default::default()
+    };
+    backup(&context, &args).await?;
+    let snapshot = get_snapshot(&context, "test/2").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    assert!(root.file.is_empty());
+
+    let args = BackupArgs {
+        fixed_block: true,
+        block_size: Some(1000),
+        ..Default::default()
+    };
+    backup(&context, &args).await?;
+    let snapshot = get_snapshot(&context, "test/3").await?;
+    let root = get_dir_entry(&context, &snapshot.root_hash).await?;
+    assert_eq!(root.file[0].size, 2500);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_repository_stats() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("a.txt"), "Hello".repeat(200)).await?;
+    fs::write(content_dir.path().join("b.txt"), "World!".repeat(200)).await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::new());
+    let context = |archive_name: &str| ProgramContext {
+        archive_name: archive_name.to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: storage.clone(),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: Default::default(),
+    };
+    for archive_name in ["a", "a", "b"] {
+        backup(&context(archive_name), &BackupArgs::default()).await?;
+    }
+
+    let stats = repository_stats(&context("a")).await?;
+    assert_eq!(stats.snapshots, 3);
+    assert_eq!(stats.logical_bytes, 6600);
+    // The two files and the one tree they are in, shared by all snapshots.
+    assert_eq!(stats.referenced_blobs, 3);
+    assert_eq!(stats.blobs, 3);
+    assert!(stats.stored_bytes > 2200 && stats.stored_bytes < 4400);
+    assert!(stats.dedup_ratio() > 1.0);
+    assert_eq!(stats.archives["a"].snapshots, 2);
+    assert_eq!(stats.archives["a"].latest_bytes, 2200);
+    assert_eq!(stats.archives["a"].logical_bytes, 4400);
+    assert_eq!(stats.archives["b"].logical_bytes, 2200);
+
+    Ok(())
+}
+
+#[test(tokio::test)]
+async fn test_runtime_tuning_chunk_size() -> Result<(), Box<dyn Error>> {
+    let content_dir = tempfile::tempdir()?;
+    fs::write(content_dir.path().join("file"), "AAAABBBBCC").await?;
+
+    let state_dir = tempfile::tempdir()?;
+    let mut context = ProgramContext {
+        archive_name: "test".to_owned(),
+        client_id: "test_client".to_owned(),
+        storage: Arc::new(MemoryStorage::new()),
+        backup_target: content_dir.path().into(),
+        state_dir: state_dir.path().into(),
+        hooks: Default::default(),
+        fs_snapshot: None,
+        database: None,
+        compression: Default::default(),
+        tuning: RuntimeTuning {
+            chunk_size: 4,
+            read_buffer_size: 3,
+            chunk_queue_depth: 1,
+            chunk_uploads: 1,
+            open_files: 1,
+            ..Default::default()
+        },
+    };
+    backup(&context, &BackupArgs::default()).await?;
+
+    let root = get_dir_entry(&context, &get_snapshot(&context, "test/1").await?.root_hash).await?;
+    assert_eq!(root.file[0].chunk_size, [4, 4, 2]);
+
+    let restore_dir = tempfile::tempdir()?;
+    context.backup_target = restore_dir.path().into();
+    restore(
+        &context,
+        &RestoreArgs {
+            snapshot: Some("1".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await?;
+    assert_eq!(
+        fs::read_to_string(restore_dir.path().join("file")).await?,
+        "AAAABBBBCC"
+    );
+
+    Ok(())
+}